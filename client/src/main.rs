@@ -8,6 +8,7 @@ extern crate tokio_core;
 extern crate openssl;
 extern crate native_tls;
 extern crate tokio_io;
+extern crate tokio_openssl;
 extern crate bytes;
 #[macro_use]
 extern crate serde_derive;
@@ -20,16 +21,22 @@ extern crate rand;
 use prost::Message;
 use tokio_core::net::TcpStream;
 use tolla_proto::proto;
-use openssl::x509::X509ReqBuilder;
+use openssl::x509::{X509, X509ReqBuilder};
 use openssl::x509::X509NameBuilder;
 use openssl::rsa::Rsa;
+use openssl::ec::{self, EcGroup, EcKey};
+use openssl::nid;
 use tokio_io::AsyncRead;
 use openssl::pkey::PKey;
 use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslConnectorBuilder, SslMethod};
+use openssl::x509::X509_FILETYPE_PEM;
+use tokio_openssl::SslConnectorExt;
 use tokio_core::reactor::Core;
 use futures::{Future, Sink, Stream};
-use std::fs::OpenOptions;
+use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
+use std::io;
 use std::net::SocketAddr;
 use std::fs::File;
 use bson::Bson;
@@ -41,31 +48,62 @@ use std::env;
 use time::{Duration, PreciseTime};
 use rand::*;
 use std::collections::HashMap;
+use std::thread;
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Ca {
     address: String,
     port: String,
+    // name on the CA's control channel certificate
+    server_name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Process {
     common_name: String,
     country: String,
-    intent: String,
+    // what the process uses data for, one certificate covers all
+    intents: Vec<String>,
     state: String,
     org: String,
+    // rsa-2048, rsa-3072, rsa-4096, ecdsa-p256 or ecdsa-p384
+    #[serde(default = "default_key_algorithm")]
+    key_algorithm: String,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_key_algorithm() -> String {
+    String::from("rsa-2048")
+}
+
+// Generate a keypair of algorithm, along with the digest to
+// sign requests with
+fn generate_key(algorithm: &str) -> Result<(PKey, MessageDigest), String> {
+    let (key, digest) = match algorithm {
+        "rsa-2048" => (Rsa::generate(2048).and_then(PKey::from_rsa), MessageDigest::sha256()),
+        "rsa-3072" => (Rsa::generate(3072).and_then(PKey::from_rsa), MessageDigest::sha256()),
+        "rsa-4096" => (Rsa::generate(4096).and_then(PKey::from_rsa), MessageDigest::sha384()),
+        "ecdsa-p256" => (ec_key(nid::X9_62_PRIME256V1), MessageDigest::sha256()),
+        "ecdsa-p384" => (ec_key(nid::SECP384R1), MessageDigest::sha384()),
+        _ => return Err(format!("unknown key algorithm {}", algorithm)),
+    };
+    key.map(|key| (key, digest)).map_err(|e| e.to_string())
+}
+
+fn ec_key(curve: nid::Nid) -> Result<PKey, openssl::error::ErrorStack> {
+    let mut group = EcGroup::from_curve_name(curve)?;
+    group.set_asn1_flag(ec::NAMED_CURVE);
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Certs {
     key: String,
     ca: String,
     cert: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Settings {
     process: Process,
     ca: Ca,
@@ -80,12 +118,125 @@ fn dump_certificate(filename: &str, data: &Vec<u8>) {
     let mut file = OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(filename)
         .unwrap();
 
     file.write_all(&data).unwrap();
 }
 
+// Writes data next to filename so that it can later be renamed into place
+fn stage_file(filename: &str, data: &[u8]) -> io::Result<String> {
+    let staged = format!("{}.tmp", filename);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&staged)?;
+
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(staged)
+}
+
+// Replaces the certificate and key so that readers never see a partial file
+fn replace_certificate(certs: &Certs, cert: &[u8], key: &[u8]) -> io::Result<()> {
+    let staged_cert = stage_file(&certs.cert, cert)?;
+    let staged_key = stage_file(&certs.key, key)?;
+    fs::rename(&staged_key, &certs.key)?;
+    fs::rename(&staged_cert, &certs.cert)
+}
+
+// PEM certificate request for process, signed with pkey
+fn certificate_request(
+    process: &Process,
+    pkey: &PKey,
+    digest: MessageDigest,
+) -> Result<Vec<u8>, String> {
+    let mut cert = match X509ReqBuilder::new() {
+        Ok(cert) => cert,
+        Err(err) => return Err(err.to_string()),
+    };
+
+    if let Err(err) = cert.set_pubkey(&pkey) {
+        return Err(err.to_string());
+    }
+
+    let mut x509_name = match X509NameBuilder::new() {
+        Ok(x509_name) => x509_name,
+        Err(err) => return Err(err.to_string()),
+    };
+
+    if let Err(err) = x509_name.append_entry_by_text("C", &process.country) {
+        return Err(err.to_string());
+    }
+    if let Err(err) = x509_name.append_entry_by_text("ST", &process.state) {
+        return Err(err.to_string());
+    }
+    if let Err(err) = x509_name.append_entry_by_text("O", &process.org) {
+        return Err(err.to_string());
+    }
+    if let Err(err) = x509_name.append_entry_by_text("CN", &process.common_name) {
+        return Err(err.to_string());
+    }
+
+    let x509_name = x509_name.build();
+
+    if let Err(err) = cert.set_subject_name(&x509_name) {
+        return Err(err.to_string());
+    }
+
+    if let Err(err) = cert.sign(&pkey, digest) {
+        return Err(err.to_string());
+    }
+
+    let cert = cert.build();
+
+    cert.to_pem().map_err(|e| e.to_string())
+}
+
+// Time until the certificate in pem should be renewed, two
+// thirds into its lifetime
+fn renewal_delay(pem: &[u8]) -> Result<std::time::Duration, String> {
+    let cert = X509::from_pem(pem).map_err(|e| e.to_string())?;
+
+    let unix = |t: String| {
+        time::strptime(&t, "%b %e %H:%M:%S %Y GMT")
+            .map(|tm| tm.to_timespec().sec)
+            .map_err(|e| e.to_string())
+    };
+    let not_before = unix(cert.not_before().to_string())?;
+    let not_after = unix(cert.not_after().to_string())?;
+
+    let renew_at = not_before + (not_after - not_before) * 2 / 3;
+    let left = renew_at - time::get_time().sec;
+    Ok(std::time::Duration::from_secs(if left > 0 { left as u64 } else { 0 }))
+}
+
+// Keep renewing the process certificate for as long as the
+// client runs. A failed renewal is retried a minute later.
+fn spawn_renewal(conf: Settings) {
+    thread::spawn(move || {
+        let mut tolla_client = TollaClient { core: Core::new().unwrap() };
+        loop {
+            let delay = File::open(&conf.certs.cert)
+                .map_err(|e| e.to_string())
+                .and_then(|mut file| {
+                    let mut pem = Vec::new();
+                    file.read_to_end(&mut pem).map_err(|e| e.to_string())?;
+                    renewal_delay(&pem)
+                })
+                .unwrap_or(std::time::Duration::from_secs(60));
+            thread::sleep(delay);
+
+            if let Err(e) = tolla_client.renew_certificate(&conf) {
+                println!("Renewing certificate failed: {}", e);
+                thread::sleep(std::time::Duration::from_secs(60));
+            }
+        }
+    });
+}
+
 fn setup_mongod(certs: &Certs, address: String) {
     let options = ClientOptions::with_ssl(&certs.ca, &certs.cert, &certs.key, true);
 
@@ -147,6 +298,7 @@ fn main() {
     let mut buf = Vec::new();
 
     tolla_client.retrieve_certificate(&conf, &mut buf).unwrap();
+    spawn_renewal(conf.clone());
 
     let mut msg = proto::FromClient::default();
     msg.msg = Some(proto::from_client::Msg::Requestips(true));
@@ -155,7 +307,8 @@ fn main() {
 
     let socket_addr: SocketAddr = format!("{}:{}", addr, conf.ca.port).parse().unwrap();
 
-    let result = tolla_client.send_protorequest(&socket_addr, msg);
+    let connector = tolla_client.connector(&conf.certs, true).unwrap();
+    let result = tolla_client.send_protorequest(&socket_addr, &conf.ca, &connector, msg);
 
     if let Some(proto::to_client::Msg::Ips(addr)) = result.unwrap().msg {
         setup_mongod(&conf.certs, addr.ip[0].clone());
@@ -164,17 +317,10 @@ fn main() {
 
 impl TollaClient {
     fn retrieve_certificate(&mut self, conf: &Settings, buf: &mut Vec<u8>) -> Result<(), String> {
-        let keypair = match Rsa::generate(1024) {
-            Ok(keypair) => keypair,
-            Err(err) => return Err(err.to_string()),
-        };
-
-        let pkey = match PKey::from_rsa(keypair) {
-            Ok(pkey) => pkey,
-            Err(err) => return Err(err.to_string()),
-        };
+        let (pkey, digest) = generate_key(&conf.process.key_algorithm)?;
 
-        let certificate = self.issue_cert_request(&conf.ca, &conf.process, &pkey)?;
+        let certificate =
+            self.issue_cert_request(&conf.ca, &conf.certs, &conf.process, &pkey, digest)?;
 
         if let proto::to_client::Msg::Certificate(c) = certificate.msg.unwrap() {
             dump_certificate("cert.pem", &c.request);
@@ -187,80 +333,110 @@ impl TollaClient {
 
         Ok(())
     }
+    // Renew the current certificate with a fresh key. The
+    // certificate itself authenticates the renewal.
+    fn renew_certificate(&mut self, conf: &Settings) -> Result<(), String> {
+        let mut current = Vec::new();
+        File::open(&conf.certs.cert)
+            .and_then(|mut file| file.read_to_end(&mut current))
+            .map_err(|e| e.to_string())?;
+
+        let (pkey, digest) = generate_key(&conf.process.key_algorithm)?;
+        let request = certificate_request(&conf.process, &pkey, digest)?;
+
+        let addr = env::var("CA_ADDRESS").unwrap();
+        let socket_addr: SocketAddr = format!("{}:{}", addr, conf.ca.port).parse().unwrap();
+
+        let mut msg = proto::FromClient::default();
+        msg.msg = Some(proto::from_client::Msg::Renewal(proto::Renewal {
+            certificate: current,
+            request: request,
+        }));
+
+        let connector = self.connector(&conf.certs, true)?;
+        let resp = self.send_protorequest(&socket_addr, &conf.ca, &connector, msg)?;
+
+        match resp.msg {
+            Some(proto::to_client::Msg::Certificate(c)) => {
+                let pkey_pem = pkey.private_key_to_pem().map_err(|e| e.to_string())?;
+                replace_certificate(&conf.certs, &c.request, &pkey_pem).map_err(|e| e.to_string())
+            }
+            other => Err(format!("renewal refused: {:?}", other)),
+        }
+    }
+
     fn issue_cert_request(
         &mut self,
         cert_auth: &Ca,
+        certs: &Certs,
         process: &Process,
         pkey: &PKey,
+        digest: MessageDigest,
     ) -> Result<proto::ToClient, String> {
-        let mut cert = match X509ReqBuilder::new() {
-            Ok(cert) => cert,
-            Err(err) => return Err(err.to_string()),
-        };
-
-        if let Err(err) = cert.set_pubkey(&pkey) {
-            return Err(err.to_string());
-        }
-
-        let mut x509_name = match X509NameBuilder::new() {
-            Ok(x509_name) => x509_name,
-            Err(err) => return Err(err.to_string()),
-        };
-
-        if let Err(err) = x509_name.append_entry_by_text("C", &process.country) {
-            return Err(err.to_string());
-        }
-        if let Err(err) = x509_name.append_entry_by_text("ST", &process.state) {
-            return Err(err.to_string());
-        }
-        if let Err(err) = x509_name.append_entry_by_text("O", &process.org) {
-            return Err(err.to_string());
-        }
-        if let Err(err) = x509_name.append_entry_by_text("CN", &process.common_name) {
-            return Err(err.to_string());
-        }
-
-        let x509_name = x509_name.build();
-
-        if let Err(err) = cert.set_subject_name(&x509_name) {
-            return Err(err.to_string());
-        }
-
-        if let Err(err) = cert.sign(&pkey, MessageDigest::sha256()) {
-            return Err(err.to_string());
-        }
-
-        let cert = cert.build();
-
-        let pem = match cert.to_pem() {
-            Ok(pem) => pem,
-            Err(err) => return Err(err.to_string()),
-        };
+        let pem = certificate_request(process, pkey, digest)?;
 
         let addr = env::var("CA_ADDRESS").unwrap();
         let socket_addr: SocketAddr = format!("{}:{}", addr, cert_auth.port).parse().unwrap();
 
+        // Handed out by the CA's /enrollment endpoint
+        let token = env::var("ENROLLMENT_TOKEN").map_err(
+            |_| "ENROLLMENT_TOKEN not set".to_string(),
+        )?;
+
         let mut msg = proto::FromClient::default();
+        msg.enrollment_token = token;
         msg.msg = Some(proto::from_client::Msg::Certificaterequest(
             proto::Certificate {
-                intent: process.intent.clone(),
+                intents: process.intents.clone(),
                 request: pem.clone(),
                 root_cert: Vec::new(),
+                users: Vec::new(),
             },
         ));
 
-        self.send_protorequest(&socket_addr, msg)
+        // No client certificate yet, the token authorizes the request
+        let connector = self.connector(certs, false)?;
+        self.send_protorequest(&socket_addr, cert_auth, &connector, msg)
+    }
+
+    // TLS connector trusting the CA certificate. The client
+    // certificate is only presented once it has been issued.
+    fn connector(&self, certs: &Certs, with_identity: bool) -> Result<SslConnector, String> {
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls()).map_err(
+            |e| e.to_string(),
+        )?;
+
+        {
+            let ctx = builder.builder_mut();
+            ctx.set_ca_file(&certs.ca).map_err(|e| e.to_string())?;
+
+            // The issued certificate is followed by its chain
+            if with_identity {
+                ctx.set_certificate_chain_file(&certs.cert)
+                    .map_err(|e| e.to_string())?;
+                ctx.set_private_key_file(&certs.key, X509_FILETYPE_PEM)
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+
+        Ok(builder.build())
     }
 
     fn send_protorequest(
         &mut self,
         addr: &SocketAddr,
+        cert_auth: &Ca,
+        connector: &SslConnector,
         req: proto::FromClient,
     ) -> Result<proto::ToClient, String> {
         let handle = self.core.handle();
 
         let work = TcpStream::connect(addr, &handle).and_then(|socket| {
-            let transport = socket.framed(proto::ProtoClient);
+            connector
+                .connect_async(&cert_auth.server_name, socket)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }).and_then(|socket| {
+            let transport = socket.framed(proto::ProtoClient::new());
             transport.send(req).and_then(
                 |socket| socket.take(1).collect(),
             )
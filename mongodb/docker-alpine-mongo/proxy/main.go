@@ -30,7 +30,8 @@ type Lease struct {
 
 type Proxy struct {
 	config       *tls.Config
-	serialNumber uint64
+	serialNumber string
+	userID       string
 	portIncoming string
 	dbAddr       string
 	caAddr       string
@@ -43,9 +44,14 @@ type Pipe struct {
 	status int32
 }
 
-type OtherName struct {
-	TypeID asn1.ObjectIdentifier
-	Value  asn1.RawValue
+// The extension tolla puts in process certificates, see
+// tolla/src/extension.rs
+var intentExtensionOID = asn1.ObjectIdentifier{1, 3, 6, 1, 4, 1, 61432, 1, 1}
+
+type IntentExtension struct {
+	Intents    []string
+	LegalBasis asn1.Enumerated
+	Users      []string `asn1:"optional,tag:0"`
 }
 
 func (l *Lease) Expired() bool {
@@ -53,11 +59,18 @@ func (l *Lease) Expired() bool {
 	return time.Since(l.leaseStart).Seconds() > 10
 }
 
-func (p *Proxy) assertPermissions(intent string) error {
+// Granted if the user consented to any of intents. tolla checks
+// the DER certificate clientCert of the process as well.
+func (p *Proxy) assertPermissions(clientCert []byte, intents []string) error {
+	certificate := pem.EncodeToMemory(&pem.Block{Type: "CERTIFICATE", Bytes: clientCert})
+	escapedCertificate := url.PathEscape(string(certificate))
+
 	values := url.Values{}
 
-	values.Add("intent", intent)
-	values.Add("user", fmt.Sprintf("%d", p.serialNumber))
+	for _, intent := range intents {
+		values.Add("intent", intent)
+	}
+	values.Add("user", p.serialNumber)
 
 	url, err := url.Parse(fmt.Sprintf("http://172.17.0.7:3001/lease/"))
 	if err != nil {
@@ -71,6 +84,7 @@ func (p *Proxy) assertPermissions(intent string) error {
 	if err != nil {
 		return err
 	}
+	request.Header.Set("X-Client-Certificate", escapedCertificate)
 
 	resp, err := p.httpClient.Do(request)
 	if err != nil {
@@ -92,8 +106,7 @@ func (p *Proxy) VerifyPeerCertificate(rawCerts [][]byte, verifiedChains [][]*x50
 	for _, chain := range verifiedChains {
 		for _, cert := range chain {
 			for _, ext := range cert.Extensions {
-				oid := asn1.ObjectIdentifier{2, 5, 29, 17}
-				if oid.Equal(ext.Id) {
+				if intentExtensionOID.Equal(ext.Id) {
 					clientCert = cert
 					intentExt = &ext
 					break
@@ -116,50 +129,27 @@ func (p *Proxy) VerifyPeerCertificate(rawCerts [][]byte, verifiedChains [][]*x50
 		}
 	}
 
-	var err error
-	var v asn1.RawValue
-	var rest []byte
-	if rest, err = asn1.Unmarshal(intentExt.Value, &v); err != nil {
-		return fmt.Errorf("failed to marshal extension")
-	}
-	fmt.Println(v.Tag)
-
-	if v.Tag != 16 {
-		fmt.Println(v.Tag)
-		return fmt.Errorf("No othername present")
-	}
-
-	rest = v.Bytes
-	if rest, err = asn1.Unmarshal(rest, &v); err != nil {
-		return fmt.Errorf("failed to marshal extension")
-	}
-
-	if !v.IsCompound {
-		return fmt.Errorf("x509: failed to unmarshal GeneralName.otherName: not compund")
-	}
-
-	fmt.Println(v.Tag)
-	if v.Tag != 0 {
-		fmt.Println(v.Tag)
-		return fmt.Errorf("No othername present")
+	var intents IntentExtension
+	rest, err := asn1.Unmarshal(intentExt.Value, &intents)
+	if err != nil || len(rest) != 0 {
+		return fmt.Errorf("failed to unmarshal intent extension")
 	}
-
-	var other OtherName
-
-	v.FullBytes = append([]byte{}, v.FullBytes...)
-	v.FullBytes[0] = asn1.TagSequence | 0x20
-
-	if _, err = asn1.Unmarshal(v.FullBytes, &other); err != nil {
-		return fmt.Errorf("failed to marshal extension")
+	if len(intents.Intents) == 0 {
+		return fmt.Errorf("No intent present in client certificate")
 	}
 
-	var intent string
-	if _, err = asn1.Unmarshal(other.Value.Bytes, &intent); err != nil {
-		return fmt.Errorf("failed to marshal extension")
+	// Certificates limited to some users only get their databases
+	if len(intents.Users) != 0 {
+		allowed := false
+		for _, user := range intents.Users {
+			allowed = allowed || user == p.userID
+		}
+		if !allowed {
+			return fmt.Errorf("Certificate is not for this user")
+		}
 	}
 
-	err = p.assertPermissions(intent)
-	if err != nil {
+	if err := p.assertPermissions(clientCert.Raw, intents.Intents); err != nil {
 		return err
 	}
 
@@ -263,11 +253,18 @@ func main() {
 		os.Exit(1)
 	}
 
+	// Database certificates are issued for the user's container name
+	userID := ""
+	if len(serverCert.DNSNames) != 0 {
+		userID = serverCert.DNSNames[0]
+	}
+
 	clientCertPool := x509.NewCertPool()
 	clientCertPool.AppendCertsFromPEM(caCert)
 
 	proxy := &Proxy{
-		serialNumber: serverCert.SerialNumber.Uint64(),
+		serialNumber: serverCert.SerialNumber.Text(16),
+		userID:       userID,
 		portIncoming: portIncoming,
 		httpClient:   &http.Client{},
 		dbAddr:       dbAddr,
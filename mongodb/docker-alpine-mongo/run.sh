@@ -5,6 +5,11 @@
 # Make sure that database is owned by user mongodb
 [ "$(stat -c %U /data/db)" = mongodb ] || chown -R mongodb /data/db
 
+# tolla writes the certificates once the container has an address
+for f in keys.pem certificate.pem CAcert.pem; do
+	while [ ! -s "$PEM_FOLDER/$f" ]; do sleep 1; done
+done
+
 exec /root/app &
 
 mkdir -p /etc/mongodb/ssl
@@ -4,7 +4,7 @@
 
 extern crate libc;
 
-use libc::{c_void, c_int, c_char, c_ulong, c_long, c_uint, c_uchar, size_t, FILE};
+use libc::{c_void, c_int, c_char, c_ulong, c_long, c_uint, c_uchar, size_t, time_t, FILE};
 use std::ptr;
 use std::mem;
 
@@ -24,11 +24,12 @@ mod libressl;
 pub use libressl::*;
 
 pub enum ASN1_INTEGER {}
+pub enum ASN1_ENUMERATED {}
 pub enum ASN1_GENERALIZEDTIME {}
 pub enum ASN1_STRING {}
 pub enum ASN1_BIT_STRING {}
+pub enum ASN1_OCTET_STRING {}
 pub enum ASN1_TIME {}
-pub enum ASN1_TYPE {}
 pub enum ASN1_OBJECT {}
 pub enum BN_CTX {}
 pub enum BN_GENCB {}
@@ -49,9 +50,11 @@ pub enum OCSP_CERTID {}
 pub enum OCSP_RESPONSE {}
 pub enum OCSP_REQUEST {}
 pub enum OCSP_ONEREQ {}
+pub enum OCSP_SINGLERESP {}
 pub enum SSL_CIPHER {}
 pub enum SSL_METHOD {}
 pub enum X509_CRL {}
+pub enum X509_REVOKED {}
 pub enum X509_EXTENSION {}
 pub enum X509_NAME {}
 pub enum X509_NAME_ENTRY {}
@@ -115,6 +118,18 @@ pub struct GENERAL_NAME {
     pub d: *mut c_void,
 }
 
+#[repr(C)]
+pub struct OTHERNAME {
+    pub type_id: *mut ASN1_OBJECT,
+    pub value: *mut ASN1_TYPE,
+}
+
+#[repr(C)]
+pub struct ASN1_TYPE {
+    pub type_: c_int,
+    pub value: *mut c_void,
+}
+
 #[repr(C)]
 pub struct X509V3_CTX {
     flags: c_int,
@@ -1186,6 +1201,18 @@ pub const OCSP_REVOKED_STATUS_CESSATIONOFOPERATION: c_int = 5;
 pub const OCSP_REVOKED_STATUS_CERTIFICATEHOLD: c_int = 6;
 pub const OCSP_REVOKED_STATUS_REMOVEFROMCRL: c_int = 8;
 
+pub const CRL_REASON_NONE: c_int = -1;
+pub const CRL_REASON_UNSPECIFIED: c_int = 0;
+pub const CRL_REASON_KEY_COMPROMISE: c_int = 1;
+pub const CRL_REASON_CA_COMPROMISE: c_int = 2;
+pub const CRL_REASON_AFFILIATION_CHANGED: c_int = 3;
+pub const CRL_REASON_SUPERSEDED: c_int = 4;
+pub const CRL_REASON_CESSATION_OF_OPERATION: c_int = 5;
+pub const CRL_REASON_CERTIFICATE_HOLD: c_int = 6;
+pub const CRL_REASON_REMOVE_FROM_CRL: c_int = 8;
+pub const CRL_REASON_PRIVILEGE_WITHDRAWN: c_int = 9;
+pub const CRL_REASON_AA_COMPROMISE: c_int = 10;
+
 pub const OCSP_RESPONSE_STATUS_SUCCESSFUL: c_int = 0;
 pub const OCSP_RESPONSE_STATUS_MALFORMEDREQUEST: c_int = 1;
 pub const OCSP_RESPONSE_STATUS_INTERNALERROR: c_int = 2;
@@ -1307,6 +1334,10 @@ pub const OPENSSL_NPN_UNSUPPORTED: c_int = 0;
 pub const OPENSSL_NPN_NEGOTIATED: c_int = 1;
 pub const OPENSSL_NPN_NO_OVERLAP: c_int = 2;
 
+pub const V_ASN1_BOOLEAN: c_int = 1;
+pub const V_ASN1_NULL: c_int = 5;
+pub const V_ASN1_OBJECT: c_int = 6;
+pub const V_ASN1_UTF8STRING: c_int = 12;
 pub const V_ASN1_GENERALIZEDTIME: c_int = 24;
 pub const V_ASN1_UTCTIME: c_int = 23;
 
@@ -1362,12 +1393,15 @@ pub const X509_V_ERR_UNABLE_TO_VERIFY_LEAF_SIGNATURE: c_int = 21;
 pub const X509_V_ERR_UNHANDLED_CRITICAL_CRL_EXTENSION: c_int = 36;
 pub const X509_V_ERR_UNHANDLED_CRITICAL_EXTENSION: c_int = 34;
 pub const X509_V_ERR_UNNESTED_RESOURCE: c_int = 46;
+pub const X509_V_ERR_UNSPECIFIED: c_int = 1;
 pub const X509_V_ERR_UNSUPPORTED_CONSTRAINT_SYNTAX: c_int = 52;
 pub const X509_V_ERR_UNSUPPORTED_CONSTRAINT_TYPE: c_int = 51;
 pub const X509_V_ERR_UNSUPPORTED_EXTENSION_FEATURE: c_int = 45;
 pub const X509_V_ERR_UNSUPPORTED_NAME_SYNTAX: c_int = 53;
 pub const X509_V_OK: c_int = 0;
 
+pub const X509_V_FLAG_CRL_CHECK: c_ulong = 0x4;
+
 #[cfg(not(any(ossl101, libressl)))]
 pub const X509_CHECK_FLAG_ALWAYS_CHECK_SUBJECT: c_uint = 0x1;
 #[cfg(not(any(ossl101, libressl)))]
@@ -1553,13 +1587,32 @@ extern "C" {
 
     pub fn ASN1_INTEGER_get(dest: *const ASN1_INTEGER) -> c_long;
     pub fn ASN1_INTEGER_set(dest: *mut ASN1_INTEGER, value: c_long) -> c_int;
+    pub fn ASN1_INTEGER_to_BN(ai: *const ASN1_INTEGER, bn: *mut BIGNUM) -> *mut BIGNUM;
+    pub fn ASN1_ENUMERATED_new() -> *mut ASN1_ENUMERATED;
+    pub fn ASN1_ENUMERATED_free(a: *mut ASN1_ENUMERATED);
+    pub fn ASN1_ENUMERATED_set(a: *mut ASN1_ENUMERATED, v: c_long) -> c_int;
     pub fn ASN1_GENERALIZEDTIME_free(tm: *mut ASN1_GENERALIZEDTIME);
     pub fn ASN1_GENERALIZEDTIME_print(b: *mut BIO, tm: *const ASN1_GENERALIZEDTIME) -> c_int;
     pub fn ASN1_STRING_type_new(ty: c_int) -> *mut ASN1_STRING;
     pub fn ASN1_TIME_free(tm: *mut ASN1_TIME);
+    pub fn ASN1_TIME_set(tm: *mut ASN1_TIME, t: time_t) -> *mut ASN1_TIME;
     pub fn ASN1_TIME_print(b: *mut BIO, tm: *const ASN1_TIME) -> c_int;
+    #[cfg(not(any(ossl101, libressl)))]
+    pub fn ASN1_TIME_diff(
+        pday: *mut c_int,
+        psec: *mut c_int,
+        from: *const ASN1_TIME,
+        to: *const ASN1_TIME,
+    ) -> c_int;
     pub fn ASN1_BIT_STRING_free(x: *mut ASN1_BIT_STRING);
     pub fn ASN1_OBJECT_free(x: *mut ASN1_OBJECT);
+    pub fn ASN1_OCTET_STRING_new() -> *mut ASN1_OCTET_STRING;
+    pub fn ASN1_OCTET_STRING_free(x: *mut ASN1_OCTET_STRING);
+    pub fn ASN1_OCTET_STRING_set(
+        x: *mut ASN1_OCTET_STRING,
+        data: *const c_uchar,
+        len: c_int,
+    ) -> c_int;
 
     pub fn BIO_ctrl(b: *mut BIO, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
     pub fn BIO_free_all(b: *mut BIO);
@@ -1836,6 +1889,7 @@ extern "C" {
     pub fn ERR_reason_error_string(err: c_ulong) -> *const c_char;
     pub fn ERR_clear_error();
 
+    pub fn EVP_get_digestbyname(name: *const c_char) -> *const EVP_MD;
     pub fn EVP_md5() -> *const EVP_MD;
     pub fn EVP_ripemd160() -> *const EVP_MD;
     pub fn EVP_sha1() -> *const EVP_MD;
@@ -1966,6 +2020,7 @@ extern "C" {
     pub fn EVP_PKEY_get1_DH(k: *mut EVP_PKEY) -> *mut DH;
     pub fn EVP_PKEY_get1_EC_KEY(k: *mut EVP_PKEY) -> *mut EC_KEY;
     pub fn EVP_PKEY_cmp(a: *const EVP_PKEY, b: *const EVP_PKEY) -> c_int;
+    pub fn EVP_PKEY_id(pkey: *const EVP_PKEY) -> c_int;
     pub fn EVP_PKEY_new_mac_key(
         type_: c_int,
         e: *mut ENGINE,
@@ -1996,6 +2051,8 @@ extern "C" {
     pub fn HMAC_CTX_copy(dst: *mut HMAC_CTX, src: *mut HMAC_CTX) -> c_int;
 
     pub fn OBJ_obj2nid(o: *const ASN1_OBJECT) -> c_int;
+    pub fn OBJ_nid2sn(n: c_int) -> *const c_char;
+    pub fn OBJ_txt2obj(s: *const c_char, no_name: c_int) -> *mut ASN1_OBJECT;
     pub fn OBJ_obj2txt(
         buf: *mut c_char,
         buf_len: c_int,
@@ -2020,6 +2077,25 @@ extern "C" {
         thisupd: *mut *mut ASN1_GENERALIZEDTIME,
         nextupd: *mut *mut ASN1_GENERALIZEDTIME,
     ) -> c_int;
+    pub fn OCSP_basic_add1_status(
+        rsp: *mut OCSP_BASICRESP,
+        cid: *mut OCSP_CERTID,
+        status: c_int,
+        reason: c_int,
+        revtime: *mut ASN1_TIME,
+        thisupd: *mut ASN1_TIME,
+        nextupd: *mut ASN1_TIME,
+    ) -> *mut OCSP_SINGLERESP;
+    pub fn OCSP_basic_sign(
+        brsp: *mut OCSP_BASICRESP,
+        signer: *mut X509,
+        key: *mut EVP_PKEY,
+        dgst: *const EVP_MD,
+        certs: *mut stack_st_X509,
+        flags: c_ulong,
+    ) -> c_int;
+    pub fn OCSP_copy_nonce(resp: *mut OCSP_BASICRESP, req: *mut OCSP_REQUEST) -> c_int;
+    pub fn OCSP_check_nonce(req: *mut OCSP_REQUEST, bs: *mut OCSP_BASICRESP) -> c_int;
     pub fn OCSP_check_validity(
         thisupd: *mut ASN1_GENERALIZEDTIME,
         nextupd: *mut ASN1_GENERALIZEDTIME,
@@ -2028,6 +2104,14 @@ extern "C" {
     ) -> c_int;
 
     pub fn OCSP_CERTID_free(id: *mut OCSP_CERTID);
+    pub fn OCSP_id_get0_info(
+        piNameHash: *mut *mut ASN1_OCTET_STRING,
+        pmd: *mut *mut ASN1_OBJECT,
+        pikeyHash: *mut *mut ASN1_OCTET_STRING,
+        pserial: *mut *mut ASN1_INTEGER,
+        cid: *mut OCSP_CERTID,
+    ) -> c_int;
+    pub fn OCSP_id_issuer_cmp(a: *mut OCSP_CERTID, b: *mut OCSP_CERTID) -> c_int;
 
     pub fn OCSP_RESPONSE_new() -> *mut OCSP_RESPONSE;
     pub fn OCSP_RESPONSE_free(r: *mut OCSP_RESPONSE);
@@ -2050,6 +2134,10 @@ extern "C" {
         length: c_long,
     ) -> *mut OCSP_REQUEST;
     pub fn OCSP_request_add0_id(r: *mut OCSP_REQUEST, id: *mut OCSP_CERTID) -> *mut OCSP_ONEREQ;
+    pub fn OCSP_request_add1_nonce(req: *mut OCSP_REQUEST, val: *mut c_uchar, len: c_int) -> c_int;
+    pub fn OCSP_request_onereq_count(req: *mut OCSP_REQUEST) -> c_int;
+    pub fn OCSP_request_onereq_get0(req: *mut OCSP_REQUEST, i: c_int) -> *mut OCSP_ONEREQ;
+    pub fn OCSP_onereq_get0_id(one: *mut OCSP_ONEREQ) -> *mut OCSP_CERTID;
 
     pub fn OCSP_ONEREQ_free(r: *mut OCSP_ONEREQ);
 
@@ -2065,6 +2153,12 @@ extern "C" {
         callback: Option<PasswordCallback>,
         user_data: *mut c_void,
     ) -> *mut X509;
+    pub fn PEM_read_bio_X509_CRL(
+        bio: *mut BIO,
+        out: *mut *mut X509_CRL,
+        callback: Option<PasswordCallback>,
+        user_data: *mut c_void,
+    ) -> *mut X509_CRL;
     pub fn PEM_read_bio_X509_REQ(
         bio: *mut BIO,
         out: *mut *mut X509_REQ,
@@ -2154,6 +2248,7 @@ extern "C" {
 
     pub fn PEM_write_bio_X509(bio: *mut BIO, x509: *mut X509) -> c_int;
     pub fn PEM_write_bio_X509_REQ(bio: *mut BIO, x509: *mut X509_REQ) -> c_int;
+    pub fn PEM_write_bio_X509_CRL(bio: *mut BIO, x509: *mut X509_CRL) -> c_int;
 
     pub fn PEM_write_bio_ECPrivateKey(
         bio: *mut BIO,
@@ -2501,6 +2596,8 @@ extern "C" {
     pub fn SSL_get0_alpn_selected(s: *const SSL, data: *mut *const c_uchar, len: *mut c_uint);
 
     pub fn X509_add_ext(x: *mut X509, ext: *mut X509_EXTENSION, loc: c_int) -> c_int;
+    pub fn X509_get_ext_by_OBJ(x: *const X509, obj: *const ASN1_OBJECT, lastpos: c_int) -> c_int;
+    pub fn X509_get_ext(x: *const X509, loc: c_int) -> *mut X509_EXTENSION;
     pub fn X509_digest(
         x: *const X509,
         digest: *const EVP_MD,
@@ -2528,6 +2625,16 @@ extern "C" {
     pub fn X509_ALGOR_free(x: *mut X509_ALGOR);
 
     pub fn X509_EXTENSION_free(ext: *mut X509_EXTENSION);
+    pub fn X509_EXTENSION_create_by_OBJ(
+        ex: *mut *mut X509_EXTENSION,
+        obj: *const ASN1_OBJECT,
+        crit: c_int,
+        data: *mut ASN1_OCTET_STRING,
+    ) -> *mut X509_EXTENSION;
+    pub fn X509_EXTENSION_get_data(ext: *mut X509_EXTENSION) -> *mut ASN1_OCTET_STRING;
+    pub fn X509_EXTENSION_get_object(ext: *mut X509_EXTENSION) -> *mut ASN1_OBJECT;
+    pub fn X509_EXTENSION_get_critical(ext: *const X509_EXTENSION) -> c_int;
+    pub fn X509V3_EXT_d2i(ext: *mut X509_EXTENSION) -> *mut c_void;
 
     pub fn X509_NAME_new() -> *mut X509_NAME;
     pub fn X509_NAME_free(x: *mut X509_NAME);
@@ -2541,6 +2648,8 @@ extern "C" {
         set: c_int,
     ) -> c_int;
     pub fn X509_NAME_get_index_by_NID(n: *mut X509_NAME, nid: c_int, last_pos: c_int) -> c_int;
+    pub fn X509_NAME_entry_count(n: *mut X509_NAME) -> c_int;
+    pub fn X509_NAME_ENTRY_get_object(ne: *mut X509_NAME_ENTRY) -> *mut ASN1_OBJECT;
 
     pub fn X509_NAME_ENTRY_free(x: *mut X509_NAME_ENTRY);
 
@@ -2554,7 +2663,19 @@ extern "C" {
     pub fn X509_STORE_add_cert(store: *mut X509_STORE, x: *mut X509) -> c_int;
     pub fn X509_STORE_set_default_paths(store: *mut X509_STORE) -> c_int;
 
+    pub fn X509_STORE_CTX_new() -> *mut X509_STORE_CTX;
     pub fn X509_STORE_CTX_free(ctx: *mut X509_STORE_CTX);
+    pub fn X509_STORE_CTX_init(
+        ctx: *mut X509_STORE_CTX,
+        store: *mut X509_STORE,
+        x509: *mut X509,
+        chain: *mut stack_st_X509,
+    ) -> c_int;
+    pub fn X509_STORE_CTX_cleanup(ctx: *mut X509_STORE_CTX);
+    pub fn X509_STORE_CTX_set0_crls(ctx: *mut X509_STORE_CTX, crls: *mut stack_st_X509_CRL);
+    pub fn X509_STORE_CTX_set_flags(ctx: *mut X509_STORE_CTX, flags: c_ulong);
+    pub fn X509_STORE_CTX_get1_chain(ctx: *mut X509_STORE_CTX) -> *mut stack_st_X509;
+    pub fn X509_verify_cert(ctx: *mut X509_STORE_CTX) -> c_int;
     pub fn X509_STORE_CTX_get_current_cert(ctx: *mut X509_STORE_CTX) -> *mut X509;
     pub fn X509_STORE_CTX_get_error(ctx: *mut X509_STORE_CTX) -> c_int;
     pub fn X509_STORE_CTX_get_ex_data(ctx: *mut X509_STORE_CTX, idx: c_int) -> *mut c_void;
@@ -2577,6 +2698,42 @@ extern "C" {
     pub fn X509_REQ_add_extensions(req: *mut X509_REQ, exts: *mut stack_st_X509_EXTENSION)
         -> c_int;
     pub fn X509_REQ_sign(x: *mut X509_REQ, pkey: *mut EVP_PKEY, md: *const EVP_MD) -> c_int;
+    pub fn X509_REQ_verify(x: *mut X509_REQ, pkey: *mut EVP_PKEY) -> c_int;
+    pub fn X509_REQ_get_extensions(req: *mut X509_REQ) -> *mut stack_st_X509_EXTENSION;
+
+    pub fn X509_CRL_new() -> *mut X509_CRL;
+    pub fn X509_CRL_free(crl: *mut X509_CRL);
+    pub fn X509_CRL_set_version(crl: *mut X509_CRL, version: c_long) -> c_int;
+    pub fn X509_CRL_set_issuer_name(crl: *mut X509_CRL, name: *mut X509_NAME) -> c_int;
+    pub fn X509_CRL_add0_revoked(crl: *mut X509_CRL, rev: *mut X509_REVOKED) -> c_int;
+    pub fn X509_CRL_add1_ext_i2d(
+        crl: *mut X509_CRL,
+        nid: c_int,
+        value: *mut c_void,
+        crit: c_int,
+        flags: c_ulong,
+    ) -> c_int;
+    pub fn X509_CRL_sort(crl: *mut X509_CRL) -> c_int;
+    pub fn X509_CRL_sign(crl: *mut X509_CRL, pkey: *mut EVP_PKEY, md: *const EVP_MD) -> c_int;
+    pub fn X509_CRL_verify(crl: *mut X509_CRL, pkey: *mut EVP_PKEY) -> c_int;
+    pub fn X509_CRL_get0_by_serial(
+        crl: *mut X509_CRL,
+        ret: *mut *mut X509_REVOKED,
+        serial: *mut ASN1_INTEGER,
+    ) -> c_int;
+
+    pub fn X509_REVOKED_new() -> *mut X509_REVOKED;
+    pub fn X509_REVOKED_free(rev: *mut X509_REVOKED);
+    pub fn X509_REVOKED_set_serialNumber(rev: *mut X509_REVOKED, serial: *mut ASN1_INTEGER)
+        -> c_int;
+    pub fn X509_REVOKED_set_revocationDate(rev: *mut X509_REVOKED, tm: *mut ASN1_TIME) -> c_int;
+    pub fn X509_REVOKED_add1_ext_i2d(
+        rev: *mut X509_REVOKED,
+        nid: c_int,
+        value: *mut c_void,
+        crit: c_int,
+        flags: c_ulong,
+    ) -> c_int;
 
     #[cfg(not(ossl101))]
     pub fn X509_VERIFY_PARAM_free(param: *mut X509_VERIFY_PARAM);
@@ -2621,6 +2778,12 @@ extern "C" {
     pub fn i2d_X509(x: *mut X509, buf: *mut *mut u8) -> c_int;
     pub fn i2d_X509_REQ_bio(b: *mut BIO, x: *mut X509_REQ) -> c_int;
     pub fn i2d_X509_REQ(x: *mut X509_REQ, buf: *mut *mut u8) -> c_int;
+    pub fn d2i_X509_CRL(
+        a: *mut *mut X509_CRL,
+        pp: *mut *const c_uchar,
+        length: c_long,
+    ) -> *mut X509_CRL;
+    pub fn i2d_X509_CRL(x: *mut X509_CRL, buf: *mut *mut u8) -> c_int;
 
     pub fn d2i_AutoPrivateKey(
         a: *mut *mut EVP_PKEY,
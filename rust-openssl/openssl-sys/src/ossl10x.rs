@@ -34,6 +34,11 @@ pub struct stack_st_X509_EXTENSION {
     pub stack: _STACK,
 }
 
+#[repr(C)]
+pub struct stack_st_X509_CRL {
+    pub stack: _STACK,
+}
+
 #[repr(C)]
 pub struct stack_st_GENERAL_NAME {
     pub stack: _STACK,
@@ -914,6 +919,8 @@ extern "C" {
     pub fn X509_get_subject_name(x: *mut ::X509) -> *mut ::X509_NAME;
     pub fn X509_set_notAfter(x: *mut ::X509, tm: *const ::ASN1_TIME) -> c_int;
     pub fn X509_set_notBefore(x: *mut ::X509, tm: *const ::ASN1_TIME) -> c_int;
+    pub fn X509_CRL_set_lastUpdate(crl: *mut ::X509_CRL, tm: *const ::ASN1_TIME) -> c_int;
+    pub fn X509_CRL_set_nextUpdate(crl: *mut ::X509_CRL, tm: *const ::ASN1_TIME) -> c_int;
     pub fn X509_get_ext_d2i(
         x: *mut ::X509,
         nid: c_int,
@@ -26,6 +26,7 @@ pub enum stack_st_X509 {}
 pub enum stack_st_X509_NAME {}
 pub enum stack_st_X509_ATTRIBUTE {}
 pub enum stack_st_X509_EXTENSION {}
+pub enum stack_st_X509_CRL {}
 pub enum stack_st_SSL_CIPHER {}
 pub enum OPENSSL_INIT_SETTINGS {}
 pub enum X509 {}
@@ -101,6 +102,8 @@ extern "C" {
     pub fn X509_get_subject_name(x: *const ::X509) -> *mut ::X509_NAME;
     pub fn X509_set1_notAfter(x: *mut ::X509, tm: *const ::ASN1_TIME) -> c_int;
     pub fn X509_set1_notBefore(x: *mut ::X509, tm: *const ::ASN1_TIME) -> c_int;
+    pub fn X509_CRL_set1_lastUpdate(crl: *mut ::X509_CRL, tm: *const ::ASN1_TIME) -> c_int;
+    pub fn X509_CRL_set1_nextUpdate(crl: *mut ::X509_CRL, tm: *const ::ASN1_TIME) -> c_int;
     pub fn X509_get_ext_d2i(
         x: *const ::X509,
         nid: c_int,
@@ -26,7 +26,8 @@
 //! ```
 use ffi;
 use foreign_types::{ForeignType, ForeignTypeRef};
-use libc::{c_long, c_char, c_int};
+use libc::{c_long, c_char, c_int, time_t};
+use std::ffi::CString;
 use std::fmt;
 use std::ptr;
 use std::slice;
@@ -34,8 +35,10 @@ use std::str;
 
 use {cvt, cvt_p};
 use bio::MemBio;
+use bn::BigNum;
 use error::ErrorStack;
 use nid::Nid;
+use stack::Stackable;
 use string::OpensslString;
 
 foreign_type! {
@@ -93,6 +96,48 @@ foreign_type! {
     pub struct Asn1TimeRef;
 }
 
+/// The difference between two [`Asn1Time`]s, see [`Asn1TimeRef::diff`]
+///
+/// [`Asn1Time`]: struct.Asn1Time.html
+/// [`Asn1TimeRef::diff`]: struct.Asn1TimeRef.html#method.diff
+#[cfg(any(ossl102, ossl110))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimeDiff {
+    /// Whole days between the times
+    pub days: c_int,
+    /// Seconds on top of `days`, with the same sign
+    pub secs: c_int,
+}
+
+impl Asn1TimeRef {
+    /// Find the time from this time to `compare`.
+    ///
+    /// This corresponds to [`ASN1_TIME_diff`].
+    ///
+    /// Requires OpenSSL 1.0.2 or newer.
+    ///
+    /// [`ASN1_TIME_diff`]: https://www.openssl.org/docs/man1.1.0/crypto/ASN1_TIME_diff.html
+    #[cfg(any(ossl102, ossl110))]
+    pub fn diff(&self, compare: &Asn1TimeRef) -> Result<TimeDiff, ErrorStack> {
+        let mut days = 0;
+        let mut secs = 0;
+
+        unsafe {
+            cvt(ffi::ASN1_TIME_diff(
+                &mut days,
+                &mut secs,
+                self.as_ptr(),
+                compare.as_ptr(),
+            ))?;
+        }
+
+        Ok(TimeDiff {
+            days: days,
+            secs: secs,
+        })
+    }
+}
+
 impl fmt::Display for Asn1TimeRef {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         unsafe {
@@ -117,6 +162,16 @@ impl Asn1Time {
     pub fn days_from_now(days: u32) -> Result<Asn1Time, ErrorStack> {
         Asn1Time::from_period(days as c_long * 60 * 60 * 24)
     }
+
+    /// Creates a new time from a number of seconds since the Unix epoch
+    pub fn from_unix(time: i64) -> Result<Asn1Time, ErrorStack> {
+        ffi::init();
+
+        unsafe {
+            let handle = cvt_p(ffi::ASN1_TIME_set(ptr::null_mut(), time as time_t))?;
+            Ok(Asn1Time::from_ptr(handle))
+        }
+    }
 }
 
 foreign_type! {
@@ -199,6 +254,20 @@ impl Asn1IntegerRef {
     pub fn get(&self) -> i64 {
         unsafe { ::ffi::ASN1_INTEGER_get(self.as_ptr()) as i64 }
     }
+
+    /// Converts the integer into a `BigNum`.
+    ///
+    /// Unlike `get`, this works for integers of any size, such as certificate serial numbers.
+    ///
+    /// OpenSSL documentation at [`ASN1_INTEGER_to_BN`].
+    ///
+    /// [`ASN1_INTEGER_to_BN`]: https://www.openssl.org/docs/man1.1.0/crypto/ASN1_INTEGER_to_BN.html
+    pub fn to_bn(&self) -> Result<BigNum, ErrorStack> {
+        unsafe {
+            cvt_p(::ffi::ASN1_INTEGER_to_BN(self.as_ptr(), ptr::null_mut()))
+                .map(|bn| BigNum::from_ptr(bn))
+        }
+    }
     /// Sets the ASN.1 value to the value of a signed 32-bit integer, for larger numbers
     /// see [`bn`].
     ///
@@ -262,6 +331,25 @@ foreign_type! {
     pub struct Asn1ObjectRef;
 }
 
+impl Stackable for Asn1Object {
+    type StackType = ffi::stack_st_ASN1_OBJECT;
+}
+
+impl str::FromStr for Asn1Object {
+    type Err = ErrorStack;
+
+    /// Constructs an `Asn1Object` from its dotted decimal form, such as `"2.5.29.17"`.
+    ///
+    /// The OID does not need to be known to OpenSSL.
+    fn from_str(txt: &str) -> Result<Asn1Object, ErrorStack> {
+        let txt = CString::new(txt).unwrap();
+        unsafe {
+            ffi::init();
+            cvt_p(ffi::OBJ_txt2obj(txt.as_ptr(), 1)).map(Asn1Object)
+        }
+    }
+}
+
 impl Asn1ObjectRef {
     /// Returns the NID associated with this OID.
     pub fn nid(&self) -> Nid {
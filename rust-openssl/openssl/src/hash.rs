@@ -11,6 +11,7 @@ use ffi::{EVP_MD_CTX_create as EVP_MD_CTX_new, EVP_MD_CTX_destroy as EVP_MD_CTX_
 
 use {cvt, cvt_p};
 use error::ErrorStack;
+use nid::Nid;
 
 #[derive(Copy, Clone)]
 pub struct MessageDigest(*const ffi::EVP_MD);
@@ -44,6 +45,23 @@ impl MessageDigest {
         unsafe { MessageDigest(ffi::EVP_ripemd160()) }
     }
 
+    /// Returns the digest with the algorithm identifier `nid`, if OpenSSL knows it.
+    pub fn from_nid(nid: Nid) -> Option<MessageDigest> {
+        unsafe {
+            ffi::init();
+            let name = ffi::OBJ_nid2sn(nid.as_raw());
+            if name.is_null() {
+                return None;
+            }
+            let digest = ffi::EVP_get_digestbyname(name);
+            if digest.is_null() {
+                None
+            } else {
+                Some(MessageDigest(digest))
+            }
+        }
+    }
+
     pub fn as_ptr(&self) -> *const ffi::EVP_MD {
         self.0
     }
@@ -281,6 +299,7 @@ pub fn hash2(t: MessageDigest, data: &[u8]) -> Result<DigestBytes, ErrorStack> {
 mod tests {
     use hex::{FromHex, ToHex};
     use std::io::prelude::*;
+    use nid;
 
     use super::*;
 
@@ -404,4 +423,11 @@ mod tests {
             hash_test(MessageDigest::ripemd160(), test);
         }
     }
+
+    #[test]
+    fn test_from_nid() {
+        let digest = MessageDigest::from_nid(nid::SHA256).unwrap();
+        assert_eq!(digest.as_ptr(), MessageDigest::sha256().as_ptr());
+        assert!(MessageDigest::from_nid(nid::COMMONNAME).is_none());
+    }
 }
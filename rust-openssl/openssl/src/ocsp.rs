@@ -5,9 +5,10 @@ use std::ptr;
 use std::mem;
 
 use {cvt, cvt_p};
-use asn1::Asn1GeneralizedTimeRef;
+use asn1::{Asn1GeneralizedTimeRef, Asn1IntegerRef, Asn1ObjectRef, Asn1TimeRef};
 use error::ErrorStack;
 use hash::MessageDigest;
+use pkey::PKeyRef;
 use stack::StackRef;
 use x509::store::X509StoreRef;
 use x509::{X509, X509Ref};
@@ -144,7 +145,76 @@ foreign_type! {
     pub struct OcspBasicResponseRef;
 }
 
+impl OcspBasicResponse {
+    /// Creates an empty basic response.
+    ///
+    /// Statuses are added with `add_status`, and the response must be signed before it is
+    /// sent.
+    pub fn new() -> Result<OcspBasicResponse, ErrorStack> {
+        unsafe {
+            ffi::init();
+
+            cvt_p(ffi::OCSP_BASICRESP_new()).map(OcspBasicResponse)
+        }
+    }
+}
+
 impl OcspBasicResponseRef {
+    /// Adds the status of the certificate identified by `id`.
+    ///
+    /// `reason` and `revocation_time` are only used if `status` is `CERT_STATUS_REVOKED`.
+    pub fn add_status(
+        &mut self,
+        id: &OcspCertIdRef,
+        status: OcspCertStatus,
+        reason: OcspRevokedStatus,
+        revocation_time: Option<&Asn1TimeRef>,
+        this_update: &Asn1TimeRef,
+        next_update: &Asn1TimeRef,
+    ) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt_p(ffi::OCSP_basic_add1_status(
+                self.as_ptr(),
+                id.as_ptr(),
+                status.as_raw(),
+                reason.as_raw(),
+                revocation_time.map(|t| t.as_ptr()).unwrap_or(ptr::null_mut()),
+                this_update.as_ptr(),
+                next_update.as_ptr(),
+            )).map(|_| ())
+        }
+    }
+
+    /// Copies the nonce of `req` into the response, if the request has one.
+    pub fn copy_nonce(&mut self, req: &OcspRequestRef) -> Result<(), ErrorStack> {
+        unsafe { cvt(ffi::OCSP_copy_nonce(self.as_ptr(), req.as_ptr())).map(|_| ()) }
+    }
+
+    /// Signs the response.
+    ///
+    /// `signer` is either the issuer of the certificates in the response, or a responder
+    /// certificate it delegated OCSP signing to. Unless `FLAG_NO_CERTS` is set, `signer` and
+    /// `certs` are included in the response.
+    pub fn sign(
+        &mut self,
+        signer: &X509Ref,
+        key: &PKeyRef,
+        digest: MessageDigest,
+        certs: Option<&StackRef<X509>>,
+        flags: Flag,
+    ) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(ffi::OCSP_basic_sign(
+                self.as_ptr(),
+                signer.as_ptr(),
+                key.as_ptr(),
+                digest.as_ptr(),
+                certs.map(|c| c.as_ptr()).unwrap_or(ptr::null_mut()),
+                flags.bits(),
+            )).map(|_| ())
+        }
+    }
+
     /// Verifies the validity of the response.
     ///
     /// The `certs` parameter contains a set of certificates that will be searched when locating the
@@ -191,7 +261,7 @@ impl OcspBasicResponseRef {
                 };
                 Some(Status {
                     status: OcspCertStatus(status),
-                    reason: OcspRevokedStatus(status),
+                    reason: OcspRevokedStatus(reason),
                     revocation_time: revocation_time,
                     this_update: Asn1GeneralizedTimeRef::from_ptr(this_update),
                     next_update: Asn1GeneralizedTimeRef::from_ptr(next_update),
@@ -226,6 +296,58 @@ impl OcspCertId {
             )).map(OcspCertId)
         }
     }
+
+    /// Constructs an ID naming only `issuer`, without a serial number.
+    ///
+    /// It is meant to be compared against the IDs of requests with `issuer_matches`.
+    pub fn from_issuer(digest: MessageDigest, issuer: &X509Ref) -> Result<OcspCertId, ErrorStack> {
+        unsafe {
+            cvt_p(ffi::OCSP_cert_to_id(
+                digest.as_ptr(),
+                ptr::null_mut(),
+                issuer.as_ptr(),
+            )).map(OcspCertId)
+        }
+    }
+}
+
+impl OcspCertIdRef {
+    /// Returns the serial number of the certificate.
+    pub fn serial_number(&self) -> &Asn1IntegerRef {
+        unsafe {
+            let mut serial = ptr::null_mut();
+            ffi::OCSP_id_get0_info(
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut serial,
+                self.as_ptr(),
+            );
+            Asn1IntegerRef::from_ptr(serial)
+        }
+    }
+
+    /// Returns the hash algorithm the issuer name and key hashes were computed with.
+    pub fn hash_algorithm(&self) -> &Asn1ObjectRef {
+        unsafe {
+            let mut algorithm = ptr::null_mut();
+            ffi::OCSP_id_get0_info(
+                ptr::null_mut(),
+                &mut algorithm,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                self.as_ptr(),
+            );
+            Asn1ObjectRef::from_ptr(algorithm)
+        }
+    }
+
+    /// Determines if both IDs name the same issuer.
+    ///
+    /// The hash algorithm, issuer name hash and issuer key hash must all match.
+    pub fn issuer_matches(&self, other: &OcspCertIdRef) -> bool {
+        unsafe { ffi::OCSP_id_issuer_cmp(self.as_ptr(), other.as_ptr()) == 0 }
+    }
 }
 
 foreign_type! {
@@ -303,6 +425,40 @@ impl OcspRequestRef {
             Ok(OcspOneReqRef::from_ptr_mut(ptr))
         }
     }
+
+    /// Adds a random nonce to the request.
+    ///
+    /// Responders echo the nonce back, which prevents replaying old responses.
+    pub fn add_nonce(&mut self) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(ffi::OCSP_request_add1_nonce(self.as_ptr(), ptr::null_mut(), -1)).map(|_| ())
+        }
+    }
+
+    /// Returns the IDs of the certificates whose status is requested.
+    pub fn cert_ids(&self) -> Vec<&OcspCertIdRef> {
+        unsafe {
+            let count = ffi::OCSP_request_onereq_count(self.as_ptr());
+            (0..count)
+                .map(|i| {
+                    let one = ffi::OCSP_request_onereq_get0(self.as_ptr(), i);
+                    OcspCertIdRef::from_ptr(ffi::OCSP_onereq_get0_id(one))
+                })
+                .collect()
+        }
+    }
+
+    /// Determines if `response` answers this request.
+    ///
+    /// Returns `true` if both carry the same nonce, or if neither has one.
+    pub fn check_nonce(&self, response: &OcspBasicResponseRef) -> bool {
+        unsafe {
+            match ffi::OCSP_check_nonce(self.as_ptr(), response.as_ptr()) {
+                1 | 2 => true,
+                _ => false,
+            }
+        }
+    }
 }
 
 foreign_type! {
@@ -312,3 +468,76 @@ foreign_type! {
     pub struct OcspOneReq;
     pub struct OcspOneReqRef;
 }
+
+#[cfg(test)]
+mod test {
+    use asn1::Asn1Time;
+    use hash::MessageDigest;
+    use nid;
+    use pkey::PKey;
+    use stack::Stack;
+    use x509::X509;
+    use x509::store::X509StoreBuilder;
+
+    use super::*;
+
+    #[test]
+    fn respond_to_request() {
+        let ca = X509::from_pem(include_bytes!("../test/root-ca.pem")).unwrap();
+        let ca_key = PKey::private_key_from_pem(include_bytes!("../test/root-ca.key")).unwrap();
+        let cert = X509::from_pem(include_bytes!("../test/cert.pem")).unwrap();
+
+        let mut req = OcspRequest::new().unwrap();
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &ca).unwrap();
+        req.add_id(id).unwrap();
+        req.add_nonce().unwrap();
+        let req = OcspRequest::from_der(&req.to_der().unwrap()).unwrap();
+
+        let ca_id = OcspCertId::from_issuer(MessageDigest::sha1(), &ca).unwrap();
+        let ids = req.cert_ids();
+        assert_eq!(ids.len(), 1);
+        assert!(ids[0].issuer_matches(&ca_id));
+        assert_eq!(ids[0].hash_algorithm().nid(), nid::SHA1);
+        assert_eq!(
+            ids[0].serial_number().get(),
+            cert.serial_number().get()
+        );
+
+        let now = Asn1Time::days_from_now(0).unwrap();
+        let next = Asn1Time::days_from_now(1).unwrap();
+        let mut basic = OcspBasicResponse::new().unwrap();
+        basic
+            .add_status(
+                ids[0],
+                CERT_STATUS_REVOKED,
+                REVOKED_STATUS_KEY_COMPROMISE,
+                Some(&now),
+                &now,
+                &next,
+            )
+            .unwrap();
+        basic.copy_nonce(&req).unwrap();
+        basic
+            .sign(&ca, &ca_key, MessageDigest::sha256(), None, Flag::empty())
+            .unwrap();
+
+        let resp = OcspResponse::create(RESPONSE_STATUS_SUCCESSFUL, Some(&basic)).unwrap();
+        let resp = OcspResponse::from_der(&resp.to_der().unwrap()).unwrap();
+        assert_eq!(resp.status(), RESPONSE_STATUS_SUCCESSFUL);
+
+        let basic = resp.basic().unwrap();
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(ca.clone()).unwrap();
+        let store = store.build();
+        basic
+            .verify(&Stack::<X509>::new().unwrap(), &store, Flag::empty())
+            .unwrap();
+        assert!(req.check_nonce(&basic));
+
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &ca).unwrap();
+        let status = basic.find_status(&id).unwrap();
+        assert_eq!(status.status, CERT_STATUS_REVOKED);
+        assert_eq!(status.reason, REVOKED_STATUS_KEY_COMPROMISE);
+        status.check_validity(60, None).unwrap();
+    }
+}
@@ -12,6 +12,7 @@ use dsa::Dsa;
 use ec::EcKey;
 use rsa::{Rsa, Padding};
 use error::ErrorStack;
+use nid::Nid;
 use util::{CallbackState, invoke_passwd_cb, invoke_passwd_cb_old};
 
 foreign_type! {
@@ -69,6 +70,11 @@ impl PKeyRef {
         unsafe { ffi::EVP_PKEY_bits(self.as_ptr()) as u32 }
     }
 
+    /// Returns the type of the key, e.g. `nid::RSAENCRYPTION` or `nid::X9_62_ID_ECPUBLICKEY`.
+    pub fn id(&self) -> Nid {
+        unsafe { Nid::from_raw(ffi::EVP_PKEY_id(self.as_ptr())) }
+    }
+
     /// Compares the public component of this key with another.
     pub fn public_eq(&self, other: &PKeyRef) -> bool {
         unsafe { ffi::EVP_PKEY_cmp(self.as_ptr(), other.as_ptr()) == 1 }
@@ -388,6 +394,7 @@ mod tests {
         let pkey = PKey::from_rsa(rsa).unwrap();
         pkey.rsa().unwrap();
         assert!(pkey.dsa().is_err());
+        assert_eq!(pkey.id(), nid::RSAENCRYPTION);
     }
 
     #[test]
@@ -413,6 +420,7 @@ mod tests {
         let pkey = PKey::from_ec_key(ec_key).unwrap();
         pkey.ec_key().unwrap();
         assert!(pkey.rsa().is_err());
+        assert_eq!(pkey.id(), nid::X9_62_ID_ECPUBLICKEY);
     }
 
     #[test]
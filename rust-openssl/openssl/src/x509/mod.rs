@@ -1,5 +1,5 @@
 #![allow(deprecated)]
-use libc::{c_int, c_long};
+use libc::{c_int, c_long, c_void};
 use ffi;
 use foreign_types::{ForeignType, ForeignTypeRef};
 use std::borrow::Borrow;
@@ -15,7 +15,8 @@ use std::slice;
 use std::str;
 
 use {cvt, cvt_p, cvt_n};
-use asn1::{Asn1StringRef, Asn1Time, Asn1TimeRef, Asn1BitStringRef, Asn1IntegerRef, Asn1ObjectRef};
+use asn1::{Asn1StringRef, Asn1Time, Asn1TimeRef, Asn1BitStringRef, Asn1IntegerRef, Asn1Object,
+           Asn1ObjectRef};
 use bio::MemBioSlice;
 use bn::{BigNum, MSB_MAYBE_ZERO};
 use conf::ConfRef;
@@ -28,16 +29,20 @@ use string::OpensslString;
 use ssl::SslRef;
 
 #[cfg(ossl10x)]
-use ffi::{X509_set_notBefore, X509_set_notAfter, ASN1_STRING_data, X509_STORE_CTX_get_chain};
+use ffi::{X509_set_notBefore, X509_set_notAfter, ASN1_STRING_data, X509_STORE_CTX_get_chain,
+          X509_CRL_set_lastUpdate, X509_CRL_set_nextUpdate};
 #[cfg(ossl110)]
 use ffi::{X509_set1_notBefore as X509_set_notBefore, X509_set1_notAfter as X509_set_notAfter,
           ASN1_STRING_get0_data as ASN1_STRING_data,
-          X509_STORE_CTX_get0_chain as X509_STORE_CTX_get_chain};
+          X509_STORE_CTX_get0_chain as X509_STORE_CTX_get_chain,
+          X509_CRL_set1_lastUpdate as X509_CRL_set_lastUpdate,
+          X509_CRL_set1_nextUpdate as X509_CRL_set_nextUpdate};
 
 #[cfg(any(all(feature = "v102", ossl102), all(feature = "v110", ossl110)))]
 pub mod verify;
 
 use x509::extension::{ExtensionType, Extension};
+use x509::store::X509StoreRef;
 
 pub mod extension;
 pub mod store;
@@ -65,7 +70,76 @@ foreign_type! {
     pub struct X509StoreContextRef;
 }
 
+impl X509StoreContext {
+    /// Returns a new store context, to verify certificates with.
+    pub fn new() -> Result<X509StoreContext, ErrorStack> {
+        unsafe {
+            ffi::init();
+
+            cvt_p(ffi::X509_STORE_CTX_new()).map(X509StoreContext)
+        }
+    }
+}
+
 impl X509StoreContextRef {
+    /// Verifies `cert` against the certificates trusted by `trust`.
+    ///
+    /// `untrusted` certificates may be used as intermediates to build the chain. If `crls` is
+    /// given, `cert` is checked against them, and fails if no CRL of its issuer is among them.
+    /// The rest of the chain is not checked for revocation.
+    ///
+    /// Returns the verified chain, starting with `cert` and ending with the trust anchor, or why
+    /// `cert` did not verify. The outer error is for OpenSSL failing to verify at all. The
+    /// context can be used again afterwards.
+    pub fn verify_cert(
+        &mut self,
+        trust: &X509StoreRef,
+        cert: &X509Ref,
+        untrusted: Option<&StackRef<X509>>,
+        crls: Option<&StackRef<X509Crl>>,
+    ) -> Result<Result<Stack<X509>, X509VerifyError>, ErrorStack> {
+        unsafe {
+            // Errors left over from earlier calls would be taken for ours
+            ErrorStack::get();
+
+            let untrusted = untrusted.map_or(ptr::null_mut(), |s| s.as_ptr());
+            let r = ffi::X509_STORE_CTX_init(
+                self.as_ptr(),
+                trust.as_ptr(),
+                cert.as_ptr(),
+                untrusted,
+            );
+            if r <= 0 {
+                return Err(ErrorStack::get());
+            }
+
+            if let Some(crls) = crls {
+                ffi::X509_STORE_CTX_set0_crls(self.as_ptr(), crls.as_ptr());
+                ffi::X509_STORE_CTX_set_flags(self.as_ptr(), ffi::X509_V_FLAG_CRL_CHECK);
+            }
+
+            let result = if ffi::X509_verify_cert(self.as_ptr()) > 0 {
+                let chain = ffi::X509_STORE_CTX_get1_chain(self.as_ptr());
+                if chain.is_null() {
+                    Err(ErrorStack::get())
+                } else {
+                    Ok(Ok(Stack::from_ptr(chain)))
+                }
+            } else {
+                // Errors other than of the certificates themselves leave the context's error
+                // unset
+                Ok(Err(self.error().unwrap_or(
+                    X509VerifyError(ffi::X509_V_ERR_UNSPECIFIED as c_long),
+                )))
+            };
+
+            // Clears the error queue of anything verification left there
+            ErrorStack::get();
+            ffi::X509_STORE_CTX_cleanup(self.as_ptr());
+            result
+        }
+    }
+
     pub fn error(&self) -> Option<X509VerifyError> {
         unsafe { X509VerifyError::from_raw(ffi::X509_STORE_CTX_get_error(self.as_ptr()) as c_long) }
     }
@@ -453,6 +527,23 @@ impl X509Ref {
         }
     }
 
+    /// Returns the DER encoded value of the extension identified by `oid`, if the certificate
+    /// has one.
+    pub fn extension_der(&self, oid: &Asn1ObjectRef) -> Option<&[u8]> {
+        unsafe {
+            let loc = ffi::X509_get_ext_by_OBJ(self.as_ptr(), oid.as_ptr(), -1);
+            if loc < 0 {
+                return None;
+            }
+
+            let data = ffi::X509_EXTENSION_get_data(ffi::X509_get_ext(self.as_ptr(), loc));
+            let ptr = ASN1_STRING_data(data as *mut _);
+            let len = ffi::ASN1_STRING_length(data as *mut _);
+
+            Some(slice::from_raw_parts(ptr as *const u8, len as usize))
+        }
+    }
+
     /// Returns this certificate's SAN entries, if they exist.
     pub fn subject_alt_names(&self) -> Option<Stack<GeneralName>> {
         unsafe {
@@ -512,6 +603,15 @@ impl X509Ref {
         }
     }
 
+    /// Returns the certificate's serial number.
+    pub fn serial_number(&self) -> &Asn1IntegerRef {
+        unsafe {
+            let serial = ffi::X509_get_serialNumber(self.as_ptr());
+            assert!(!serial.is_null());
+            Asn1IntegerRef::from_ptr(serial)
+        }
+    }
+
     /// Returns the certificate's signature
     pub fn signature(&self) -> &Asn1BitStringRef {
         unsafe {
@@ -703,6 +803,90 @@ impl X509Extension {
             cvt_p(ffi::X509V3_EXT_nconf_nid(conf, context, name, value)).map(X509Extension)
         }
     }
+
+    /// Constructs an X509 extension from its OID and the DER encoding of its value.
+    ///
+    /// This allows extensions OpenSSL does not know about, such as private ones.
+    pub fn new_from_der(
+        oid: &Asn1ObjectRef,
+        critical: bool,
+        der_contents: &[u8],
+    ) -> Result<X509Extension, ErrorStack> {
+        unsafe {
+            ffi::init();
+            let data = cvt_p(ffi::ASN1_OCTET_STRING_new())?;
+            let ext = cvt(ffi::ASN1_OCTET_STRING_set(
+                data,
+                der_contents.as_ptr(),
+                der_contents.len() as c_int,
+            )).and_then(|_| {
+                cvt_p(ffi::X509_EXTENSION_create_by_OBJ(
+                    ptr::null_mut(),
+                    oid.as_ptr(),
+                    critical as c_int,
+                    data,
+                ))
+            });
+            // The extension holds a copy of the value
+            ffi::ASN1_OCTET_STRING_free(data);
+            ext.map(X509Extension)
+        }
+    }
+}
+
+impl X509ExtensionRef {
+    /// Returns the OID identifying this extension.
+    pub fn object(&self) -> &Asn1ObjectRef {
+        unsafe { Asn1ObjectRef::from_ptr(ffi::X509_EXTENSION_get_object(self.as_ptr())) }
+    }
+
+    /// Returns whether this extension is marked critical.
+    pub fn critical(&self) -> bool {
+        unsafe { ffi::X509_EXTENSION_get_critical(self.as_ptr()) != 0 }
+    }
+
+    /// Returns the DER encoded value of this extension.
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            let data = ffi::X509_EXTENSION_get_data(self.as_ptr());
+            let ptr = ASN1_STRING_data(data as *mut _);
+            let len = ffi::ASN1_STRING_length(data as *mut _);
+
+            slice::from_raw_parts(ptr as *const u8, len as usize)
+        }
+    }
+
+    /// Decodes this extension if it is a `subjectAltName`.
+    pub fn subject_alt_names(&self) -> Option<Stack<GeneralName>> {
+        if self.object().nid() != nid::SUBJECT_ALT_NAME {
+            return None;
+        }
+
+        unsafe {
+            let stack = ffi::X509V3_EXT_d2i(self.as_ptr());
+            if stack.is_null() {
+                return None;
+            }
+
+            Some(Stack::from_ptr(stack as *mut _))
+        }
+    }
+
+    /// Decodes this extension if it is an `extendedKeyUsage`, into the OIDs of the usages.
+    pub fn extended_key_usage(&self) -> Option<Stack<Asn1Object>> {
+        if self.object().nid() != nid::EXT_KEY_USAGE {
+            return None;
+        }
+
+        unsafe {
+            let stack = ffi::X509V3_EXT_d2i(self.as_ptr());
+            if stack.is_null() {
+                return None;
+            }
+
+            Some(Stack::from_ptr(stack as *mut _))
+        }
+    }
 }
 
 pub struct X509NameBuilder(X509Name);
@@ -782,7 +966,16 @@ impl X509NameRef {
     pub fn entries_by_nid<'a>(&'a self, nid: Nid) -> X509NameEntries<'a> {
         X509NameEntries {
             name: self,
-            nid: nid,
+            nid: Some(nid),
+            loc: -1,
+        }
+    }
+
+    /// Returns an iterator over all entries of the name, in order.
+    pub fn entries<'a>(&'a self) -> X509NameEntries<'a> {
+        X509NameEntries {
+            name: self,
+            nid: None,
             loc: -1,
         }
     }
@@ -790,7 +983,7 @@ impl X509NameRef {
 
 pub struct X509NameEntries<'a> {
     name: &'a X509NameRef,
-    nid: Nid,
+    nid: Option<Nid>,
     loc: c_int,
 }
 
@@ -799,11 +992,20 @@ impl<'a> Iterator for X509NameEntries<'a> {
 
     fn next(&mut self) -> Option<&'a X509NameEntryRef> {
         unsafe {
-            self.loc =
-                ffi::X509_NAME_get_index_by_NID(self.name.as_ptr(), self.nid.as_raw(), self.loc);
-
-            if self.loc == -1 {
-                return None;
+            match self.nid {
+                Some(nid) => {
+                    self.loc =
+                        ffi::X509_NAME_get_index_by_NID(self.name.as_ptr(), nid.as_raw(), self.loc);
+                    if self.loc == -1 {
+                        return None;
+                    }
+                }
+                None => {
+                    self.loc += 1;
+                    if self.loc >= ffi::X509_NAME_entry_count(self.name.as_ptr()) {
+                        return None;
+                    }
+                }
             }
 
             let entry = ffi::X509_NAME_get_entry(self.name.as_ptr(), self.loc);
@@ -829,6 +1031,15 @@ impl X509NameEntryRef {
             Asn1StringRef::from_ptr(data)
         }
     }
+
+    /// Returns the type of the entry, e.g. the object of `nid::COMMONNAME`.
+    pub fn object(&self) -> &Asn1ObjectRef {
+        unsafe {
+            let object = ffi::X509_NAME_ENTRY_get_object(self.as_ptr());
+            assert!(!object.is_null());
+            Asn1ObjectRef::from_ptr(object)
+        }
+    }
 }
 
 pub struct X509ReqBuilder(X509Req);
@@ -961,6 +1172,271 @@ impl X509ReqRef {
             Ok(PKey::from_ptr(pkey))
         }
     }
+
+    /// Checks the request's self-signature against `key`, normally its own public key.
+    pub fn verify(&self, key: &PKeyRef) -> Result<bool, ErrorStack> {
+        unsafe { cvt_n(ffi::X509_REQ_verify(self.as_ptr(), key.as_ptr())).map(|n| n != 0) }
+    }
+
+    /// Returns the extensions the request asks for, which may be none.
+    pub fn extensions(&self) -> Result<Stack<X509Extension>, ErrorStack> {
+        unsafe {
+            // Errors left over from earlier calls would be taken for ours
+            ErrorStack::get();
+
+            let extensions = ffi::X509_REQ_get_extensions(self.as_ptr());
+            if !extensions.is_null() {
+                return Ok(Stack::from_ptr(extensions));
+            }
+
+            // OpenSSL returns null both for no extensions and for
+            // extensions it can not decode
+            let errors = ErrorStack::get();
+            if errors.errors().is_empty() {
+                Stack::new()
+            } else {
+                Err(errors)
+            }
+        }
+    }
+}
+
+/// The reason a certificate was revoked, as recorded in a CRL.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CrlReason(c_int);
+
+impl CrlReason {
+    pub fn from_raw(raw: c_int) -> CrlReason {
+        CrlReason(raw)
+    }
+
+    pub fn as_raw(&self) -> c_int {
+        self.0
+    }
+}
+
+pub const CRL_REASON_UNSPECIFIED: CrlReason = CrlReason(ffi::CRL_REASON_UNSPECIFIED);
+pub const CRL_REASON_KEY_COMPROMISE: CrlReason = CrlReason(ffi::CRL_REASON_KEY_COMPROMISE);
+pub const CRL_REASON_CA_COMPROMISE: CrlReason = CrlReason(ffi::CRL_REASON_CA_COMPROMISE);
+pub const CRL_REASON_AFFILIATION_CHANGED: CrlReason =
+    CrlReason(ffi::CRL_REASON_AFFILIATION_CHANGED);
+pub const CRL_REASON_SUPERSEDED: CrlReason = CrlReason(ffi::CRL_REASON_SUPERSEDED);
+pub const CRL_REASON_CESSATION_OF_OPERATION: CrlReason =
+    CrlReason(ffi::CRL_REASON_CESSATION_OF_OPERATION);
+pub const CRL_REASON_CERTIFICATE_HOLD: CrlReason = CrlReason(ffi::CRL_REASON_CERTIFICATE_HOLD);
+pub const CRL_REASON_REMOVE_FROM_CRL: CrlReason = CrlReason(ffi::CRL_REASON_REMOVE_FROM_CRL);
+pub const CRL_REASON_PRIVILEGE_WITHDRAWN: CrlReason =
+    CrlReason(ffi::CRL_REASON_PRIVILEGE_WITHDRAWN);
+pub const CRL_REASON_AA_COMPROMISE: CrlReason = CrlReason(ffi::CRL_REASON_AA_COMPROMISE);
+
+/// A builder type which can create `X509Revoked` entries for a CRL.
+pub struct X509RevokedBuilder(X509Revoked);
+
+impl X509RevokedBuilder {
+    /// Creates a new builder.
+    pub fn new() -> Result<X509RevokedBuilder, ErrorStack> {
+        unsafe {
+            ffi::init();
+            cvt_p(ffi::X509_REVOKED_new()).map(|p| X509RevokedBuilder(X509Revoked(p)))
+        }
+    }
+
+    /// Sets the serial number of the revoked certificate.
+    pub fn set_serial_number(&mut self, serial_number: &Asn1IntegerRef) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(ffi::X509_REVOKED_set_serialNumber(
+                self.0.as_ptr(),
+                serial_number.as_ptr(),
+            )).map(|_| ())
+        }
+    }
+
+    /// Sets the time at which the certificate was revoked.
+    pub fn set_revocation_date(&mut self, date: &Asn1TimeRef) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(ffi::X509_REVOKED_set_revocationDate(
+                self.0.as_ptr(),
+                date.as_ptr(),
+            )).map(|_| ())
+        }
+    }
+
+    /// Adds a reasonCode extension to the entry.
+    pub fn set_reason(&mut self, reason: CrlReason) -> Result<(), ErrorStack> {
+        unsafe {
+            let code = cvt_p(ffi::ASN1_ENUMERATED_new())?;
+            let result = cvt(ffi::ASN1_ENUMERATED_set(code, reason.as_raw() as c_long))
+                .and_then(|_| {
+                    cvt(ffi::X509_REVOKED_add1_ext_i2d(
+                        self.0.as_ptr(),
+                        ffi::NID_crl_reason,
+                        code as *mut c_void,
+                        0,
+                        0,
+                    ))
+                });
+            ffi::ASN1_ENUMERATED_free(code);
+            result.map(|_| ())
+        }
+    }
+
+    /// Consumes the builder, returning the entry.
+    pub fn build(self) -> X509Revoked {
+        self.0
+    }
+}
+
+foreign_type! {
+    type CType = ffi::X509_REVOKED;
+    fn drop = ffi::X509_REVOKED_free;
+
+    /// An entry of a certificate revocation list.
+    pub struct X509Revoked;
+    /// Reference to `X509Revoked`.
+    pub struct X509RevokedRef;
+}
+
+/// A builder type which can create `X509Crl` objects.
+pub struct X509CrlBuilder(X509Crl);
+
+impl X509CrlBuilder {
+    /// Creates a new builder.
+    pub fn new() -> Result<X509CrlBuilder, ErrorStack> {
+        unsafe {
+            ffi::init();
+            cvt_p(ffi::X509_CRL_new()).map(|p| X509CrlBuilder(X509Crl(p)))
+        }
+    }
+
+    /// Sets the version of the CRL.
+    ///
+    /// Note that the version is zero-indexed; a CRL with extensions must be version 2, which
+    /// corresponds to passing `1` to this method.
+    pub fn set_version(&mut self, version: i32) -> Result<(), ErrorStack> {
+        unsafe { cvt(ffi::X509_CRL_set_version(self.0.as_ptr(), version.into())).map(|_| ()) }
+    }
+
+    /// Sets the issuer name of the CRL. This must be the subject name of the signing certificate.
+    pub fn set_issuer_name(&mut self, issuer_name: &X509NameRef) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(ffi::X509_CRL_set_issuer_name(
+                self.0.as_ptr(),
+                issuer_name.as_ptr(),
+            )).map(|_| ())
+        }
+    }
+
+    /// Sets the time at which the CRL was issued.
+    pub fn set_last_update(&mut self, last_update: &Asn1TimeRef) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(X509_CRL_set_lastUpdate(
+                self.0.as_ptr(),
+                last_update.as_ptr(),
+            )).map(|_| ())
+        }
+    }
+
+    /// Sets the time by which the next CRL will be issued.
+    pub fn set_next_update(&mut self, next_update: &Asn1TimeRef) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(X509_CRL_set_nextUpdate(
+                self.0.as_ptr(),
+                next_update.as_ptr(),
+            )).map(|_| ())
+        }
+    }
+
+    /// Adds a cRLNumber extension. The number should increase with every CRL issued.
+    pub fn set_crl_number(&mut self, number: &Asn1IntegerRef) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(ffi::X509_CRL_add1_ext_i2d(
+                self.0.as_ptr(),
+                ffi::NID_crl_number,
+                number.as_ptr() as *mut c_void,
+                0,
+                0,
+            )).map(|_| ())
+        }
+    }
+
+    /// Adds a revoked certificate to the CRL.
+    pub fn add_revoked(&mut self, revoked: X509Revoked) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(ffi::X509_CRL_add0_revoked(self.0.as_ptr(), revoked.as_ptr()))?;
+            mem::forget(revoked);
+            Ok(())
+        }
+    }
+
+    /// Sorts the revoked certificates by serial number and signs the CRL with a private key.
+    pub fn sign(&mut self, key: &PKeyRef, hash: MessageDigest) -> Result<(), ErrorStack> {
+        unsafe {
+            cvt(ffi::X509_CRL_sort(self.0.as_ptr()))?;
+            cvt(ffi::X509_CRL_sign(
+                self.0.as_ptr(),
+                key.as_ptr(),
+                hash.as_ptr(),
+            )).map(|_| ())
+        }
+    }
+
+    /// Consumes the builder, returning the CRL.
+    pub fn build(self) -> X509Crl {
+        self.0
+    }
+}
+
+foreign_type! {
+    type CType = ffi::X509_CRL;
+    fn drop = ffi::X509_CRL_free;
+
+    /// A certificate revocation list.
+    pub struct X509Crl;
+    /// Reference to `X509Crl`.
+    pub struct X509CrlRef;
+}
+
+impl X509Crl {
+    /// Returns a new builder.
+    pub fn builder() -> Result<X509CrlBuilder, ErrorStack> {
+        X509CrlBuilder::new()
+    }
+
+    from_pem!(X509Crl, ffi::PEM_read_bio_X509_CRL);
+    from_der!(X509Crl, ffi::d2i_X509_CRL);
+}
+
+impl Stackable for X509Crl {
+    type StackType = ffi::stack_st_X509_CRL;
+}
+
+impl X509CrlRef {
+    to_pem!(ffi::PEM_write_bio_X509_CRL);
+    to_der!(ffi::i2d_X509_CRL);
+
+    /// Checks the signature of the CRL against the issuer's public key.
+    pub fn verify(&self, key: &PKeyRef) -> Result<bool, ErrorStack> {
+        unsafe { cvt_n(ffi::X509_CRL_verify(self.as_ptr(), key.as_ptr())).map(|n| n != 0) }
+    }
+
+    /// Returns the entry revoking the certificate with `serial_number`, if there is one.
+    pub fn get_by_serial(&self, serial_number: &Asn1IntegerRef) -> Option<&X509RevokedRef> {
+        unsafe {
+            let mut revoked = ptr::null_mut();
+            let found = ffi::X509_CRL_get0_by_serial(
+                self.as_ptr(),
+                &mut revoked,
+                serial_number.as_ptr(),
+            );
+
+            // 2 means the entry is marked removeFromCRL
+            if found == 1 && !revoked.is_null() {
+                Some(X509RevokedRef::from_ptr(revoked))
+            } else {
+                None
+            }
+        }
+    }
 }
 
 /// A collection of X.509 extensions.
@@ -1126,6 +1602,35 @@ impl GeneralNameRef {
             Some(slice::from_raw_parts(ptr as *const u8, len as usize))
         }
     }
+
+    /// Returns the type-id of this `GeneralName` if it is an `otherName`.
+    pub fn other_name_type(&self) -> Option<&Asn1ObjectRef> {
+        unsafe {
+            if (*self.as_ptr()).type_ != ffi::GEN_OTHERNAME {
+                return None;
+            }
+
+            let other_name = (*self.as_ptr()).d as *mut ffi::OTHERNAME;
+            Some(Asn1ObjectRef::from_ptr((*other_name).type_id))
+        }
+    }
+
+    /// Returns the value of this `GeneralName` if it is an `otherName` holding a string type,
+    /// such as a `UTF8String`.
+    pub fn other_name_value(&self) -> Option<&Asn1StringRef> {
+        unsafe {
+            if (*self.as_ptr()).type_ != ffi::GEN_OTHERNAME {
+                return None;
+            }
+
+            let other_name = (*self.as_ptr()).d as *mut ffi::OTHERNAME;
+            let value = (*other_name).value;
+            match (*value).type_ {
+                ffi::V_ASN1_BOOLEAN | ffi::V_ASN1_NULL | ffi::V_ASN1_OBJECT => None,
+                _ => Some(Asn1StringRef::from_ptr((*value).value as *mut _)),
+            }
+        }
+    }
 }
 
 impl Stackable for GeneralName {
@@ -1,6 +1,8 @@
+use ffi;
 use hex::{FromHex, ToHex};
+use libc::c_long;
 
-use asn1::Asn1Time;
+use asn1::{Asn1Object, Asn1Time};
 use bn::{BigNum, MSB_MAYBE_ZERO};
 use ec::{NAMED_CURVE, EcGroup, EcKey};
 use hash::MessageDigest;
@@ -8,7 +10,9 @@ use nid::X9_62_PRIME256V1;
 use pkey::PKey;
 use rsa::Rsa;
 use stack::Stack;
-use x509::{X509, X509Generator, X509Name, X509Req};
+use x509::{X509, X509Crl, X509Extension, X509Generator, X509Name, X509NameRef, X509Req,
+           X509RevokedBuilder, X509StoreContext, CRL_REASON_KEY_COMPROMISE};
+use x509::store::X509StoreBuilder;
 use x509::extension::{Extension, BasicConstraints, KeyUsage, ExtendedKeyUsage,
                       SubjectKeyIdentifier, AuthorityKeyIdentifier, SubjectAlternativeName};
 use ssl::{SslMethod, SslContextBuilder};
@@ -225,6 +229,63 @@ fn test_subject_alt_name_iter() {
     assert!(subject_alt_names_iter.next().is_none());
 }
 
+#[test]
+fn test_subject_alt_name_other_name() {
+    let pkey = pkey();
+
+    let mut name = X509Name::builder().unwrap();
+    name.append_entry_by_nid(nid::COMMONNAME, "foobar.com")
+        .unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&pkey).unwrap();
+    let subject_alternative_name = SubjectAlternativeName::new()
+        .other_name("1.3.6.1.4.1.99;UTF8:research")
+        .dns("example.com")
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(subject_alternative_name).unwrap();
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+    let cert = builder.build();
+
+    let subject_alt_names = cert.subject_alt_names().unwrap();
+    assert_eq!(2, subject_alt_names.len());
+    assert_eq!(
+        "1.3.6.1.4.1.99",
+        subject_alt_names[0].other_name_type().unwrap().to_string()
+    );
+    assert_eq!(
+        "research",
+        &*subject_alt_names[0].other_name_value().unwrap().as_utf8().unwrap()
+    );
+    assert!(subject_alt_names[0].dnsname().is_none());
+    assert!(subject_alt_names[1].other_name_type().is_none());
+    assert!(subject_alt_names[1].other_name_value().is_none());
+}
+
+#[test]
+fn test_extension_from_der() {
+    let pkey = pkey();
+    let oid = "1.3.6.1.4.1.99.1".parse::<Asn1Object>().unwrap();
+    assert_eq!("1.3.6.1.4.1.99.1", oid.to_string());
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_pubkey(&pkey).unwrap();
+    // UTF8String "research"
+    let value = b"\x0c\x08research";
+    let ext = X509Extension::new_from_der(&oid, false, value).unwrap();
+    builder.append_extension(ext).unwrap();
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+    let cert = X509::from_der(&builder.build().to_der().unwrap()).unwrap();
+
+    assert_eq!(cert.extension_der(&oid), Some(&value[..]));
+    let other = "1.3.6.1.4.1.99.2".parse::<Asn1Object>().unwrap();
+    assert!(cert.extension_der(&other).is_none());
+}
+
 #[test]
 fn x509_builder() {
     let pkey = pkey();
@@ -326,6 +387,69 @@ fn x509_req_builder() {
     builder.sign(&pkey, MessageDigest::sha256()).unwrap();
 }
 
+#[test]
+fn x509_req_extensions() {
+    let pkey = pkey();
+
+    let mut name = X509Name::builder().unwrap();
+    name.append_entry_by_nid(nid::COMMONNAME, "foobar.com")
+        .unwrap();
+    let name = name.build();
+
+    let mut builder = X509Req::builder().unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_pubkey(&pkey).unwrap();
+
+    let mut extensions = Stack::new().unwrap();
+    let key_usage = KeyUsage::new().digital_signature().build().unwrap();
+    extensions.push(key_usage).unwrap();
+    let subject_alternative_name = SubjectAlternativeName::new()
+        .dns("example.com")
+        .build(&builder.x509v3_context(None))
+        .unwrap();
+    extensions.push(subject_alternative_name).unwrap();
+    let ext_key_usage = ExtendedKeyUsage::new().server_auth().build().unwrap();
+    extensions.push(ext_key_usage).unwrap();
+    builder.add_extensions(&extensions).unwrap();
+
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+
+    let req = builder.build();
+    assert!(req.verify(&pkey).unwrap());
+    let other = X509::from_pem(include_bytes!("../../test/cert.pem")).unwrap();
+    assert!(!req.verify(&other.public_key().unwrap()).unwrap());
+
+    let req = X509Req::from_der(&req.to_der().unwrap()).unwrap();
+    let extensions = req.extensions().unwrap();
+    assert_eq!(extensions.len(), 3);
+    assert_eq!(extensions[0].object().nid(), nid::KEY_USAGE);
+    assert!(extensions[0].subject_alt_names().is_none());
+    let alt_names = extensions[1].subject_alt_names().unwrap();
+    assert_eq!(alt_names[0].dnsname(), Some("example.com"));
+    assert!(!extensions[1].critical());
+    let usages = extensions[2].extended_key_usage().unwrap();
+    assert_eq!(usages.len(), 1);
+    assert_eq!(usages[0].nid(), nid::SERVER_AUTH);
+    assert!(!extensions[2].data().is_empty());
+
+    let mut builder = X509Req::builder().unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_pubkey(&pkey).unwrap();
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+    assert_eq!(builder.build().extensions().unwrap().len(), 0);
+}
+
+#[test]
+fn test_name_entries() {
+    let cert = include_bytes!("../../test/nid_test_cert.pem");
+    let cert = X509::from_pem(cert).unwrap();
+    let entries = cert.subject_name().entries().collect::<Vec<_>>();
+
+    assert!(entries.len() > 1);
+    assert!(entries.iter().any(|e| e.object().nid() == nid::COMMONNAME));
+    assert!(entries.iter().any(|e| e.object().nid() == nid::PKCS9_EMAILADDRESS));
+}
+
 #[test]
 fn test_stack_from_pem() {
     let certs = include_bytes!("../../test/certs.pem");
@@ -405,3 +529,173 @@ fn clone_x509() {
     let cert = X509::from_pem(cert).unwrap();
     cert.clone();
 }
+
+#[test]
+fn x509_crl_builder() {
+    let pkey = pkey();
+
+    let mut name = X509Name::builder().unwrap();
+    name.append_entry_by_nid(nid::COMMONNAME, "ca.foobar.com")
+        .unwrap();
+    let name = name.build();
+
+    let revoked_serial = BigNum::from_u32(42).unwrap().to_asn1_integer().unwrap();
+    let other_serial = BigNum::from_u32(43).unwrap().to_asn1_integer().unwrap();
+
+    let mut revoked = X509RevokedBuilder::new().unwrap();
+    revoked.set_serial_number(&revoked_serial).unwrap();
+    revoked
+        .set_revocation_date(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    revoked.set_reason(CRL_REASON_KEY_COMPROMISE).unwrap();
+
+    let mut builder = X509Crl::builder().unwrap();
+    builder.set_version(1).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder
+        .set_last_update(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_next_update(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder
+        .set_crl_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    builder.add_revoked(revoked.build()).unwrap();
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+
+    let der = builder.build().to_der().unwrap();
+    let crl = X509Crl::from_der(&der).unwrap();
+    assert!(crl.verify(&pkey).unwrap());
+    assert!(!crl.verify(&self::pkey()).unwrap());
+
+    assert!(crl.get_by_serial(&revoked_serial).is_some());
+    assert!(crl.get_by_serial(&other_serial).is_none());
+
+    let pem = crl.to_pem().unwrap();
+    assert_eq!(X509Crl::from_pem(&pem).unwrap().to_der().unwrap(), der);
+}
+
+fn chain_cert(cn: &str, pkey: &PKey, issuer: Option<(&X509NameRef, &PKey)>, serial: u32) -> X509 {
+    let mut name = X509Name::builder().unwrap();
+    name.append_entry_by_nid(nid::COMMONNAME, cn).unwrap();
+    let name = name.build();
+    let (issuer_name, issuer_key) = issuer.unwrap_or((&name, pkey));
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(issuer_name).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder.set_pubkey(pkey).unwrap();
+    builder
+        .set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    if cn != "leaf" {
+        let basic_constraints = BasicConstraints::new().critical().ca().build().unwrap();
+        builder.append_extension(basic_constraints).unwrap();
+    }
+    builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+fn chain_crl(issuer: &X509, pkey: &PKey, revoked_serial: Option<u32>) -> X509Crl {
+    let mut builder = X509Crl::builder().unwrap();
+    builder.set_version(1).unwrap();
+    builder.set_issuer_name(issuer.subject_name()).unwrap();
+    builder
+        .set_last_update(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_next_update(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    if let Some(serial) = revoked_serial {
+        let mut revoked = X509RevokedBuilder::new().unwrap();
+        revoked
+            .set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        revoked
+            .set_revocation_date(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder.add_revoked(revoked.build()).unwrap();
+    }
+    builder.sign(pkey, MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+#[test]
+fn verify_cert() {
+    let root_key = pkey();
+    let root = chain_cert("root", &root_key, None, 1);
+    let intermediate_key = pkey();
+    let intermediate = chain_cert(
+        "intermediate",
+        &intermediate_key,
+        Some((root.subject_name(), &root_key)),
+        2,
+    );
+    let leaf = chain_cert(
+        "leaf",
+        &pkey(),
+        Some((intermediate.subject_name(), &intermediate_key)),
+        3,
+    );
+
+    let mut store = X509StoreBuilder::new().unwrap();
+    store.add_cert(root.clone()).unwrap();
+    let store = store.build();
+    let mut untrusted = Stack::new().unwrap();
+    untrusted.push(intermediate.clone()).unwrap();
+
+    let mut context = X509StoreContext::new().unwrap();
+    let err = context
+        .verify_cert(&store, &leaf, None, None)
+        .unwrap()
+        .err()
+        .unwrap();
+    assert_eq!(err.as_raw(), ffi::X509_V_ERR_UNABLE_TO_GET_ISSUER_CERT_LOCALLY as c_long);
+
+    let chain = context
+        .verify_cert(&store, &leaf, Some(&untrusted), None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(chain.len(), 3);
+    assert_eq!(chain[0].to_der().unwrap(), leaf.to_der().unwrap());
+    assert_eq!(chain[2].to_der().unwrap(), root.to_der().unwrap());
+
+    let mut crls = Stack::new().unwrap();
+    crls.push(chain_crl(&intermediate, &intermediate_key, None))
+        .unwrap();
+    assert!(
+        context
+            .verify_cert(&store, &leaf, Some(&untrusted), Some(&crls))
+            .unwrap()
+            .is_ok()
+    );
+
+    // The leaf is revoked by its issuer
+    let mut crls = Stack::new().unwrap();
+    crls.push(chain_crl(&intermediate, &intermediate_key, Some(3)))
+        .unwrap();
+    let err = context
+        .verify_cert(&store, &leaf, Some(&untrusted), Some(&crls))
+        .unwrap()
+        .err()
+        .unwrap();
+    assert_eq!(err.as_raw(), ffi::X509_V_ERR_CERT_REVOKED as c_long);
+
+    // Only the root's CRL, which does not cover the leaf
+    let mut crls = Stack::new().unwrap();
+    crls.push(chain_crl(&root, &root_key, Some(3))).unwrap();
+    let err = context
+        .verify_cert(&store, &leaf, Some(&untrusted), Some(&crls))
+        .unwrap()
+        .err()
+        .unwrap();
+    assert_eq!(err.as_raw(), ffi::X509_V_ERR_UNABLE_TO_GET_CRL as c_long);
+}
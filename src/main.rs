@@ -10,15 +10,16 @@ extern crate iron;
 extern crate tolla_proto;
 extern crate log;
 extern crate simplelog;
+extern crate bytes;
+extern crate openssl;
 
-use tokio_proto::TcpServer;
 use std::net::TcpStream;
 use tolla_proto::proto;
 use lib_tolla::*;
 use std::io::Write;
 use std::io::Read;
 use std::env;
-use prost::Message;
+use bytes::BytesMut;
 use std::{thread, time};
 use iron::Iron;
 use router::Router;
@@ -26,6 +27,8 @@ use std::sync::{Arc, Mutex};
 use log::LogLevel;
 use simplelog::{Config, WriteLogger, LogLevelFilter};
 use std::fs::File;
+use openssl::ssl::{SslConnectorBuilder, SslMethod};
+use openssl::x509::X509_FILETYPE_PEM;
 
 fn main() {
     let log_conf = Config {
@@ -48,26 +51,133 @@ fn main() {
 
     match args[1].as_ref() {
         "http" => run_http(),
+        "repair" => run_repair(),
+        "root" => run_root(args[2].as_ref()),
         "client" => run_client(String::from("0.0.0.0"), 8900, args[2].as_ref()),
         _ => println!("Wrong"),
     }
 }
 
+// Configure the engine from the environment
+fn build_engine() -> consent::ConsentEngine {
+    let mut builder = consent::ConsentEngineBuilder::new();
+    builder.port(27017).deamon(format!("http://127.0.0.1:2375"));
+
+    if let Ok(addr) = env::var("MONGODB_PORT_27017_TCP_ADDR") {
+        builder.address(addr);
+    }
+
+    // Use SQLite instead of MongoDB when a database file is given
+    if let Ok(path) = env::var("SQLITE_PATH") {
+        builder.sqlite(path);
+    }
+
+    // The CA identity defaults to PEM files under /var/lib/tolla/ca
+    if let Ok(path) = env::var("CA_PKCS12") {
+        let password = env::var("CA_PASSWORD").unwrap_or(String::new());
+        builder.ca_pkcs12(path, password);
+    }
+
+    if let Ok(path) = env::var("MOUNT_ROOT") {
+        builder.mount_root(path);
+    }
+
+    // e.g. "ecdsa-p256", defaults to "rsa-2048"
+    if let Ok(algorithm) = env::var("KEY_ALGORITHM") {
+        builder.key_algorithm(algorithm.parse().unwrap());
+    }
+
+    // e.g. "C=NO,ST=TR,O=IFI,CN=tolla", only used for a new CA
+    if let Ok(subject) = env::var("CA_SUBJECT") {
+        builder.ca_subject(subject);
+    }
+
+    // Bits of security a requested certificate's key must have
+    if let Ok(strength) = env::var("MIN_KEY_STRENGTH") {
+        builder.min_key_strength(strength.parse().unwrap());
+    }
+
+    // Comma separated, e.g. "IFI,UIT". Unset allows any.
+    if let Ok(organizations) = env::var("ALLOWED_ORGANIZATIONS") {
+        for organization in organizations.split(',') {
+            builder.allow_organization(organization.trim().to_string());
+        }
+    }
+
+    // Comma separated, e.g. "research,statistics". Intents must
+    // be registered through POST /intents, and when this is set,
+    // also be one of these.
+    if let Ok(intents) = env::var("ALLOWED_INTENTS") {
+        for intent in intents.split(',') {
+            builder.allow_intent(intent.trim().to_string());
+        }
+    }
+
+    // Seconds process certificates are valid, a year by default
+    if let Ok(seconds) = env::var("CERT_VALIDITY") {
+        builder.default_validity(seconds.parse().unwrap());
+    }
+
+    // Comma separated, e.g. "research=3600,statistics=600"
+    if let Ok(validities) = env::var("INTENT_VALIDITY") {
+        for validity in validities.split(',') {
+            let mut parts = validity.splitn(2, '=');
+            let intent = parts.next().unwrap().trim().to_string();
+            let seconds = parts.next().expect("expected intent=seconds").trim();
+            builder.intent_validity(intent, seconds.parse().unwrap());
+        }
+    }
+
+    // Comma separated, e.g. "statistics=public-task". Other
+    // intents are processed under consent.
+    if let Ok(bases) = env::var("LEGAL_BASIS") {
+        for basis in bases.split(',') {
+            let mut parts = basis.splitn(2, '=');
+            let intent = parts.next().unwrap().trim().to_string();
+            let basis = parts.next().expect("expected intent=basis").trim();
+            builder.intent_legal_basis(intent, basis.parse().unwrap());
+        }
+    }
+
+    // Comma separated, e.g. "subject-alt-name,extended-key-usage"
+    if let Ok(extensions) = env::var("REQUEST_EXTENSIONS") {
+        for extension in extensions.split(',') {
+            builder.allow_extension(extension.trim().parse().unwrap());
+        }
+    }
+
+    // Bearer token for administrative requests, e.g. POST
+    // /enrollment. Unset refuses them.
+    if let Ok(token) = env::var("ADMIN_TOKEN") {
+        builder.admin_token(token);
+    }
+
+    // Sign OCSP responses with a delegated responder certificate
+    if env::var("OCSP_DELEGATE").is_ok() {
+        builder.delegate_ocsp();
+    }
+
+    builder.build().unwrap()
+}
+
 fn run_http() {
     for (key, value) in env::vars() {
         println!("{}: {}", key, value);
     }
-    let addr = env::var("MONGODB_PORT_27017_TCP_ADDR").unwrap();
+    let consent = build_engine();
 
-    let consent = consent::ConsentEngineBuilder::new()
-        .address(addr)
-        .port(27017)
-        .deamon(format!("http://127.0.0.1:2375"))
-        .build()
-        .unwrap();
+    // Containers may have come back with other addresses
+    match consent.refresh_db_certificates() {
+        Ok(users) => println!("Re-issued {} database certificates", users.len()),
+        Err(err) => println!("Unable to refresh database certificates: {}", err),
+    }
 
     let consent_ref = Arc::new(Mutex::new(consent));
 
+    // Mark consents whose lifetime has passed, and follow
+    // containers that moved, once a minute
+    consent::spawn_sweeper(consent_ref.clone(), time::Duration::from_secs(60));
+
     let handlers = endpoints::Handlers::new(consent_ref.clone());
 
     let mut router = Router::new();
@@ -76,25 +186,94 @@ fn run_http() {
     router.post("/register", handlers.register, "register");
     router.delete("/:user", handlers.remove, "remove");
     router.get("/lease/", handlers.lease, "lease");
+    router.post("/enrollment", handlers.enroll, "enroll");
+    router.post("/:user/grant", handlers.grant, "grant");
+    router.post("/:user/withdraw", handlers.withdraw, "withdraw");
+    router.get("/:user/consent", handlers.consent_as_of, "consent_as_of");
+    router.get("/audit", handlers.audit_log, "audit_log");
+    router.get("/audit/verify", handlers.audit_verify, "audit_verify");
+    router.post("/revocations", handlers.revoke, "revoke");
+    router.get("/crl", handlers.crl, "crl");
+    router.post("/ocsp", handlers.ocsp, "ocsp");
+    router.get("/certificates", handlers.certificates, "certificates");
+    router.get("/certificates/:serial", handlers.certificate, "certificate");
+    router.get("/intents", handlers.intents, "intents");
+    router.post("/intents", handlers.register_intent, "register_intent");
 
     thread::spawn(move || { Iron::new(router).http("0.0.0.0:3001").unwrap(); });
 
     let addr = "0.0.0.0:8900".parse().unwrap();
-    let mut server = TcpServer::new(proto::ProtoProto, addr);
+    register::serve(consent_ref, &addr).unwrap();
+}
 
-    server.threads(8);
+// Clean up users left half onboarded, e.g. by a crash
+fn run_repair() {
+    let consent = build_engine();
 
-    server.serve(move || {
-        Ok(register::ProtoService { engine: consent_ref.clone() })
-    });
+    match consent.repair() {
+        Ok(users) => {
+            for user in &users {
+                println!("Cleaned up {}", user);
+            }
+            println!("Repaired {} users", users.len());
+        }
+        Err(err) => println!("Repair failed: {}", err),
+    }
+}
+
+// Sign a new intermediate with the offline root kept in dir,
+// creating the root the first time. The intermediate is stored
+// as the CA identity for the server to pick up.
+fn run_root(dir: &str) {
+    let mut builder = consent::ConsentEngineBuilder::new();
+    if let Ok(path) = env::var("CA_PKCS12") {
+        let password = env::var("CA_PASSWORD").unwrap_or(String::new());
+        builder.ca_pkcs12(path, password);
+    }
+    if let Ok(algorithm) = env::var("KEY_ALGORITHM") {
+        builder.key_algorithm(algorithm.parse().unwrap());
+    }
+    if let Ok(subject) = env::var("CA_SUBJECT") {
+        builder.ca_subject(subject);
+    }
+
+    let key = format!("{}/key.pem", dir);
+    let cert = format!("{}/cert.pem", dir);
+    match builder.issue_intermediate(key, cert) {
+        Ok(()) => println!("Issued a new intermediate from the root in {}", dir),
+        Err(err) => println!("Issuing intermediate failed: {}", err),
+    }
 }
 
 fn run_client(addr: String, port: u16, name: &str) {
     // Sleep such that server has time to get up and running
     thread::sleep(time::Duration::from_millis(100));
 
+    // The control channel only accepts clients with a
+    // certificate issued by the CA
+    let ca = env::var("CA_CERT").unwrap_or(String::from("ca.pem"));
+    let cert = env::var("CLIENT_CERT").unwrap_or(String::from("cert.pem"));
+    let key = env::var("CLIENT_KEY").unwrap_or(String::from("key.pem"));
+
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    {
+        let ctx = builder.builder_mut();
+        ctx.set_ca_file(&ca).unwrap();
+        ctx.set_certificate_chain_file(&cert).unwrap();
+        ctx.set_private_key_file(&key, X509_FILETYPE_PEM).unwrap();
+    }
+    let connector = builder.build();
+
     let fmt_addr = format!("{}:{}", addr, port);
-    let mut conn = match TcpStream::connect(&fmt_addr) {
+    let socket = match TcpStream::connect(&fmt_addr) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("{}", err.to_string());
+            return;
+        }
+    };
+
+    let mut conn = match connector.connect(tls::SERVER_NAME, socket) {
         Ok(conn) => conn,
         Err(err) => {
             println!("{}", err.to_string());
@@ -108,13 +287,13 @@ fn run_client(addr: String, port: u16, name: &str) {
 
         email: String::from("lol"),
     }));
-    let mut buf = Vec::new();
-    if let Err(err) = msg.encode(&mut buf) {
+    let mut buf = BytesMut::new();
+    if let Err(err) = proto::encode_frame(&msg, &mut buf, proto::DEFAULT_MAX_FRAME_LENGTH) {
         println!("{}", err.to_string());
         return;
     }
 
-    if let Err(err) = conn.write(buf.as_slice()) {
+    if let Err(err) = conn.write_all(&buf) {
         println!("{}", err.to_string());
     }
 
@@ -0,0 +1,305 @@
+use openssl::sha::sha256;
+use serde_json;
+use std::str;
+use ca::Authority;
+use error::TollaError;
+
+// prev_hash of the first entry in the log
+pub const GENESIS_HASH: &'static str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// A recorded lease decision. Every entry includes the hash of
+// the one before it, so altering, removing or reordering past
+// entries breaks the chain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    // position in the log, starting at 1
+    #[serde(rename = "_id")]
+    pub seq: i64,
+    // unix timestamp
+    pub timestamp: i64,
+    // serial number of the certificate asking for the lease
+    pub certificate: String,
+    // empty if no consent matched the certificate
+    pub user: String,
+    // intents asked for, joined by commas
+    pub intent: String,
+    pub granted: bool,
+    pub reason: String,
+    pub prev_hash: String,
+    // hex-encoded sha256 over the fields above
+    pub hash: String,
+}
+
+impl AuditEntry {
+    // Create the entry following prev, or the first entry if None
+    pub fn new(
+        prev: Option<&AuditEntry>,
+        timestamp: i64,
+        certificate: String,
+        user: String,
+        intent: String,
+        granted: bool,
+        reason: String,
+    ) -> Result<AuditEntry, TollaError> {
+        let (seq, prev_hash) = match prev {
+            Some(prev) => (prev.seq + 1, prev.hash.clone()),
+            None => (1, String::from(GENESIS_HASH)),
+        };
+
+        let mut entry = AuditEntry {
+            seq: seq,
+            timestamp: timestamp,
+            certificate: certificate,
+            user: user,
+            intent: intent,
+            granted: granted,
+            reason: reason,
+            prev_hash: prev_hash,
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash()?;
+        Ok(entry)
+    }
+
+    // Hash every field but the hash itself. The fields are
+    // json-encoded so they cannot bleed into each other.
+    pub fn compute_hash(&self) -> Result<String, TollaError> {
+        let content = serde_json::to_vec(&(
+            self.seq,
+            self.timestamp,
+            &self.certificate,
+            &self.user,
+            &self.intent,
+            self.granted,
+            &self.reason,
+            &self.prev_hash,
+        )).map_err(|e| TollaError::Integrity(e.to_string()))?;
+
+        Ok(to_hex(&sha256(&content)))
+    }
+}
+
+// The CA's signature over the head of the log. The hash chain
+// alone does not show entries cut off its end, or the whole log
+// being rewritten and rehashed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditCheckpoint {
+    // seq and hash of the entry signed
+    pub seq: i64,
+    pub hash: String,
+    // hex-encoded signature over seq and hash
+    pub signature: String,
+}
+
+impl AuditCheckpoint {
+    // Have authority sign head, the last entry in the log
+    pub fn sign(head: &AuditEntry, authority: &Authority) -> Result<AuditCheckpoint, TollaError> {
+        let content = checkpoint_content(head.seq, &head.hash)?;
+        Ok(AuditCheckpoint {
+            seq: head.seq,
+            hash: head.hash.clone(),
+            signature: to_hex(&authority.sign(&content)?),
+        })
+    }
+
+    // Check the checkpoint was signed by authority
+    pub fn verify(&self, authority: &Authority) -> Result<(), TollaError> {
+        let content = checkpoint_content(self.seq, &self.hash)?;
+        let signed = match from_hex(&self.signature) {
+            Some(signature) => authority.verify_signature(&content, &signature)?,
+            None => false,
+        };
+
+        if !signed {
+            return Err(TollaError::Integrity(
+                format!("checkpoint at entry {} is not signed by the CA", self.seq),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn checkpoint_content(seq: i64, hash: &String) -> Result<Vec<u8>, TollaError> {
+    serde_json::to_vec(&(seq, hash)).map_err(|e| TollaError::Integrity(e.to_string()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .concat()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            str::from_utf8(pair).ok().and_then(
+                |b| u8::from_str_radix(b, 16).ok(),
+            )
+        })
+        .collect()
+}
+
+// Verify that entries continue the chain after prev, and agree
+// with checkpoint. Returns the last entry checked, or an error
+// naming the first entry that does not fit.
+pub fn verify_chain(
+    prev: Option<AuditEntry>,
+    entries: &[AuditEntry],
+    checkpoint: Option<&AuditCheckpoint>,
+) -> Result<Option<AuditEntry>, TollaError> {
+    let mut prev = prev;
+
+    for entry in entries {
+        let (seq, prev_hash) = match prev {
+            Some(ref p) => (p.seq + 1, p.hash.clone()),
+            None => (1, String::from(GENESIS_HASH)),
+        };
+
+        if entry.seq != seq {
+            return Err(TollaError::Integrity(
+                format!("expected entry {}, found {}", seq, entry.seq),
+            ));
+        }
+        if entry.prev_hash != prev_hash {
+            return Err(TollaError::Integrity(
+                format!("entry {} does not follow entry {}", entry.seq, seq - 1),
+            ));
+        }
+        if entry.hash != entry.compute_hash()? {
+            return Err(TollaError::Integrity(
+                format!("entry {} has been modified", entry.seq),
+            ));
+        }
+        match checkpoint {
+            Some(c) if c.seq == entry.seq && c.hash != entry.hash => {
+                return Err(TollaError::Integrity(
+                    format!("entry {} differs from the signed checkpoint", entry.seq),
+                ))
+            }
+            _ => {}
+        }
+        prev = Some(entry.clone());
+    }
+
+    Ok(prev)
+}
+
+// Check a log ending in last is not shorter than checkpoint,
+// once verify_chain went over every entry. A log with entries
+// needs a checkpoint.
+pub fn verify_head(
+    last: Option<&AuditEntry>,
+    checkpoint: Option<&AuditCheckpoint>,
+) -> Result<(), TollaError> {
+    let seq = last.map_or(0, |e| e.seq);
+    match checkpoint {
+        None if seq > 0 => Err(TollaError::Integrity(
+            String::from("audit log has no signed checkpoint"),
+        )),
+        Some(c) if seq < c.seq => Err(TollaError::Integrity(format!(
+            "audit log ends at entry {}, before the signed entry {}",
+            seq,
+            c.seq
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use audit::{verify_chain, verify_head, AuditCheckpoint, AuditEntry};
+    use ca::{Authority, CaSubject};
+    use keys::KeyPolicy;
+
+    fn chain(len: usize) -> Vec<AuditEntry> {
+        let mut entries: Vec<AuditEntry> = Vec::new();
+        for i in 0..len {
+            let entry = AuditEntry::new(
+                entries.last(),
+                i as i64,
+                String::from("42"),
+                String::from("alice"),
+                String::from("research"),
+                i % 2 == 0,
+                String::new(),
+            ).unwrap();
+            entries.push(entry);
+        }
+        entries
+    }
+
+    #[test]
+    fn test_intact_chain_verifies() {
+        let entries = chain(4);
+        let last = verify_chain(None, &entries, None).unwrap().unwrap();
+        assert_eq!(last.seq, 4);
+
+        // Verifying in pages gives the same result
+        let first = verify_chain(None, &entries[..2], None).unwrap();
+        assert_eq!(verify_chain(first, &entries[2..], None).unwrap(), Some(last));
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let mut modified = chain(3);
+        modified[1].granted = !modified[1].granted;
+        assert!(verify_chain(None, &modified, None).is_err());
+
+        let mut removed = chain(3);
+        removed.remove(1);
+        assert!(verify_chain(None, &removed, None).is_err());
+
+        // Rehashing a modified entry breaks the link to the next one
+        let mut rehashed = chain(3);
+        rehashed[1].reason = String::from("forged");
+        rehashed[1].hash = rehashed[1].compute_hash().unwrap();
+        assert!(verify_chain(None, &rehashed, None).is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_covers_the_head() {
+        let authority = Authority::new(KeyPolicy::default(), &CaSubject::default()).unwrap();
+        let entries = chain(3);
+        let checkpoint = AuditCheckpoint::sign(&entries[2], &authority).unwrap();
+        checkpoint.verify(&authority).unwrap();
+
+        let last = verify_chain(None, &entries, Some(&checkpoint)).unwrap();
+        verify_head(last.as_ref(), Some(&checkpoint)).unwrap();
+        assert!(verify_head(last.as_ref(), None).is_err());
+        verify_head(None, None).unwrap();
+
+        // Entries cut off the end
+        let last = verify_chain(None, &entries[..2], Some(&checkpoint)).unwrap();
+        assert!(verify_head(last.as_ref(), Some(&checkpoint)).is_err());
+
+        // A rewritten log hashes to a different head
+        let mut rewritten: Vec<AuditEntry> = Vec::new();
+        for entry in &entries {
+            let entry = AuditEntry::new(
+                rewritten.last(),
+                entry.timestamp,
+                entry.certificate.clone(),
+                entry.user.clone(),
+                entry.intent.clone(),
+                !entry.granted,
+                entry.reason.clone(),
+            ).unwrap();
+            rewritten.push(entry);
+        }
+        assert!(verify_chain(None, &rewritten, None).is_ok());
+        assert!(verify_chain(None, &rewritten, Some(&checkpoint)).is_err());
+
+        // Whose head cannot be signed without the CA key
+        let other = Authority::new(KeyPolicy::default(), &CaSubject::default()).unwrap();
+        let mut forged = AuditCheckpoint::sign(&rewritten[2], &other).unwrap();
+        assert!(forged.verify(&authority).is_err());
+        forged.signature = String::from("zz");
+        assert!(forged.verify(&authority).is_err());
+    }
+}
@@ -1,246 +1,584 @@
-use openssl::rsa::Rsa;
-use openssl::pkey::PKey;
-use openssl::x509::{X509Builder, X509NameBuilder, X509Extension, X509Req};
+use openssl::pkey::{PKey, PKeyRef};
+use openssl::x509::{X509Builder, X509Name, X509NameBuilder, X509NameRef, X509Extension, X509Req,
+                    X509Crl, X509CrlBuilder, X509RevokedBuilder};
 use openssl::hash::MessageDigest;
 use openssl::asn1::Asn1Time;
-use openssl::bn::BigNum;
-use openssl::nid;
+use openssl::bn::{self, BigNum, BigNumRef};
+use openssl::nid::{self, Nid};
+use openssl::ocsp::{self, Flag, OcspBasicResponse, OcspCertId, OcspRequest, OcspResponse,
+                    OcspRevokedStatus};
+use openssl::pkcs12::Pkcs12;
+use openssl::sign::{Signer, Verifier};
+use openssl::stack::Stack;
+use openssl::x509::{X509, X509Ref, X509StoreContext};
+use openssl::x509::store::X509StoreBuilder;
 use uuid::Uuid;
 use bytes::{BufMut, BytesMut};
-use consent::Intent;
+use consent::{now, Intent};
+use error::TollaError;
+use extension::IntentExtension;
+use inventory::serial_to_hex;
+use keys::{signature_digest, KeyPolicy};
+use policy::{GrantedExtensions, IssuancePolicy};
+use revocation::Revocation;
 use tolla_proto::proto;
-use rand::random;
-
-// Structure storing the ca's
-// asymetric keypair
-pub struct Authority {
-    key_pair: PKey,
-    root_ctf: Box<Vec<u8>>,
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Where the ca's keypair and root certificate
+// are kept between restarts
+#[derive(Clone, Debug)]
+pub enum Identity {
+    // PEM-encoded private key and certificate in two files
+    Pem { key: PathBuf, cert: PathBuf },
+    // A single password protected PKCS#12 archive
+    Pkcs12 { path: PathBuf, password: String },
 }
 
-impl Authority {
-    // Creates a new builder.
-    pub fn new() -> Result<Authority, String> {
-        let rsa = match Rsa::generate(1024) {
-            Ok(kp) => kp,
-            Err(e) => return Err(e.to_string()),
-        };
+impl Identity {
+    // True if any part of the identity is present on disk
+    fn exists(&self) -> bool {
+        match *self {
+            Identity::Pem { ref key, ref cert } => key.exists() || cert.exists(),
+            Identity::Pkcs12 { ref path, .. } => path.exists(),
+        }
+    }
+}
 
-        let keypair = match PKey::from_rsa(rsa) {
-            Ok(kp) => kp,
-            Err(e) => return Err(e.to_string()),
-        };
+// Distinguished name of the authority, written like
+// "C=NO,ST=TR,O=IFI,CN=tolla". The root and intermediate share
+// it, with their role added to the common name. Certificates
+// the authority issues for itself are named after it too.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaSubject {
+    fields: Vec<(String, String)>,
+}
 
-        let mut builder = X509Builder::new().unwrap();
-        builder.set_pubkey(&keypair).unwrap();
+impl Default for CaSubject {
+    fn default() -> CaSubject {
+        "C=NO,ST=TR,O=IFI,CN=tolla".parse().unwrap()
+    }
+}
 
-        let expiration = Asn1Time::days_from_now(365).unwrap();
-        builder.set_not_after(&expiration).unwrap();
+impl FromStr for CaSubject {
+    type Err = TollaError;
+
+    fn from_str(s: &str) -> Result<CaSubject, TollaError> {
+        let mut fields = Vec::new();
+        for part in s.split(',') {
+            let mut field = part.splitn(2, '=');
+            match (field.next().map(str::trim), field.next().map(str::trim)) {
+                (Some(name), Some(value)) if !name.is_empty() && !value.is_empty() => {
+                    fields.push((name.to_string(), value.to_string()))
+                }
+                _ => return Err(TollaError::Config(format!("malformed CA subject {}", s))),
+            }
+        }
+
+        if fields.iter().filter(|&&(ref name, _)| name == "CN").count() != 1 {
+            return Err(TollaError::Config(
+                String::from("CA subject needs exactly one CN"),
+            ));
+        }
+
+        let subject = CaSubject { fields: fields };
+        subject.name("").map_err(|e| {
+            TollaError::Config(format!("invalid CA subject {}: {}", s, e))
+        })?;
+        Ok(subject)
+    }
+}
 
-        let valid = Asn1Time::days_from_now(0).unwrap();
-        builder.set_not_before(&valid).unwrap();
+impl CaSubject {
+    // The name of the CA in role, e.g. "root CA"
+    fn name(&self, role: &str) -> Result<X509Name, TollaError> {
+        let mut name = X509NameBuilder::new()?;
+        for &(ref field, ref value) in &self.fields {
+            match field.as_str() {
+                "CN" if !role.is_empty() => {
+                    name.append_entry_by_text(field, &format!("{} {}", value, role))?
+                }
+                _ => name.append_entry_by_text(field, value)?,
+            }
+        }
+        Ok(name.build())
+    }
+}
 
-        let mut x509_name = X509NameBuilder::new().unwrap();
-        x509_name.append_entry_by_text("C", "NO").unwrap();
-        x509_name.append_entry_by_text("ST", "TR").unwrap();
-        x509_name.append_entry_by_text("O", "IFI").unwrap();
-        x509_name.append_entry_by_text("CN", "localhost").unwrap();
-        let x509_name = x509_name.build();
+// What the OCSP responder says about a certificate
+#[derive(Debug, Clone, PartialEq)]
+pub enum CertStatus {
+    Good,
+    Revoked(Revocation),
+    // Not issued by the authority
+    Unknown,
+}
 
-        builder.set_subject_name(&x509_name).unwrap();
+// Responder certificate the authority delegates OCSP signing
+// to. Like the root certificate it is kept PEM-encoded.
+pub struct OcspDelegate {
+    key_pair: PKey,
+    cert: Vec<u8>,
+}
 
-        builder.set_issuer_name(&x509_name).unwrap();
+// Root of the hierarchy, meant to be kept offline. Its only
+// job is to sign the intermediate the online authority issues
+// certificates with.
+pub struct RootAuthority {
+    key_pair: PKey,
+    cert: Vec<u8>,
+}
 
-        let ca_ext = X509Extension::new(None, None, "basicConstraints", "CA:TRUE").unwrap();
+impl RootAuthority {
+    // Loads the root stored at identity. The keypair and
+    // certificate are only generated (and written) if nothing
+    // is stored there yet.
+    pub fn load_or_create(
+        identity: &Identity,
+        key_policy: KeyPolicy,
+        subject: &CaSubject,
+    ) -> Result<RootAuthority, TollaError> {
+        if identity.exists() {
+            info!("Loading root CA from {:?}", identity);
+            let (key_pair, cert, _) = load_identity(identity)?;
+            return Ok(RootAuthority {
+                key_pair: key_pair,
+                cert: cert.to_pem()?,
+            });
+        }
+
+        info!("No root CA found, creating {:?}", identity);
+        let root = RootAuthority::new(key_policy, subject)?;
+        store_identity(identity, &root.key_pair, &root.certificate()?, &[])?;
+        Ok(root)
+    }
 
-        builder.append_extension(ca_ext).unwrap();
+    // Creates a new self-signed root, valid for ten years. The
+    // CAs it signs may not sign further CAs.
+    pub fn new(key_policy: KeyPolicy, subject: &CaSubject) -> Result<RootAuthority, TollaError> {
+        let keypair = key_policy.algorithm.generate()?;
+        let cert = ca_certificate(
+            &keypair,
+            &subject.name("root CA")?,
+            None,
+            3650,
+            "critical,CA:TRUE,pathlen:1",
+            "critical,keyCertSign,cRLSign",
+        )?;
 
-        let ca_ext =
-            X509Extension::new_nid(None, None, nid::KEY_USAGE, "digitalSignature, keyCertSign")
-                .unwrap();
+        Ok(RootAuthority {
+            key_pair: keypair,
+            cert: cert.to_pem()?,
+        })
+    }
 
-        builder.append_extension(ca_ext).unwrap();
+    pub fn certificate(&self) -> Result<X509, TollaError> {
+        Ok(X509::from_pem(&self.cert)?)
+    }
 
-        let ca_ext =
-            X509Extension::new_nid(None, None, nid::EXT_KEY_USAGE, "serverAuth, clientAuth")
-                .unwrap();
+    // Sign a new intermediate for the online authority, valid
+    // for a year. It cannot sign further CAs.
+    pub fn issue_intermediate(
+        &self,
+        key_policy: KeyPolicy,
+        subject: &CaSubject,
+    ) -> Result<Authority, TollaError> {
+        let keypair = key_policy.algorithm.generate()?;
+        let root = self.certificate()?;
+        let cert = ca_certificate(
+            &keypair,
+            &subject.name("issuing CA")?,
+            Some((&root, &self.key_pair)),
+            365,
+            "critical,CA:TRUE,pathlen:0",
+            "critical,digitalSignature,keyCertSign,cRLSign",
+        )?;
 
-        builder.append_extension(ca_ext).unwrap();
+        Ok(Authority {
+            key_pair: keypair,
+            ca_cert: cert.to_pem()?,
+            root_cert: self.cert.clone(),
+            key_policy: key_policy,
+        })
+    }
+}
 
-        builder.sign(&keypair, MessageDigest::sha256()).unwrap();
+// The online authority. It issues certificates with an
+// intermediate signed by the root.
+pub struct Authority {
+    key_pair: PKey,
+    // PEM-encoded intermediate and root certificate. They are
+    // the same for authorities from before the root was split
+    // off.
+    ca_cert: Vec<u8>,
+    root_cert: Vec<u8>,
+    key_policy: KeyPolicy,
+}
 
-        let x509 = builder.build();
+impl Authority {
+    // Loads the authority stored at identity. A new hierarchy
+    // is only generated (and written) if nothing is stored
+    // there yet.
+    pub fn load_or_create(
+        identity: &Identity,
+        key_policy: KeyPolicy,
+        subject: &CaSubject,
+    ) -> Result<Authority, TollaError> {
+        if identity.exists() {
+            info!("Loading CA identity from {:?}", identity);
+            return Authority::load(identity, key_policy);
+        }
+
+        warn!("No CA identity found, creating {:?} under a throwaway root", identity);
+        let authority = Authority::new(key_policy, subject)?;
+        authority.store(identity)?;
+        Ok(authority)
+    }
 
-        let x509_pem = x509.to_pem().unwrap();
+    // Loads a previously stored authority: the intermediate's
+    // keypair and certificate, followed by the root. Fails if
+    // the stored key does not belong to the stored certificate,
+    // or the root did not issue it. The stored key is kept even
+    // if key_policy would generate another kind.
+    pub fn load(identity: &Identity, key_policy: KeyPolicy) -> Result<Authority, TollaError> {
+        let (key_pair, cert, chain) = load_identity(identity)?;
+
+        let root_cert = match chain.last() {
+            Some(root) => {
+                if root.issued(&cert).is_err() {
+                    return Err(TollaError::Crypto(String::from(
+                        "stored CA certificate was not issued by the stored root",
+                    )));
+                }
+                root.to_pem()?
+            }
+            None => cert.to_pem()?,
+        };
 
         Ok(Authority {
-            key_pair: keypair,
-            root_ctf: Box::new(x509_pem),
+            key_pair: key_pair,
+            ca_cert: cert.to_pem()?,
+            root_cert: root_cert,
+            key_policy: key_policy,
         })
     }
 
+    // Writes the keypair, the intermediate and the root to
+    // identity. The root's key is not part of it.
+    pub fn store(&self, identity: &Identity) -> Result<(), TollaError> {
+        let mut chain = Vec::new();
+        if self.ca_cert != self.root_cert {
+            chain.push(self.root_certificate()?);
+        }
+        store_identity(identity, &self.key_pair, &self.certificate()?, &chain)
+    }
+
+    // Creates a new hierarchy with keypairs of the algorithm in
+    // key_policy. The root's key is thrown away, so no further
+    // intermediates can be issued; see RootAuthority for that.
+    pub fn new(key_policy: KeyPolicy, subject: &CaSubject) -> Result<Authority, TollaError> {
+        RootAuthority::new(key_policy, subject)?.issue_intermediate(key_policy, subject)
+    }
+
+    // The root certificate relying parties should trust
     pub fn get_cert(&self) -> Vec<u8> {
-        self.root_ctf.clone().to_vec()
+        self.root_cert.clone()
+    }
+
+    // The intermediate certificates are signed with, parsed
+    pub fn certificate(&self) -> Result<X509, TollaError> {
+        Ok(X509::from_pem(&self.ca_cert)?)
+    }
+
+    pub fn root_certificate(&self) -> Result<X509, TollaError> {
+        Ok(X509::from_pem(&self.root_cert)?)
+    }
+
+    // The intermediate followed by the root, PEM-encoded
+    pub fn chain(&self) -> Vec<u8> {
+        let mut chain = self.ca_cert.clone();
+        if self.ca_cert != self.root_cert {
+            chain.extend_from_slice(&self.root_cert);
+        }
+        chain
+    }
+
+    // Sign data with the CA key, e.g. to vouch for the head of
+    // the audit log
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, TollaError> {
+        let mut signer = Signer::new(signature_digest(&self.key_pair), &self.key_pair)?;
+        signer.update(data)?;
+        Ok(signer.finish()?)
+    }
+
+    // Whether signature over data was made with the CA key
+    pub fn verify_signature(&self, data: &[u8], signature: &[u8]) -> Result<bool, TollaError> {
+        let mut verifier = Verifier::new(signature_digest(&self.key_pair), &self.key_pair)?;
+        verifier.update(data)?;
+        Ok(verifier.finish(signature).unwrap_or(false))
     }
 
-    // Create certificate and keypair
+    // Create certificate and keypair for a tenant database,
+    // valid for the DNS name host and the IP address ip
     pub fn create_db_certificate(
         &self,
+        serial_number: &BigNumRef,
+        host: &str,
+        ip: &str,
         keys: &mut BytesMut,
         cert: &mut BytesMut,
-    ) -> Result<u32, String> {
-        let rsa = match Rsa::generate(1024) {
-            Ok(kp) => kp,
-            Err(e) => return Err(e.to_string()),
-        };
+    ) -> Result<(), TollaError> {
+        let valid_host = host.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '.' => true,
+            _ => false,
+        });
+        if host.is_empty() || !valid_host {
+            return Err(TollaError::InvalidRequest(format!("{} is not a DNS name", host)));
+        }
+        let ip = ip.parse::<IpAddr>().map_err(|_| {
+            TollaError::InvalidRequest(format!("{} is not an IP address", ip))
+        })?;
+        let alt_names = format!("DNS:{},IP:{}", host, ip);
+
+        let (keypair, x509) = self.issue_certificate(
+            "user",
+            serial_number,
+            365,
+            &[
+                (nid::EXT_KEY_USAGE, "serverAuth"),
+                (nid::SUBJECT_ALT_NAME, &alt_names),
+            ],
+        )?;
 
-        let keypair = match PKey::from_rsa(rsa) {
-            Ok(kp) => kp,
-            Err(e) => return Err(e.to_string()),
-        };
+        // Relying parties get the chain up to the root
+        let mut x509_pem = x509.to_pem()?;
+        x509_pem.extend_from_slice(&self.chain());
+        let keys_pem = keypair.private_key_to_pem().unwrap();
 
-        let mut builder = X509Builder::new().unwrap();
-        builder.set_pubkey(&keypair).unwrap();
+        // make sure that the buffers are all zeroed
+        keys.clear();
+        cert.clear();
 
-        builder.set_version(3).map_err(|e| e.to_string())?;
+        cert.reserve(x509_pem.len());
+        keys.reserve(keys_pem.len());
+        cert.put_slice(x509_pem.as_slice());
+        keys.put_slice(keys_pem.as_slice());
 
-        let rand_num = random::<u32>();
+        Ok(())
+    }
 
-        let bignum = BigNum::from_u32(rand_num).map_err(|e| e.to_string())?;
-        let serial_number = bignum.to_asn1_integer().map_err(|e| e.to_string())?;
-        builder.set_serial_number(&serial_number).map_err(
-            |e| e.to_string(),
+    // Create the keypair and certificate the control channel
+    // presents to clients
+    pub fn create_server_certificate(
+        &self,
+        common_name: &str,
+    ) -> Result<(PKey, X509), TollaError> {
+        self.issue_certificate(
+            common_name,
+            &random_serial_number()?,
+            365,
+            &[(nid::EXT_KEY_USAGE, "serverAuth")],
+        )
+    }
+
+    // Create a responder certificate the authority delegates
+    // OCSP signing to. Clients are told not to check its own
+    // revocation status, so it only lives for a week.
+    pub fn create_ocsp_delegate(&self) -> Result<OcspDelegate, TollaError> {
+        let (keypair, x509) = self.issue_certificate(
+            "ocsp",
+            &random_serial_number()?,
+            7,
+            &[
+                (nid::KEY_USAGE, "digitalSignature"),
+                (nid::EXT_KEY_USAGE, "OCSPSigning"),
+                (nid::ID_PKIX_OCSP_NOCHECK, "ignored"),
+            ],
         )?;
+        Ok(OcspDelegate {
+            key_pair: keypair,
+            cert: x509.to_pem()?,
+        })
+    }
 
-        let expiration = Asn1Time::days_from_now(365).unwrap();
+    // Issue a certificate for common_name, valid for days, with
+    // the given extensions, along with a fresh keypair
+    fn issue_certificate(
+        &self,
+        common_name: &str,
+        serial_number: &BigNumRef,
+        days: u32,
+        extensions: &[(Nid, &str)],
+    ) -> Result<(PKey, X509), TollaError> {
+        let keypair = self.key_policy.algorithm.generate()?;
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_pubkey(&keypair).unwrap();
+
+        // X.509 v3, versions count from 0
+        builder.set_version(2)?;
+
+        builder.set_serial_number(&serial_number.to_asn1_integer()?)?;
+
+        let expiration = Asn1Time::days_from_now(days).unwrap();
         builder.set_not_after(&expiration).unwrap();
 
         let valid = Asn1Time::days_from_now(0).unwrap();
         builder.set_not_before(&valid).unwrap();
 
-        let mut x509_name = X509NameBuilder::new().map_err(|e| e.to_string())?;
-        x509_name.append_entry_by_text("C", "NO").map_err(
-            |e| e.to_string(),
-        )?;
-        x509_name.append_entry_by_text("ST", "TR").map_err(
-            |e| e.to_string(),
-        )?;
-        x509_name.append_entry_by_text("O", "IFI").map_err(
-            |e| e.to_string(),
-        )?;
-        x509_name.append_entry_by_text("CN", "user").map_err(|e| {
-            e.to_string()
-        })?;
+        // Named like the CA, but for the common name
+        let ca_cert = self.certificate()?;
+        let mut x509_name = X509NameBuilder::new()?;
+        for entry in ca_cert.subject_name().entries() {
+            let nid = entry.object().nid();
+            if nid != nid::COMMONNAME {
+                x509_name.append_entry_by_nid(nid, &entry.data().as_utf8()?)?;
+            }
+        }
+        x509_name.append_entry_by_text("CN", common_name)?;
         let x509_name = x509_name.build();
 
         builder.set_subject_name(&x509_name).unwrap();
+        builder.set_issuer_name(ca_cert.subject_name())?;
 
-        let mut x509_name = X509NameBuilder::new().unwrap();
-        x509_name.append_entry_by_text("C", "NO").unwrap();
-        x509_name.append_entry_by_text("ST", "TR").unwrap();
-        x509_name.append_entry_by_text("O", "IFI").unwrap();
-        x509_name.append_entry_by_text("CN", "localhost").unwrap();
-        let x509_name = x509_name.build();
-
-        builder.set_issuer_name(&x509_name).unwrap();
-        let key = X509Extension::new_nid(None, None, nid::EXT_KEY_USAGE, "serverAuth").unwrap();
-
-        builder.append_extension(key).unwrap();
+        let ext = authority_key_id(&builder, &ca_cert)?;
+        builder.append_extension(ext)?;
 
+        for &(nid, value) in extensions {
+            let ext = X509Extension::new_nid(None, None, nid, value)?;
+            builder.append_extension(ext)?;
+        }
 
         builder
-            .sign(&self.key_pair, MessageDigest::sha256())
+            .sign(&self.key_pair, signature_digest(&self.key_pair))
             .unwrap();
 
-        let x509 = builder.build();
-
-        let x509_pem = x509.to_pem().map_err(|e| e.to_string())?;
-        let keys_pem = keypair.private_key_to_pem().unwrap();
-
-        // make sure that the buffers are all zeroed
-        keys.clear();
-        cert.clear();
-
-        cert.reserve(x509_pem.len());
-        keys.reserve(keys_pem.len());
-        cert.put_slice(x509_pem.as_slice());
-        keys.put_slice(keys_pem.as_slice());
-
-        Ok((rand_num))
+        Ok((keypair, builder.build()))
     }
 
-    // Create an identity certificate from ceritficate request.
+    // Create an identity certificate for intents from ceritficate
+    // request, if it passes policy and the authority's key policy.
+    // Extensions the request asks for are granted as policy allows.
+    // With users, the certificate is limited to their data.
     pub fn sign_certificate(
         &self,
         buf: &[u8],
-        intent: String,
-    ) -> Result<(Intent, proto::Certificate), String> {
+        intents: Vec<String>,
+        users: Option<Vec<String>>,
+        serial_number: &BigNumRef,
+        policy: &IssuancePolicy,
+    ) -> Result<(Intent, proto::Certificate), TollaError> {
+        let req = X509Req::from_pem(buf).map_err(|e| {
+            TollaError::InvalidRequest(e.to_string())
+        })?;
+        let subject = policy.check(&req, &intents)?;
+        let granted = policy.check_extensions(&req)?;
+        let pubkey = req.public_key()?;
+        self.key_policy.check(&pubkey)?;
+
+        let (extension, validity) = intent_extension(intents, users, policy)?;
+        self.issue_identity_certificate(
+            &pubkey,
+            &subject,
+            &extension,
+            granted,
+            serial_number,
+            validity,
+        )
+    }
 
+    // Renew old, an identity certificate the authority issued for
+    // intents. The new certificate is for the key of request if
+    // given, which must be for the same subject, else for the
+    // key of old. Both have to pass the current policies. Users
+    // old is limited to stay as they are. Requested extensions are
+    // only granted when asked for again in request.
+    pub fn renew_certificate(
+        &self,
+        old: &X509Ref,
+        request: Option<&[u8]>,
+        intents: Vec<String>,
+        serial_number: &BigNumRef,
+        policy: &IssuancePolicy,
+    ) -> Result<(Intent, proto::Certificate), TollaError> {
+        if self.certificate()?.issued(old).is_err() {
+            return Err(TollaError::Forbidden(
+                String::from("certificate was not issued by this authority"),
+            ));
+        }
+        let subject = policy.check_subject(old.subject_name(), &intents)?;
+
+        let (pubkey, granted) = match request {
+            Some(buf) => {
+                let req = X509Req::from_pem(buf).map_err(|e| {
+                    TollaError::InvalidRequest(e.to_string())
+                })?;
+                if policy.check(&req, &intents)? != subject {
+                    return Err(TollaError::InvalidRequest(
+                        String::from("request is not for the subject of the certificate"),
+                    ));
+                }
+                (req.public_key()?, policy.check_extensions(&req)?)
+            }
+            None => (old.public_key()?, GrantedExtensions::default()),
+        };
+        self.key_policy.check(&pubkey)?;
+
+        let users = IntentExtension::from_certificate(old)?.and_then(|e| e.users);
+        let (extension, validity) = intent_extension(intents, users, policy)?;
+        self.issue_identity_certificate(
+            &pubkey,
+            &subject,
+            &extension,
+            granted,
+            serial_number,
+            validity,
+        )
+    }
+
+    // Identity certificate for pubkey with the subject attributes
+    // subject, valid for validity seconds. What it may be used
+    // for is in extension, along with the granted extensions.
+    fn issue_identity_certificate(
+        &self,
+        pubkey: &PKeyRef,
+        subject: &[(Nid, String)],
+        extension: &IntentExtension,
+        granted: GrantedExtensions,
+        serial_number: &BigNumRef,
+        validity: u32,
+    ) -> Result<(Intent, proto::Certificate), TollaError> {
         let mut cert = X509Builder::new().unwrap();
-        let req = X509Req::from_pem(buf).unwrap();
-        let pubkey = req.public_key().unwrap();
+        // X.509 v3, as the certificate carries extensions
+        cert.set_version(2)?;
 
-        let expiration = Asn1Time::days_from_now(365).unwrap();
+        let expiration = Asn1Time::from_unix(now() + validity as i64)?;
         cert.set_not_after(&expiration).unwrap();
 
         let valid = Asn1Time::days_from_now(0).unwrap();
         cert.set_not_before(&valid).unwrap();
 
-        cert.set_pubkey(&pubkey).unwrap();
-        let cn = req.subject_name()
-            .entries_by_nid(nid::COMMONNAME)
-            .nth(0)
-            .ok_or("no common name")?;
-        let country = req.subject_name()
-            .entries_by_nid(nid::COUNTRYNAME)
-            .nth(0)
-            .ok_or("no contry")?;
-        let org = req.subject_name()
-            .entries_by_nid(nid::ORGANIZATIONNAME)
-            .nth(0)
-            .ok_or("no org")?;
-        let state = req.subject_name()
-            .entries_by_nid(nid::STATEORPROVINCENAME)
-            .nth(0)
-            .ok_or("no state")?;
-
-        let mut x509_name = X509NameBuilder::new().map_err(|e| e.to_string())?;
-
-        x509_name
-            .append_entry_by_nid(nid::COMMONNAME, &cn.data().as_utf8().unwrap().to_string())
-            .map_err(|e| e.to_string())?;
-        x509_name
-            .append_entry_by_nid(
-                nid::COUNTRYNAME,
-                &country.data().as_utf8().unwrap().to_string(),
-            )
-            .map_err(|e| e.to_string())?;
-        x509_name
-            .append_entry_by_nid(
-                nid::ORGANIZATIONNAME,
-                &org.data().as_utf8().unwrap().to_string(),
-            )
-            .map_err(|e| e.to_string())?;
+        cert.set_pubkey(pubkey).unwrap();
 
-        x509_name
-            .append_entry_by_nid(
-                nid::STATEORPROVINCENAME,
-                &state.data().as_utf8().unwrap().to_string(),
-            )
-            .map_err(|e| e.to_string())?;
+        cert.set_serial_number(&serial_number.to_asn1_integer()?)?;
 
+        let mut x509_name = X509NameBuilder::new()?;
+        for &(nid, ref value) in subject {
+            x509_name.append_entry_by_nid(nid, value)?;
+        }
         let x509_name = x509_name.build();
 
         cert.set_subject_name(&x509_name).unwrap();
 
-        let mut x509_name = X509NameBuilder::new().unwrap();
-        x509_name.append_entry_by_text("C", "NO").unwrap();
-        x509_name.append_entry_by_text("ST", "TR").unwrap();
-        x509_name.append_entry_by_text("O", "IFI").unwrap();
-        x509_name.append_entry_by_text("CN", "localhost").unwrap();
-        let x509_name = x509_name.build();
+        let ca_cert = self.certificate()?;
+        cert.set_issuer_name(ca_cert.subject_name())?;
 
-        cert.set_issuer_name(&x509_name).unwrap();
+        let ext = authority_key_id(&cert, &ca_cert)?;
+        cert.append_extension(ext)?;
 
         let subject_id = Uuid::new_v4();
         let ext = X509Extension::new_nid(
@@ -248,85 +586,619 @@ impl Authority {
             None,
             nid::SUBJECT_KEY_IDENTIFIER,
             &subject_id.simple().to_string(),
-        ).map_err(|e| e.to_string())?;
+        )?;
 
         let key = X509Extension::new_nid(None, None, nid::KEY_USAGE, "digitalSignature").unwrap();
         cert.append_extension(key).unwrap();
 
-        let key = X509Extension::new_nid(None, None, nid::EXT_KEY_USAGE, "clientAuth").unwrap();
+        let usage = match granted.server_auth {
+            true => "clientAuth,serverAuth",
+            false => "clientAuth",
+        };
+        let key = X509Extension::new_nid(None, None, nid::EXT_KEY_USAGE, usage).unwrap();
 
         cert.append_extension(key).unwrap();
 
-        cert.append_extension(ext).map_err(|e| e.to_string())?;
-
-        let mut s = String::from(intent.clone());
-        s.insert_str(0, "otherName:1.3.6.1.4.1.99;UTF8:");
-
-        let ext = X509Extension::new_nid(
-            None,
-            Some(&cert.x509v3_context(None, None)),
-            nid::SUBJECT_ALT_NAME,
-            &s,
-        ).map_err(|e| e.to_string())?;
+        if let Some(alt_names) = granted.alt_names {
+            cert.append_extension(alt_names)?;
+        }
 
-        cert.append_extension(ext).map_err(|e| e.to_string())?;
+        cert.append_extension(ext)?;
 
-        cert.sign(&self.key_pair, MessageDigest::sha256()).unwrap();
+        cert.append_extension(extension.to_x509_extension()?)?;
 
-        let serialized = cert.build().to_pem().map_err(|e| e.to_string())?;
+        cert.sign(&self.key_pair, signature_digest(&self.key_pair)).unwrap();
 
-        println!("{}", String::from_utf8_lossy(serialized.as_slice()));
+        let mut serialized = cert.build().to_pem()?;
+        serialized.extend_from_slice(&self.chain());
 
         let intent = Intent {
             id: subject_id.simple().to_string(),
-            intent: vec![intent.clone()],
+            intent: extension.intents.clone(),
         };
 
         Ok((
             intent,
             proto::Certificate {
-                intent: String::from(""),
+                intents: extension.intents.clone(),
+                users: extension.users.clone().unwrap_or_default(),
                 request: serialized,
-                root_cert: self.root_ctf.clone().to_vec(),
+                root_cert: self.chain(),
             },
         ))
     }
+
+    // Create a CRL listing revocations, signed by the authority.
+    // number must grow with every CRL. Relying parties should
+    // fetch a new one within a day.
+    pub fn create_crl(
+        &self,
+        revocations: &[Revocation],
+        number: u32,
+    ) -> Result<X509Crl, TollaError> {
+        let cert = self.certificate()?;
+
+        let mut builder = X509CrlBuilder::new()?;
+        builder.set_version(1)?;
+        builder.set_issuer_name(cert.subject_name())?;
+        builder.set_last_update(&Asn1Time::days_from_now(0)?)?;
+        builder.set_next_update(&Asn1Time::days_from_now(1)?)?;
+        builder.set_crl_number(&BigNum::from_u32(number)?.to_asn1_integer()?)?;
+
+        for revocation in revocations {
+            let serial_number = BigNum::from_hex_str(&revocation.serial_number)?;
+
+            let mut revoked = X509RevokedBuilder::new()?;
+            revoked.set_serial_number(&serial_number.to_asn1_integer()?)?;
+            revoked.set_revocation_date(&Asn1Time::from_unix(revocation.revoked_at)?)?;
+            revoked.set_reason(revocation.reason.crl_reason())?;
+            builder.add_revoked(revoked.build())?;
+        }
+
+        builder.sign(&self.key_pair, signature_digest(&self.key_pair))?;
+        Ok(builder.build())
+    }
+
+    // Verify cert up to the root, the way relying parties do,
+    // and check it against crl if given, which must be one of
+    // ours. Returns the chain, starting with cert.
+    pub fn verify_certificate(
+        &self,
+        cert: &X509Ref,
+        crl: Option<X509Crl>,
+    ) -> Result<Stack<X509>, TollaError> {
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(self.root_certificate()?)?;
+        let store = store.build();
+
+        let mut untrusted = Stack::new()?;
+        if self.ca_cert != self.root_cert {
+            untrusted.push(self.certificate()?)?;
+        }
+        let crls = match crl {
+            Some(crl) => {
+                let mut crls = Stack::new()?;
+                crls.push(crl)?;
+                Some(crls)
+            }
+            None => None,
+        };
+
+        let mut context = X509StoreContext::new()?;
+        context
+            .verify_cert(&store, cert, Some(&untrusted), crls.as_ref().map(|c| &**c))?
+            .map_err(|e| {
+                TollaError::Unauthorized(format!("certificate does not verify: {}", e))
+            })
+    }
+
+    // Answer the DER-encoded OCSP request. status looks up our
+    // certificates by hex serial number; certificates from
+    // other issuers are unknown. The response echoes the nonce
+    // of the request and is signed by delegate if given, else
+    // by the authority itself.
+    pub fn create_ocsp_response<F>(
+        &self,
+        request: &[u8],
+        delegate: Option<&OcspDelegate>,
+        status: F,
+    ) -> Result<Vec<u8>, TollaError>
+    where
+        F: Fn(&str) -> Result<CertStatus, TollaError>,
+    {
+        let req = match OcspRequest::from_der(request) {
+            Ok(req) => req,
+            Err(_) => {
+                let resp = OcspResponse::create(ocsp::RESPONSE_STATUS_MALFORMED_REQUEST, None)?;
+                return Ok(resp.to_der()?);
+            }
+        };
+
+        // Our certificates share the issuer half of their ids,
+        // which names the intermediate hashed with the algorithm
+        // the client chose
+        let cert = self.certificate()?;
+
+        let this_update = Asn1Time::days_from_now(0)?;
+        let next_update = Asn1Time::days_from_now(1)?;
+
+        let mut basic = OcspBasicResponse::new()?;
+        for id in req.cert_ids() {
+            let issuer_id = match MessageDigest::from_nid(id.hash_algorithm().nid()) {
+                Some(digest) => Some(OcspCertId::from_issuer(digest, &cert)?),
+                None => None,
+            };
+            let found = match issuer_id {
+                Some(ref issuer_id) if id.issuer_matches(issuer_id) => {
+                    status(&serial_to_hex(&id.serial_number().to_bn()?)?)?
+                }
+                _ => CertStatus::Unknown,
+            };
+
+            match found {
+                CertStatus::Good => {
+                    basic.add_status(
+                        id,
+                        ocsp::CERT_STATUS_GOOD,
+                        ocsp::REVOKED_STATUS_NO_STATUS,
+                        None,
+                        &this_update,
+                        &next_update,
+                    )?
+                }
+                CertStatus::Revoked(revocation) => {
+                    // OCSP uses the reason codes of CRLs
+                    let reason = revocation.reason.crl_reason().as_raw();
+                    let revoked_at = Asn1Time::from_unix(revocation.revoked_at)?;
+                    basic.add_status(
+                        id,
+                        ocsp::CERT_STATUS_REVOKED,
+                        OcspRevokedStatus::from_raw(reason),
+                        Some(&revoked_at),
+                        &this_update,
+                        &next_update,
+                    )?
+                }
+                CertStatus::Unknown => {
+                    basic.add_status(
+                        id,
+                        ocsp::CERT_STATUS_UNKNOWN,
+                        ocsp::REVOKED_STATUS_NO_STATUS,
+                        None,
+                        &this_update,
+                        &next_update,
+                    )?
+                }
+            }
+        }
+
+        basic.copy_nonce(&req)?;
+        match delegate {
+            Some(delegate) => {
+                let responder = X509::from_pem(&delegate.cert)?;
+                let key = &delegate.key_pair;
+                basic.sign(&responder, key, signature_digest(key), None, Flag::empty())?
+            }
+            None => {
+                let key = &self.key_pair;
+                basic.sign(&cert, key, signature_digest(key), None, Flag::empty())?
+            }
+        }
+
+        let resp = OcspResponse::create(ocsp::RESPONSE_STATUS_SUCCESSFUL, Some(&basic))?;
+        Ok(resp.to_der()?)
+    }
+}
+
+// 128 random bits. The top bit is set, so serial numbers are
+// never zero and all have the same length.
+pub fn random_serial_number() -> Result<BigNum, TollaError> {
+    let mut serial_number = BigNum::new()?;
+    serial_number.rand(128, bn::MSB_ONE, false)?;
+    Ok(serial_number)
+}
+
+// The intent extension for checked intents, and how many
+// seconds a certificate for them is valid: as long as the
+// shortest lived of them. All intents must share a legal basis.
+fn intent_extension(
+    intents: Vec<String>,
+    users: Option<Vec<String>>,
+    policy: &IssuancePolicy,
+) -> Result<(IntentExtension, u32), TollaError> {
+    let legal_basis = policy.legal_basis(&intents[0]);
+    if let Some(other) = intents.iter().find(|i| policy.legal_basis(i) != legal_basis) {
+        return Err(TollaError::InvalidRequest(format!(
+            "intents {} and {} have different legal bases",
+            intents[0],
+            other
+        )));
+    }
+
+    let validity = intents
+        .iter()
+        .map(|i| policy.validity(i))
+        .min()
+        .unwrap_or(policy.default_validity);
+
+    let extension = IntentExtension {
+        intents: intents,
+        legal_basis: legal_basis,
+        users: users,
+    };
+    Ok((extension, validity))
+}
+
+// Identifies the key of issuer in a certificate it signs. Falls
+// back to the issuer's name and serial number for CAs created
+// without a subject key identifier.
+fn authority_key_id(builder: &X509Builder, issuer: &X509Ref) -> Result<X509Extension, TollaError> {
+    Ok(X509Extension::new_nid(
+        None,
+        Some(&builder.x509v3_context(Some(issuer), None)),
+        nid::AUTHORITY_KEY_IDENTIFIER,
+        "keyid,issuer",
+    )?)
+}
+
+// Certificate for the CA with keypair and subject, valid for
+// days. issuer is the certificate and key of the signing CA,
+// None for a self-signed root.
+fn ca_certificate(
+    keypair: &PKeyRef,
+    subject: &X509NameRef,
+    issuer: Option<(&X509Ref, &PKeyRef)>,
+    days: u32,
+    basic_constraints: &str,
+    key_usage: &str,
+) -> Result<X509, TollaError> {
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&random_serial_number()?.to_asn1_integer()?)?;
+    builder.set_pubkey(keypair)?;
+    builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&Asn1Time::days_from_now(days)?)?;
+    builder.set_subject_name(subject)?;
+
+    let ext = X509Extension::new_nid(None, None, nid::BASIC_CONSTRAINTS, basic_constraints)?;
+    builder.append_extension(ext)?;
+    let ext = X509Extension::new_nid(None, None, nid::KEY_USAGE, key_usage)?;
+    builder.append_extension(ext)?;
+    let ext = X509Extension::new_nid(
+        None,
+        Some(&builder.x509v3_context(None, None)),
+        nid::SUBJECT_KEY_IDENTIFIER,
+        "hash",
+    )?;
+    builder.append_extension(ext)?;
+
+    let issuer_key = match issuer {
+        Some((issuer_cert, issuer_key)) => {
+            builder.set_issuer_name(issuer_cert.subject_name())?;
+            let ext = authority_key_id(&builder, issuer_cert)?;
+            builder.append_extension(ext)?;
+            issuer_key
+        }
+        None => {
+            // The root's own subject key identifier is read back
+            builder.set_issuer_name(subject)?;
+            let ext = X509Extension::new_nid(
+                None,
+                Some(&builder.x509v3_context(None, None)),
+                nid::AUTHORITY_KEY_IDENTIFIER,
+                "keyid:always",
+            )?;
+            builder.append_extension(ext)?;
+            keypair
+        }
+    };
+
+    builder.sign(issuer_key, signature_digest(issuer_key))?;
+    Ok(builder.build())
+}
+
+// Reads the keypair and certificate stored at identity, along
+// with any certificates stored after it. Fails if the key does
+// not belong to the certificate.
+fn load_identity(identity: &Identity) -> Result<(PKey, X509, Vec<X509>), TollaError> {
+    let (key_pair, mut certs) = match *identity {
+        Identity::Pem { ref key, ref cert } => {
+            let key = PKey::private_key_from_pem(&read_file(key)?)?;
+            let certs = X509::stack_from_pem(&read_file(cert)?)?;
+            (key, certs)
+        }
+        Identity::Pkcs12 {
+            ref path,
+            ref password,
+        } => {
+            let archive = Pkcs12::from_der(&read_file(path)?)?;
+            let parsed = archive.parse(password)?;
+            let mut certs = vec![parsed.cert];
+            certs.extend(parsed.chain);
+            (parsed.pkey, certs)
+        }
+    };
+
+    if certs.is_empty() {
+        return Err(TollaError::Crypto(String::from("no stored CA certificate")));
+    }
+    let cert = certs.remove(0);
+
+    let public_key = cert.public_key()?;
+    if !public_key.public_eq(&key_pair) {
+        return Err(TollaError::Crypto(String::from(
+            "stored CA key does not match the stored CA certificate",
+        )));
+    }
+
+    Ok((key_pair, cert, certs))
+}
+
+// Writes keypair and cert to identity, followed by chain
+fn store_identity(
+    identity: &Identity,
+    keypair: &PKeyRef,
+    cert: &X509,
+    chain: &[X509],
+) -> Result<(), TollaError> {
+    match *identity {
+        Identity::Pem { key: ref key_path, cert: ref cert_path } => {
+            let mut pem = cert.to_pem()?;
+            for extra in chain {
+                pem.extend_from_slice(&extra.to_pem()?);
+            }
+            write_file(key_path, &keypair.private_key_to_pem()?, 0o600)?;
+            write_file(cert_path, &pem, 0o644)
+        }
+        Identity::Pkcs12 {
+            ref path,
+            ref password,
+        } => {
+            let mut ca = Stack::new()?;
+            for extra in chain {
+                ca.push(extra.clone())?;
+            }
+            let mut builder = Pkcs12::builder();
+            builder.ca(ca);
+            let archive = builder.build(password, "tolla-ca", keypair, cert)?;
+            write_file(path, &archive.to_der()?, 0o600)
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, TollaError> {
+    let mut file = File::open(path).map_err(|e| storage_error(path, e))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).map_err(
+        |e| storage_error(path, e),
+    )?;
+    Ok(buf)
+}
+
+// Writes content to path, creating any missing parent directories
+fn write_file(path: &Path, content: &[u8], mode: u32) -> Result<(), TollaError> {
+    if let Some(parent) = path.parent() {
+        DirBuilder::new().recursive(true).create(parent).map_err(
+            |e| storage_error(parent, e),
+        )?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(mode)
+        .open(path)
+        .map_err(|e| storage_error(path, e))?;
+
+    file.write_all(content).map_err(|e| storage_error(path, e))
+}
+
+fn storage_error(path: &Path, err: io::Error) -> TollaError {
+    TollaError::Storage(format!("{}: {}", path.display(), err.to_string()))
 }
 
 #[cfg(test)]
 mod test {
-    use ca;
+    use ca::{self, CaSubject};
     use openssl::rsa::Rsa;
     use openssl::pkey::PKey;
-    use openssl::x509::X509ReqBuilder;
+    use openssl::x509::{X509Extension, X509ReqBuilder};
     use openssl::hash::MessageDigest;
     use openssl::x509::X509;
     use openssl::x509::X509NameBuilder;
+    use openssl::bn::BigNum;
+    use openssl::ocsp::{self, Flag, OcspBasicResponse, OcspCertId, OcspRequest, OcspResponse};
+    use openssl::stack::Stack;
+    use openssl::x509::store::X509StoreBuilder;
+    use bytes::BytesMut;
+    use error::TollaError;
+    use extension::{IntentExtension, LegalBasis};
+    use inventory::{describe_certificate, serial_to_hex};
+    use keys::{KeyAlgorithm, KeyPolicy};
+    use policy::{IssuancePolicy, RequestedExtension};
     use openssl::nid;
+    use revocation::{Reason, Revocation};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        env::temp_dir().join(format!("tolla-ca-{}", Uuid::new_v4().simple()))
+    }
+
+    fn pem_identity(dir: &PathBuf) -> ca::Identity {
+        ca::Identity::Pem {
+            key: dir.join("key.pem"),
+            cert: dir.join("cert.pem"),
+        }
+    }
+
+    fn authority() -> ca::Authority {
+        ca::Authority::new(KeyPolicy::default(), &CaSubject::default()).unwrap()
+    }
+
+    fn load_or_create(identity: &ca::Identity) -> Result<ca::Authority, TollaError> {
+        ca::Authority::load_or_create(identity, KeyPolicy::default(), &CaSubject::default())
+    }
 
     #[test]
     fn test_create_certificate() {
-        let authority = ca::Authority::new();
+        let authority = ca::Authority::new(KeyPolicy::default(), &CaSubject::default());
         assert!(authority.is_ok(), true);
     }
 
+    #[test]
+    fn test_pem_identity_survives_restart() {
+        let dir = temp_dir();
+        let identity = pem_identity(&dir);
+
+        let first = load_or_create(&identity).unwrap();
+        let second = load_or_create(&identity).unwrap();
+
+        assert_eq!(first.get_cert(), second.get_cert());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pkcs12_identity_survives_restart() {
+        let dir = temp_dir();
+        let identity = ca::Identity::Pkcs12 {
+            path: dir.join("ca.p12"),
+            password: String::from("secret"),
+        };
+
+        let first = load_or_create(&identity).unwrap();
+        let second = load_or_create(&identity).unwrap();
+
+        assert_eq!(first.get_cert(), second.get_cert());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mismatched_identity_is_rejected() {
+        let dir = temp_dir();
+        let other_dir = temp_dir();
+        load_or_create(&pem_identity(&dir)).unwrap();
+        load_or_create(&pem_identity(&other_dir)).unwrap();
+
+        fs::copy(other_dir.join("key.pem"), dir.join("key.pem")).unwrap();
+
+        assert!(load_or_create(&pem_identity(&dir)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&other_dir).unwrap();
+    }
+
+    #[test]
+    fn test_root_issues_intermediate() {
+        let dir = temp_dir();
+        let root_identity = pem_identity(&dir.join("root"));
+        let identity = pem_identity(&dir.join("ca"));
+        let subject = CaSubject::default();
+
+        let root =
+            ca::RootAuthority::load_or_create(&root_identity, KeyPolicy::default(), &subject)
+                .unwrap();
+        root.issue_intermediate(KeyPolicy::default(), &subject)
+            .unwrap()
+            .store(&identity)
+            .unwrap();
+
+        // The stored chain ends in the root, which issued the intermediate
+        let authority = ca::Authority::load(&identity, KeyPolicy::default()).unwrap();
+        let root_cert = root.certificate().unwrap();
+        let ca_cert = authority.certificate().unwrap();
+        assert_eq!(authority.get_cert(), root_cert.to_pem().unwrap());
+        assert!(root_cert.issued(&ca_cert).is_ok());
+        assert!(ca_cert.issued(&ca_cert).is_err());
+
+        // A restarted root keeps its key
+        let restarted =
+            ca::RootAuthority::load_or_create(&root_identity, KeyPolicy::default(), &subject)
+                .unwrap();
+        assert_eq!(restarted.certificate().unwrap().to_pem().unwrap(), authority.get_cert());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_issuer_follows_ca_subject() {
+        let subject = "C=NO, O=UiT, CN=research".parse::<CaSubject>().unwrap();
+        let authority = ca::Authority::new(KeyPolicy::default(), &subject).unwrap();
+        let ca_cert = authority.certificate().unwrap();
+        let common_name = |cert: &X509| {
+            let entry = cert.subject_name().entries_by_nid(nid::COMMONNAME).next().unwrap();
+            entry.data().as_utf8().unwrap().to_string()
+        };
+        assert_eq!(common_name(&ca_cert), "research issuing CA");
+        assert_eq!(common_name(&authority.root_certificate().unwrap()), "research root CA");
+
+        // Issued certificates chain to the CA by name and key id,
+        // both of which issued checks
+        let (_, server) = authority.create_server_certificate("tolla").unwrap();
+        assert!(ca_cert.issued(&server).is_ok());
+        assert!(authority.root_certificate().unwrap().issued(&server).is_err());
+
+        let organization = server.subject_name().entries_by_nid(nid::ORGANIZATIONNAME).next();
+        assert_eq!(organization.unwrap().data().as_utf8().unwrap().to_string(), "UiT");
+
+        assert!("C=NO,O=UiT".parse::<CaSubject>().is_err());
+        assert!("C=NO,CN".parse::<CaSubject>().is_err());
+        assert!("XX=1,CN=tolla".parse::<CaSubject>().is_err());
+    }
+
+    // PEM-encoded certificate request for keypair
+    fn cert_request(keypair: &PKey) -> Vec<u8> {
+        let mut req = X509ReqBuilder::new().unwrap();
+        req.set_pubkey(keypair).unwrap();
+
+        let mut x509_name = X509NameBuilder::new().unwrap();
+        x509_name.append_entry_by_text("C", "NO").unwrap();
+        x509_name.append_entry_by_text("ST", "TR").unwrap();
+        x509_name.append_entry_by_text("O", "IFI").unwrap();
+        x509_name.append_entry_by_text("CN", "process").unwrap();
+        req.set_subject_name(&x509_name.build()).unwrap();
+
+        req.sign(keypair, MessageDigest::sha256()).unwrap();
+        req.build().to_pem().unwrap()
+    }
+
+    // A request for process asking for alt_names, and to serve TLS
+    fn extension_request(keypair: &PKey, alt_names: &str) -> Vec<u8> {
+        let mut req = X509ReqBuilder::new().unwrap();
+        req.set_pubkey(keypair).unwrap();
+        let mut x509_name = X509NameBuilder::new().unwrap();
+        x509_name.append_entry_by_text("CN", "process").unwrap();
+        req.set_subject_name(&x509_name.build()).unwrap();
+
+        let mut extensions = Stack::new().unwrap();
+        let san = X509Extension::new_nid(None, None, nid::SUBJECT_ALT_NAME, alt_names).unwrap();
+        extensions.push(san).unwrap();
+        let eku = X509Extension::new_nid(None, None, nid::EXT_KEY_USAGE, "serverAuth").unwrap();
+        extensions.push(eku).unwrap();
+        req.add_extensions(&extensions).unwrap();
+
+        req.sign(keypair, MessageDigest::sha256()).unwrap();
+        req.build().to_pem().unwrap()
+    }
+
     #[test]
     fn test_cert_request() {
-        let authority = ca::Authority::new();
+        let authority = ca::Authority::new(KeyPolicy::default(), &CaSubject::default());
         assert!(authority.is_ok(), true);
         let authority = authority.unwrap();
 
         let mut req = X509ReqBuilder::new().unwrap();
-        let rsa = Rsa::generate(1024).unwrap();
+        let rsa = Rsa::generate(2048).unwrap();
 
         let keypair = PKey::from_rsa(rsa).unwrap();
 
         req.set_pubkey(&keypair).unwrap();
 
         let mut x509_name = X509NameBuilder::new().unwrap();
-        x509_name
-            .append_entry_by_nid(nid::BASIC_CONSTRAINTS, "ASN1:UTF8String:Purpose")
-            .unwrap();
+        x509_name.append_entry_by_text("C", "NO").unwrap();
+        x509_name.append_entry_by_text("ST", "TR").unwrap();
+        x509_name.append_entry_by_text("O", "IFI").unwrap();
+        x509_name.append_entry_by_text("CN", "process").unwrap();
         req.set_subject_name(&x509_name.build()).unwrap();
 
         req.sign(&keypair, MessageDigest::sha256()).unwrap();
@@ -335,10 +1207,339 @@ mod test {
 
         let pem_raw = req.to_pem().unwrap();
 
-        println!("{}", String::from(pem_raw));
+        let res = authority
+            .sign_certificate(
+                &pem_raw,
+                vec![String::from("Purpose")],
+                None,
+                &ca::random_serial_number().unwrap(),
+                &IssuancePolicy::default(),
+            )
+            .unwrap();
+
+        X509::from_pem(res.1.request.as_slice()).unwrap();
+    }
+
+    #[test]
+    fn test_issued_chain() {
+        let authority = authority();
+        let keypair = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let res = authority
+            .sign_certificate(
+                &cert_request(&keypair),
+                vec![String::from("Purpose")],
+                None,
+                &ca::random_serial_number().unwrap(),
+                &IssuancePolicy::default(),
+            )
+            .unwrap();
+
+        // The leaf comes with the chain up to the root
+        let chain = X509::stack_from_pem(res.1.request.as_slice()).unwrap();
+        assert_eq!(chain.len(), 3);
+        assert!(chain[1].issued(&chain[0]).is_ok());
+        assert!(chain[2].issued(&chain[1]).is_ok());
+        assert_eq!(chain[2].to_pem().unwrap(), authority.get_cert());
+        assert_eq!(res.1.root_cert, authority.chain());
+
+        // The intent can be read back from the certificate
+        let (serial_number, subject, intents) = describe_certificate(&chain[0]).unwrap();
+        let serial = chain[0].serial_number().to_bn().unwrap();
+        assert_eq!(serial_number, serial_to_hex(&serial).unwrap());
+        assert!(subject.ends_with("CN=process"));
+        assert_eq!(intents, vec![String::from("Purpose")]);
+
+        // In its own extension rather than the subjectAltName
+        let extension = IntentExtension::from_certificate(&chain[0]).unwrap().unwrap();
+        assert_eq!(extension.legal_basis, LegalBasis::Consent);
+        assert_eq!(extension.users, None);
+        assert!(chain[0].subject_alt_names().is_none());
+    }
+
+    #[test]
+    fn test_multiple_intents() {
+        let authority = authority();
+        let keypair = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let intents = vec![String::from("research"), String::from("statistics")];
+
+        let mut policy = IssuancePolicy::default();
+        policy.validity.insert(String::from("statistics"), 3600);
+        let users = vec![String::from("alice")];
+        let (intent, cert) = authority
+            .sign_certificate(
+                &cert_request(&keypair),
+                intents.clone(),
+                Some(users.clone()),
+                &ca::random_serial_number().unwrap(),
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(intent.intent, intents);
+        assert_eq!(cert.users, users);
+        let cert = X509::from_pem(&cert.request).unwrap();
+        assert_eq!(describe_certificate(&cert).unwrap().2, intents);
+        let extension = IntentExtension::from_certificate(&cert).unwrap().unwrap();
+        assert_eq!(extension.users, Some(users));
+
+        // Only intents processed under the same legal basis
+        policy.legal_basis.insert(String::from("statistics"), LegalBasis::PublicTask);
+        let res = authority.sign_certificate(
+            &cert_request(&keypair),
+            intents,
+            None,
+            &ca::random_serial_number().unwrap(),
+            &policy,
+        );
+        match res {
+            Err(TollaError::InvalidRequest(_)) => (),
+            other => panic!("expected InvalidRequest, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_requested_extensions() {
+        let authority = authority();
+        let keypair = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let sign = |req: &[u8], policy: &IssuancePolicy| {
+            authority.sign_certificate(
+                req,
+                vec![String::from("research")],
+                None,
+                &ca::random_serial_number().unwrap(),
+                policy,
+            )
+        };
+        let is_forbidden = |res: Result<_, TollaError>| match res {
+            Err(TollaError::Forbidden(_)) => true,
+            _ => false,
+        };
+
+        // Refused unless the policy allows them
+        let req = extension_request(&keypair, "DNS:process.example.com");
+        let mut policy = IssuancePolicy::default();
+        assert!(is_forbidden(sign(&req, &policy)));
+
+        policy.extensions = vec![
+            RequestedExtension::SubjectAltName,
+            RequestedExtension::ExtendedKeyUsage,
+        ];
+        let (_, cert) = sign(&req, &policy).unwrap();
+        let cert = X509::from_pem(&cert.request).unwrap();
+        let names = cert.subject_alt_names().unwrap();
+        assert_eq!(names[0].dnsname(), Some("process.example.com"));
+        assert!(IntentExtension::from_certificate(&cert).unwrap().is_some());
+
+        let req = extension_request(&keypair, "email:process@example.com");
+        assert!(is_forbidden(sign(&req, &policy)));
+    }
+
+    #[test]
+    fn test_weak_keys_are_rejected() {
+        let authority = authority();
+
+        let keypair = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+        let res = authority.sign_certificate(
+            &cert_request(&keypair),
+            vec![String::from("Purpose")],
+            None,
+            &ca::random_serial_number().unwrap(),
+            &IssuancePolicy::default(),
+        );
+        match res {
+            Err(TollaError::InvalidRequest(_)) => (),
+            other => panic!("expected InvalidRequest, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_ecdsa_authority() {
+        let policy = KeyPolicy {
+            algorithm: KeyAlgorithm::EcdsaP384,
+            min_strength: 128,
+        };
+        let authority = ca::Authority::new(policy, &CaSubject::default()).unwrap();
+        let ca_cert = authority.certificate().unwrap();
+        assert_eq!(ca_cert.signature_algorithm().object().nid(), nid::ECDSA_WITH_SHA384);
+
+        let keypair = KeyAlgorithm::EcdsaP256.generate().unwrap();
+        let (_, cert) = authority
+            .sign_certificate(
+                &cert_request(&keypair),
+                vec![String::from("Purpose")],
+                None,
+                &ca::random_serial_number().unwrap(),
+                &IssuancePolicy::default(),
+            )
+            .unwrap();
+        let cert = X509::from_pem(&cert.request).unwrap();
+        assert_eq!(cert.signature_algorithm().object().nid(), nid::ECDSA_WITH_SHA384);
+        assert!(ca_cert.issued(&cert).is_ok());
+
+        // Tenant keys follow the policy as well
+        let mut key = BytesMut::new();
+        let mut pem = BytesMut::new();
+        let serial_number = ca::random_serial_number().unwrap();
+        authority
+            .create_db_certificate(&serial_number, "alice", "10.0.0.2", &mut key, &mut pem)
+            .unwrap();
+        let db_cert = X509::from_pem(&pem).unwrap();
+        assert_eq!(db_cert.public_key().unwrap().bits(), 384);
+
+        // Reachable by name and address
+        let names = db_cert.subject_alt_names().unwrap();
+        assert_eq!(names[0].dnsname(), Some("alice"));
+        assert_eq!(names[1].ipaddress(), Some(&[10, 0, 0, 2][..]));
+
+        let host = "alice,IP:1.1.1.1";
+        let ip = "10.0.0.2";
+        let res = authority.create_db_certificate(&serial_number, host, ip, &mut key, &mut pem);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_crl_lists_revocations() {
+        let authority = authority();
+        let revocations = vec![
+            Revocation {
+                serial_number: String::from("4d2"),
+                reason: Reason::KeyCompromise,
+                revoked_at: 1500000000,
+            },
+        ];
 
-        let res = authority.sign_certificate(&pem_raw).unwrap();
+        let crl = authority.create_crl(&revocations, 1).unwrap();
+        let public_key = authority.certificate().unwrap().public_key().unwrap();
+        assert!(crl.verify(&public_key).unwrap());
+
+        let revoked = BigNum::from_u32(1234).unwrap().to_asn1_integer().unwrap();
+        let other = BigNum::from_u32(1235).unwrap().to_asn1_integer().unwrap();
+        assert!(crl.get_by_serial(&revoked).is_some());
+        assert!(crl.get_by_serial(&other).is_none());
+    }
+
+    #[test]
+    fn test_verify_certificate() {
+        let authority = authority();
+        let keypair = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let (_, cert) = authority
+            .sign_certificate(
+                &cert_request(&keypair),
+                vec![String::from("research")],
+                None,
+                &ca::random_serial_number().unwrap(),
+                &IssuancePolicy::default(),
+            )
+            .unwrap();
+        let cert = X509::from_pem(&cert.request).unwrap();
+
+        let crl = authority.create_crl(&[], 1).unwrap();
+        let chain = authority.verify_certificate(&cert, Some(crl)).unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[2].to_pem().unwrap(), authority.get_cert());
+
+        let revocations = vec![
+            Revocation {
+                serial_number: serial_to_hex(&cert.serial_number().to_bn().unwrap()).unwrap(),
+                reason: Reason::KeyCompromise,
+                revoked_at: 1500000000,
+            },
+        ];
+        let crl = authority.create_crl(&revocations, 2).unwrap();
+        match authority.verify_certificate(&cert, Some(crl)) {
+            Err(TollaError::Unauthorized(_)) => (),
+            other => panic!("expected Unauthorized, got {:?}", other.map(|_| ())),
+        }
+
+        // Certificates of another authority do not chain up to our root
+        let other = ca::Authority::new(KeyPolicy::default(), &CaSubject::default()).unwrap();
+        let crl = other.create_crl(&[], 1).unwrap();
+        assert!(other.verify_certificate(&cert, Some(crl)).is_err());
+        assert!(other.verify_certificate(&cert, None).is_err());
+    }
+
+    // Ask authority for the status of certs, answered by delegate
+    fn ocsp_status(
+        authority: &ca::Authority,
+        delegate: &ca::OcspDelegate,
+        certs: &[(&X509, &X509)],
+        digest: MessageDigest,
+        revoked_serial: &str,
+    ) -> (OcspRequest, OcspBasicResponse) {
+        let mut req = OcspRequest::new().unwrap();
+        for &(cert, issuer) in certs {
+            let id = OcspCertId::from_cert(digest, cert, issuer).unwrap();
+            req.add_id(id).unwrap();
+        }
+        req.add_nonce().unwrap();
+
+        let der = authority
+            .create_ocsp_response(&req.to_der().unwrap(), Some(delegate), |serial| {
+                match serial == revoked_serial {
+                    true => Ok(ca::CertStatus::Revoked(Revocation {
+                        serial_number: serial.to_string(),
+                        reason: Reason::KeyCompromise,
+                        revoked_at: 1500000000,
+                    })),
+                    false => Ok(ca::CertStatus::Good),
+                }
+            })
+            .unwrap();
+
+        let resp = OcspResponse::from_der(&der).unwrap();
+        assert_eq!(resp.status(), ocsp::RESPONSE_STATUS_SUCCESSFUL);
+        (req, resp.basic().unwrap())
+    }
+
+    #[test]
+    fn test_ocsp_response_by_delegate() {
+        let authority = authority();
+        let ca_cert = authority.certificate().unwrap();
+        let delegate = authority.create_ocsp_delegate().unwrap();
+        let (_, revoked) = authority.create_server_certificate("revoked").unwrap();
+        let (_, good) = authority.create_server_certificate("good").unwrap();
+        let revoked_serial = serial_to_hex(&revoked.serial_number().to_bn().unwrap()).unwrap();
+
+        let certs = [(&revoked, &ca_cert), (&good, &ca_cert)];
+        let sha1 = MessageDigest::sha1();
+        let (req, basic) = ocsp_status(&authority, &delegate, &certs, sha1, &revoked_serial);
+
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(authority.root_certificate().unwrap()).unwrap();
+        store.add_cert(ca_cert.clone()).unwrap();
+        let store = store.build();
+        basic.verify(&Stack::<X509>::new().unwrap(), &store, Flag::empty()).unwrap();
+        assert!(req.check_nonce(&basic));
+
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &revoked, &ca_cert).unwrap();
+        let status = basic.find_status(&id).unwrap();
+        assert_eq!(status.status, ocsp::CERT_STATUS_REVOKED);
+        assert_eq!(status.reason, ocsp::REVOKED_STATUS_KEY_COMPROMISE);
+
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &good, &ca_cert).unwrap();
+        assert_eq!(basic.find_status(&id).unwrap().status, ocsp::CERT_STATUS_GOOD);
+
+        // Ids hashed with SHA-256 name our certificates as well
+        let sha256 = MessageDigest::sha256();
+        let (_, basic) = ocsp_status(&authority, &delegate, &certs, sha256, &revoked_serial);
+        let id = OcspCertId::from_cert(sha256, &revoked, &ca_cert).unwrap();
+        assert_eq!(basic.find_status(&id).unwrap().status, ocsp::CERT_STATUS_REVOKED);
+
+        // Certificates of another issuer are unknown
+        let foreign = authority().certificate().unwrap();
+        let foreign_certs = [(&foreign, &foreign)];
+        let (_, basic) = ocsp_status(&authority, &delegate, &foreign_certs, sha1, "");
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &foreign, &foreign).unwrap();
+        assert_eq!(basic.find_status(&id).unwrap().status, ocsp::CERT_STATUS_UNKNOWN);
+    }
+
+    #[test]
+    fn test_malformed_ocsp_request() {
+        let authority = authority();
+        let der = authority
+            .create_ocsp_response(b"garbage", None, |_| Ok(ca::CertStatus::Good))
+            .unwrap();
 
-        X509::from_pem(res.1.as_slice()).unwrap();
+        let resp = OcspResponse::from_der(&der).unwrap();
+        assert_eq!(resp.status(), ocsp::RESPONSE_STATUS_MALFORMED_REQUEST);
     }
 }
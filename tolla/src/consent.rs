@@ -1,46 +1,282 @@
-use bson;
-use mongodb::{Client, ThreadedClient};
-use mongodb::db::ThreadedDatabase;
 use tolla_proto::proto;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use chrono::Utc;
 use bytes::BytesMut;
-use ca::Authority;
-use docker;
+use audit::{self, AuditCheckpoint, AuditEntry};
+use ca::{self, Authority, CaSubject, CertStatus, Identity, OcspDelegate};
+use error::TollaError;
+use inventory::{serial_to_hex, CertificateQuery, CertificateStatus, IssuedCertificate};
+use extension::LegalBasis;
+use keys::{KeyAlgorithm, KeyPolicy};
+use policy::{IssuancePolicy, RequestedExtension};
+use revocation::{Reason, Revocation};
+use docker::{self, ContainerRuntime, ShipliftRuntime};
+use std::path::PathBuf;
+use storage::{ConsentStore, MongoStore, SqliteStore};
+use tls::{self, PeerIdentity};
+use openssl::bn::BigNum;
+use openssl::memcmp;
+use openssl::sha::sha256;
+use openssl::ssl::SslAcceptor;
+use openssl::x509::{X509, X509Ref};
+use uuid::Uuid;
 
 // Describes a user and his consents
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Consent {
     #[serde(rename = "_id")]
     pub id: String,
-    // used to cross-reference the id with certificate
-    pub serial_number: i32,
+    // hex serial number of the database certificate, used to
+    // cross-reference the id with the certificate
+    pub serial_number: String,
     pub purpose: Vec<String>,
+    // seconds from granted_at until the consent expires, 0 for never
+    #[serde(default)]
+    pub lifetime: i64,
+    #[serde(default)]
+    pub marketing: bool,
+    #[serde(default)]
+    pub profiling: bool,
+    #[serde(default)]
+    pub public: bool,
+    // unix timestamp
+    #[serde(default)]
+    pub granted_at: i64,
+    // set by the sweeper once the lifetime has passed
+    #[serde(default)]
+    pub expired: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// Outcome of checking an intent against a consent
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Granted,
+    Denied(String),
+}
+
+impl Consent {
+    // Unix timestamp at which the consent lapses, if ever
+    pub fn expires_at(&self) -> Option<i64> {
+        match self.lifetime {
+            0 => None,
+            lifetime => Some(self.granted_at + lifetime),
+        }
+    }
+
+    // Grant or withdraw a single purpose or flag
+    fn set(&mut self, purpose: &String, granted: bool) {
+        match purpose.as_str() {
+            "marketing" => self.marketing = granted,
+            "profiling" => self.profiling = granted,
+            "public" => self.public = granted,
+            _ => {
+                self.purpose.retain(|p| p != purpose);
+                if granted {
+                    self.purpose.push(purpose.clone());
+                }
+            }
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expired || self.expires_at().map_or(false, |t| now >= t)
+    }
+
+    // Marketing, profiling and publishing need the matching flag;
+    // any other intent has to be among the purposes.
+    pub fn permits(&self, intent: &str, now: i64) -> Decision {
+        if self.is_expired(now) {
+            return Decision::Denied(String::from("Consent expired"));
+        }
+
+        let allowed = match intent {
+            "marketing" => self.marketing,
+            "profiling" => self.profiling,
+            "public" => self.public,
+            _ => self.purpose.iter().any(|p| p == intent),
+        };
+
+        match allowed {
+            true => Decision::Granted,
+            false => Decision::Denied(String::from("Consents did not match")),
+        }
+    }
+}
+
+// A change to a user's consent. Purposes passed to Grant and
+// Withdraw may also be marketing, profiling or public to
+// toggle the corresponding flag.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ConsentChange {
+    Register(Consent),
+    Grant(Vec<String>),
+    Withdraw(Vec<String>),
+    Expire,
+    Remove,
+    // The database certificate was re-issued with this hex
+    // serial number
+    Recertify(String),
+}
+
+// One entry in a user's append-only consent history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConsentEvent {
+    // <user>:<version>
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub user: String,
+    // starts at 1 and increases by one per event
+    pub version: i64,
+    // unix timestamp of when the change was recorded
+    pub timestamp: i64,
+    pub change: ConsentChange,
+}
+
+impl ConsentEvent {
+    // The state after this event, given the state before it
+    fn apply(&self, state: Option<Consent>) -> Option<Consent> {
+        let mut consent = match (&self.change, state) {
+            (&ConsentChange::Register(ref c), _) => {
+                let mut c = c.clone();
+                c.id = self.user.clone();
+                return Some(c);
+            }
+            (&ConsentChange::Remove, _) => return None,
+            (_, None) => return None,
+            (_, Some(c)) => c,
+        };
+
+        match self.change {
+            ConsentChange::Grant(ref purposes) => {
+                for purpose in purposes {
+                    consent.set(purpose, true);
+                }
+                // A re-grant renews the consent's lifetime
+                consent.granted_at = self.timestamp;
+                consent.expired = false;
+            }
+            ConsentChange::Withdraw(ref purposes) => {
+                for purpose in purposes {
+                    consent.set(purpose, false);
+                }
+            }
+            ConsentChange::Expire => consent.expired = true,
+            ConsentChange::Recertify(ref serial_number) => {
+                consent.serial_number = serial_number.clone()
+            }
+            _ => (),
+        }
+        Some(consent)
+    }
+}
+
+// Derive the consent state from a user's history
+pub fn replay(events: &[ConsentEvent]) -> Option<Consent> {
+    events.iter().fold(None, |state, event| event.apply(state))
+}
+
+// Current time as a unix timestamp
+pub fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+// User ids name containers and mount directories, so they are
+// limited to 1 to 64 of A-Z, a-z, 0-9, _ and -
+pub fn check_user_id(id: &str) -> Result<(), TollaError> {
+    let valid = !id.is_empty() && id.len() <= 64 &&
+        id.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => true,
+            _ => false,
+        });
+
+    if !valid {
+        return Err(TollaError::InvalidRequest(format!("invalid user id {:?}", id)));
+    }
+    Ok(())
+}
+
+// Names the enrollment token a certificate was requested with,
+// so the token itself is never stored
+pub fn enrollment_token_id(token: &str) -> String {
+    audit::to_hex(&sha256(token.as_bytes())[..8])
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Intent {
     #[serde(rename = "_id")]
     // Container ID
     pub id: String,
     pub intent: Vec<String>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+
+// An intent processes can be issued certificates for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegisteredIntent {
+    #[serde(rename = "_id")]
+    // e.g. "research"
+    pub name: String,
+    // What the data is processed for, as told to users
+    pub description: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct View {
     #[serde(rename = "_id")]
     pub id: String,
     pub ip: String,
 }
 
+// Random serial numbers to try before giving up on finding
+// one that is not taken
+const SERIAL_NUMBER_ATTEMPTS: u32 = 8;
+
+// Times to re-issue a database certificate for a container that
+// keeps moving when it is restarted to pick up the new one
+const RECERTIFY_ATTEMPTS: u32 = 3;
+
 pub struct ConsentEngineBuilder {
     address: Option<String>,
     port: Option<u16>,
     deamon: Option<String>,
+    ca_identity: Option<Identity>,
+    sqlite: Option<String>,
+    mount_root: Option<String>,
+    delegate_ocsp: bool,
+    key_policy: KeyPolicy,
+    issuance_policy: IssuancePolicy,
+    ca_subject: Option<String>,
+    admin_token: Option<String>,
 }
 
 pub struct ConsentEngine {
-    client: Client,
+    store: Box<ConsentStore>,
     deamon: docker::StoreManager,
     authority: Authority,
+    // Outstanding one-time tokens for certificate requests
+    enrollment_tokens: Mutex<HashSet<String>>,
+    // Holds a directory of certificates for every tenant
+    mount_root: String,
+    // Signs OCSP responses in place of the authority
+    ocsp_delegate: Option<OcspDelegate>,
+    // What certificate requests are checked against
+    issuance_policy: IssuancePolicy,
+    // Bearer token for administrative requests, which are
+    // refused if there is none
+    admin_token: Option<String>,
+}
+
+// Completed steps of onboarding a user, undone in
+// reverse if a later step fails
+#[derive(Debug)]
+enum OnboardStep {
+    // Serial number of the tenant's database certificate
+    Certificate(String),
+    Mountdir(String),
+    Container(String),
+    View(String),
 }
 
 impl ConsentEngineBuilder {
@@ -49,6 +285,14 @@ impl ConsentEngineBuilder {
             address: None,
             port: None,
             deamon: None,
+            ca_identity: None,
+            sqlite: None,
+            mount_root: None,
+            delegate_ocsp: false,
+            key_policy: KeyPolicy::default(),
+            issuance_policy: IssuancePolicy::default(),
+            ca_subject: None,
+            admin_token: None,
         }
     }
 
@@ -70,32 +314,219 @@ impl ConsentEngineBuilder {
         self
     }
 
-    pub fn build(&self) -> Result<ConsentEngine, String> {
-        let address = self.address.clone().ok_or_else(
-            || format!("address not present"),
-        )?;
-        let port = self.port.ok_or_else(|| format!("port not present"))?;
+    // Keep the CA identity as PEM-encoded key and certificate files
+    pub fn ca_pem(&mut self, key: String, cert: String) -> &mut ConsentEngineBuilder {
+        self.ca_identity = Some(Identity::Pem {
+            key: PathBuf::from(key),
+            cert: PathBuf::from(cert),
+        });
+        self
+    }
 
-        let deamon_address = self.deamon.clone().ok_or_else(
-            || format!("deamon address not present"),
-        )?;
+    // Name the CA subject, e.g. "C=NO,ST=TR,O=IFI,CN=tolla". A
+    // CA identity that is already stored keeps its name.
+    pub fn ca_subject(&mut self, subject: String) -> &mut ConsentEngineBuilder {
+        self.ca_subject = Some(subject);
+        self
+    }
 
-        let deamon = docker::StoreManager::new(&deamon_address);
+    // Keep the CA identity in a password protected PKCS#12 archive
+    pub fn ca_pkcs12(&mut self, path: String, password: String) -> &mut ConsentEngineBuilder {
+        self.ca_identity = Some(Identity::Pkcs12 {
+            path: PathBuf::from(path),
+            password: password,
+        });
+        self
+    }
 
-        let authority = Authority::new().unwrap();
+    // Store everything in a SQLite database instead of MongoDB
+    pub fn sqlite(&mut self, path: String) -> &mut ConsentEngineBuilder {
+        self.sqlite = Some(path);
+        self
+    }
 
-        let client = match Client::connect(&address, port) {
-            Err(err) => return Err(err.to_string()),
-            Ok(c) => c,
+    // Keep tenant certificates under path instead of /tmp/certificates.
+    // Tenants read them through the volumes of the tolla container.
+    pub fn mount_root(&mut self, path: String) -> &mut ConsentEngineBuilder {
+        self.mount_root = Some(path);
+        self
+    }
+
+    // Sign OCSP responses with a responder certificate issued at
+    // startup, so the CA key is not needed for every response
+    pub fn delegate_ocsp(&mut self) -> &mut ConsentEngineBuilder {
+        self.delegate_ocsp = true;
+        self
+    }
+
+    // Generate CA and tenant keys with algorithm. A CA identity
+    // that is already stored keeps its key.
+    pub fn key_algorithm(&mut self, algorithm: KeyAlgorithm) -> &mut ConsentEngineBuilder {
+        self.key_policy.algorithm = algorithm;
+        self
+    }
+
+    // Refuse certificate requests for keys with fewer bits of
+    // security than strength, e.g. 128 to require RSA-3072
+    pub fn min_key_strength(&mut self, strength: u32) -> &mut ConsentEngineBuilder {
+        self.key_policy.min_strength = strength;
+        self
+    }
+
+    // Only issue certificates to organisation. Without any
+    // registered organisation every O is accepted.
+    pub fn allow_organization(&mut self, organization: String) -> &mut ConsentEngineBuilder {
+        self.issuance_policy.organizations.push(organization);
+        self
+    }
+
+    // Issue certificates valid for seconds, unless configured
+    // per intent. Defaults to a year.
+    pub fn default_validity(&mut self, seconds: u32) -> &mut ConsentEngineBuilder {
+        self.issuance_policy.default_validity = seconds;
+        self
+    }
+
+    // Issue certificates for intent valid for seconds, e.g. 3600
+    // so that withdrawn consent takes effect within the hour
+    pub fn intent_validity(&mut self, intent: String, seconds: u32) -> &mut ConsentEngineBuilder {
+        self.issuance_policy.validity.insert(intent, seconds);
+        self
+    }
+
+    // Record in certificates for intent that it is processed
+    // under basis. Intents default to consent.
+    pub fn intent_legal_basis(
+        &mut self,
+        intent: String,
+        basis: LegalBasis,
+    ) -> &mut ConsentEngineBuilder {
+        self.issuance_policy.legal_basis.insert(intent, basis);
+        self
+    }
+
+    // Grant extension when a certificate request asks for it.
+    // Requests with other extensions are refused.
+    pub fn allow_extension(&mut self, extension: RequestedExtension) -> &mut ConsentEngineBuilder {
+        self.issuance_policy.extensions.push(extension);
+        self
+    }
+
+    // Only issue certificates for intent, out of the registered
+    // intents. Without any, every registered intent is accepted.
+    pub fn allow_intent(&mut self, intent: String) -> &mut ConsentEngineBuilder {
+        self.issuance_policy.intents.push(intent);
+        self
+    }
+
+    // Accept token as bearer token for administrative requests,
+    // like handing out enrollment tokens. Without it they are refused.
+    pub fn admin_token(&mut self, token: String) -> &mut ConsentEngineBuilder {
+        self.admin_token = Some(token);
+        self
+    }
+
+    // Build an engine backed by MongoDB, or by SQLite if a path is set
+    pub fn build(&self) -> Result<ConsentEngine, TollaError> {
+        let store: Box<ConsentStore> = match self.sqlite {
+            Some(ref path) => Box::new(SqliteStore::open(path)?),
+            None => {
+                let address = self.address.clone().ok_or_else(|| {
+                    TollaError::Config(String::from("address not present"))
+                })?;
+                let port = self.port.ok_or_else(|| {
+                    TollaError::Config(String::from("port not present"))
+                })?;
+                Box::new(MongoStore::connect(&address, port)?)
+            }
+        };
+
+        self.build_with_store(store)
+    }
+
+    // Have the offline root with PEM-encoded key and certificate
+    // sign a new intermediate, and store it as the CA identity
+    // the engine is built with. The root is created if it does
+    // not exist yet. An already stored CA identity is not
+    // overwritten.
+    pub fn issue_intermediate(
+        &self,
+        root_key: String,
+        root_cert: String,
+    ) -> Result<(), TollaError> {
+        let root_identity = Identity::Pem {
+            key: PathBuf::from(root_key),
+            cert: PathBuf::from(root_cert),
+        };
+
+        let subject = self.parse_ca_subject()?;
+        let root = ca::RootAuthority::load_or_create(&root_identity, self.key_policy, &subject)?;
+        root.issue_intermediate(self.key_policy, &subject)?
+            .store(&self.ca_identity())
+    }
+
+    fn parse_ca_subject(&self) -> Result<CaSubject, TollaError> {
+        match self.ca_subject {
+            Some(ref subject) => subject.parse(),
+            None => Ok(CaSubject::default()),
+        }
+    }
+
+    // The configured CA identity, PEM files under
+    // /var/lib/tolla/ca by default
+    fn ca_identity(&self) -> Identity {
+        self.ca_identity.clone().unwrap_or_else(|| {
+            Identity::Pem {
+                key: PathBuf::from("/var/lib/tolla/ca/key.pem"),
+                cert: PathBuf::from("/var/lib/tolla/ca/cert.pem"),
+            }
+        })
+    }
+
+    // Build an engine on top of an already constructed store
+    pub fn build_with_store(&self, store: Box<ConsentStore>) -> Result<ConsentEngine, TollaError> {
+        let deamon_address = self.deamon.clone().ok_or_else(|| {
+            TollaError::Config(String::from("deamon address not present"))
+        })?;
+
+        let runtime = ShipliftRuntime::new(&deamon_address)?;
+
+        self.build_with(store, Box::new(runtime))
+    }
+
+    // Build an engine on top of an already constructed store and
+    // container runtime. The deamon address is not used.
+    pub fn build_with(
+        &self,
+        store: Box<ConsentStore>,
+        runtime: Box<ContainerRuntime>,
+    ) -> Result<ConsentEngine, TollaError> {
+        let deamon = docker::StoreManager::with_runtime(runtime);
+
+        let authority = Authority::load_or_create(
+            &self.ca_identity(),
+            self.key_policy,
+            &self.parse_ca_subject()?,
+        )?;
+        let ocsp_delegate = match self.delegate_ocsp {
+            true => Some(authority.create_ocsp_delegate()?),
+            false => None,
         };
 
         let engine = ConsentEngine {
-            client: client,
+            store: store,
             deamon: deamon,
             authority: authority,
+            enrollment_tokens: Mutex::new(HashSet::new()),
+            mount_root: self.mount_root.clone().unwrap_or_else(
+                || String::from("/tmp/certificates"),
+            ),
+            ocsp_delegate: ocsp_delegate,
+            issuance_policy: self.issuance_policy.clone(),
+            admin_token: self.admin_token.clone(),
         };
 
-        let views = engine.get_views().map_err(|e| e.to_string())?;
+        let views = engine.get_views()?;
 
         let v = views
             .iter()
@@ -108,23 +539,64 @@ impl ConsentEngineBuilder {
 }
 
 impl ConsentEngine {
-    pub fn handle_incoming(&self, msg: proto::FromClient) -> Result<proto::ToClient, String> {
+    // Handle a message from the control channel. peer is the
+    // client's verified certificate, if it presented one.
+    pub fn handle_incoming(
+        &self,
+        msg: proto::FromClient,
+        peer: Option<&PeerIdentity>,
+    ) -> Result<proto::ToClient, TollaError> {
         let inner = match msg.msg {
             Some(msg) => msg,
-            None => return Err(String::from("empty message")),
+            None => {
+                return Err(TollaError::InvalidRequest(String::from("empty message")))
+            }
         };
 
         let mut response = proto::ToClient::default();
 
-        info!("Got message {:?}", inner);
+        info!("Got message {:?} from {:?}", inner, peer);
+
+        // Certificate requests are how clients bootstrap, and need
+        // a token. Everything else requires a client certificate
+        // that has not been revoked. Consent may be registered for
+        // any user, so that takes the admin token as well.
+        let authorized = match inner {
+            proto::from_client::Msg::Certificaterequest(_) => {
+                self.redeem_enrollment_token(&msg.enrollment_token)
+            }
+            proto::from_client::Msg::Consent(_) => match peer {
+                Some(peer) => {
+                    !self.is_revoked(peer)? && self.authorize_admin(Some(&msg.admin_token)).is_ok()
+                }
+                None => false,
+            },
+            _ => match peer {
+                Some(peer) => !self.is_revoked(peer)?,
+                None => false,
+            },
+        };
+
+        if !authorized {
+            warn!("Rejected unauthorized message from {:?}", peer);
+            let err = TollaError::Unauthorized(String::from("unauthorized"));
+            response.msg = Some(proto::to_client::Msg::Error(err.to_proto()));
+            return Ok(response);
+        }
 
         let result = match inner {
             // Not used
             proto::from_client::Msg::Consent(c) => {
                 let consent = Consent {
-                    serial_number: 0,
+                    serial_number: String::new(),
                     id: c.id,
                     purpose: c.purpose,
+                    lifetime: c.lifetime as i64,
+                    marketing: c.marketing,
+                    profiling: c.profiling,
+                    public: c.public,
+                    granted_at: now(),
+                    expired: false,
                 };
                 self.add_consent(&consent)
             }
@@ -139,17 +611,19 @@ impl ConsentEngine {
                 self.onboard_user(&u.userid, vec!["static".to_string()])
             }
             proto::from_client::Msg::Certificaterequest(r) => {
-                match self.handle_cert_request(r) {
-                    Ok(cert) => {
-                        response.msg = Some(proto::to_client::Msg::Certificate(cert.clone()))
-                    }
-                    Err(e) => {
-                        error!("{}", e.to_string());
-                        return Err(e.to_string());
-                    }
-                }
-
-                Ok(())
+                let requester = match peer {
+                    Some(peer) => format!("peer:{}", peer.common_name),
+                    None => format!("token:{}", enrollment_token_id(&msg.enrollment_token)),
+                };
+                // Rejected requests are answered with the reason
+                self.handle_cert_request(r, requester).map(|cert| {
+                    response.msg = Some(proto::to_client::Msg::Certificate(cert))
+                })
+            }
+            proto::from_client::Msg::Renewal(r) => {
+                self.handle_renewal(r, peer).map(|cert| {
+                    response.msg = Some(proto::to_client::Msg::Certificate(cert))
+                })
             }
             proto::from_client::Msg::Requestips(_) => {
                 match self.get_tenant_ips() {
@@ -161,7 +635,7 @@ impl ConsentEngine {
                     }
                     Err(err) => {
                         error!("{}", err.to_string());
-                        return Err(err.to_string());
+                        return Err(err);
                     }
                 }
                 Ok(())
@@ -170,229 +644,641 @@ impl ConsentEngine {
 
         match result {
             Err(e) => {
-                response.msg = Some(proto::to_client::Msg::Error(
-                    proto::Error { error: e.to_string() },
-                ))
+                response.msg = Some(proto::to_client::Msg::Error(e.to_proto()))
             }
             Ok(_) => response.success = true,
         }
         Ok(response)
     }
 
-    // Retrieve all tenant's ip addresses
-    pub fn get_tenant_ips(&self) -> Result<Vec<String>, String> {
-        let coll = self.client.db("test").collection("view");
-        let cursor = coll.find(None, None).map_err(|e| e.to_string())?;
-        let mut ips = Vec::new();
-
-        for entry in cursor {
-            if let Ok(item) = entry {
-                let view: View = match bson::from_bson(bson::Bson::Document(item)) {
-                    Ok(c) => c,
-                    Err(err) => {
-                        error!("{}", err.to_string());
-                        return Err(err.to_string());
-                    }
-                };
-                ips.push(view.ip);
+    // Check credential, the bearer token of a request, is the
+    // admin token
+    pub fn authorize_admin(&self, credential: Option<&str>) -> Result<(), TollaError> {
+        let token = match self.admin_token {
+            Some(ref token) => token,
+            None => {
+                return Err(TollaError::Forbidden(String::from("no admin token configured")))
             }
+        };
+
+        // Compared as digests to take the same time for any credential
+        match credential {
+            Some(credential) if memcmp::eq(
+                &sha256(credential.as_bytes()),
+                &sha256(token.as_bytes()),
+            ) => Ok(()),
+            _ => Err(TollaError::Unauthorized(String::from("admin token required"))),
         }
+    }
 
-        info!("Came here");
-        Ok(ips)
+    // Issue a token allowing a single certificate request
+    pub fn new_enrollment_token(&self) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        self.enrollment_tokens.lock().unwrap().insert(token.clone());
+        token
     }
 
-    // add a user consent
-    pub fn add_consent(&self, consent: &Consent) -> Result<(), String> {
-        let serialized_consent = match bson::to_bson(consent) {
-            Ok(res) => res,
-            Err(err) => return Err(err.to_string()),
-        };
+    // Consume token, returning whether it was outstanding
+    fn redeem_enrollment_token(&self, token: &String) -> bool {
+        self.enrollment_tokens.lock().unwrap().remove(token)
+    }
 
-        let consents = self.client.db("test").collection("consents");
+    // Acceptor for the control channel, presenting a
+    // certificate issued by the authority
+    pub fn tls_acceptor(&self) -> Result<SslAcceptor, TollaError> {
+        tls::acceptor(&self.authority)
+    }
 
-        if let bson::Bson::Document(document) = serialized_consent {
-            if let Err(e) = consents.insert_one(document, None) {
-                error!("{}", e.to_string());
-                return Err(e.to_string());
-            }
+    // Retrieve all tenant's ip addresses
+    pub fn get_tenant_ips(&self) -> Result<Vec<String>, TollaError> {
+        let views = self.store.get_views()?;
+        Ok(views.into_iter().map(|v| v.ip).collect())
+    }
+
+    // add a user consent
+    pub fn add_consent(&self, consent: &Consent) -> Result<(), TollaError> {
+        let change = ConsentChange::Register(consent.clone());
+        if let Err(e) = self.record(&consent.id, change) {
+            error!("{}", e.to_string());
+            return Err(e);
         }
         Ok(())
     }
 
-    // Remove user by id
-    pub fn remove_user(&self, user_id: &String) -> Result<(), String> {
-        let consents = self.client.db("test").collection("consents");
+    // Grant additional purposes to a user's consent
+    pub fn grant_purposes(&self, user_id: &String, purposes: Vec<String>) -> Result<(), TollaError> {
+        self.record(user_id, ConsentChange::Grant(purposes))
+    }
 
-        if let Err(err) = consents.delete_one(doc! { "_id" => user_id }, None) {
-            return Err(err.to_string());
-        };
+    // Withdraw purposes from a user's consent
+    pub fn withdraw_purposes(
+        &self,
+        user_id: &String,
+        purposes: Vec<String>,
+    ) -> Result<(), TollaError> {
+        self.record(user_id, ConsentChange::Withdraw(purposes))
+    }
 
-        let views = self.client.db("test").collection("view");
-        if let Err(err) = views.delete_one(doc! { "_id" => user_id }, None) {
-            return Err(err.to_string());
-        };
+    // The user's consent as it stood at timestamp
+    pub fn consent_as_of(&self, user_id: &String, timestamp: i64) -> Result<Option<Consent>, TollaError> {
+        let events = self.store.consent_events(user_id)?;
+        let events = events
+            .into_iter()
+            .take_while(|e| e.timestamp <= timestamp)
+            .collect::<Vec<ConsentEvent>>();
+        Ok(replay(&events))
+    }
 
-        Ok(())
+    pub fn consent_history(&self, user_id: &String) -> Result<Vec<ConsentEvent>, TollaError> {
+        self.store.consent_events(user_id)
     }
 
-    pub fn get_consent(&self, id: String) -> Result<Consent, String> {
-        let consents = self.client.db("test").collection("consents");
+    // Append change to the user's history and bring the
+    // stored current state up to date with it
+    fn record(&self, user_id: &String, change: ConsentChange) -> Result<(), TollaError> {
+        let mut events = self.store.consent_events(user_id)?;
+        let previous = replay(&events);
 
-        let consent_doc = match consents.find_one(Some(doc! { "_id" => id }), None) {
-            Ok(c) => c.unwrap(),
-            Err(err) => return Err(err.to_string()),
-        };
+        match (&previous, &change) {
+            (&Some(_), &ConsentChange::Register(_)) => {
+                return Err(TollaError::AlreadyExists(
+                    format!("consent for {} already exists", user_id),
+                ))
+            }
+            (&None, &ConsentChange::Register(_)) => (),
+            (&None, _) => {
+                return Err(TollaError::NotFound(format!("no consent for {}", user_id)))
+            }
+            _ => (),
+        }
 
-        let consent = match bson::from_bson(bson::Bson::Document(consent_doc)) {
-            Ok(c) => c,
-            Err(err) => return Err(err.to_string()),
+        let version = events.len() as i64 + 1;
+        let event = ConsentEvent {
+            id: format!("{}:{}", user_id, version),
+            user: user_id.clone(),
+            version: version,
+            timestamp: now(),
+            change: change,
         };
 
-        Ok(consent)
+        self.store.append_consent_event(&event)?;
+        events.push(event);
+
+        match (previous, replay(&events)) {
+            (None, Some(current)) => self.store.add_consent(&current),
+            (Some(_), Some(current)) => self.store.update_consent(&current),
+            (Some(_), None) => self.store.remove_consent(user_id),
+            (None, None) => Ok(()),
+        }
+    }
+
+    // Remove user by id. The consent history is kept.
+    pub fn remove_user(&self, user_id: &String) -> Result<(), TollaError> {
+        if self.store.get_consent(user_id)?.is_some() {
+            self.record(user_id, ConsentChange::Remove)?;
+        }
+        self.store.remove_view(user_id)
     }
 
-    // Retrieve a consent by its serial number
-    pub fn consent_by_serial_num(&self, serial_num: u32) -> Result<Consent, String> {
-        let consents = self.client.db("test").collection("consents");
+    pub fn get_consent(&self, id: String) -> Result<Consent, TollaError> {
+        self.store.get_consent(&id)?.ok_or_else(|| {
+            TollaError::NotFound(format!("no consent for {}", id))
+        })
+    }
 
-        let consent_doc =
-            match consents.find_one(Some(doc! { "serial_number" => serial_num }), None) {
-                Ok(c) => c.unwrap(),
-                Err(err) => return Err(err.to_string()),
-            };
+    // Retrieve a consent by its hex serial number
+    pub fn consent_by_serial_num(&self, serial_num: &String) -> Result<Consent, TollaError> {
+        self.store.consent_by_serial_num(serial_num)?.ok_or_else(|| {
+            TollaError::NotFound(format!("no consent with serial number {}", serial_num))
+        })
+    }
 
-        let consent = match bson::from_bson(bson::Bson::Document(consent_doc)) {
-            Ok(c) => c,
-            Err(err) => return Err(err.to_string()),
-        };
+    // Decide whether the certificate with serial_num may be
+    // used for intent. Every decision is written to the audit log,
+    // including requests from certificates without a consent.
+    pub fn decide_lease(&self, serial_num: &String, intent: &str) -> Result<Decision, TollaError> {
+        self.decide_lease_any(serial_num, &[intent.to_string()])
+    }
 
-        Ok(consent)
+    // Decide a lease for a certificate issued for several
+    // intents. It is granted if any of them is. The decision is
+    // audited once, for the intents joined by commas.
+    pub fn decide_lease_any(
+        &self,
+        serial_num: &String,
+        intents: &[String],
+    ) -> Result<Decision, TollaError> {
+        let now = now();
+        let certificate = serial_num.clone();
+        let intent = intents.join(",");
+
+        let consent = match self.consent_by_serial_num(serial_num) {
+            Ok(consent) => consent,
+            Err(err) => {
+                let decision = Decision::Denied(err.to_string());
+                self.audit(now, certificate, String::new(), &intent, &decision)?;
+                return Err(err);
+            }
+        };
 
+        let decision = match self.store.get_revocation(&certificate)? {
+            Some(_) => Decision::Denied(String::from("Certificate revoked")),
+            None => {
+                let mut decision = Decision::Denied(String::from("No intent"));
+                for intent in intents {
+                    decision = consent.permits(intent, now);
+                    if decision == Decision::Granted {
+                        break;
+                    }
+                }
+                decision
+            }
+        };
+        self.audit(now, certificate, consent.id, &intent, &decision)?;
+        Ok(decision)
     }
 
-    pub fn add_intent(&self, intent: &Intent) -> Result<(), String> {
-        let serialized_intent = match bson::to_bson(intent) {
-            Ok(res) => res,
-            Err(err) => return Err(err.to_string()),
+    // Revoke the certificate with the hex serial_num. Fails if
+    // it is already revoked.
+    pub fn revoke_certificate(
+        &self,
+        serial_num: &String,
+        reason: Reason,
+    ) -> Result<(), TollaError> {
+        let revocation = Revocation {
+            serial_number: serial_num.clone(),
+            reason: reason,
+            revoked_at: now(),
         };
 
-        let intents = self.client.db("test").collection("intents");
+        self.store.add_revocation(&revocation)?;
+        info!("Revoked certificate {} ({:?})", serial_num, reason);
 
-        if let bson::Bson::Document(document) = serialized_intent {
-            if let Err(e) = intents.insert_one(document, None) {
-                return Err(e.to_string());
-            }
+        // Certificates issued before the inventory was kept are not in it
+        if let Some(mut issued) = self.store.get_certificate(&revocation.serial_number)? {
+            issued.status = CertificateStatus::Revoked;
+            self.store.update_certificate(&issued)?;
         }
         Ok(())
     }
 
-    pub fn get_intent(&self, id: &String) -> Result<Intent, String> {
-        let intents = self.client.db("test").collection("intents");
+    // Look up an issued certificate by its hex serial number
+    pub fn get_certificate(&self, serial_number: &String) -> Result<IssuedCertificate, TollaError> {
+        self.store.get_certificate(serial_number)?.ok_or_else(|| {
+            TollaError::NotFound(format!("no certificate {}", serial_number))
+        })
+    }
+
+    // Issued certificates matching query, soonest expiring first
+    pub fn find_certificates(
+        &self,
+        query: &CertificateQuery,
+    ) -> Result<Vec<IssuedCertificate>, TollaError> {
+        let now = now();
+        let mut found = self.store
+            .get_certificates()?
+            .into_iter()
+            .filter(|cert| query.matches(cert, now))
+            .collect::<Vec<IssuedCertificate>>();
+        found.sort_by_key(|cert| cert.not_after);
+        Ok(found)
+    }
+
+    // DER-encoded CRL listing every revoked certificate
+    pub fn crl(&self) -> Result<Vec<u8>, TollaError> {
+        let revocations = self.store.get_revocations()?;
 
-        let intent_doc = match intents.find_one(Some(doc! { "_id" => id }), None) {
-            Ok(c) => {
-                match c {
-                    Some(s) => s,
-                    None => return Err(String::from("what")),
+        // The counter starts at the time of the first CRL, past
+        // the time-based numbers used before it was kept
+        let number = self.store.next_crl_number(now())?;
+        let crl = self.authority.create_crl(&revocations, number as u32)?;
+        Ok(crl.to_der()?)
+    }
+
+    // Verify a PEM-encoded certificate presented to us outside
+    // the control channel, e.g. by a process whose lease a
+    // database proxy asks for
+    pub fn verify_peer(&self, pem: &[u8]) -> Result<PeerIdentity, TollaError> {
+        let cert = X509::from_pem(pem).map_err(|e| TollaError::InvalidRequest(e.to_string()))?;
+        self.verify_peer_certificate(&cert)
+    }
+
+    // Verify a certificate a client presented. It has to chain up
+    // to our root and must not be revoked.
+    pub fn verify_peer_certificate(&self, cert: &X509Ref) -> Result<PeerIdentity, TollaError> {
+        self.authority.verify_certificate(cert, None)?;
+
+        let peer = PeerIdentity::from_certificate(cert)?;
+        if self.is_revoked(&peer)? {
+            return Err(TollaError::Unauthorized(String::from("certificate revoked")));
+        }
+        Ok(peer)
+    }
+
+    // Revocations are looked up as they are now rather than in
+    // the last CRL
+    fn is_revoked(&self, peer: &PeerIdentity) -> Result<bool, TollaError> {
+        Ok(self.store.get_revocation(&peer.serial_number)?.is_some())
+    }
+
+    // Answer a DER-encoded OCSP request for our certificates.
+    // Serial numbers missing from the inventory are unknown.
+    pub fn ocsp_response(&self, request: &[u8]) -> Result<Vec<u8>, TollaError> {
+        self.authority.create_ocsp_response(
+            request,
+            self.ocsp_delegate.as_ref(),
+            |serial_number| {
+                let serial_number = serial_number.to_string();
+                if self.store.get_certificate(&serial_number)?.is_none() {
+                    return Ok(CertStatus::Unknown);
                 }
-            }
-            Err(err) => return Err(err.to_string()),
-        };
+                Ok(match self.store.get_revocation(&serial_number)? {
+                    Some(revocation) => CertStatus::Revoked(revocation),
+                    None => CertStatus::Good,
+                })
+            },
+        )
+    }
 
-        let intent = match bson::from_bson(bson::Bson::Document(intent_doc)) {
-            Ok(c) => c,
-            Err(err) => return Err(err.to_string()),
+    // Append a lease decision to the audit log, and have the
+    // authority sign the new head
+    fn audit(
+        &self,
+        timestamp: i64,
+        certificate: String,
+        user: String,
+        intent: &str,
+        decision: &Decision,
+    ) -> Result<(), TollaError> {
+        let (granted, reason) = match *decision {
+            Decision::Granted => (true, String::new()),
+            Decision::Denied(ref reason) => (false, reason.clone()),
         };
 
-        Ok(intent)
+        let last = self.store.last_audit_entry()?;
+        let entry = AuditEntry::new(
+            last.as_ref(),
+            timestamp,
+            certificate,
+            user,
+            intent.to_string(),
+            granted,
+            reason,
+        )?;
+
+        self.store.append_audit_entry(&entry)?;
+        let checkpoint = AuditCheckpoint::sign(&entry, &self.authority)?;
+        self.store.set_audit_checkpoint(&checkpoint)
     }
 
-    pub fn register_view(&self, view: &View) -> Result<(), String> {
-        let serialized_view = match bson::to_bson(view) {
-            Ok(res) => res,
-            Err(err) => return Err(err.to_string()),
-        };
+    // Up to limit audit entries following seq after
+    pub fn audit_entries(&self, after: i64, limit: i64) -> Result<Vec<AuditEntry>, TollaError> {
+        self.store.audit_entries(after, limit)
+    }
+
+    // Check the whole audit log against its hash chain, and the
+    // chain against the head the authority signed last.
+    // Returns the number of entries verified.
+    pub fn verify_audit_log(&self) -> Result<i64, TollaError> {
+        let checkpoint = self.store.audit_checkpoint()?;
+        if let Some(ref checkpoint) = checkpoint {
+            checkpoint.verify(&self.authority)?;
+        }
+
+        let mut last = None;
+
+        loop {
+            let after = last.as_ref().map_or(0, |e: &AuditEntry| e.seq);
+            let entries = self.store.audit_entries(after, 1000)?;
+            if entries.is_empty() {
+                break;
+            }
+            last = audit::verify_chain(last, &entries, checkpoint.as_ref())?;
+        }
+
+        audit::verify_head(last.as_ref(), checkpoint.as_ref())?;
+        Ok(last.map_or(0, |e| e.seq))
+    }
 
-        let views = self.client.db("test").collection("view");
+    // Mark consents whose lifetime has passed as expired.
+    // Returns the number of consents marked.
+    pub fn expire_consents(&self) -> Result<usize, TollaError> {
+        let now = now();
+        let mut expired = 0;
 
-        if let bson::Bson::Document(document) = serialized_view {
-            if let Err(e) = views.insert_one(document, None) {
-                error!("Unable to register view: {}", e.to_string());
-                return Err(e.to_string());
+        for consent in self.store.get_consents()? {
+            if consent.expired || !consent.is_expired(now) {
+                continue;
             }
+
+            self.record(&consent.id, ConsentChange::Expire)?;
+            info!("Consent for {} expired", consent.id);
+            expired += 1;
         }
 
+        Ok(expired)
+    }
+
+    pub fn add_intent(&self, intent: &Intent) -> Result<(), TollaError> {
+        self.store.add_intent(intent)
+    }
+
+    pub fn get_intent(&self, id: &String) -> Result<Intent, TollaError> {
+        self.store.get_intent(id)?.ok_or_else(
+            || TollaError::NotFound(format!("no intent {}", id)),
+        )
+    }
+
+    // Allow certificates to be issued for intent. Fails if it
+    // is already registered.
+    pub fn register_intent(&self, intent: &RegisteredIntent) -> Result<(), TollaError> {
+        if intent.name.is_empty() {
+            return Err(TollaError::InvalidRequest(String::from("empty intent")));
+        }
+        self.store.add_registered_intent(intent)?;
+        info!("Registered intent {}", intent.name);
         Ok(())
     }
 
-    // abandon ship boys
-    pub fn deboard_user(&self, user_id: &String) -> Result<(), String> {
-        self.deamon.remove_container(user_id)?;
+    pub fn registered_intents(&self) -> Result<Vec<RegisteredIntent>, TollaError> {
+        self.store.get_registered_intents()
+    }
 
-        self.remove_user(user_id)?;
+    // Check every one of intents is registered. The issuance
+    // policy may narrow them down further.
+    fn check_registered_intents(&self, intents: &[String]) -> Result<(), TollaError> {
+        for intent in intents {
+            if self.store.get_registered_intent(intent)?.is_none() {
+                return Err(TollaError::Forbidden(format!("unknown intent {}", intent)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn register_view(&self, view: &View) -> Result<(), TollaError> {
+        if let Err(e) = self.store.add_view(view) {
+            error!("Unable to register view: {}", e.to_string());
+            return Err(e);
+        }
 
         Ok(())
     }
 
-    // retrieve all docker ids
-    pub fn get_views(&self) -> Result<Vec<View>, String> {
-        let views = self.client.db("test").collection("views");
+    // abandon ship boys
+    pub fn deboard_user(&self, user_id: &String) -> Result<(), TollaError> {
+        check_user_id(user_id)?;
+        let consent = self.store.get_consent(user_id)?;
 
-        let mut vec = Vec::new();
+        if !self.clean_up(user_id)? && consent.is_none() {
+            return Err(TollaError::NotFound(format!("no user {}", user_id)));
+        }
 
-        let cursor = views.find(None, None).map_err(|e| e.to_string())?;
-        for entry in cursor {
-            if let Ok(item) = entry {
-                let view: View = match bson::from_bson(bson::Bson::Document(item)) {
-                    Ok(c) => c,
-                    Err(err) => return Err(err.to_string()),
-                };
-                vec.push(view);
+        // The database certificate goes away with the container
+        if let Some(consent) = consent {
+            match self.revoke_certificate(&consent.serial_number, Reason::CessationOfOperation) {
+                Ok(()) | Err(TollaError::AlreadyExists(_)) => (),
+                Err(err) => return Err(err),
             }
         }
 
-        Ok(vec)
+        self.remove_user(user_id)
     }
 
-    pub fn consent_based_view(&self, id: &String) -> Result<String, String> {
-        let coll = self.client.db("test").collection("views");
+    // Remove the container, view and mount directory of a user,
+    // whichever exist. Returns whether anything was removed.
+    fn clean_up(&self, user_id: &String) -> Result<bool, TollaError> {
+        let mut removed = false;
 
-        let cursor = coll.find(None, None).map_err(|e| e.to_string())?;
-        for entry in cursor {
-            if let Ok(item) = entry {
-                let view: View = match bson::from_bson(bson::Bson::Document(item)) {
-                    Ok(c) => c,
-                    Err(err) => return Err(err.to_string()),
-                };
-                if &view.id == id {
-                    return Ok(view.ip);
-                }
+        if self.deamon.verify_container_id(user_id)? {
+            self.deamon.remove_container(user_id)?;
+            removed = true;
+        }
+
+        if self.store.get_views()?.iter().any(|v| &v.id == user_id) {
+            self.store.remove_view(user_id)?;
+            removed = true;
+        }
+
+        if self.deamon.remove_mountdir(&self.mount_root, &self.mountdir(user_id))? {
+            removed = true;
+        }
+
+        Ok(removed)
+    }
+
+    // Find users left half onboarded, i.e. with a view or mount
+    // directory but no consent, and clean them up.
+    // Returns the ids of the users cleaned up.
+    pub fn repair(&self) -> Result<Vec<String>, TollaError> {
+        let mut candidates = self.store
+            .get_views()?
+            .into_iter()
+            .map(|v| v.id)
+            .collect::<Vec<String>>();
+        candidates.extend(self.deamon.mountdirs(&self.mount_root)?);
+        candidates.sort();
+        candidates.dedup();
+
+        let mut repaired = Vec::new();
+        for user_id in candidates {
+            if let Err(err) = check_user_id(&user_id) {
+                warn!("Not repairing {}: {}", user_id, err);
+                continue;
+            }
+            if self.store.get_consent(&user_id)?.is_some() {
+                continue;
+            }
+            if self.clean_up(&user_id)? {
+                info!("Cleaned up half onboarded user {}", user_id);
+                repaired.push(user_id);
             }
         }
-        return Err(String::from("could not find it"));
+
+        Ok(repaired)
+    }
+
+    fn mountdir(&self, user_id: &String) -> String {
+        format!("{}/{}", self.mount_root, user_id)
+    }
+
+    // retrieve all docker ids
+    pub fn get_views(&self) -> Result<Vec<View>, TollaError> {
+        self.store.get_views()
     }
 
-    pub fn onboard_user(&self, id: &String, purposes: Vec<String>) -> Result<(), String> {
-        match self.deamon.verify_container_id(id) {
-            Ok(true) => return Err("user already exists".to_owned()),
-            Err(err) => return Err(err.to_string()),
-            Ok(false) => (),
+    pub fn consent_based_view(&self, id: &String) -> Result<String, TollaError> {
+        let views = self.store.get_views()?;
+        match views.into_iter().find(|v| &v.id == id) {
+            Some(view) => Ok(view.ip),
+            None => Err(TollaError::NotFound(String::from("could not find it"))),
         }
+    }
 
-        if let Ok(_) = self.consent_based_view(id) {
+    // Onboarding is idempotent per user. Onboarding a user again
+    // with the same purposes does nothing, and anything left
+    // behind by an earlier attempt that failed is removed first.
+    pub fn onboard_user(&self, id: &String, purposes: Vec<String>) -> Result<(), TollaError> {
+        check_user_id(id)?;
+        if let Some(consent) = self.store.get_consent(id)? {
+            if consent.purpose == purposes && self.consent_based_view(id).is_ok() {
+                info!("User {} is already onboarded", id);
+                return Ok(());
+            }
             error!("Tried to onboard existing user");
-            return Err(format!("user already exists"));
+            return Err(TollaError::AlreadyExists(String::from("user already exists")));
+        }
+
+        if self.clean_up(id)? {
+            warn!("Removed leftovers of an earlier attempt to onboard {}", id);
+        }
+
+        let mut done = Vec::new();
+        if let Err(err) = self.run_onboarding(id, purposes, &mut done) {
+            error!("Onboarding {} failed: {}", id, err);
+            self.undo_onboarding(done);
+            return Err(err);
         }
 
+        Ok(())
+    }
+
+    // Onboard a user, pushing every completed step onto done
+    fn run_onboarding(
+        &self,
+        id: &String,
+        purposes: Vec<String>,
+        done: &mut Vec<OnboardStep>,
+    ) -> Result<(), TollaError> {
+        let absolute_path = self.mountdir(id);
+
+        // A partially written directory is removed as well
+        done.push(OnboardStep::Mountdir(absolute_path.clone()));
+        self.deamon.new_mountdir(HashMap::new(), &absolute_path)?;
+
+        let mut env = Vec::new();
+
+        env.push(format!("PEM_FOLDER={}", absolute_path));
+        env.push("LISTEN_ADDR=:8080".to_string());
+        env.push("DB_ADDR=27017".to_string());
+        // should contain hostname of CA
+        env.push("CA_ADDR=8080".to_string());
+
+        let (_, ip) = self.deamon.new_container(&String::from("tenant"), id, env)?;
+        done.push(OnboardStep::Container(id.clone()));
+
+        // The container waits for its certificate, which can
+        // only name its address once it is running
+        let serial_number = self.write_db_certificate(id, &ip)?;
+        done.push(OnboardStep::Certificate(serial_number.clone()));
+
+        let view = View {
+            id: id.clone(),
+            ip: ip,
+        };
+        self.register_view(&view)?;
+        done.push(OnboardStep::View(id.clone()));
+
+        self.add_consent(&Consent {
+            id: id.clone(),
+            serial_number: serial_number,
+            purpose: purposes,
+            lifetime: 0,
+            marketing: false,
+            profiling: false,
+            public: false,
+            granted_at: now(),
+            expired: false,
+        })
+    }
+
+    // Undo completed onboarding steps, latest first. Stops at the
+    // first step that cannot be undone, so that the mount directory
+    // is left for repair to find.
+    fn undo_onboarding(&self, done: Vec<OnboardStep>) {
+        for step in done.into_iter().rev() {
+            let result = match step {
+                OnboardStep::Certificate(ref serial_num) => {
+                    self.revoke_certificate(serial_num, Reason::CessationOfOperation)
+                }
+                OnboardStep::Mountdir(ref path) => {
+                    self.deamon.remove_mountdir(&self.mount_root, path).map(|_| ())
+                }
+                OnboardStep::Container(ref id) => self.deamon.remove_container(id),
+                OnboardStep::View(ref id) => self.store.remove_view(id),
+            };
+
+            if let Err(err) = result {
+                error!("Unable to undo {:?}: {}", step, err);
+                return;
+            }
+        }
+    }
+
+    // Issue the database certificate of user for the container
+    // at ip, and write it to the user's mount directory.
+    // Returns its hex serial number.
+    fn write_db_certificate(&self, id: &String, ip: &str) -> Result<String, TollaError> {
         let mut key = BytesMut::new();
         let mut certificate = BytesMut::new();
 
-
-        let serial_number = self.authority.create_db_certificate(
+        // The container name is resolvable by other containers
+        let serial_number = self.new_serial_number()?;
+        self.authority.create_db_certificate(
+            &serial_number,
+            id,
+            ip,
             &mut key,
             &mut certificate,
         )?;
+        let serial_number = serial_to_hex(&serial_number)?;
+
+        let issued = IssuedCertificate::new(
+            &X509::from_pem(&certificate)?,
+            Vec::new(),
+            String::new(),
+            format!("user:{}", id),
+        )?;
+        self.store.add_certificate(&issued)?;
 
         let ca = self.authority.get_cert();
 
@@ -406,68 +1292,275 @@ impl ConsentEngine {
         files.insert("keys.pem", &mut key);
         files.insert("CAcert.pem", &mut ca_cert);
 
-        let mut absolute_path = String::from("/tmp/certificates/");
-        absolute_path.push_str(id);
-
-        self.deamon.new_mountdir(files, &absolute_path)?;
+        // A certificate that never made it to the container is
+        // not left valid
+        if let Err(err) = self.deamon.new_mountdir(files, &self.mountdir(id)) {
+            let reason = Reason::CessationOfOperation;
+            if let Err(err) = self.revoke_certificate(&serial_number, reason) {
+                error!("Unable to revoke unused certificate {}: {}", serial_number, err);
+            }
+            return Err(err);
+        }
+        Ok(serial_number)
+    }
 
-        //let cert_path = format!(
-        //    "{}/{}:{}:Z",
-        //    path,
-        //    "certificate.pem",
-        //    "/config/certificate.pem"
-        //);
-        //let key_path = format!("{}/{}:{}:Z", path, "keys.pem", "/config/keys.pem");
-        //let ca_path = format!("{}/{}:{}:Z", path, "CAcert.pem", "/config/CAcert.pem");
+    // Re-issue the database certificates of users whose
+    // container has moved to another address since it was
+    // onboarded, e.g. when the deamon restarted. The old
+    // certificates are revoked and the containers restarted to
+    // pick up the new ones. Users that fail are logged and
+    // skipped, to be tried again on the next call. Returns the
+    // ids of the users whose certificate was re-issued.
+    pub fn refresh_db_certificates(&self) -> Result<Vec<String>, TollaError> {
+        let mut refreshed = Vec::new();
+        for view in self.store.get_views()? {
+            match self.refresh_db_certificate(&view) {
+                Ok(true) => {
+                    info!("Re-issued the database certificate of {}", view.id);
+                    refreshed.push(view.id);
+                }
+                Ok(false) => (),
+                Err(err) => {
+                    error!("Unable to refresh the database certificate of {}: {}", view.id, err)
+                }
+            }
+        }
 
-        let mut env = Vec::new();
+        Ok(refreshed)
+    }
 
-        env.push(format!("PEM_FOLDER=/tmp/certificates/{}", id));
-        env.push("LISTEN_ADDR=:8080".to_string());
-        env.push("DB_ADDR=27017".to_string());
-        // should contain hostname of CA
-        env.push("CA_ADDR=8080".to_string());
+    // Re-issue the database certificate of the user with view if
+    // the container has moved. Restarting it can move it again,
+    // so the address is checked again after every restart.
+    fn refresh_db_certificate(&self, view: &View) -> Result<bool, TollaError> {
+        // Half onboarded users are left for repair
+        let mut serial_number = match self.store.get_consent(&view.id)? {
+            Some(consent) => consent.serial_number,
+            None => return Ok(false),
+        };
 
-        let res = self.deamon.new_container(&String::from("tenant"), id, env);
+        let mut known = view.ip.clone();
+        for _ in 0..RECERTIFY_ATTEMPTS {
+            let ip = self.deamon.container_address(&view.id)?;
+            if ip.is_empty() || ip == known {
+                return Ok(known != view.ip);
+            }
 
-        match res {
-            Ok(x) => {
-                let (id, ip) = x;
-                let view = View { id: id, ip: ip };
-                if let Err(err) = self.register_view(&view) {
-                    error!("{}", err.to_string());
-                    return Err(err.to_string());
-                };
+            info!("Container of {} moved from {} to {}", view.id, known, ip);
+            let renewed = self.write_db_certificate(&view.id, &ip)?;
+            self.record(&view.id, ConsentChange::Recertify(renewed.clone()))?;
+            match self.revoke_certificate(&serial_number, Reason::Superseded) {
+                Ok(()) | Err(TollaError::AlreadyExists(_)) => (),
+                Err(err) => return Err(err),
             }
-            Err(err) => {
-                error!("{}", err.to_string());
-                return Err(err);
+
+            self.store.remove_view(&view.id)?;
+            self.register_view(&View {
+                id: view.id.clone(),
+                ip: ip.clone(),
+            })?;
+            serial_number = renewed;
+            known = ip;
+
+            self.deamon.restart_container(&view.id)?;
+        }
+
+        Err(TollaError::Runtime(
+            format!("container of {} keeps moving", view.id),
+        ))
+    }
+
+    // A random serial number that no issued or revoked
+    // certificate has
+    fn new_serial_number(&self) -> Result<BigNum, TollaError> {
+        for _ in 0..SERIAL_NUMBER_ATTEMPTS {
+            let serial_number = ca::random_serial_number()?;
+            let hex = serial_to_hex(&serial_number)?;
+
+            if self.store.get_certificate(&hex)?.is_none() &&
+                self.store.get_revocation(&hex)?.is_none()
+            {
+                return Ok(serial_number);
             }
+            warn!("Serial number {} is already taken", hex);
         }
 
-        self.add_consent(&Consent {
-            id: id.clone(),
-            serial_number: serial_number as i32,
-            purpose: purposes.clone(),
+        Err(TollaError::Crypto(String::from("unable to pick a free serial number")))
+    }
+
+    // Renew the certificate the peer is connected with. It must
+    // still be active, and is superseded by the new one.
+    fn handle_renewal(
+        &self,
+        renewal: proto::Renewal,
+        peer: Option<&PeerIdentity>,
+    ) -> Result<proto::Certificate, TollaError> {
+        let old = X509::from_pem(&renewal.certificate).map_err(|e| {
+            TollaError::InvalidRequest(e.to_string())
         })?;
 
-        Ok(())
+        // Only the holder of the certificate may renew it
+        let holder = PeerIdentity::from_certificate(&old)?;
+        if peer.map(|p| &p.fingerprint) != Some(&holder.fingerprint) {
+            return Err(TollaError::Forbidden(
+                String::from("certificate does not belong to the peer"),
+            ));
+        }
+
+        let serial_number = serial_to_hex(&old.serial_number().to_bn()?)?;
+        let issued = self.get_certificate(&serial_number)?;
+        if issued.status != CertificateStatus::Active || issued.not_after <= now() {
+            return Err(TollaError::Forbidden(
+                format!("certificate {} is no longer valid", serial_number),
+            ));
+        }
+
+        if issued.intents.is_empty() {
+            return Err(TollaError::InvalidRequest(
+                String::from("only identity certificates can be renewed"),
+            ));
+        }
+        self.check_registered_intents(&issued.intents)?;
+
+        let request = match renewal.request.is_empty() {
+            true => None,
+            false => Some(renewal.request.as_slice()),
+        };
+        let new_serial_number = self.new_serial_number()?;
+        let (intent, cert) = self.authority.renew_certificate(
+            &old,
+            request,
+            issued.intents.clone(),
+            &new_serial_number,
+            &self.issuance_policy,
+        )?;
+
+        self.add_intent(&intent)?;
+
+        let renewed = IssuedCertificate::new(
+            &X509::from_pem(&cert.request)?,
+            intent.intent.clone(),
+            intent.id.clone(),
+            format!("renewal:{}", serial_number),
+        )?;
+        self.store.add_certificate(&renewed)?;
+        self.revoke_certificate(&serial_number, Reason::Superseded)?;
+
+        info!("Renewed certificate {} as {}", serial_number, renewed.serial_number);
+        Ok(cert)
     }
 
-    fn handle_cert_request(&self, req: proto::Certificate) -> Result<proto::Certificate, String> {
+    fn handle_cert_request(
+        &self,
+        req: proto::Certificate,
+        requester: String,
+    ) -> Result<proto::Certificate, TollaError> {
+        if let Err(e) = self.check_registered_intents(&req.intents) {
+            warn!("Rejected certificate request: {}", e);
+            return Err(e);
+        }
+
+        // No users leaves it to the consent of each user
+        let users = if req.users.is_empty() {
+            None
+        } else {
+            Some(req.users)
+        };
+
+        let serial_number = self.new_serial_number()?;
         let (intent, cert) = match self.authority.sign_certificate(
             req.request.as_slice(),
-            req.intent,
+            req.intents,
+            users,
+            &serial_number,
+            &self.issuance_policy,
         ) {
             Ok(c) => c,
             Err(e) => {
-                println!("{}", e.to_string());
-                return Err(e.to_string());
+                warn!("Rejected certificate request: {}", e);
+                return Err(e);
             }
         };
 
         self.add_intent(&intent)?;
 
+        let issued = IssuedCertificate::new(
+            &X509::from_pem(&cert.request)?,
+            intent.intent.clone(),
+            intent.id.clone(),
+            requester,
+        )?;
+        self.store.add_certificate(&issued)?;
+
         Ok(cert)
     }
 }
+
+// Periodically mark expired consents in the background, and
+// re-issue the database certificates of containers that got
+// another address when they were started again
+pub fn spawn_sweeper(engine: Arc<Mutex<ConsentEngine>>, interval: Duration) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        let engine = engine.lock().unwrap();
+        if let Err(err) = engine.expire_consents() {
+            error!("Unable to expire consents: {}", err);
+        }
+        if let Err(err) = engine.refresh_db_certificates() {
+            error!("Unable to refresh database certificates: {}", err);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use consent::{replay, Consent, ConsentChange, ConsentEvent};
+
+    fn event(version: i64, change: ConsentChange) -> ConsentEvent {
+        ConsentEvent {
+            id: format!("alice:{}", version),
+            user: String::from("alice"),
+            version: version,
+            timestamp: version * 100,
+            change: change,
+        }
+    }
+
+    #[test]
+    fn test_replay_history() {
+        let registered = Consent {
+            id: String::from("alice"),
+            serial_number: String::from("1"),
+            purpose: vec![String::from("research")],
+            lifetime: 50,
+            marketing: false,
+            profiling: true,
+            public: false,
+            granted_at: 100,
+            expired: false,
+        };
+
+        let history = vec![
+            event(1, ConsentChange::Register(registered.clone())),
+            event(2, ConsentChange::Withdraw(vec![String::from("profiling")])),
+            event(3, ConsentChange::Expire),
+            event(4, ConsentChange::Grant(vec![String::from("research")])),
+            event(5, ConsentChange::Remove),
+        ];
+
+        assert_eq!(replay(&history[..1]), Some(registered));
+
+        let withdrawn = replay(&history[..2]).unwrap();
+        assert!(!withdrawn.profiling);
+        assert!(replay(&history[..3]).unwrap().is_expired(0));
+
+        // Re-granting renews the lifetime without duplicating purposes
+        let regranted = replay(&history[..4]).unwrap();
+        assert_eq!(regranted.purpose, vec![String::from("research")]);
+        assert_eq!(regranted.expires_at(), Some(450));
+        assert!(!regranted.is_expired(449));
+
+        assert_eq!(replay(&history), None);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use docker::{ContainerInfo, ContainerRuntime, ContainerSpec};
+use error::TollaError;
+
+struct FakeState {
+    // Keyed by container id
+    containers: HashMap<String, ContainerInfo>,
+    created: u32,
+}
+
+// In-process runtime handing out synthetic ids and addresses.
+// Lets the engine onboard and deboard users without docker.
+pub struct FakeRuntime {
+    state: Mutex<FakeState>,
+}
+
+impl FakeRuntime {
+    pub fn new() -> FakeRuntime {
+        FakeRuntime {
+            state: Mutex::new(FakeState {
+                containers: HashMap::new(),
+                created: 0,
+            }),
+        }
+    }
+}
+
+impl FakeState {
+    // Resolve a container id or name to its id
+    fn resolve(&self, id: &str) -> Result<String, TollaError> {
+        self.containers
+            .values()
+            .find(|c| c.id == id || c.name == id)
+            .map(|c| c.id.clone())
+            .ok_or_else(|| {
+                TollaError::NotFound(format!("No such container: {}", id))
+            })
+    }
+}
+
+impl ContainerRuntime for FakeRuntime {
+    fn create(&self, spec: &ContainerSpec) -> Result<String, TollaError> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.containers.values().any(|c| c.name == spec.name) {
+            return Err(TollaError::AlreadyExists(
+                format!("Conflict. The name {} is already in use", spec.name),
+            ));
+        }
+
+        state.created += 1;
+        let id = format!("{:064x}", state.created);
+
+        state.containers.insert(
+            id.clone(),
+            ContainerInfo {
+                id: id.clone(),
+                name: spec.name.clone(),
+                ip: String::new(),
+                running: false,
+            },
+        );
+        Ok(id)
+    }
+
+    fn start(&self, id: &str) -> Result<(), TollaError> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.resolve(id)?;
+
+        // Addresses are derived from the creation order, e.g. 10.0.0.2
+        let n = u32::from_str_radix(&id, 16).unwrap_or(0);
+        let container = state.containers.get_mut(&id).unwrap();
+        container.ip = format!("10.0.{}.{}", n / 254, n % 254 + 1);
+        container.running = true;
+        Ok(())
+    }
+
+    // Containers keep their address
+    fn restart(&self, id: &str) -> Result<(), TollaError> {
+        self.start(id)
+    }
+
+    fn inspect(&self, id: &str) -> Result<ContainerInfo, TollaError> {
+        let state = self.state.lock().unwrap();
+        let id = state.resolve(id)?;
+        Ok(state.containers[&id].clone())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), TollaError> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.resolve(id)?;
+        state.containers.remove(&id);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<ContainerInfo>, TollaError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.containers.values().cloned().collect())
+    }
+}
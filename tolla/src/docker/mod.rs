@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder};
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use bytes::BytesMut;
+use error::TollaError;
+
+mod fake;
+mod remote;
+
+pub use self::fake::FakeRuntime;
+pub use self::remote::ShipliftRuntime;
+
+// Everything needed to create a container
+#[derive(Clone, Debug)]
+pub struct ContainerSpec {
+    pub image: String,
+    pub name: String,
+    pub env: Vec<String>,
+    pub volumes_from: Vec<String>,
+}
+
+// State of a container as reported by the runtime.
+// ip is empty while the container is not running.
+#[derive(Clone, Debug)]
+pub struct ContainerInfo {
+    pub id: String,
+    pub name: String,
+    pub ip: String,
+    pub running: bool,
+}
+
+// Operations the store manager needs from a container runtime.
+// Containers can be referred to either by id or by name.
+pub trait ContainerRuntime: Send {
+    // Creates a container and returns its id
+    fn create(&self, spec: &ContainerSpec) -> Result<String, TollaError>;
+    fn start(&self, id: &str) -> Result<(), TollaError>;
+    fn restart(&self, id: &str) -> Result<(), TollaError>;
+    fn inspect(&self, id: &str) -> Result<ContainerInfo, TollaError>;
+    fn remove(&self, id: &str) -> Result<(), TollaError>;
+    fn list(&self) -> Result<Vec<ContainerInfo>, TollaError>;
+}
+
+pub struct StoreManager {
+    runtime: Box<ContainerRuntime>,
+}
+
+impl StoreManager {
+    // Connect to the docker deamon
+    pub fn new(host: &String) -> Result<StoreManager, TollaError> {
+        let runtime = ShipliftRuntime::new(host)?;
+        Ok(StoreManager::with_runtime(Box::new(runtime)))
+    }
+
+    // Manage containers through the given runtime
+    pub fn with_runtime(runtime: Box<ContainerRuntime>) -> StoreManager {
+        StoreManager { runtime: runtime }
+    }
+
+    // Start containers by id
+    pub fn start_containers(&self, ids: Vec<String>) -> Result<(), TollaError> {
+
+        info!("starting containers");
+        for container_id in ids {
+            match self.runtime.inspect(&container_id) {
+                Ok(ref info) if info.running => continue,
+                Ok(_) => (),
+                Err(err) => {
+                    warn!("Unable to inspect {}: {}", container_id, err);
+                    continue;
+                }
+            }
+
+            if let Err(err) = self.runtime.start(&container_id) {
+                error!("{}", err.to_string());
+                return Err(err);
+            }
+            info!("Successfully started {}", container_id);
+        }
+
+        Ok(())
+    }
+
+    // Retrive container by name
+    fn container_by_id(&self, id: &String) -> Result<Option<ContainerInfo>, TollaError> {
+        let containers = self.runtime.list()?;
+
+        Ok(containers.into_iter().find(
+            |container| &container.name == id || &container.id == id,
+        ))
+    }
+
+    // check container id exists
+    pub fn verify_container_id(&self, id: &String) -> Result<bool, TollaError> {
+        match self.container_by_id(id) {
+            Err(err) => return Err(err),
+            Ok(container) => {
+                return Ok(container.is_some());
+            }
+        }
+    }
+
+    // Creates a directory from which the container can read its content
+    pub fn new_mountdir(
+        &self,
+        contents: HashMap<&str, &mut BytesMut>,
+        dirname: &String,
+    ) -> Result<(), TollaError> {
+        info!("Creating dir {}", dirname);
+        if let Err(err) = DirBuilder::new().recursive(true).create(dirname) {
+            return Err(TollaError::Runtime(err.to_string()));
+        }
+
+        for (filename, content) in &contents {
+            let mut file = match File::create(format!("{}/{}", dirname, filename)) {
+                Ok(file) => file,
+                Err(err) => return Err(TollaError::Runtime(err.to_string())),
+            };
+            info!("Creating file {}/{}", dirname, filename);
+
+            if let Err(err) = file.write_all(content) {
+                return Err(TollaError::Runtime(err.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Removes a directory created by new_mountdir under root.
+    // Returns false if there was nothing to remove. Refuses
+    // anything that does not resolve to a path below root.
+    pub fn remove_mountdir(&self, root: &String, dirname: &String) -> Result<bool, TollaError> {
+        let root = match fs::canonicalize(root) {
+            Ok(root) => root,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(TollaError::Runtime(err.to_string())),
+        };
+        let path = match fs::canonicalize(dirname) {
+            Ok(path) => path,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(TollaError::Runtime(err.to_string())),
+        };
+        if path == root || !path.starts_with(&root) {
+            return Err(TollaError::Forbidden(
+                format!("{} is not below {}", dirname, root.display()),
+            ));
+        }
+
+        match fs::remove_dir_all(&path) {
+            Ok(()) => {
+                info!("Removed dir {}", dirname);
+                Ok(true)
+            }
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(TollaError::Runtime(err.to_string())),
+        }
+    }
+
+    // Names of the mount directories under root
+    pub fn mountdirs(&self, root: &String) -> Result<Vec<String>, TollaError> {
+        let entries = match fs::read_dir(root) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(TollaError::Runtime(err.to_string())),
+        };
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| TollaError::Runtime(e.to_string()))?;
+            if entry.path().is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    pub fn remove_container(&self, id: &String) -> Result<(), TollaError> {
+        self.runtime.remove(id)
+    }
+
+    // Restart a container, e.g. to have it pick up new certificates
+    pub fn restart_container(&self, id: &String) -> Result<(), TollaError> {
+        self.runtime.restart(id)
+    }
+
+    // Current IP address of a container, empty if it is not running
+    pub fn container_address(&self, id: &String) -> Result<String, TollaError> {
+        Ok(self.runtime.inspect(id)?.ip)
+    }
+
+    // Create a new container from image with name, and starts it.
+    // The function returns the IPAddress on success. If the
+    // container cannot be started it is removed again.
+    pub fn new_container(
+        &self,
+        image: &str,
+        name: &str,
+        env: Vec<String>,
+    ) -> Result<(String, String), TollaError> {
+        let spec = ContainerSpec {
+            image: image.to_string(),
+            name: name.to_string(),
+            env: env,
+            volumes_from: vec![String::from("tolla")],
+        };
+
+        let id = match self.runtime.create(&spec) {
+            Ok(id) => id,
+            Err(err) => {
+                error!("{}", err.to_string());
+                return Err(err);
+            }
+        };
+
+        // Read ipaddress of container
+        let info = match self.runtime.start(&id).and_then(|_| self.runtime.inspect(&id)) {
+            Ok(info) => info,
+            Err(err) => {
+                error!("{}", err.to_string());
+                if let Err(e) = self.runtime.remove(&id) {
+                    error!("Unable to remove container {}: {}", id, e);
+                }
+                return Err(err);
+            }
+        };
+
+        info!("successfully created container: {}:{}", id, info.ip);
+        Ok((id, info.ip))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use docker::{FakeRuntime, StoreManager};
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_new_container_on_fake_runtime() {
+        let manager = StoreManager::with_runtime(Box::new(FakeRuntime::new()));
+        let name = String::from("alice");
+
+        assert_eq!(manager.verify_container_id(&name).unwrap(), false);
+
+        let (id, ip) = manager.new_container("tenant", &name, vec![]).unwrap();
+        assert!(!ip.is_empty());
+        assert!(manager.verify_container_id(&name).unwrap());
+        assert!(manager.verify_container_id(&id).unwrap());
+        assert!(manager.new_container("tenant", &name, vec![]).is_err());
+
+        manager.remove_container(&name).unwrap();
+        assert_eq!(manager.verify_container_id(&name).unwrap(), false);
+    }
+
+    #[test]
+    fn test_start_containers_skips_running() {
+        let manager = StoreManager::with_runtime(Box::new(FakeRuntime::new()));
+        let (id, _) = manager.new_container("tenant", "bob", vec![]).unwrap();
+
+        manager.start_containers(vec![id]).unwrap();
+    }
+
+    #[test]
+    fn test_remove_mountdir_stays_below_root() {
+        let manager = StoreManager::with_runtime(Box::new(FakeRuntime::new()));
+        let dir = env::temp_dir().join(format!("tolla-docker-{}", Uuid::new_v4().simple()));
+        let root = dir.join("certificates").to_string_lossy().into_owned();
+        let outside = dir.join("ca").to_string_lossy().into_owned();
+        manager.new_mountdir(HashMap::new(), &format!("{}/alice", root)).unwrap();
+        manager.new_mountdir(HashMap::new(), &outside).unwrap();
+
+        assert!(manager.remove_mountdir(&root, &root).is_err());
+        assert!(manager.remove_mountdir(&root, &format!("{}/", root)).is_err());
+        assert!(manager.remove_mountdir(&root, &format!("{}/../ca", root)).is_err());
+        assert!(fs::metadata(&outside).is_ok());
+
+        assert!(manager.remove_mountdir(&root, &format!("{}/alice", root)).unwrap());
+        assert!(!manager.remove_mountdir(&root, &format!("{}/alice", root)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
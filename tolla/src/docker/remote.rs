@@ -0,0 +1,98 @@
+use shiplift::{self, Docker};
+use shiplift::builder::ContainerOptionsBuilder;
+use shiplift::builder::RmContainerOptions;
+use url::Url;
+use docker::{ContainerInfo, ContainerRuntime, ContainerSpec};
+use error::TollaError;
+
+// Runtime talking to a docker deamon through shiplift
+pub struct ShipliftRuntime {
+    deamon: Docker,
+}
+
+impl ShipliftRuntime {
+    // Connect to the deamon listening on host, e.g. http://127.0.0.1:2375
+    pub fn new(host: &String) -> Result<ShipliftRuntime, TollaError> {
+        let url = Url::parse(host).map_err(|e| {
+            TollaError::Config(format!("{}: {}", host, e.to_string()))
+        })?;
+        info!("Using docker deamon at {}", url);
+        Ok(ShipliftRuntime { deamon: Docker::host(url) })
+    }
+}
+
+impl ContainerRuntime for ShipliftRuntime {
+    fn create(&self, spec: &ContainerSpec) -> Result<String, TollaError> {
+        let containers = self.deamon.containers();
+
+        let mut opts = ContainerOptionsBuilder::new(&spec.image);
+
+        let env: Vec<&str> = spec.env.iter().map(|s| &**s).collect();
+        let volumes_from: Vec<&str> = spec.volumes_from.iter().map(|s| &**s).collect();
+
+        opts.env(env);
+        opts.name(&spec.name);
+        opts.volumes_from(volumes_from);
+
+        let info = containers.create(&opts.build()).map_err(runtime_error)?;
+        Ok(info.Id)
+    }
+
+    fn start(&self, id: &str) -> Result<(), TollaError> {
+        let containers = self.deamon.containers();
+        containers.get(id).start().map_err(runtime_error)
+    }
+
+    fn restart(&self, id: &str) -> Result<(), TollaError> {
+        let containers = self.deamon.containers();
+        containers.get(id).restart(None).map_err(runtime_error)
+    }
+
+    fn inspect(&self, id: &str) -> Result<ContainerInfo, TollaError> {
+        let containers = self.deamon.containers();
+        let details = containers.get(id).inspect().map_err(runtime_error)?;
+
+        Ok(ContainerInfo {
+            id: details.Id,
+            name: details.Name.trim_left_matches('/').to_string(),
+            ip: details.NetworkSettings.IPAddress,
+            running: details.State.Running,
+        })
+    }
+
+    fn remove(&self, id: &str) -> Result<(), TollaError> {
+        let containers = self.deamon.containers();
+        let rm_opts = RmContainerOptions::builder().force(true).build();
+
+        containers.get(id).remove(rm_opts).map_err(runtime_error)
+    }
+
+    fn list(&self) -> Result<Vec<ContainerInfo>, TollaError> {
+        let containers = self.deamon.containers();
+
+        let listed = containers.list(&Default::default()).map_err(
+            runtime_error,
+        )?;
+
+        let mut infos = Vec::new();
+        for container in listed {
+            infos.push(self.inspect(&container.Id)?);
+        }
+        Ok(infos)
+    }
+}
+
+// The deamon answers 404 for unknown containers and
+// 409 for names already in use
+fn runtime_error(err: shiplift::Error) -> TollaError {
+    match err {
+        shiplift::Error::Fault { code, message } => {
+            match code.to_u16() {
+                404 => TollaError::NotFound(message),
+                409 => TollaError::AlreadyExists(message),
+                _ => TollaError::Runtime(message),
+            }
+        }
+        err => TollaError::Runtime(err.to_string()),
+    }
+}
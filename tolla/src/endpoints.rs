@@ -6,14 +6,36 @@ use router::Router;
 use std::str;
 use serde_json;
 use iron::status::Status;
-use consent::ConsentEngine;
+use iron::mime::Mime;
+use iron::headers::{Authorization, Bearer};
+use consent::{self, ConsentEngine, Decision, RegisteredIntent};
+use error::TollaError;
+use inventory::{parse_serial, CertificateQuery, CertificateStatus};
+use revocation::Reason;
 use urlencoded::UrlEncodedQuery;
+use url::percent_encoding::percent_decode;
+
+// Header the database proxy passes the percent-encoded PEM
+// certificate of a process asking for a lease in
+pub const CLIENT_CERTIFICATE: &'static str = "X-Client-Certificate";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct User {
     pub id: String,
     pub purposes: Vec<String>,
 }
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Purposes {
+    pub purposes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RevokeRequest {
+    // hex, e.g. "4d2"
+    pub serial_number: String,
+    pub reason: Reason,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Query {
     pub user: String,
@@ -27,6 +49,19 @@ pub struct Handlers {
     pub register: Register,
     pub remove: Remove,
     pub lease: Lease,
+    pub enroll: Enroll,
+    pub grant: Grant,
+    pub withdraw: Withdraw,
+    pub consent_as_of: ConsentAsOf,
+    pub audit_log: AuditLog,
+    pub audit_verify: AuditVerify,
+    pub revoke: Revoke,
+    pub crl: Crl,
+    pub ocsp: Ocsp,
+    pub certificates: Certificates,
+    pub certificate: Certificate,
+    pub intents: Intents,
+    pub register_intent: RegisterIntent,
 }
 
 impl Handlers {
@@ -36,6 +71,19 @@ impl Handlers {
             register: Register::new(router.clone()),
             remove: Remove::new(router.clone()),
             lease: Lease::new(router.clone()),
+            enroll: Enroll::new(router.clone()),
+            grant: Grant::new(router.clone()),
+            withdraw: Withdraw::new(router.clone()),
+            consent_as_of: ConsentAsOf::new(router.clone()),
+            audit_log: AuditLog::new(router.clone()),
+            audit_verify: AuditVerify::new(router.clone()),
+            revoke: Revoke::new(router.clone()),
+            crl: Crl::new(router.clone()),
+            ocsp: Ocsp::new(router.clone()),
+            certificates: Certificates::new(router.clone()),
+            certificate: Certificate::new(router.clone()),
+            intents: Intents::new(router.clone()),
+            register_intent: RegisterIntent::new(router.clone()),
         }
     }
 }
@@ -56,6 +104,75 @@ pub struct Lease {
     router: Arc<Mutex<ConsentEngine>>,
 }
 
+pub struct Enroll {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct Grant {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct Withdraw {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct ConsentAsOf {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct AuditLog {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct AuditVerify {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct Revoke {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct Crl {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct Ocsp {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct Certificates {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct Certificate {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct Intents {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+pub struct RegisterIntent {
+    router: Arc<Mutex<ConsentEngine>>,
+}
+
+// Respond to a failed engine call with the status matching the error
+fn error_response(err: TollaError) -> Response {
+    let status = match err {
+        TollaError::NotFound(_) => Status::NotFound,
+        TollaError::AlreadyExists(_) => Status::Conflict,
+        TollaError::Forbidden(_) => Status::Forbidden,
+        TollaError::Unauthorized(_) => Status::Unauthorized,
+        TollaError::InvalidRequest(_) => Status::BadRequest,
+        TollaError::Storage(_) => Status::ServiceUnavailable,
+        TollaError::Runtime(_) => Status::BadGateway,
+        TollaError::Integrity(_) => Status::Conflict,
+        TollaError::Config(_) |
+        TollaError::Crypto(_) => Status::InternalServerError,
+    };
+    Response::with((status, err.to_string()))
+}
+
 impl QueryHandler {
     pub fn new(router: Arc<Mutex<ConsentEngine>>) -> QueryHandler {
         QueryHandler { router: router }
@@ -75,7 +192,7 @@ impl Handler for QueryHandler {
             &String::from(user),
         ) {
             Ok(ip) => ip,
-            Err(err) => return Ok(Response::with((Status::BadRequest, err.to_string()))),
+            Err(err) => return Ok(error_response(err)),
         };
         Ok(Response::with((Status::Ok, ip)))
     }
@@ -106,7 +223,7 @@ impl Handler for Register {
             deserialized.purposes,
         )
         {
-            return Ok(Response::with((Status::BadRequest, err.to_string())));
+            return Ok(error_response(err));
         };
         Ok(Response::with(Status::Ok))
     }
@@ -128,7 +245,7 @@ impl Handler for Remove {
 
         let router = self.router.clone();
         if let Err(err) = router.lock().unwrap().deboard_user(&String::from(user)) {
-            return Ok(Response::with((Status::BadRequest, err.to_string())));
+            return Ok(error_response(err));
         };
         Ok(Response::with(Status::Ok))
     }
@@ -168,24 +285,495 @@ impl Handler for Lease {
             }
         };
 
-        let serial_number = match user[0].parse::<u32>() {
-            Err(err) => return Ok(Response::with((Status::BadRequest, err.to_string()))),
+        // The proxy sends the hex serial number of its certificate
+        let serial_number = match parse_serial(&user[0]) {
+            Err(err) => return Ok(error_response(err)),
             Ok(serial_number) => serial_number,
         };
 
         let router = self.router.clone();
-        let consent = match router.lock().unwrap().consent_by_serial_num(serial_number) {
-            Err(err) => return Ok(Response::with((Status::BadRequest, err.to_string()))),
-            Ok(consent) => consent,
+        let router = router.lock().unwrap();
+
+        // and the certificate the process presented to it
+        let pem = match req.headers.get_raw(CLIENT_CERTIFICATE) {
+            Some(values) if values.len() == 1 => percent_decode(&values[0]).collect::<Vec<u8>>(),
+            _ => {
+                let err = TollaError::Unauthorized(String::from("no client certificate"));
+                return Ok(error_response(err));
+            }
+        };
+        if let Err(err) = router.verify_peer(&pem) {
+            return Ok(error_response(err));
+        }
+
+        // A certificate for several intents is leased if any is consented
+        let decision = match router.decide_lease_any(&serial_number, intent) {
+            Err(err) => return Ok(error_response(err)),
+            Ok(decision) => decision,
+        };
+
+        match decision {
+            Decision::Granted => return Ok(Response::with(Status::Ok)),
+            Decision::Denied(reason) => return Ok(Response::with((Status::Forbidden, reason))),
+        }
+    }
+}
+
+impl Enroll {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> Enroll {
+        Enroll { router: router }
+    }
+}
+
+// Hands out a one-time token for requesting a
+// client certificate over the control channel
+impl Handler for Enroll {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let router = router.lock().unwrap();
+        if let Err(err) = authorize_admin(&router, req) {
+            return Ok(error_response(err));
+        }
+
+        let token = router.new_enrollment_token();
+        Ok(Response::with((Status::Ok, token)))
+    }
+}
+
+// Check the request carries the admin token in an
+// "Authorization: Bearer" header
+fn authorize_admin(engine: &ConsentEngine, req: &Request) -> Result<(), TollaError> {
+    let token = req.headers.get::<Authorization<Bearer>>().map(
+        |auth| auth.token.as_str(),
+    );
+    engine.authorize_admin(token)
+}
+
+// Read an integer query parameter, falling back to default
+// if it is not present
+fn query_i64(req: &mut Request, name: &str, default: i64) -> Result<i64, String> {
+    let query = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(query) => query,
+        Err(_) => return Ok(default),
+    };
+
+    match query.get(name) {
+        Some(value) => value[0].parse::<i64>().map_err(|e| e.to_string()),
+        None => Ok(default),
+    }
+}
+
+// Read a text query parameter, if present
+fn query_string(req: &mut Request, name: &str) -> Option<String> {
+    match req.get_ref::<UrlEncodedQuery>() {
+        Ok(query) => query.get(name).map(|value| value[0].clone()),
+        Err(_) => None,
+    }
+}
+
+// Build a certificate query from the ?intent=, ?subject=,
+// ?requester=, ?status= and ?expires_within_days= parameters
+fn certificate_query(req: &mut Request) -> Result<CertificateQuery, String> {
+    let status = match query_string(req, "status") {
+        Some(ref status) if status == "Active" => Some(CertificateStatus::Active),
+        Some(ref status) if status == "Revoked" => Some(CertificateStatus::Revoked),
+        Some(status) => return Err(format!("unknown status {}", status)),
+        None => None,
+    };
+
+    let expires_within = match query_i64(req, "expires_within_days", -1)? {
+        days if days >= 0 => Some(days * 24 * 60 * 60),
+        _ => None,
+    };
+
+    Ok(CertificateQuery {
+        intent: query_string(req, "intent"),
+        subject: query_string(req, "subject"),
+        requester: query_string(req, "requester"),
+        status: status,
+        expires_within: expires_within,
+    })
+}
+
+// Read the user from the route and the purposes from the json body
+fn user_and_purposes(req: &mut Request) -> Result<(String, Vec<String>), String> {
+    let user = req.extensions
+        .get::<Router>()
+        .unwrap()
+        .find("user")
+        .unwrap_or("/")
+        .to_string();
+
+    let mut raw = String::new();
+    req.body.read_to_string(&mut raw).map_err(|e| e.to_string())?;
+
+    let purposes: Purposes = serde_json::from_str(&raw).map_err(|e| e.to_string())?;
+    Ok((user, purposes.purposes))
+}
+
+impl Grant {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> Grant {
+        Grant { router: router }
+    }
+}
+
+impl Handler for Grant {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let router = router.lock().unwrap();
+        if let Err(err) = authorize_admin(&router, req) {
+            return Ok(error_response(err));
+        }
+
+        let (user, purposes) = match user_and_purposes(req) {
+            Ok(r) => r,
+            Err(err) => return Ok(Response::with((Status::BadRequest, err))),
         };
 
-        match consent.purpose.contains(&intent[0]) {
-            true => return Ok(Response::with(Status::Ok)),
-            false => {
+        if let Err(err) = router.grant_purposes(&user, purposes) {
+            return Ok(error_response(err));
+        };
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+impl Withdraw {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> Withdraw {
+        Withdraw { router: router }
+    }
+}
+
+impl Handler for Withdraw {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let router = router.lock().unwrap();
+        if let Err(err) = authorize_admin(&router, req) {
+            return Ok(error_response(err));
+        }
+
+        let (user, purposes) = match user_and_purposes(req) {
+            Ok(r) => r,
+            Err(err) => return Ok(Response::with((Status::BadRequest, err))),
+        };
+
+        if let Err(err) = router.withdraw_purposes(&user, purposes) {
+            return Ok(error_response(err));
+        };
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+impl ConsentAsOf {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> ConsentAsOf {
+        ConsentAsOf { router: router }
+    }
+}
+
+// Responds with the user's consent at the unix timestamp
+// given by ?as_of=, or the current consent if it is left out
+impl Handler for ConsentAsOf {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let user = req.extensions
+            .get::<Router>()
+            .unwrap()
+            .find("user")
+            .unwrap_or("/")
+            .to_string();
+
+        let timestamp = match query_i64(req, "as_of", consent::now()) {
+            Ok(t) => t,
+            Err(err) => return Ok(Response::with((Status::BadRequest, err))),
+        };
+
+        let router = self.router.clone();
+        let consent = match router.lock().unwrap().consent_as_of(&user, timestamp) {
+            Ok(Some(consent)) => consent,
+            Ok(None) => {
                 return Ok(Response::with(
-                    (Status::Forbidden, format!("Consents did not match")),
+                    (Status::NotFound, format!("no consent for {}", user)),
                 ))
             }
+            Err(err) => return Ok(error_response(err)),
+        };
+
+        match serde_json::to_string(&consent) {
+            Ok(body) => Ok(Response::with((Status::Ok, body))),
+            Err(err) => Ok(Response::with((Status::InternalServerError, err.to_string()))),
         }
     }
 }
+
+impl AuditLog {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> AuditLog {
+        AuditLog { router: router }
+    }
+}
+
+// Pages through the audit log. ?after= is the seq of the last
+// entry already seen and ?limit= the page size.
+impl Handler for AuditLog {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let after = match query_i64(req, "after", 0) {
+            Ok(after) => after,
+            Err(err) => return Ok(Response::with((Status::BadRequest, err))),
+        };
+        let limit = match query_i64(req, "limit", 100) {
+            Ok(limit) if limit > 0 && limit <= 1000 => limit,
+            Ok(_) => {
+                return Ok(Response::with(
+                    (Status::BadRequest, "limit must be between 1 and 1000"),
+                ))
+            }
+            Err(err) => return Ok(Response::with((Status::BadRequest, err))),
+        };
+
+        let router = self.router.clone();
+        let entries = match router.lock().unwrap().audit_entries(after, limit) {
+            Ok(entries) => entries,
+            Err(err) => return Ok(error_response(err)),
+        };
+
+        match serde_json::to_string(&entries) {
+            Ok(body) => Ok(Response::with((Status::Ok, body))),
+            Err(err) => Ok(Response::with((Status::InternalServerError, err.to_string()))),
+        }
+    }
+}
+
+impl AuditVerify {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> AuditVerify {
+        AuditVerify { router: router }
+    }
+}
+
+// Responds with the number of entries if the hash chain is
+// intact, and with 409 naming the broken entry otherwise
+impl Handler for AuditVerify {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let result = router.lock().unwrap().verify_audit_log();
+        match result {
+            Ok(count) => Ok(Response::with((Status::Ok, count.to_string()))),
+            Err(err) => Ok(error_response(err)),
+        }
+    }
+}
+
+impl Revoke {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> Revoke {
+        Revoke { router: router }
+    }
+}
+
+// Revokes the certificate given by the json body, e.g.
+// {"serial_number": "4d2", "reason": "KeyCompromise"}.
+// Needs the admin token.
+impl Handler for Revoke {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let router = router.lock().unwrap();
+        if let Err(err) = authorize_admin(&router, req) {
+            return Ok(error_response(err));
+        }
+
+        let mut raw = String::new();
+
+        if let Err(e) = req.body.read_to_string(&mut raw) {
+            return Ok(Response::with((Status::BadRequest, e.to_string())));
+        }
+
+        let request: RevokeRequest = match serde_json::from_str(&raw) {
+            Ok(r) => r,
+            Err(e) => return Ok(Response::with((Status::BadRequest, e.to_string()))),
+        };
+
+        let serial_number = match parse_serial(&request.serial_number) {
+            Ok(serial_number) => serial_number,
+            Err(err) => return Ok(error_response(err)),
+        };
+
+        let result = router.revoke_certificate(&serial_number, request.reason);
+        if let Err(err) = result {
+            return Ok(error_response(err));
+        };
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+impl Crl {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> Crl {
+        Crl { router: router }
+    }
+}
+
+// Serves the DER-encoded certificate revocation list
+impl Handler for Crl {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let crl = match router.lock().unwrap().crl() {
+            Ok(crl) => crl,
+            Err(err) => return Ok(error_response(err)),
+        };
+
+        let mime = "application/pkix-crl".parse::<Mime>().unwrap();
+        Ok(Response::with((Status::Ok, mime, crl)))
+    }
+}
+
+impl Ocsp {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> Ocsp {
+        Ocsp { router: router }
+    }
+}
+
+// Answers a DER-encoded OCSP request posted as the body. Malformed
+// requests get an OCSP error response rather than an HTTP error.
+impl Handler for Ocsp {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let mut raw = Vec::new();
+
+        if let Err(e) = req.body.read_to_end(&mut raw) {
+            return Ok(Response::with((Status::BadRequest, e.to_string())));
+        }
+
+        let router = self.router.clone();
+        let resp = match router.lock().unwrap().ocsp_response(&raw) {
+            Ok(resp) => resp,
+            Err(err) => return Ok(error_response(err)),
+        };
+
+        let mime = "application/ocsp-response".parse::<Mime>().unwrap();
+        Ok(Response::with((Status::Ok, mime, resp)))
+    }
+}
+
+impl Certificates {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> Certificates {
+        Certificates { router: router }
+    }
+}
+
+// Lists issued certificates, optionally filtered, e.g.
+// ?intent=research or ?expires_within_days=7. Needs the
+// admin token.
+impl Handler for Certificates {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let router = router.lock().unwrap();
+        if let Err(err) = authorize_admin(&router, req) {
+            return Ok(error_response(err));
+        }
+
+        let query = match certificate_query(req) {
+            Ok(query) => query,
+            Err(err) => return Ok(Response::with((Status::BadRequest, err))),
+        };
+
+        let certificates = match router.find_certificates(&query) {
+            Ok(certificates) => certificates,
+            Err(err) => return Ok(error_response(err)),
+        };
+
+        match serde_json::to_string(&certificates) {
+            Ok(body) => Ok(Response::with((Status::Ok, body))),
+            Err(err) => Ok(Response::with((Status::InternalServerError, err.to_string()))),
+        }
+    }
+}
+
+impl Certificate {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> Certificate {
+        Certificate { router: router }
+    }
+}
+
+// Serves the issued certificate with the hex serial number in
+// the route. Needs the admin token.
+impl Handler for Certificate {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let router = router.lock().unwrap();
+        if let Err(err) = authorize_admin(&router, req) {
+            return Ok(error_response(err));
+        }
+
+        let serial = req.extensions
+            .get::<Router>()
+            .unwrap()
+            .find("serial")
+            .unwrap_or("/")
+            .to_string();
+
+        let serial_number = match parse_serial(&serial) {
+            Ok(serial_number) => serial_number,
+            Err(err) => return Ok(error_response(err)),
+        };
+
+        let certificate = match router.get_certificate(&serial_number) {
+            Ok(certificate) => certificate,
+            Err(err) => return Ok(error_response(err)),
+        };
+
+        match serde_json::to_string(&certificate) {
+            Ok(body) => Ok(Response::with((Status::Ok, body))),
+            Err(err) => Ok(Response::with((Status::InternalServerError, err.to_string()))),
+        }
+    }
+}
+
+impl Intents {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> Intents {
+        Intents { router: router }
+    }
+}
+
+// Lists the intents certificates can be issued for
+impl Handler for Intents {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let intents = match router.lock().unwrap().registered_intents() {
+            Ok(intents) => intents,
+            Err(err) => return Ok(error_response(err)),
+        };
+
+        match serde_json::to_string(&intents) {
+            Ok(body) => Ok(Response::with((Status::Ok, body))),
+            Err(err) => Ok(Response::with((Status::InternalServerError, err.to_string()))),
+        }
+    }
+}
+
+impl RegisterIntent {
+    pub fn new(router: Arc<Mutex<ConsentEngine>>) -> RegisterIntent {
+        RegisterIntent { router: router }
+    }
+}
+
+// Registers the intent given by the json body, e.g.
+// {"name": "research", "description": "Medical research"}.
+// Needs the admin token.
+impl Handler for RegisterIntent {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let router = self.router.clone();
+        let router = router.lock().unwrap();
+        if let Err(err) = authorize_admin(&router, req) {
+            return Ok(error_response(err));
+        }
+
+        let mut raw = String::new();
+
+        if let Err(e) = req.body.read_to_string(&mut raw) {
+            return Ok(Response::with((Status::BadRequest, e.to_string())));
+        }
+
+        let intent: RegisteredIntent = match serde_json::from_str(&raw) {
+            Ok(intent) => intent,
+            Err(e) => return Ok(Response::with((Status::BadRequest, e.to_string()))),
+        };
+
+        if let Err(err) = router.register_intent(&intent) {
+            return Ok(error_response(err));
+        };
+        Ok(Response::with(Status::Ok))
+    }
+}
@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::fmt;
+use bson;
+use mongodb;
+use openssl::error::ErrorStack;
+use rusqlite;
+use serde_json;
+use tolla_proto::proto;
+
+// Errors returned throughout lib_tolla. Each variant carries a
+// human readable message; the variant itself says what kind of
+// failure it was, e.g. for picking an HTTP status.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TollaError {
+    NotFound(String),
+    AlreadyExists(String),
+    // The caller is known, but not allowed to do this
+    Forbidden(String),
+    // The caller did not authenticate
+    Unauthorized(String),
+    InvalidRequest(String),
+    // The engine was set up with missing or bad settings
+    Config(String),
+    // The database or the files backing it failed
+    Storage(String),
+    // The container runtime failed
+    Runtime(String),
+    Crypto(String),
+    // Stored data does not match its checksums
+    Integrity(String),
+}
+
+impl TollaError {
+    // Code sent to clients in proto::Error
+    pub fn code(&self) -> proto::ErrorCode {
+        match *self {
+            TollaError::NotFound(_) => proto::ErrorCode::NotFound,
+            TollaError::AlreadyExists(_) => proto::ErrorCode::AlreadyExists,
+            TollaError::Forbidden(_) => proto::ErrorCode::Forbidden,
+            TollaError::Unauthorized(_) => proto::ErrorCode::Unauthorized,
+            TollaError::InvalidRequest(_) => proto::ErrorCode::InvalidRequest,
+            TollaError::Config(_) => proto::ErrorCode::Internal,
+            TollaError::Storage(_) => proto::ErrorCode::Storage,
+            TollaError::Runtime(_) => proto::ErrorCode::Runtime,
+            TollaError::Crypto(_) => proto::ErrorCode::Crypto,
+            TollaError::Integrity(_) => proto::ErrorCode::Internal,
+        }
+    }
+
+    pub fn to_proto(&self) -> proto::Error {
+        proto::Error {
+            error: self.to_string(),
+            code: self.code() as i32,
+        }
+    }
+}
+
+impl fmt::Display for TollaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for TollaError {
+    fn description(&self) -> &str {
+        match *self {
+            TollaError::NotFound(ref msg) |
+            TollaError::AlreadyExists(ref msg) |
+            TollaError::Forbidden(ref msg) |
+            TollaError::Unauthorized(ref msg) |
+            TollaError::InvalidRequest(ref msg) |
+            TollaError::Config(ref msg) |
+            TollaError::Storage(ref msg) |
+            TollaError::Runtime(ref msg) |
+            TollaError::Crypto(ref msg) |
+            TollaError::Integrity(ref msg) => msg,
+        }
+    }
+}
+
+impl From<ErrorStack> for TollaError {
+    fn from(err: ErrorStack) -> TollaError {
+        TollaError::Crypto(err.to_string())
+    }
+}
+
+// Unique constraint violations mean the key is taken
+impl From<rusqlite::Error> for TollaError {
+    fn from(err: rusqlite::Error) -> TollaError {
+        match err {
+            rusqlite::Error::SqliteFailure(ref e, _)
+                if e.code == rusqlite::ffi::ErrorCode::ConstraintViolation => {
+                TollaError::AlreadyExists(err.to_string())
+            }
+            _ => TollaError::Storage(err.to_string()),
+        }
+    }
+}
+
+// MongoDB reports duplicate keys as error E11000
+impl From<mongodb::Error> for TollaError {
+    fn from(err: mongodb::Error) -> TollaError {
+        let msg = err.to_string();
+        match msg.contains("E11000") {
+            true => TollaError::AlreadyExists(msg),
+            false => TollaError::Storage(msg),
+        }
+    }
+}
+
+impl From<bson::EncoderError> for TollaError {
+    fn from(err: bson::EncoderError) -> TollaError {
+        TollaError::Storage(err.to_string())
+    }
+}
+
+impl From<bson::DecoderError> for TollaError {
+    fn from(err: bson::DecoderError) -> TollaError {
+        TollaError::Storage(err.to_string())
+    }
+}
+
+// Records are stored as json documents
+impl From<serde_json::Error> for TollaError {
+    fn from(err: serde_json::Error) -> TollaError {
+        TollaError::Storage(err.to_string())
+    }
+}
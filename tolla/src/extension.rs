@@ -0,0 +1,312 @@
+use openssl::asn1::Asn1Object;
+use openssl::x509::{X509Extension, X509Ref};
+use error::TollaError;
+use std::str::FromStr;
+
+// OID of the extension carrying what a certificate may be used
+// for, under tolla's private enterprise arc. Every arc fits in 31
+// bits, which Go's crypto/x509 needs to parse the certificate.
+pub const INTENT_EXTENSION_OID: &'static str = "1.3.6.1.4.1.61432.1.1";
+
+// The extension's value is the DER encoding of
+//
+//   TollaIntents ::= SEQUENCE {
+//       intents     SEQUENCE SIZE (1..MAX) OF UTF8String,
+//       legalBasis  ENUMERATED {
+//           consent(0), contract(1), legalObligation(2),
+//           vitalInterests(3), publicTask(4),
+//           legitimateInterests(5) },
+//       users       [0] IMPLICIT SEQUENCE OF UTF8String OPTIONAL
+//   }
+const SEQUENCE: u8 = 0x30;
+const UTF8_STRING: u8 = 0x0c;
+const ENUMERATED: u8 = 0x0a;
+const USERS: u8 = 0xa0;
+
+// Lawful grounds for processing personal data, as in article
+// 6(1) of the GDPR. Written as e.g. "consent" or "public-task".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LegalBasis {
+    Consent,
+    Contract,
+    LegalObligation,
+    VitalInterests,
+    PublicTask,
+    LegitimateInterests,
+}
+
+impl Default for LegalBasis {
+    fn default() -> LegalBasis {
+        LegalBasis::Consent
+    }
+}
+
+impl LegalBasis {
+    fn to_enumerated(&self) -> u8 {
+        match *self {
+            LegalBasis::Consent => 0,
+            LegalBasis::Contract => 1,
+            LegalBasis::LegalObligation => 2,
+            LegalBasis::VitalInterests => 3,
+            LegalBasis::PublicTask => 4,
+            LegalBasis::LegitimateInterests => 5,
+        }
+    }
+
+    fn from_enumerated(value: u8) -> Option<LegalBasis> {
+        match value {
+            0 => Some(LegalBasis::Consent),
+            1 => Some(LegalBasis::Contract),
+            2 => Some(LegalBasis::LegalObligation),
+            3 => Some(LegalBasis::VitalInterests),
+            4 => Some(LegalBasis::PublicTask),
+            5 => Some(LegalBasis::LegitimateInterests),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for LegalBasis {
+    type Err = TollaError;
+
+    fn from_str(s: &str) -> Result<LegalBasis, TollaError> {
+        match s {
+            "consent" => Ok(LegalBasis::Consent),
+            "contract" => Ok(LegalBasis::Contract),
+            "legal-obligation" => Ok(LegalBasis::LegalObligation),
+            "vital-interests" => Ok(LegalBasis::VitalInterests),
+            "public-task" => Ok(LegalBasis::PublicTask),
+            "legitimate-interests" => Ok(LegalBasis::LegitimateInterests),
+            _ => Err(TollaError::Config(format!("unknown legal basis {}", s))),
+        }
+    }
+}
+
+// What a process certificate may be used for
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntentExtension {
+    pub intents: Vec<String>,
+    pub legal_basis: LegalBasis,
+    // Users whose data the certificate is limited to. None
+    // leaves it to the consent of each user.
+    pub users: Option<Vec<String>>,
+}
+
+impl IntentExtension {
+    pub fn to_der(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_tlv(&mut content, SEQUENCE, &strings(&self.intents));
+        write_tlv(&mut content, ENUMERATED, &[self.legal_basis.to_enumerated()]);
+        if let Some(ref users) = self.users {
+            write_tlv(&mut content, USERS, &strings(users));
+        }
+
+        let mut der = Vec::new();
+        write_tlv(&mut der, SEQUENCE, &content);
+        der
+    }
+
+    pub fn from_der(der: &[u8]) -> Result<IntentExtension, TollaError> {
+        let mut outer = Reader { data: der };
+        let mut fields = Reader { data: outer.read(SEQUENCE)? };
+        outer.finish()?;
+
+        let intents = read_strings(fields.read(SEQUENCE)?)?;
+        if intents.is_empty() {
+            return Err(malformed("no intents"));
+        }
+
+        let legal_basis = fields.read(ENUMERATED)?;
+        let legal_basis = match legal_basis.len() {
+            1 => LegalBasis::from_enumerated(legal_basis[0]),
+            _ => None,
+        }.ok_or_else(|| malformed("unknown legal basis"))?;
+
+        let users = match fields.peek() {
+            Some(USERS) => Some(read_strings(fields.read(USERS)?)?),
+            _ => None,
+        };
+        fields.finish()?;
+
+        Ok(IntentExtension {
+            intents: intents,
+            legal_basis: legal_basis,
+            users: users,
+        })
+    }
+
+    // The extension to add to a certificate. It is not critical,
+    // so TLS libraries that do not know it still accept the
+    // certificate.
+    pub fn to_x509_extension(&self) -> Result<X509Extension, TollaError> {
+        let oid = INTENT_EXTENSION_OID.parse::<Asn1Object>()?;
+        Ok(X509Extension::new_from_der(&oid, false, &self.to_der())?)
+    }
+
+    // The extension of cert, if it has one
+    pub fn from_certificate(cert: &X509Ref) -> Result<Option<IntentExtension>, TollaError> {
+        let oid = INTENT_EXTENSION_OID.parse::<Asn1Object>()?;
+        match cert.extension_der(&oid) {
+            Some(der) => IntentExtension::from_der(der).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn malformed(what: &str) -> TollaError {
+    TollaError::Crypto(format!("malformed intent extension: {}", what))
+}
+
+// Content of a SEQUENCE OF UTF8String
+fn strings(values: &[String]) -> Vec<u8> {
+    let mut content = Vec::new();
+    for value in values {
+        write_tlv(&mut content, UTF8_STRING, value.as_bytes());
+    }
+    content
+}
+
+fn read_strings(content: &[u8]) -> Result<Vec<String>, TollaError> {
+    let mut reader = Reader { data: content };
+    let mut values = Vec::new();
+    while reader.peek().is_some() {
+        let value = reader.read(UTF8_STRING)?;
+        let value = String::from_utf8(value.to_vec()).map_err(|_| malformed("not UTF-8"))?;
+        values.push(value);
+    }
+    Ok(values)
+}
+
+fn write_tlv(out: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    out.push(tag);
+
+    let len = content.len() as u64;
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = (0..8)
+            .rev()
+            .map(|i| (len >> (i * 8)) as u8)
+            .skip_while(|&b| b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(&bytes);
+    }
+
+    out.extend_from_slice(content);
+}
+
+// Reads DER one element at a time. Only definite, minimally
+// encoded lengths are accepted.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.data.first().cloned()
+    }
+
+    // Content of the next element, which must have tag
+    fn read(&mut self, tag: u8) -> Result<&'a [u8], TollaError> {
+        if self.data.len() < 2 {
+            return Err(malformed("truncated"));
+        }
+        if self.data[0] != tag {
+            return Err(malformed(&format!("expected tag {:#04x}", tag)));
+        }
+
+        let (len, header) = match self.data[1] {
+            len @ 0...0x7f => (len as usize, 2),
+            0x81...0x84 => {
+                let n = (self.data[1] & 0x7f) as usize;
+                if self.data.len() < 2 + n || self.data[2] == 0 {
+                    return Err(malformed("bad length"));
+                }
+                let len = self.data[2..2 + n]
+                    .iter()
+                    .fold(0usize, |len, &b| (len << 8) | b as usize);
+                if len < 0x80 {
+                    return Err(malformed("bad length"));
+                }
+                (len, 2 + n)
+            }
+            _ => return Err(malformed("bad length")),
+        };
+
+        if self.data.len() - header < len {
+            return Err(malformed("truncated"));
+        }
+        let content = &self.data[header..header + len];
+        self.data = &self.data[header + len..];
+        Ok(content)
+    }
+
+    fn finish(&self) -> Result<(), TollaError> {
+        match self.data.is_empty() {
+            true => Ok(()),
+            false => Err(malformed("trailing data")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use extension::{IntentExtension, LegalBasis};
+
+    #[test]
+    fn test_round_trip() {
+        let ext = IntentExtension {
+            intents: vec![String::from("research"), String::from("statistics")],
+            legal_basis: LegalBasis::PublicTask,
+            users: Some(vec![String::from("alice")]),
+        };
+        assert_eq!(IntentExtension::from_der(&ext.to_der()).unwrap(), ext);
+
+        let ext = IntentExtension {
+            intents: vec![String::from("research")],
+            legal_basis: LegalBasis::Consent,
+            users: None,
+        };
+        let der = ext.to_der();
+        assert_eq!(
+            der,
+            b"\x30\x0f\x30\x0a\x0c\x08research\x0a\x01\x00".to_vec()
+        );
+        assert_eq!(IntentExtension::from_der(&der).unwrap(), ext);
+    }
+
+    #[test]
+    fn test_long_values() {
+        let ext = IntentExtension {
+            intents: vec!["x".repeat(300)],
+            legal_basis: LegalBasis::Contract,
+            users: None,
+        };
+        let der = ext.to_der();
+        assert_eq!(&der[..4], &[0x30, 0x82, 0x01, 0x37]);
+        assert_eq!(IntentExtension::from_der(&der).unwrap(), ext);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let der = IntentExtension {
+            intents: vec![String::from("research")],
+            legal_basis: LegalBasis::Consent,
+            users: None,
+        }.to_der();
+
+        assert!(IntentExtension::from_der(&der[..der.len() - 1]).is_err());
+
+        let mut trailing = der.clone();
+        trailing.push(0);
+        assert!(IntentExtension::from_der(&trailing).is_err());
+
+        // No intents
+        assert!(IntentExtension::from_der(b"\x30\x05\x30\x00\x0a\x01\x00").is_err());
+        // Unknown legal basis
+        assert!(IntentExtension::from_der(b"\x30\x07\x30\x02\x0c\x00\x0a\x01\x09").is_err());
+        // Indefinite length
+        assert!(IntentExtension::from_der(b"\x30\x80\x00\x00").is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::bn::{BigNum, BigNumRef};
+use openssl::nid;
+use openssl::x509::{X509NameRef, X509Ref};
+use error::TollaError;
+use extension::IntentExtension;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CertificateStatus {
+    Active,
+    Revoked,
+}
+
+// A certificate issued by the authority
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IssuedCertificate {
+    // hex serial number of the certificate, see serial_to_hex
+    #[serde(rename = "_id")]
+    pub serial_number: String,
+    // e.g. "C=NO, ST=TR, O=IFI, CN=process"
+    pub subject: String,
+    pub intents: Vec<String>,
+    // empty if the certificate has no SubjectKeyIdentifier
+    pub subject_key_id: String,
+    // unix timestamps
+    pub not_before: i64,
+    pub not_after: i64,
+    // who asked for the certificate, e.g. "user:alice" for the
+    // database certificate of an onboarded user
+    pub requester: String,
+    pub status: CertificateStatus,
+}
+
+impl IssuedCertificate {
+    // Describe cert, freshly issued to requester
+    pub fn new(
+        cert: &X509Ref,
+        intents: Vec<String>,
+        subject_key_id: String,
+        requester: String,
+    ) -> Result<IssuedCertificate, TollaError> {
+        Ok(IssuedCertificate {
+            serial_number: serial_to_hex(&cert.serial_number().to_bn()?)?,
+            subject: distinguished_name(cert.subject_name()),
+            intents: intents,
+            subject_key_id: subject_key_id,
+            not_before: unix_time(cert.not_before())?,
+            not_after: unix_time(cert.not_after())?,
+            requester: requester,
+            status: CertificateStatus::Active,
+        })
+    }
+}
+
+// Filters for searching the inventory. Fields that are not
+// set match every certificate.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CertificateQuery {
+    pub intent: Option<String>,
+    // part of the subject, e.g. "CN=process"
+    pub subject: Option<String>,
+    pub requester: Option<String>,
+    pub status: Option<CertificateStatus>,
+    // only certificates that are still valid at now, but not
+    // after now + expires_within seconds
+    pub expires_within: Option<i64>,
+}
+
+impl CertificateQuery {
+    pub fn matches(&self, cert: &IssuedCertificate, now: i64) -> bool {
+        if let Some(ref intent) = self.intent {
+            if !cert.intents.contains(intent) {
+                return false;
+            }
+        }
+        if let Some(ref subject) = self.subject {
+            if !cert.subject.contains(subject.as_str()) {
+                return false;
+            }
+        }
+        if let Some(ref requester) = self.requester {
+            if &cert.requester != requester {
+                return false;
+            }
+        }
+        if let Some(status) = self.status {
+            if cert.status != status {
+                return false;
+            }
+        }
+        if let Some(seconds) = self.expires_within {
+            if cert.not_after < now || cert.not_after > now + seconds {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Hex digits in the 128-bit serial numbers the authority picks
+pub const SERIAL_HEX_DIGITS: usize = 32;
+
+// Serial numbers are kept as lowercase hex, zero-padded to
+// SERIAL_HEX_DIGITS, so that every serial number has a single
+// form whatever the client sends, e.g. Go's big.Int Text(16)
+pub fn serial_to_hex(serial: &BigNumRef) -> Result<String, TollaError> {
+    let hex = serial.to_hex_str()?.to_lowercase();
+    Ok(format!(
+        "{:0>width$}",
+        hex.trim_left_matches('0'),
+        width = SERIAL_HEX_DIGITS
+    ))
+}
+
+// Bring a hex serial number from a client into the form it is
+// stored in
+pub fn parse_serial(hex: &str) -> Result<String, TollaError> {
+    match BigNum::from_hex_str(hex) {
+        Ok(ref serial) if !serial.is_negative() => serial_to_hex(serial),
+        _ => Err(TollaError::InvalidRequest(format!("bad serial number {}", hex))),
+    }
+}
+
+// Type-id of the otherName in the SubjectAltName the authority
+// recorded a certificate's intent in before it had the intent
+// extension
+pub const INTENT_OID: &'static str = "1.3.6.1.4.1.99";
+
+// Serial number, subject and intents of a certificate a client
+// presented, read from the certificate itself. Certificates
+// without intents, like database certificates, give none.
+pub fn describe_certificate(
+    cert: &X509Ref,
+) -> Result<(String, String, Vec<String>), TollaError> {
+    let serial_number = serial_to_hex(&cert.serial_number().to_bn()?)?;
+    let subject = distinguished_name(cert.subject_name());
+
+    if let Some(extension) = IntentExtension::from_certificate(cert)? {
+        return Ok((serial_number, subject, extension.intents));
+    }
+
+    let mut intents = Vec::new();
+    if let Some(names) = cert.subject_alt_names() {
+        for name in names.iter() {
+            match (name.other_name_type(), name.other_name_value()) {
+                (Some(oid), Some(value)) if oid.to_string() == INTENT_OID => {
+                    intents.push(value.as_utf8()?.to_string())
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok((serial_number, subject, intents))
+}
+
+// The subject fields the authority issues certificates with,
+// in the order they are printed
+const NAME_FIELDS: [(nid::Nid, &'static str); 4] = [
+    (nid::COUNTRYNAME, "C"),
+    (nid::STATEORPROVINCENAME, "ST"),
+    (nid::ORGANIZATIONNAME, "O"),
+    (nid::COMMONNAME, "CN"),
+];
+
+fn distinguished_name(name: &X509NameRef) -> String {
+    let mut parts = Vec::new();
+    for &(nid, field) in NAME_FIELDS.iter() {
+        for entry in name.entries_by_nid(nid) {
+            if let Ok(value) = entry.data().as_utf8() {
+                parts.push(format!("{}={}", field, value));
+            }
+        }
+    }
+    parts.join(", ")
+}
+
+// Seconds from the epoch to time, as counted by OpenSSL
+fn unix_time(time: &Asn1TimeRef) -> Result<i64, TollaError> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    Ok(diff.days as i64 * 24 * 60 * 60 + diff.secs as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use inventory::{parse_serial, unix_time, CertificateQuery, CertificateStatus,
+                    IssuedCertificate};
+    use openssl::asn1::Asn1Time;
+
+    fn certificate(intent: &str, not_after: i64) -> IssuedCertificate {
+        IssuedCertificate {
+            serial_number: String::from("2a"),
+            subject: String::from("C=NO, ST=TR, O=IFI, CN=process"),
+            intents: vec![String::from(intent)],
+            subject_key_id: String::new(),
+            not_before: 0,
+            not_after: not_after,
+            requester: String::from("user:alice"),
+            status: CertificateStatus::Active,
+        }
+    }
+
+    #[test]
+    fn test_query_filters() {
+        let cert = certificate("research", 1000);
+        assert!(CertificateQuery::default().matches(&cert, 0));
+
+        let mut query = CertificateQuery::default();
+        query.intent = Some(String::from("research"));
+        query.subject = Some(String::from("CN=process"));
+        assert!(query.matches(&cert, 0));
+
+        query.status = Some(CertificateStatus::Revoked);
+        assert!(!query.matches(&cert, 0));
+
+        let mut query = CertificateQuery::default();
+        query.expires_within = Some(100);
+        assert!(query.matches(&cert, 950));
+        assert!(!query.matches(&cert, 800));
+        // Certificates that already expired are not expiring
+        assert!(!query.matches(&cert, 1001));
+    }
+
+    #[test]
+    fn test_serials_are_normalized() {
+        assert_eq!(parse_serial("00FF").unwrap(), "000000000000000000000000000000ff");
+        assert_eq!(parse_serial("1a2b").unwrap(), "00000000000000000000000000001a2b");
+        assert_eq!(parse_serial("0").unwrap(), "00000000000000000000000000000000");
+        let long = "1234567890abcdef1234567890abcdef12";
+        assert_eq!(parse_serial(long).unwrap(), long);
+        assert!(parse_serial("xyz").is_err());
+        assert!(parse_serial("-1").is_err());
+    }
+
+    #[test]
+    fn test_unix_time() {
+        for &time in &[0, 1510000000, 2524608000] {
+            assert_eq!(unix_time(&Asn1Time::from_unix(time).unwrap()).unwrap(), time);
+        }
+    }
+}
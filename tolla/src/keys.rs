@@ -0,0 +1,159 @@
+use openssl::ec::{self, EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::{self, Nid};
+use openssl::pkey::{PKey, PKeyRef};
+use openssl::rsa::Rsa;
+use error::TollaError;
+use std::str::FromStr;
+
+// Keys the authority can generate, for itself and for tenants.
+// Written as e.g. "rsa-2048" or "ecdsa-p256".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyAlgorithm {
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+    EcdsaP256,
+    EcdsaP384,
+}
+
+impl Default for KeyAlgorithm {
+    fn default() -> KeyAlgorithm {
+        KeyAlgorithm::Rsa2048
+    }
+}
+
+impl KeyAlgorithm {
+    // Generate a fresh keypair
+    pub fn generate(&self) -> Result<PKey, TollaError> {
+        let key = match *self {
+            KeyAlgorithm::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?)?,
+            KeyAlgorithm::Rsa3072 => PKey::from_rsa(Rsa::generate(3072)?)?,
+            KeyAlgorithm::Rsa4096 => PKey::from_rsa(Rsa::generate(4096)?)?,
+            KeyAlgorithm::EcdsaP256 => PKey::from_ec_key(ec_key(nid::X9_62_PRIME256V1)?)?,
+            KeyAlgorithm::EcdsaP384 => PKey::from_ec_key(ec_key(nid::SECP384R1)?)?,
+        };
+        Ok(key)
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = TollaError;
+
+    fn from_str(s: &str) -> Result<KeyAlgorithm, TollaError> {
+        match s {
+            "rsa-2048" => Ok(KeyAlgorithm::Rsa2048),
+            "rsa-3072" => Ok(KeyAlgorithm::Rsa3072),
+            "rsa-4096" => Ok(KeyAlgorithm::Rsa4096),
+            "ecdsa-p256" => Ok(KeyAlgorithm::EcdsaP256),
+            "ecdsa-p384" => Ok(KeyAlgorithm::EcdsaP384),
+            _ => Err(TollaError::Config(format!("unknown key algorithm {}", s))),
+        }
+    }
+}
+
+// The keys the authority generates, and the weakest key it
+// signs certificates for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyPolicy {
+    pub algorithm: KeyAlgorithm,
+    // bits of security, see key_strength
+    pub min_strength: u32,
+}
+
+impl Default for KeyPolicy {
+    // RSA-2048 both ways
+    fn default() -> KeyPolicy {
+        KeyPolicy {
+            algorithm: KeyAlgorithm::Rsa2048,
+            min_strength: 112,
+        }
+    }
+}
+
+impl KeyPolicy {
+    // Fails if key is weaker than the policy allows
+    pub fn check(&self, key: &PKeyRef) -> Result<(), TollaError> {
+        let strength = key_strength(key)?;
+        if strength < self.min_strength {
+            return Err(TollaError::InvalidRequest(format!(
+                "key has {} bits of security, at least {} required",
+                strength,
+                self.min_strength
+            )));
+        }
+        Ok(())
+    }
+}
+
+// The curve is named in certificates rather than spelled out
+fn ec_key(curve: Nid) -> Result<EcKey, TollaError> {
+    let mut group = EcGroup::from_curve_name(curve)?;
+    group.set_asn1_flag(ec::NAMED_CURVE);
+    Ok(EcKey::generate(&group)?)
+}
+
+// Bits of security of key, as estimated by NIST SP 800-57.
+// Only RSA and ECDSA keys are supported.
+pub fn key_strength(key: &PKeyRef) -> Result<u32, TollaError> {
+    let bits = key.bits();
+    match key.id() {
+        nid::RSAENCRYPTION => {
+            let strength = match bits {
+                0...2047 => 80,
+                2048...3071 => 112,
+                3072...7679 => 128,
+                7680...15359 => 192,
+                _ => 256,
+            };
+            Ok(strength)
+        }
+        nid::X9_62_ID_ECPUBLICKEY => Ok(bits / 2),
+        _ => Err(TollaError::InvalidRequest(
+            String::from("only RSA and ECDSA keys are supported"),
+        )),
+    }
+}
+
+// Digest to sign with key. Keys stronger than 128 bits of
+// security get SHA-384 so the digest does not weaken them.
+pub fn signature_digest(key: &PKeyRef) -> MessageDigest {
+    match key.id() {
+        nid::X9_62_ID_ECPUBLICKEY if key.bits() > 256 => MessageDigest::sha384(),
+        nid::RSAENCRYPTION if key.bits() >= 4096 => MessageDigest::sha384(),
+        _ => MessageDigest::sha256(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use keys::{key_strength, signature_digest, KeyAlgorithm, KeyPolicy};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+
+    #[test]
+    fn test_generated_keys() {
+        let key = KeyAlgorithm::Rsa2048.generate().unwrap();
+        assert_eq!(key_strength(&key).unwrap(), 112);
+        assert_eq!(signature_digest(&key).as_ptr(), MessageDigest::sha256().as_ptr());
+
+        let key = KeyAlgorithm::EcdsaP256.generate().unwrap();
+        assert_eq!(key_strength(&key).unwrap(), 128);
+        assert_eq!(signature_digest(&key).as_ptr(), MessageDigest::sha256().as_ptr());
+
+        let key = KeyAlgorithm::EcdsaP384.generate().unwrap();
+        assert_eq!(key_strength(&key).unwrap(), 192);
+        assert_eq!(signature_digest(&key).as_ptr(), MessageDigest::sha384().as_ptr());
+
+        let key = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+        assert_eq!(key_strength(&key).unwrap(), 80);
+        assert!(KeyPolicy::default().check(&key).is_err());
+    }
+
+    #[test]
+    fn test_parse_algorithm() {
+        assert_eq!("ecdsa-p384".parse::<KeyAlgorithm>().unwrap(), KeyAlgorithm::EcdsaP384);
+        assert!("rsa-1024".parse::<KeyAlgorithm>().is_err());
+    }
+}
@@ -10,6 +10,7 @@ extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_proto;
 extern crate tokio_service;
+extern crate tokio_openssl;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
@@ -27,15 +28,24 @@ extern crate openssl;
 extern crate tolla_proto;
 extern crate bytes;
 extern crate urlencoded;
-extern crate rand;
+extern crate rusqlite;
 
 
 // Public modules
 pub mod consent;
 pub mod proxy;
 pub mod endpoints;
+pub mod storage;
+pub mod docker;
+pub mod tls;
+pub mod audit;
+pub mod revocation;
+pub mod inventory;
+pub mod keys;
+pub mod policy;
+pub mod extension;
+pub mod error;
 mod ca;
 
 // Private modules
 pub mod register;
-mod docker;
@@ -0,0 +1,348 @@
+use openssl::nid::{self, Nid};
+use openssl::x509::{X509Extension, X509NameRef, X509ReqRef};
+use error::TollaError;
+use extension::LegalBasis;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// Subject attributes a certificate request must carry, once
+// each. Nothing else may be in the subject.
+const SUBJECT_FIELDS: [(Nid, &'static str); 4] = [
+    (nid::COUNTRYNAME, "C"),
+    (nid::STATEORPROVINCENAME, "ST"),
+    (nid::ORGANIZATIONNAME, "O"),
+    (nid::COMMONNAME, "CN"),
+];
+
+// A year, in seconds
+const DEFAULT_VALIDITY: u32 = 365 * 24 * 60 * 60;
+
+// Extensions of certificate requests the authority can be
+// configured to grant. Written as "subject-alt-name" or
+// "extended-key-usage".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestedExtension {
+    // DNS names and IP addresses, e.g. for processes that also
+    // serve TLS
+    SubjectAltName,
+    // serverAuth, besides the clientAuth every certificate has
+    ExtendedKeyUsage,
+}
+
+impl FromStr for RequestedExtension {
+    type Err = TollaError;
+
+    fn from_str(s: &str) -> Result<RequestedExtension, TollaError> {
+        match s {
+            "subject-alt-name" => Ok(RequestedExtension::SubjectAltName),
+            "extended-key-usage" => Ok(RequestedExtension::ExtendedKeyUsage),
+            _ => Err(TollaError::Config(format!("unknown request extension {}", s))),
+        }
+    }
+}
+
+// What a request is granted of the extensions it asks for
+#[derive(Default)]
+pub struct GrantedExtensions {
+    // The request's own subjectAltName
+    pub alt_names: Option<X509Extension>,
+    pub server_auth: bool,
+}
+
+// What a certificate request has to satisfy before the
+// authority signs it, and for how long. The strength of the key
+// is up to the authority's KeyPolicy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IssuancePolicy {
+    // Registered organisations the O of a request must be one
+    // of. Empty allows any organisation.
+    pub organizations: Vec<String>,
+    // Intents certificates are issued for, besides being
+    // registered with the engine. Empty allows every registered
+    // intent.
+    pub intents: Vec<String>,
+    // Seconds certificates for an intent are valid, for intents
+    // that should not get default_validity
+    pub validity: HashMap<String, u32>,
+    pub default_validity: u32,
+    // Legal basis certificates for an intent are issued under.
+    // Intents not listed are processed by consent.
+    pub legal_basis: HashMap<String, LegalBasis>,
+    // Extensions requests may ask for. Requests asking for any
+    // other extension are refused.
+    pub extensions: Vec<RequestedExtension>,
+}
+
+impl Default for IssuancePolicy {
+    fn default() -> IssuancePolicy {
+        IssuancePolicy {
+            organizations: Vec::new(),
+            intents: Vec::new(),
+            validity: HashMap::new(),
+            default_validity: DEFAULT_VALIDITY,
+            legal_basis: HashMap::new(),
+            extensions: Vec::new(),
+        }
+    }
+}
+
+impl IssuancePolicy {
+    // Check that req is signed by its own key, asks for known
+    // intents and has an acceptable subject. Returns the subject
+    // attributes to issue the certificate with, as in
+    // SUBJECT_FIELDS.
+    pub fn check(
+        &self,
+        req: &X509ReqRef,
+        intents: &[String],
+    ) -> Result<Vec<(Nid, String)>, TollaError> {
+        let key = req.public_key().map_err(|_| {
+            TollaError::InvalidRequest(String::from("request has no usable public key"))
+        })?;
+        if !req.verify(&key).unwrap_or(false) {
+            return Err(TollaError::InvalidRequest(
+                String::from("request signature does not verify"),
+            ));
+        }
+
+        self.check_subject(req.subject_name(), intents)
+    }
+
+    // Check intents and subject as check does, without a request.
+    // Used to renew certificates issued under an earlier policy.
+    pub fn check_subject(
+        &self,
+        subject: &X509NameRef,
+        intents: &[String],
+    ) -> Result<Vec<(Nid, String)>, TollaError> {
+        if intents.is_empty() {
+            return Err(TollaError::InvalidRequest(String::from("no intent")));
+        }
+        for (i, intent) in intents.iter().enumerate() {
+            if intent.is_empty() {
+                return Err(TollaError::InvalidRequest(String::from("empty intent")));
+            }
+            if intents[..i].contains(intent) {
+                return Err(TollaError::InvalidRequest(format!("intent {} given twice", intent)));
+            }
+            if !self.intents.is_empty() && !self.intents.contains(intent) {
+                return Err(TollaError::Forbidden(format!("unknown intent {}", intent)));
+            }
+        }
+
+        for entry in subject.entries() {
+            let nid = entry.object().nid();
+            if !SUBJECT_FIELDS.iter().any(|&(field, _)| field == nid) {
+                return Err(TollaError::InvalidRequest(
+                    format!("subject attribute {} not allowed", entry.object()),
+                ));
+            }
+        }
+
+        let mut fields = Vec::new();
+        for &(nid, field) in SUBJECT_FIELDS.iter() {
+            let mut entries = subject.entries_by_nid(nid);
+            let value = match (entries.next(), entries.next()) {
+                (Some(entry), None) => {
+                    entry.data().as_utf8().map(|v| v.to_string()).map_err(|_| {
+                        TollaError::InvalidRequest(format!("{} is not valid text", field))
+                    })?
+                }
+                (None, _) => return Err(TollaError::InvalidRequest(format!("no {}", field))),
+                (Some(_), Some(_)) => {
+                    return Err(TollaError::InvalidRequest(format!("more than one {}", field)))
+                }
+            };
+            fields.push((nid, value));
+        }
+
+        let organization = &fields[2].1;
+        if !self.organizations.is_empty() && !self.organizations.contains(organization) {
+            return Err(TollaError::Forbidden(
+                format!("organisation {} is not registered", organization),
+            ));
+        }
+
+        Ok(fields)
+    }
+
+    // Check the extensions req asks for against extensions.
+    // Returns what to issue the certificate with.
+    pub fn check_extensions(&self, req: &X509ReqRef) -> Result<GrantedExtensions, TollaError> {
+        let extensions = req.extensions().map_err(|_| {
+            TollaError::InvalidRequest(String::from("request extensions can not be decoded"))
+        })?;
+
+        let mut granted = GrantedExtensions::default();
+        let mut seen = Vec::new();
+        for ext in extensions.iter() {
+            let kind = ext.object().nid();
+            if seen.contains(&kind) {
+                return Err(TollaError::InvalidRequest(
+                    format!("extension {} requested twice", ext.object()),
+                ));
+            }
+            seen.push(kind);
+
+            if kind == nid::SUBJECT_ALT_NAME &&
+                self.extensions.contains(&RequestedExtension::SubjectAltName)
+            {
+                let names = ext.subject_alt_names().ok_or_else(|| {
+                    TollaError::InvalidRequest(String::from("bad subjectAltName"))
+                })?;
+                for name in names.iter() {
+                    let dns = name.dnsname().map_or(false, |name| !name.is_empty());
+                    if !dns && name.ipaddress().is_none() {
+                        return Err(TollaError::Forbidden(
+                            String::from("only DNS names and IP addresses can be requested"),
+                        ));
+                    }
+                }
+                granted.alt_names =
+                    Some(X509Extension::new_from_der(ext.object(), ext.critical(), ext.data())?);
+            } else if kind == nid::EXT_KEY_USAGE &&
+                       self.extensions.contains(&RequestedExtension::ExtendedKeyUsage)
+            {
+                let usages = ext.extended_key_usage().ok_or_else(|| {
+                    TollaError::InvalidRequest(String::from("bad extendedKeyUsage"))
+                })?;
+                for usage in usages.iter() {
+                    match usage.nid() {
+                        nid::CLIENT_AUTH => {}
+                        nid::SERVER_AUTH => granted.server_auth = true,
+                        _ => {
+                            return Err(TollaError::Forbidden(
+                                format!("extended key usage {} is not allowed", usage),
+                            ))
+                        }
+                    }
+                }
+            } else {
+                return Err(TollaError::Forbidden(
+                    format!("extension {} is not allowed", ext.object()),
+                ));
+            }
+        }
+
+        Ok(granted)
+    }
+
+    // Seconds a certificate for intent is valid
+    pub fn validity(&self, intent: &str) -> u32 {
+        self.validity.get(intent).cloned().unwrap_or(self.default_validity)
+    }
+
+    pub fn legal_basis(&self, intent: &str) -> LegalBasis {
+        self.legal_basis.get(intent).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::TollaError;
+    use extension::LegalBasis;
+    use policy::IssuancePolicy;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509Req, X509ReqBuilder, X509NameBuilder};
+
+    fn request(fields: &[(&str, &str)]) -> X509Req {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut req = X509ReqBuilder::new().unwrap();
+        req.set_pubkey(&key).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        for &(field, value) in fields {
+            name.append_entry_by_text(field, value).unwrap();
+        }
+        req.set_subject_name(&name.build()).unwrap();
+        req.sign(&key, MessageDigest::sha256()).unwrap();
+        req.build()
+    }
+
+    fn intents(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    const SUBJECT: [(&'static str, &'static str); 4] =
+        [("C", "NO"), ("ST", "TR"), ("O", "IFI"), ("CN", "process")];
+
+    fn is_forbidden<T>(res: Result<T, TollaError>) -> bool {
+        match res {
+            Err(TollaError::Forbidden(_)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_subject_is_checked() {
+        let policy = IssuancePolicy::default();
+
+        let fields = policy.check(&request(&SUBJECT), &intents(&["research"])).unwrap();
+        assert_eq!(fields[3].1, "process");
+        assert!(policy.check(&request(&SUBJECT), &intents(&["research", "statistics"])).is_ok());
+
+        assert!(policy.check(&request(&SUBJECT[1..]), &intents(&["research"])).is_err());
+
+        let mut extra = SUBJECT.to_vec();
+        extra.push(("OU", "admin"));
+        assert!(policy.check(&request(&extra), &intents(&["research"])).is_err());
+
+        let mut twice = SUBJECT.to_vec();
+        twice.push(("CN", "other"));
+        assert!(policy.check(&request(&twice), &intents(&["research"])).is_err());
+    }
+
+    #[test]
+    fn test_organisations_and_intents() {
+        let policy = IssuancePolicy {
+            organizations: vec![String::from("IFI")],
+            intents: vec![String::from("research")],
+            ..IssuancePolicy::default()
+        };
+        let req = request(&SUBJECT);
+
+        assert!(policy.check(&req, &intents(&["research"])).is_ok());
+        assert!(is_forbidden(policy.check(&req, &intents(&["marketing"]))));
+        assert!(policy.check(&req, &intents(&[""])).is_err());
+
+        // Every intent of the request has to be known
+        assert!(is_forbidden(policy.check(&req, &intents(&["research", "marketing"]))));
+        assert!(policy.check(&req, &intents(&["research", "research"])).is_err());
+        assert!(policy.check(&req, &[]).is_err());
+
+        let other = request(&[("C", "NO"), ("ST", "TR"), ("O", "UIT"), ("CN", "process")]);
+        assert!(is_forbidden(policy.check(&other, &intents(&["research"]))));
+        let research = intents(&["research"]);
+        assert!(is_forbidden(policy.check_subject(other.subject_name(), &research)));
+    }
+
+    #[test]
+    fn test_validity_and_legal_basis_per_intent() {
+        let mut policy = IssuancePolicy::default();
+        policy.validity.insert(String::from("research"), 3600);
+
+        assert_eq!(policy.validity("research"), 3600);
+        assert_eq!(policy.validity("statistics"), 365 * 24 * 60 * 60);
+
+        policy.legal_basis.insert(String::from("research"), LegalBasis::PublicTask);
+        assert_eq!(policy.legal_basis("research"), LegalBasis::PublicTask);
+        assert_eq!(policy.legal_basis("statistics"), LegalBasis::Consent);
+    }
+
+    #[test]
+    fn test_signature_is_verified() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let other = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        // A request for key, signed by someone else
+        let mut req = X509ReqBuilder::new().unwrap();
+        req.set_pubkey(&key).unwrap();
+        req.set_subject_name(request(&SUBJECT).subject_name()).unwrap();
+        req.sign(&other, MessageDigest::sha256()).unwrap();
+
+        let policy = IssuancePolicy::default();
+        assert!(policy.check(&req.build(), &intents(&["research"])).is_err());
+    }
+}
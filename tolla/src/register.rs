@@ -1,12 +1,20 @@
-use futures::{future, Future};
+use futures::{future, Future, Stream};
 use tokio_service::Service;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_openssl::SslAcceptorExt;
+use tokio_proto::BindServer;
 use std::io;
+use std::net::SocketAddr;
 use tolla_proto::proto;
 use std::sync::{Arc, Mutex};
 use consent::ConsentEngine;
+use tls::PeerIdentity;
 
 pub struct ProtoService {
     pub engine: Arc<Mutex<ConsentEngine>>,
+    // None if the client did not present a certificate
+    pub peer: Option<PeerIdentity>,
 }
 
 impl Service for ProtoService {
@@ -17,7 +25,7 @@ impl Service for ProtoService {
 
     fn call(&self, req: Self::Request) -> Self::Future {
         let engine = self.engine.clone();
-        let resp = engine.lock().unwrap().handle_incoming(req);
+        let resp = engine.lock().unwrap().handle_incoming(req, self.peer.as_ref());
         match resp {
             Ok(r) => return Box::new(future::ok(r)),
             Err(err) => {
@@ -28,3 +36,54 @@ impl Service for ProtoService {
         }
     }
 }
+
+// Serve the protobuf control channel over TLS on addr.
+// Blocks for as long as the listener is up.
+pub fn serve(engine: Arc<Mutex<ConsentEngine>>, addr: &SocketAddr) -> Result<(), String> {
+    let acceptor = engine.lock().unwrap().tls_acceptor().map_err(
+        |e| e.to_string(),
+    )?;
+
+    let mut core = Core::new().map_err(|e| e.to_string())?;
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(addr, &handle).map_err(|e| e.to_string())?;
+
+    let done = listener.incoming().for_each(move |(socket, client_addr)| {
+        let engine = engine.clone();
+        let conn_handle = handle.clone();
+
+        let conn = acceptor
+            .accept_async(socket)
+            .map(move |stream| {
+                // Clients with a revoked certificate are treated as
+                // not having presented one
+                let peer = stream.get_ref().ssl().peer_certificate().and_then(|cert| {
+                    match engine.lock().unwrap().verify_peer_certificate(&cert) {
+                        Ok(peer) => Some(peer),
+                        Err(err) => {
+                            warn!("Rejected certificate from {}: {}", client_addr, err);
+                            None
+                        }
+                    }
+                });
+
+                proto::ProtoProto::new().bind_server(
+                    &conn_handle,
+                    stream,
+                    ProtoService {
+                        engine: engine,
+                        peer: peer,
+                    },
+                );
+            })
+            .map_err(move |err| {
+                warn!("TLS handshake with {} failed: {}", client_addr, err)
+            });
+
+        handle.spawn(conn);
+        Ok(())
+    });
+
+    core.run(done).map_err(|e| e.to_string())
+}
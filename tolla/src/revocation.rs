@@ -0,0 +1,41 @@
+use openssl::x509::{self, CrlReason};
+
+// Why a certificate was revoked. Serialized by name,
+// e.g. "KeyCompromise".
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    Unspecified,
+    KeyCompromise,
+    AffiliationChanged,
+    Superseded,
+    // The user was deboarded or never finished onboarding
+    CessationOfOperation,
+    // The process is no longer allowed to use its certificate
+    PrivilegeWithdrawn,
+}
+
+impl Reason {
+    // Reason code written to the CRL
+    pub fn crl_reason(&self) -> CrlReason {
+        match *self {
+            Reason::Unspecified => x509::CRL_REASON_UNSPECIFIED,
+            Reason::KeyCompromise => x509::CRL_REASON_KEY_COMPROMISE,
+            Reason::AffiliationChanged => x509::CRL_REASON_AFFILIATION_CHANGED,
+            Reason::Superseded => x509::CRL_REASON_SUPERSEDED,
+            Reason::CessationOfOperation => x509::CRL_REASON_CESSATION_OF_OPERATION,
+            Reason::PrivilegeWithdrawn => x509::CRL_REASON_PRIVILEGE_WITHDRAWN,
+        }
+    }
+}
+
+// A certificate issued by the authority that must no longer
+// be trusted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Revocation {
+    // hex serial number of the certificate
+    #[serde(rename = "_id")]
+    pub serial_number: String,
+    pub reason: Reason,
+    // unix timestamp
+    pub revoked_at: i64,
+}
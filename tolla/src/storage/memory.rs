@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use audit::{AuditCheckpoint, AuditEntry};
+use consent::{Consent, ConsentEvent, Intent, RegisteredIntent, View};
+use inventory::IssuedCertificate;
+use revocation::Revocation;
+use storage::ConsentStore;
+use error::TollaError;
+
+// Keeps everything in process memory. Intended for tests
+// and for running the engine without a database.
+pub struct MemoryStore {
+    consents: Mutex<HashMap<String, Consent>>,
+    intents: Mutex<HashMap<String, Intent>>,
+    registered_intents: Mutex<HashMap<String, RegisteredIntent>>,
+    views: Mutex<HashMap<String, View>>,
+    events: Mutex<HashMap<String, ConsentEvent>>,
+    // Ordered by seq
+    audit: Mutex<Vec<AuditEntry>>,
+    checkpoint: Mutex<Option<AuditCheckpoint>>,
+    revocations: Mutex<HashMap<String, Revocation>>,
+    crl_number: Mutex<Option<i64>>,
+    certificates: Mutex<HashMap<String, IssuedCertificate>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            consents: Mutex::new(HashMap::new()),
+            intents: Mutex::new(HashMap::new()),
+            registered_intents: Mutex::new(HashMap::new()),
+            views: Mutex::new(HashMap::new()),
+            events: Mutex::new(HashMap::new()),
+            audit: Mutex::new(Vec::new()),
+            checkpoint: Mutex::new(None),
+            revocations: Mutex::new(HashMap::new()),
+            crl_number: Mutex::new(None),
+            certificates: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+// Insert value under id unless the id is already taken
+fn insert_new<T: Clone>(
+    map: &Mutex<HashMap<String, T>>,
+    id: &String,
+    value: &T,
+) -> Result<(), TollaError> {
+    let mut map = map.lock().unwrap();
+    if map.contains_key(id) {
+        return Err(TollaError::AlreadyExists(format!("duplicate key: {}", id)));
+    }
+    map.insert(id.clone(), value.clone());
+    Ok(())
+}
+
+impl ConsentStore for MemoryStore {
+    fn add_consent(&self, consent: &Consent) -> Result<(), TollaError> {
+        insert_new(&self.consents, &consent.id, consent)
+    }
+
+    fn get_consent(&self, id: &String) -> Result<Option<Consent>, TollaError> {
+        Ok(self.consents.lock().unwrap().get(id).cloned())
+    }
+
+    fn consent_by_serial_num(&self, serial_num: &String) -> Result<Option<Consent>, TollaError> {
+        let consents = self.consents.lock().unwrap();
+        Ok(
+            consents
+                .values()
+                .find(|c| &c.serial_number == serial_num)
+                .cloned(),
+        )
+    }
+
+    fn get_consents(&self) -> Result<Vec<Consent>, TollaError> {
+        Ok(self.consents.lock().unwrap().values().cloned().collect())
+    }
+
+    fn update_consent(&self, consent: &Consent) -> Result<(), TollaError> {
+        let mut consents = self.consents.lock().unwrap();
+        match consents.get_mut(&consent.id) {
+            Some(stored) => *stored = consent.clone(),
+            None => {
+                return Err(TollaError::NotFound(
+                    format!("no consent for {}", consent.id),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_consent(&self, id: &String) -> Result<(), TollaError> {
+        self.consents.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn append_consent_event(&self, event: &ConsentEvent) -> Result<(), TollaError> {
+        insert_new(&self.events, &event.id, event)
+    }
+
+    fn consent_events(&self, user: &String) -> Result<Vec<ConsentEvent>, TollaError> {
+        let events = self.events.lock().unwrap();
+        let mut history = events
+            .values()
+            .filter(|e| &e.user == user)
+            .cloned()
+            .collect::<Vec<ConsentEvent>>();
+        history.sort_by_key(|e| e.version);
+        Ok(history)
+    }
+
+    fn append_audit_entry(&self, entry: &AuditEntry) -> Result<(), TollaError> {
+        let mut audit = self.audit.lock().unwrap();
+        if audit.last().map_or(false, |last| last.seq >= entry.seq) {
+            return Err(TollaError::AlreadyExists(
+                format!("duplicate key: {}", entry.seq),
+            ));
+        }
+        audit.push(entry.clone());
+        Ok(())
+    }
+
+    fn audit_entries(&self, after: i64, limit: i64) -> Result<Vec<AuditEntry>, TollaError> {
+        let audit = self.audit.lock().unwrap();
+        Ok(
+            audit
+                .iter()
+                .filter(|e| e.seq > after)
+                .take(limit as usize)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn last_audit_entry(&self) -> Result<Option<AuditEntry>, TollaError> {
+        Ok(self.audit.lock().unwrap().last().cloned())
+    }
+
+    fn set_audit_checkpoint(&self, checkpoint: &AuditCheckpoint) -> Result<(), TollaError> {
+        *self.checkpoint.lock().unwrap() = Some(checkpoint.clone());
+        Ok(())
+    }
+
+    fn audit_checkpoint(&self) -> Result<Option<AuditCheckpoint>, TollaError> {
+        Ok(self.checkpoint.lock().unwrap().clone())
+    }
+
+    fn add_revocation(&self, revocation: &Revocation) -> Result<(), TollaError> {
+        insert_new(&self.revocations, &revocation.serial_number, revocation)
+    }
+
+    fn get_revocation(&self, serial_number: &String) -> Result<Option<Revocation>, TollaError> {
+        Ok(self.revocations.lock().unwrap().get(serial_number).cloned())
+    }
+
+    fn get_revocations(&self) -> Result<Vec<Revocation>, TollaError> {
+        Ok(self.revocations.lock().unwrap().values().cloned().collect())
+    }
+
+    fn next_crl_number(&self, first: i64) -> Result<i64, TollaError> {
+        let mut number = self.crl_number.lock().unwrap();
+        let next = number.map(|n| n + 1).unwrap_or(first);
+        *number = Some(next);
+        Ok(next)
+    }
+
+    fn add_certificate(&self, cert: &IssuedCertificate) -> Result<(), TollaError> {
+        insert_new(&self.certificates, &cert.serial_number, cert)
+    }
+
+    fn get_certificate(
+        &self,
+        serial_number: &String,
+    ) -> Result<Option<IssuedCertificate>, TollaError> {
+        Ok(self.certificates.lock().unwrap().get(serial_number).cloned())
+    }
+
+    fn get_certificates(&self) -> Result<Vec<IssuedCertificate>, TollaError> {
+        Ok(self.certificates.lock().unwrap().values().cloned().collect())
+    }
+
+    fn update_certificate(&self, cert: &IssuedCertificate) -> Result<(), TollaError> {
+        let mut certificates = self.certificates.lock().unwrap();
+        match certificates.get_mut(&cert.serial_number) {
+            Some(stored) => *stored = cert.clone(),
+            None => {
+                return Err(TollaError::NotFound(
+                    format!("no certificate {}", cert.serial_number),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn add_intent(&self, intent: &Intent) -> Result<(), TollaError> {
+        insert_new(&self.intents, &intent.id, intent)
+    }
+
+    fn get_intent(&self, id: &String) -> Result<Option<Intent>, TollaError> {
+        Ok(self.intents.lock().unwrap().get(id).cloned())
+    }
+
+    fn add_registered_intent(&self, intent: &RegisteredIntent) -> Result<(), TollaError> {
+        insert_new(&self.registered_intents, &intent.name, intent)
+    }
+
+    fn get_registered_intent(
+        &self,
+        name: &String,
+    ) -> Result<Option<RegisteredIntent>, TollaError> {
+        Ok(self.registered_intents.lock().unwrap().get(name).cloned())
+    }
+
+    fn get_registered_intents(&self) -> Result<Vec<RegisteredIntent>, TollaError> {
+        Ok(self.registered_intents.lock().unwrap().values().cloned().collect())
+    }
+
+    fn add_view(&self, view: &View) -> Result<(), TollaError> {
+        insert_new(&self.views, &view.id, view)
+    }
+
+    fn remove_view(&self, id: &String) -> Result<(), TollaError> {
+        self.views.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn get_views(&self) -> Result<Vec<View>, TollaError> {
+        Ok(self.views.lock().unwrap().values().cloned().collect())
+    }
+}
@@ -0,0 +1,331 @@
+use openssl::bn::BigNum;
+use audit::{AuditCheckpoint, AuditEntry};
+use error::TollaError;
+use consent::{Consent, ConsentEvent, Intent, RegisteredIntent, View};
+use inventory::{serial_to_hex, IssuedCertificate};
+use revocation::Revocation;
+
+mod memory;
+mod mongo;
+mod sqlite;
+
+pub use self::memory::MemoryStore;
+pub use self::mongo::MongoStore;
+pub use self::sqlite::SqliteStore;
+
+// Persistence used by the consent engine. Lookups return
+// Ok(None) when nothing is stored under the given key.
+pub trait ConsentStore: Send {
+    fn add_consent(&self, consent: &Consent) -> Result<(), TollaError>;
+    fn get_consent(&self, id: &String) -> Result<Option<Consent>, TollaError>;
+    fn consent_by_serial_num(&self, serial_num: &String) -> Result<Option<Consent>, TollaError>;
+    fn get_consents(&self) -> Result<Vec<Consent>, TollaError>;
+    // Replace the consent stored under consent.id
+    fn update_consent(&self, consent: &Consent) -> Result<(), TollaError>;
+    fn remove_consent(&self, id: &String) -> Result<(), TollaError>;
+
+    // Consent history is append-only. Appending fails if an event
+    // with the same user and version is already stored.
+    fn append_consent_event(&self, event: &ConsentEvent) -> Result<(), TollaError>;
+    // All events for user, ordered by version
+    fn consent_events(&self, user: &String) -> Result<Vec<ConsentEvent>, TollaError>;
+
+    // The audit log is append-only. Appending fails if an entry
+    // with the same seq is already stored.
+    fn append_audit_entry(&self, entry: &AuditEntry) -> Result<(), TollaError>;
+    // Up to limit entries with seq greater than after, ordered by seq
+    fn audit_entries(&self, after: i64, limit: i64) -> Result<Vec<AuditEntry>, TollaError>;
+    fn last_audit_entry(&self) -> Result<Option<AuditEntry>, TollaError>;
+    // The checkpoint replaces the one stored before
+    fn set_audit_checkpoint(&self, checkpoint: &AuditCheckpoint) -> Result<(), TollaError>;
+    fn audit_checkpoint(&self) -> Result<Option<AuditCheckpoint>, TollaError>;
+
+    // Fails if the serial number is already revoked
+    fn add_revocation(&self, revocation: &Revocation) -> Result<(), TollaError>;
+    fn get_revocation(&self, serial_number: &String) -> Result<Option<Revocation>, TollaError>;
+    fn get_revocations(&self) -> Result<Vec<Revocation>, TollaError>;
+    // Number for the next CRL. first the first time, one more
+    // than the number returned before after that.
+    fn next_crl_number(&self, first: i64) -> Result<i64, TollaError>;
+
+    // Every certificate the authority issued, by serial number
+    fn add_certificate(&self, cert: &IssuedCertificate) -> Result<(), TollaError>;
+    fn get_certificate(
+        &self,
+        serial_number: &String,
+    ) -> Result<Option<IssuedCertificate>, TollaError>;
+    fn get_certificates(&self) -> Result<Vec<IssuedCertificate>, TollaError>;
+    // Replace the certificate stored under cert.serial_number
+    fn update_certificate(&self, cert: &IssuedCertificate) -> Result<(), TollaError>;
+
+    fn add_intent(&self, intent: &Intent) -> Result<(), TollaError>;
+    fn get_intent(&self, id: &String) -> Result<Option<Intent>, TollaError>;
+
+    // The intent registry, keyed by name. Adding fails if the
+    // name is already registered.
+    fn add_registered_intent(&self, intent: &RegisteredIntent) -> Result<(), TollaError>;
+    fn get_registered_intent(
+        &self,
+        name: &String,
+    ) -> Result<Option<RegisteredIntent>, TollaError>;
+    fn get_registered_intents(&self) -> Result<Vec<RegisteredIntent>, TollaError>;
+
+    fn add_view(&self, view: &View) -> Result<(), TollaError>;
+    fn remove_view(&self, id: &String) -> Result<(), TollaError>;
+    fn get_views(&self) -> Result<Vec<View>, TollaError>;
+}
+
+// Before serial numbers were random 128-bit hex, they were u32s,
+// and consents kept them as i32s. Gives the current form of
+// such a serial number.
+fn legacy_serial(number: i64) -> Result<String, TollaError> {
+    serial_to_hex(&BigNum::from_u32(number as u32)?)
+}
+
+#[cfg(test)]
+mod test {
+    use audit::{AuditCheckpoint, AuditEntry};
+    use consent::{Consent, ConsentChange, ConsentEvent, Intent, RegisteredIntent, View};
+    use inventory::{CertificateStatus, IssuedCertificate};
+    use revocation::{Reason, Revocation};
+    use rusqlite::Connection;
+    use serde_json::{self, Value};
+    use std::env;
+    use std::fs;
+    use storage::{ConsentStore, MemoryStore, SqliteStore};
+    use uuid::Uuid;
+
+    fn consent(id: &str, serial_number: &str) -> Consent {
+        Consent {
+            id: String::from(id),
+            serial_number: String::from(serial_number),
+            purpose: vec![String::from("research")],
+            lifetime: 0,
+            marketing: false,
+            profiling: false,
+            public: false,
+            granted_at: 0,
+            expired: false,
+        }
+    }
+
+    fn event(user: &str, version: i64) -> ConsentEvent {
+        ConsentEvent {
+            id: format!("{}:{}", user, version),
+            user: String::from(user),
+            version: version,
+            timestamp: version,
+            change: ConsentChange::Grant(vec![String::from("research")]),
+        }
+    }
+
+    fn exercise(store: &ConsentStore) {
+        store.add_consent(&consent("alice", "7")).unwrap();
+        assert!(store.add_consent(&consent("alice", "8")).is_err());
+
+        let found = store.get_consent(&String::from("alice")).unwrap().unwrap();
+        assert_eq!(found.purpose, vec![String::from("research")]);
+
+        let found = store.consent_by_serial_num(&String::from("7")).unwrap().unwrap();
+        assert_eq!(found.id, "alice");
+        assert!(store.consent_by_serial_num(&String::from("8")).unwrap().is_none());
+
+        let mut updated = consent("alice", "7");
+        updated.expired = true;
+        store.update_consent(&updated).unwrap();
+        assert!(store.get_consents().unwrap()[0].expired);
+        assert!(store.update_consent(&consent("bob", "9")).is_err());
+
+        store.remove_consent(&String::from("alice")).unwrap();
+        assert!(store.get_consent(&String::from("alice")).unwrap().is_none());
+
+        store.append_consent_event(&event("alice", 2)).unwrap();
+        store.append_consent_event(&event("alice", 1)).unwrap();
+        store.append_consent_event(&event("bob", 1)).unwrap();
+        assert!(store.append_consent_event(&event("alice", 1)).is_err());
+
+        let history = store.consent_events(&String::from("alice")).unwrap();
+        assert_eq!(history, vec![event("alice", 1), event("alice", 2)]);
+
+        assert!(store.last_audit_entry().unwrap().is_none());
+        let mut entries: Vec<AuditEntry> = Vec::new();
+        for _ in 0..3 {
+            let entry = AuditEntry::new(
+                entries.last(),
+                0,
+                String::from("7"),
+                String::from("alice"),
+                String::from("research"),
+                true,
+                String::new(),
+            ).unwrap();
+            store.append_audit_entry(&entry).unwrap();
+            entries.push(entry);
+        }
+        assert!(store.append_audit_entry(&entries[1]).is_err());
+        assert_eq!(store.last_audit_entry().unwrap().as_ref(), entries.last());
+        assert_eq!(store.audit_entries(1, 1).unwrap(), vec![entries[1].clone()]);
+        assert_eq!(store.audit_entries(0, 10).unwrap(), entries);
+
+        assert!(store.audit_checkpoint().unwrap().is_none());
+        for entry in &entries {
+            let checkpoint = AuditCheckpoint {
+                seq: entry.seq,
+                hash: entry.hash.clone(),
+                signature: String::from("00"),
+            };
+            store.set_audit_checkpoint(&checkpoint).unwrap();
+            assert_eq!(store.audit_checkpoint().unwrap(), Some(checkpoint));
+        }
+
+        let revocation = Revocation {
+            serial_number: String::from("7"),
+            reason: Reason::KeyCompromise,
+            revoked_at: 0,
+        };
+        store.add_revocation(&revocation).unwrap();
+        assert!(store.add_revocation(&revocation).is_err());
+        assert_eq!(
+            store.get_revocation(&String::from("7")).unwrap(),
+            Some(revocation.clone())
+        );
+        assert!(store.get_revocation(&String::from("8")).unwrap().is_none());
+        assert_eq!(store.get_revocations().unwrap(), vec![revocation]);
+
+        assert_eq!(store.next_crl_number(100).unwrap(), 100);
+        assert_eq!(store.next_crl_number(100).unwrap(), 101);
+        assert_eq!(store.next_crl_number(0).unwrap(), 102);
+
+        let mut cert = IssuedCertificate {
+            serial_number: String::from("7"),
+            subject: String::from("C=NO, ST=TR, O=IFI, CN=process"),
+            intents: vec![String::from("research")],
+            subject_key_id: String::new(),
+            not_before: 0,
+            not_after: 100,
+            requester: String::from("user:alice"),
+            status: CertificateStatus::Active,
+        };
+        store.add_certificate(&cert).unwrap();
+        assert!(store.add_certificate(&cert).is_err());
+        cert.status = CertificateStatus::Revoked;
+        store.update_certificate(&cert).unwrap();
+        assert_eq!(
+            store.get_certificate(&String::from("7")).unwrap(),
+            Some(cert.clone())
+        );
+        assert!(store.get_certificate(&String::from("8")).unwrap().is_none());
+        assert_eq!(store.get_certificates().unwrap(), vec![cert]);
+
+        store
+            .add_intent(&Intent {
+                id: String::from("process"),
+                intent: vec![String::from("research")],
+            })
+            .unwrap();
+        let intent = store.get_intent(&String::from("process")).unwrap().unwrap();
+        assert_eq!(intent.intent, vec![String::from("research")]);
+        assert!(store.get_intent(&String::from("other")).unwrap().is_none());
+
+        let registered = RegisteredIntent {
+            name: String::from("research"),
+            description: String::from("Medical research"),
+        };
+        store.add_registered_intent(&registered).unwrap();
+        assert!(store.add_registered_intent(&registered).is_err());
+        assert_eq!(
+            store.get_registered_intent(&String::from("research")).unwrap(),
+            Some(registered.clone())
+        );
+        assert!(store.get_registered_intent(&String::from("other")).unwrap().is_none());
+        assert_eq!(store.get_registered_intents().unwrap(), vec![registered]);
+
+        store
+            .add_view(&View {
+                id: String::from("alice"),
+                ip: String::from("10.0.0.2"),
+            })
+            .unwrap();
+        assert_eq!(store.get_views().unwrap().len(), 1);
+        store.remove_view(&String::from("alice")).unwrap();
+        assert!(store.get_views().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        exercise(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        exercise(&SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_serial_numbers_are_migrated() {
+        let path = env::temp_dir().join(format!("tolla-{}.db", Uuid::new_v4().simple()));
+
+        // Written by a version with u32 serial numbers, and one
+        // keeping hex without leading zeros
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE consents (
+                     id            TEXT PRIMARY KEY,
+                     serial_number INTEGER NOT NULL,
+                     body          TEXT NOT NULL
+                 );
+                 CREATE TABLE certificates (
+                     id   TEXT PRIMARY KEY,
+                     body TEXT NOT NULL
+                 );",
+            ).unwrap();
+
+            let mut alice = serde_json::to_value(&consent("alice", "")).unwrap();
+            alice["serial_number"] = Value::from(-2);
+            conn.execute(
+                "INSERT INTO consents (id, serial_number, body) VALUES (?1, ?2, ?3)",
+                &[&String::from("alice"), &-2, &alice.to_string()],
+            ).unwrap();
+
+            let cert = IssuedCertificate {
+                serial_number: String::from("1234"),
+                subject: String::from("C=NO, ST=TR, O=IFI, CN=process"),
+                intents: vec![String::from("research")],
+                subject_key_id: String::new(),
+                not_before: 0,
+                not_after: 100,
+                requester: String::from("user:alice"),
+                status: CertificateStatus::Active,
+            };
+            conn.execute(
+                "INSERT INTO certificates (id, body) VALUES (?1, ?2)",
+                &[&cert.serial_number, &serde_json::to_string(&cert).unwrap()],
+            ).unwrap();
+        }
+
+        for _ in 0..2 {
+            let store = SqliteStore::open(&path).unwrap();
+
+            // u32 0xfffffffe was stored as -2
+            let alice = String::from("000000000000000000000000fffffffe");
+            let found = store.consent_by_serial_num(&alice).unwrap().unwrap();
+            assert_eq!(found.id, "alice");
+            assert_eq!(found.serial_number, alice);
+
+            let padded = String::from("00000000000000000000000000001234");
+            let cert = store.get_certificate(&padded).unwrap().unwrap();
+            assert_eq!(cert.serial_number, padded);
+            assert!(store.get_certificate(&String::from("1234")).unwrap().is_none());
+
+            // All-digit hex stays as it is
+            if store.get_consent(&String::from("bob")).unwrap().is_none() {
+                store.add_consent(&consent("bob", &padded)).unwrap();
+            }
+            let found = store.consent_by_serial_num(&padded).unwrap().unwrap();
+            assert_eq!(found.id, "bob");
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
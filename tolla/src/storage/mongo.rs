@@ -0,0 +1,401 @@
+use bson;
+use bson::Document;
+use mongodb::{Client, ThreadedClient};
+use mongodb::coll::options::{FindOneAndUpdateOptions, FindOptions, ReplaceOptions,
+                             ReturnDocument, UpdateOptions};
+use mongodb::db::ThreadedDatabase;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use audit::{AuditCheckpoint, AuditEntry};
+use consent::{Consent, ConsentEvent, Intent, RegisteredIntent, View};
+use inventory::{parse_serial, IssuedCertificate};
+use revocation::Revocation;
+use storage::{legacy_serial, ConsentStore};
+use error::TollaError;
+
+const DATABASE: &'static str = "test";
+const CHECKPOINT_ID: &'static str = "head";
+const CRL_NUMBER_ID: &'static str = "crl";
+const SCHEMA_ID: &'static str = "version";
+
+// Kept in the schema collection. Databases written before it
+// was kept are at 0.
+const SCHEMA_VERSION: i32 = 1;
+
+// A counter kept in the counters collection
+#[derive(Deserialize)]
+struct Counter {
+    number: i64,
+}
+
+#[derive(Deserialize)]
+struct Schema {
+    version: i32,
+}
+
+// Stores consents, intents and views in MongoDB
+pub struct MongoStore {
+    client: Client,
+}
+
+impl MongoStore {
+    pub fn connect(address: &str, port: u16) -> Result<MongoStore, TollaError> {
+        let client = Client::connect(address, port)?;
+        let store = MongoStore { client: client };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    // Bring a database written by an older version up to date
+    fn migrate(&self) -> Result<(), TollaError> {
+        let version = self.find_one::<Schema>("schema", doc! { "_id" => SCHEMA_ID })?
+            .map(|schema| schema.version)
+            .unwrap_or(0);
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        self.migrate_serial_numbers()?;
+
+        let mut options = ReplaceOptions::new();
+        options.upsert = Some(true);
+        let coll = self.client.db(DATABASE).collection("schema");
+        coll.replace_one(
+            doc! { "_id" => SCHEMA_ID },
+            doc! { "_id" => SCHEMA_ID, "version" => SCHEMA_VERSION },
+            Some(options),
+        )?;
+        info!("Migrated database to version {}", SCHEMA_VERSION);
+        Ok(())
+    }
+
+    // Consents held serial numbers as i32s, and hex serial
+    // numbers were kept without leading zeros. Running it again
+    // after it was interrupted picks up where it stopped.
+    fn migrate_serial_numbers(&self) -> Result<(), TollaError> {
+        let db = self.client.db(DATABASE);
+
+        let consents = db.collection("consents");
+        let documents = consents.find(None, None)?.collect::<Result<Vec<Document>, _>>()?;
+        for consent in documents {
+            let serial_number = match consent.get("serial_number") {
+                Some(&bson::Bson::I32(number)) => legacy_serial(number as i64)?,
+                Some(&bson::Bson::I64(number)) => legacy_serial(number)?,
+                Some(&bson::Bson::String(ref hex)) => parse_serial(hex)?,
+                _ => {
+                    return Err(TollaError::Storage(
+                        format!("consent {:?} has no serial number", consent.get("_id")),
+                    ))
+                }
+            };
+            let id = match consent.get("_id") {
+                Some(id) => id.clone(),
+                None => continue,
+            };
+
+            consents.update_one(
+                doc! { "_id" => id },
+                doc! { "$set" => { "serial_number" => serial_number } },
+                None,
+            )?;
+        }
+
+        // Both are keyed by serial number, and an _id can't be
+        // changed in place
+        for name in &["revocations", "certificates"] {
+            let coll = db.collection(name);
+            let documents = coll.find(None, None)?.collect::<Result<Vec<Document>, _>>()?;
+            for mut document in documents {
+                let id = match document.get("_id") {
+                    Some(&bson::Bson::String(ref id)) => id.clone(),
+                    _ => continue,
+                };
+                let serial_number = parse_serial(&id)?;
+                if serial_number == id {
+                    continue;
+                }
+
+                document.insert("_id", serial_number.clone());
+                let mut options = ReplaceOptions::new();
+                options.upsert = Some(true);
+                coll.replace_one(doc! { "_id" => serial_number }, document, Some(options))?;
+                coll.delete_one(doc! { "_id" => id }, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert<T: Serialize>(&self, collection: &str, item: &T) -> Result<(), TollaError> {
+        let serialized = bson::to_bson(item)?;
+
+        let coll = self.client.db(DATABASE).collection(collection);
+
+        if let bson::Bson::Document(document) = serialized {
+            if let Err(e) = coll.insert_one(document, None) {
+                error!("Unable to insert into {}: {}", collection, e.to_string());
+                return Err(TollaError::from(e));
+            }
+        }
+        Ok(())
+    }
+
+    fn find_one<T: DeserializeOwned>(
+        &self,
+        collection: &str,
+        filter: Document,
+    ) -> Result<Option<T>, TollaError> {
+        let coll = self.client.db(DATABASE).collection(collection);
+
+        let document = match coll.find_one(Some(filter), None) {
+            Ok(Some(d)) => d,
+            Ok(None) => return Ok(None),
+            Err(err) => return Err(TollaError::from(err)),
+        };
+
+        bson::from_bson(bson::Bson::Document(document))
+            .map(Some)
+            .map_err(TollaError::from)
+    }
+
+    fn find_all<T: DeserializeOwned>(
+        &self,
+        collection: &str,
+        filter: Option<Document>,
+    ) -> Result<Vec<T>, TollaError> {
+        self.find_with(collection, filter, None)
+    }
+
+    fn find_with<T: DeserializeOwned>(
+        &self,
+        collection: &str,
+        filter: Option<Document>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<T>, TollaError> {
+        let coll = self.client.db(DATABASE).collection(collection);
+
+        let cursor = coll.find(filter, options)?;
+
+        let mut items = Vec::new();
+        for entry in cursor {
+            let item = entry?;
+            let item = bson::from_bson(bson::Bson::Document(item))?;
+            items.push(item);
+        }
+        Ok(items)
+    }
+
+    fn replace<T: Serialize>(
+        &self,
+        collection: &str,
+        id: &String,
+        item: &T,
+    ) -> Result<(), TollaError> {
+        let serialized = bson::to_bson(item)?;
+
+        let coll = self.client.db(DATABASE).collection(collection);
+
+        let document = match serialized {
+            bson::Bson::Document(document) => document,
+            _ => return Err(TollaError::Storage(format!("{} is not a document", id))),
+        };
+
+        let result = coll.replace_one(doc! { "_id" => id }, document, None)?;
+
+        if result.matched_count == 0 {
+            return Err(TollaError::NotFound(
+                format!("no {} entry for {}", collection, id),
+            ));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, collection: &str, id: &String) -> Result<(), TollaError> {
+        let coll = self.client.db(DATABASE).collection(collection);
+
+        coll.delete_one(doc! { "_id" => id }, None)
+            .map(|_| ())
+            .map_err(TollaError::from)
+    }
+}
+
+impl ConsentStore for MongoStore {
+    fn add_consent(&self, consent: &Consent) -> Result<(), TollaError> {
+        self.insert("consents", consent)
+    }
+
+    fn get_consent(&self, id: &String) -> Result<Option<Consent>, TollaError> {
+        self.find_one("consents", doc! { "_id" => id })
+    }
+
+    fn consent_by_serial_num(&self, serial_num: &String) -> Result<Option<Consent>, TollaError> {
+        self.find_one("consents", doc! { "serial_number" => serial_num })
+    }
+
+    fn get_consents(&self) -> Result<Vec<Consent>, TollaError> {
+        self.find_all("consents", None)
+    }
+
+    fn update_consent(&self, consent: &Consent) -> Result<(), TollaError> {
+        self.replace("consents", &consent.id, consent)
+    }
+
+    fn remove_consent(&self, id: &String) -> Result<(), TollaError> {
+        self.delete("consents", id)
+    }
+
+    // Events are keyed by <user>:<version>, so the unique
+    // _id index rejects a second event with the same version
+    fn append_consent_event(&self, event: &ConsentEvent) -> Result<(), TollaError> {
+        self.insert("consent_events", event)
+    }
+
+    fn consent_events(&self, user: &String) -> Result<Vec<ConsentEvent>, TollaError> {
+        let mut events: Vec<ConsentEvent> = self.find_all(
+            "consent_events",
+            Some(doc! { "user" => user }),
+        )?;
+        events.sort_by_key(|e| e.version);
+        Ok(events)
+    }
+
+    // Entries are keyed by seq, so the unique _id index
+    // rejects a second entry at the same position
+    fn append_audit_entry(&self, entry: &AuditEntry) -> Result<(), TollaError> {
+        self.insert("audit", entry)
+    }
+
+    fn audit_entries(&self, after: i64, limit: i64) -> Result<Vec<AuditEntry>, TollaError> {
+        let mut options = FindOptions::new();
+        options.sort = Some(doc! { "_id" => 1 });
+        options.limit = Some(limit);
+
+        self.find_with(
+            "audit",
+            Some(doc! { "_id" => { "$gt" => after } }),
+            Some(options),
+        )
+    }
+
+    fn last_audit_entry(&self) -> Result<Option<AuditEntry>, TollaError> {
+        let mut options = FindOptions::new();
+        options.sort = Some(doc! { "_id" => (-1) });
+        options.limit = Some(1);
+
+        let mut entries: Vec<AuditEntry> = self.find_with("audit", None, Some(options))?;
+        Ok(entries.pop())
+    }
+
+    // The only document in its collection, under a fixed _id
+    fn set_audit_checkpoint(&self, checkpoint: &AuditCheckpoint) -> Result<(), TollaError> {
+        let mut document = match bson::to_bson(checkpoint)? {
+            bson::Bson::Document(document) => document,
+            _ => return Err(TollaError::Storage(String::from("checkpoint is not a document"))),
+        };
+        document.insert("_id", CHECKPOINT_ID);
+
+        let mut options = ReplaceOptions::new();
+        options.upsert = Some(true);
+        let coll = self.client.db(DATABASE).collection("audit_checkpoint");
+        coll.replace_one(doc! { "_id" => CHECKPOINT_ID }, document, Some(options))
+            .map(|_| ())
+            .map_err(TollaError::from)
+    }
+
+    fn audit_checkpoint(&self) -> Result<Option<AuditCheckpoint>, TollaError> {
+        self.find_one("audit_checkpoint", doc! { "_id" => CHECKPOINT_ID })
+    }
+
+    // Revocations are keyed by serial number
+    fn add_revocation(&self, revocation: &Revocation) -> Result<(), TollaError> {
+        self.insert("revocations", revocation)
+    }
+
+    fn get_revocation(&self, serial_number: &String) -> Result<Option<Revocation>, TollaError> {
+        self.find_one("revocations", doc! { "_id" => serial_number })
+    }
+
+    fn get_revocations(&self) -> Result<Vec<Revocation>, TollaError> {
+        self.find_all("revocations", None)
+    }
+
+    // Seeded with an upsert, since $inc and $setOnInsert can't
+    // both set the number in a single update
+    fn next_crl_number(&self, first: i64) -> Result<i64, TollaError> {
+        let coll = self.client.db(DATABASE).collection("counters");
+        let filter = doc! { "_id" => CRL_NUMBER_ID };
+
+        let mut seed = UpdateOptions::new();
+        seed.upsert = Some(true);
+        coll.update_one(
+            filter.clone(),
+            doc! { "$setOnInsert" => { "number" => (first - 1) } },
+            Some(seed),
+        )?;
+
+        let mut options = FindOneAndUpdateOptions::new();
+        options.return_document = Some(ReturnDocument::After);
+        let document = coll.find_one_and_update(
+            filter,
+            doc! { "$inc" => { "number" => 1i64 } },
+            Some(options),
+        )?
+            .ok_or(TollaError::Storage(String::from("no CRL number")))?;
+
+        let counter: Counter = bson::from_bson(bson::Bson::Document(document))?;
+        Ok(counter.number)
+    }
+
+    fn add_certificate(&self, cert: &IssuedCertificate) -> Result<(), TollaError> {
+        self.insert("certificates", cert)
+    }
+
+    fn get_certificate(
+        &self,
+        serial_number: &String,
+    ) -> Result<Option<IssuedCertificate>, TollaError> {
+        self.find_one("certificates", doc! { "_id" => serial_number })
+    }
+
+    fn get_certificates(&self) -> Result<Vec<IssuedCertificate>, TollaError> {
+        self.find_all("certificates", None)
+    }
+
+    fn update_certificate(&self, cert: &IssuedCertificate) -> Result<(), TollaError> {
+        self.replace("certificates", &cert.serial_number, cert)
+    }
+
+    fn add_intent(&self, intent: &Intent) -> Result<(), TollaError> {
+        self.insert("intents", intent)
+    }
+
+    fn get_intent(&self, id: &String) -> Result<Option<Intent>, TollaError> {
+        self.find_one("intents", doc! { "_id" => id })
+    }
+
+    fn add_registered_intent(&self, intent: &RegisteredIntent) -> Result<(), TollaError> {
+        self.insert("registered_intents", intent)
+    }
+
+    fn get_registered_intent(
+        &self,
+        name: &String,
+    ) -> Result<Option<RegisteredIntent>, TollaError> {
+        self.find_one("registered_intents", doc! { "_id" => name })
+    }
+
+    fn get_registered_intents(&self) -> Result<Vec<RegisteredIntent>, TollaError> {
+        self.find_all("registered_intents", None)
+    }
+
+    fn add_view(&self, view: &View) -> Result<(), TollaError> {
+        self.insert("view", view)
+    }
+
+    fn remove_view(&self, id: &String) -> Result<(), TollaError> {
+        self.delete("view", id)
+    }
+
+    fn get_views(&self) -> Result<Vec<View>, TollaError> {
+        self.find_all("view", None)
+    }
+}
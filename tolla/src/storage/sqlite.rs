@@ -0,0 +1,442 @@
+use rusqlite::{self, Connection};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use std::path::Path;
+use audit::{AuditCheckpoint, AuditEntry};
+use consent::{Consent, ConsentEvent, Intent, RegisteredIntent, View};
+use inventory::{parse_serial, IssuedCertificate};
+use revocation::Revocation;
+use storage::{legacy_serial, ConsentStore};
+use error::TollaError;
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS consents (
+        id            TEXT PRIMARY KEY,
+        serial_number TEXT NOT NULL,
+        body          TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS consent_events (
+        user    TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        body    TEXT NOT NULL,
+        PRIMARY KEY (user, version)
+    );
+    CREATE TABLE IF NOT EXISTS audit (
+        seq  INTEGER PRIMARY KEY,
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS audit_checkpoint (
+        id   INTEGER PRIMARY KEY CHECK (id = 1),
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS revocations (
+        id   TEXT PRIMARY KEY,
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS crl_number (
+        id     INTEGER PRIMARY KEY CHECK (id = 1),
+        number INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS certificates (
+        id   TEXT PRIMARY KEY,
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS intents (
+        id   TEXT PRIMARY KEY,
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS registered_intents (
+        id   TEXT PRIMARY KEY,
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS views (
+        id   TEXT PRIMARY KEY,
+        body TEXT NOT NULL
+    );
+";
+
+// Kept in the database's user_version. Databases written before
+// it was kept are at 0.
+const SCHEMA_VERSION: i32 = 1;
+
+// Stores every record as a JSON document keyed by its id
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteStore, TollaError> {
+        let conn = Connection::open(path)?;
+        SqliteStore::with_connection(conn)
+    }
+
+    pub fn in_memory() -> Result<SqliteStore, TollaError> {
+        let conn = Connection::open_in_memory()?;
+        SqliteStore::with_connection(conn)
+    }
+
+    fn with_connection(conn: Connection) -> Result<SqliteStore, TollaError> {
+        conn.execute_batch(SCHEMA)?;
+        let store = SqliteStore { conn: conn };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    // Bring a database written by an older version up to date
+    fn migrate(&self) -> Result<(), TollaError> {
+        let version = self.conn
+            .query_row("PRAGMA user_version", &[], |row| row.get::<_, i32>(0))?;
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        self.conn.execute_batch("BEGIN")?;
+        match self.migrate_serial_numbers() {
+            Ok(()) => {
+                self.conn.execute_batch(
+                    &format!("PRAGMA user_version = {}; COMMIT", SCHEMA_VERSION),
+                )?;
+                info!("Migrated database to version {}", SCHEMA_VERSION);
+                Ok(())
+            }
+            Err(err) => {
+                self.conn.execute_batch("ROLLBACK")?;
+                Err(err)
+            }
+        }
+    }
+
+    // Consents held serial numbers as i32s in an INTEGER column,
+    // which turns all-digit hex into numbers, so the table is
+    // recreated. Hex serial numbers were kept without leading
+    // zeros.
+    fn migrate_serial_numbers(&self) -> Result<(), TollaError> {
+        self.conn.execute_batch(
+            "ALTER TABLE consents RENAME TO consents_old;
+             CREATE TABLE consents (
+                 id            TEXT PRIMARY KEY,
+                 serial_number TEXT NOT NULL,
+                 body          TEXT NOT NULL
+             );",
+        )?;
+
+        for (id, body) in self.rows("consents_old")? {
+            let mut consent: Value = serde_json::from_str(&body)?;
+            let serial_number = if let Some(number) = consent["serial_number"].as_i64() {
+                legacy_serial(number)?
+            } else if let Some(hex) = consent["serial_number"].as_str() {
+                parse_serial(hex)?
+            } else {
+                return Err(TollaError::Storage(
+                    format!("consent {} has no serial number", id),
+                ));
+            };
+            consent["serial_number"] = Value::String(serial_number.clone());
+
+            self.conn.execute(
+                "INSERT INTO consents (id, serial_number, body) VALUES (?1, ?2, ?3)",
+                &[&id, &serial_number, &consent.to_string()],
+            )?;
+        }
+        self.conn.execute_batch("DROP TABLE consents_old")?;
+
+        // Both are keyed by serial number
+        for table in &["revocations", "certificates"] {
+            for (id, body) in self.rows(table)? {
+                let serial_number = parse_serial(&id)?;
+                let mut item: Value = serde_json::from_str(&body)?;
+                item["_id"] = Value::String(serial_number.clone());
+
+                self.conn.execute(
+                    &format!("UPDATE {} SET id = ?2, body = ?3 WHERE id = ?1", table),
+                    &[&id, &serial_number, &item.to_string()],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Every id and body in table
+    fn rows(&self, table: &str) -> Result<Vec<(String, String)>, TollaError> {
+        let mut stmt = self.conn
+            .prepare(&format!("SELECT id, body FROM {}", table))?;
+        let rows = stmt.query_map(&[], |row| {
+            (row.get::<_, String>(0), row.get::<_, String>(1))
+        })?;
+
+        let mut items = Vec::new();
+        for row in rows {
+            items.push(row?);
+        }
+        Ok(items)
+    }
+
+    fn insert<T: Serialize>(&self, table: &str, id: &String, item: &T) -> Result<(), TollaError> {
+        let body = serde_json::to_string(item)?;
+        self.conn
+            .execute(
+                &format!("INSERT INTO {} (id, body) VALUES (?1, ?2)", table),
+                &[id, &body],
+            )
+            .map(|_| ())
+            .map_err(TollaError::from)
+    }
+
+    fn find_one<T: DeserializeOwned>(
+        &self,
+        table: &str,
+        column: &str,
+        value: &rusqlite::types::ToSql,
+    ) -> Result<Option<T>, TollaError> {
+        let result = self.conn.query_row(
+            &format!("SELECT body FROM {} WHERE {} = ?1", table, column),
+            &[value],
+            |row| row.get::<_, String>(0),
+        );
+
+        match result {
+            Ok(body) => serde_json::from_str(&body).map(Some).map_err(TollaError::from),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(TollaError::from(err)),
+        }
+    }
+
+    fn find_all<T: DeserializeOwned>(&self, table: &str) -> Result<Vec<T>, TollaError> {
+        let mut stmt = self.conn
+            .prepare(&format!("SELECT body FROM {}", table))?;
+        let rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+
+        let mut items = Vec::new();
+        for row in rows {
+            let body = row?;
+            items.push(serde_json::from_str(&body)?);
+        }
+        Ok(items)
+    }
+
+    fn delete(&self, table: &str, id: &String) -> Result<(), TollaError> {
+        self.conn
+            .execute(&format!("DELETE FROM {} WHERE id = ?1", table), &[id])
+            .map(|_| ())
+            .map_err(TollaError::from)
+    }
+}
+
+impl ConsentStore for SqliteStore {
+    fn add_consent(&self, consent: &Consent) -> Result<(), TollaError> {
+        let body = serde_json::to_string(consent)?;
+        self.conn
+            .execute(
+                "INSERT INTO consents (id, serial_number, body) VALUES (?1, ?2, ?3)",
+                &[&consent.id, &consent.serial_number, &body],
+            )
+            .map(|_| ())
+            .map_err(TollaError::from)
+    }
+
+    fn get_consent(&self, id: &String) -> Result<Option<Consent>, TollaError> {
+        self.find_one("consents", "id", id)
+    }
+
+    fn consent_by_serial_num(&self, serial_num: &String) -> Result<Option<Consent>, TollaError> {
+        self.find_one("consents", "serial_number", serial_num)
+    }
+
+    fn get_consents(&self) -> Result<Vec<Consent>, TollaError> {
+        self.find_all("consents")
+    }
+
+    fn update_consent(&self, consent: &Consent) -> Result<(), TollaError> {
+        let body = serde_json::to_string(consent)?;
+        let updated = self.conn
+            .execute(
+                "UPDATE consents SET serial_number = ?2, body = ?3 WHERE id = ?1",
+                &[&consent.id, &consent.serial_number, &body],
+            )?;
+
+        if updated == 0 {
+            return Err(TollaError::NotFound(
+                format!("no consent for {}", consent.id),
+            ));
+        }
+        Ok(())
+    }
+
+    fn remove_consent(&self, id: &String) -> Result<(), TollaError> {
+        self.delete("consents", id)
+    }
+
+    fn append_consent_event(&self, event: &ConsentEvent) -> Result<(), TollaError> {
+        let body = serde_json::to_string(event)?;
+        self.conn
+            .execute(
+                "INSERT INTO consent_events (user, version, body) VALUES (?1, ?2, ?3)",
+                &[&event.user, &event.version, &body],
+            )
+            .map(|_| ())
+            .map_err(TollaError::from)
+    }
+
+    fn consent_events(&self, user: &String) -> Result<Vec<ConsentEvent>, TollaError> {
+        let mut stmt = self.conn
+            .prepare(
+                "SELECT body FROM consent_events WHERE user = ?1 ORDER BY version",
+            )?;
+        let rows = stmt.query_map(&[user], |row| row.get::<_, String>(0))?;
+
+        let mut events = Vec::new();
+        for row in rows {
+            let body = row?;
+            events.push(serde_json::from_str(&body)?);
+        }
+        Ok(events)
+    }
+
+    fn append_audit_entry(&self, entry: &AuditEntry) -> Result<(), TollaError> {
+        let body = serde_json::to_string(entry)?;
+        self.conn
+            .execute(
+                "INSERT INTO audit (seq, body) VALUES (?1, ?2)",
+                &[&entry.seq, &body],
+            )
+            .map(|_| ())
+            .map_err(TollaError::from)
+    }
+
+    fn audit_entries(&self, after: i64, limit: i64) -> Result<Vec<AuditEntry>, TollaError> {
+        let mut stmt = self.conn
+            .prepare("SELECT body FROM audit WHERE seq > ?1 ORDER BY seq LIMIT ?2")?;
+        let rows = stmt.query_map(&[&after, &limit], |row| row.get::<_, String>(0))?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let body = row?;
+            entries.push(serde_json::from_str(&body)?);
+        }
+        Ok(entries)
+    }
+
+    fn last_audit_entry(&self) -> Result<Option<AuditEntry>, TollaError> {
+        let result = self.conn.query_row(
+            "SELECT body FROM audit ORDER BY seq DESC LIMIT 1",
+            &[],
+            |row| row.get::<_, String>(0),
+        );
+
+        match result {
+            Ok(body) => serde_json::from_str(&body).map(Some).map_err(TollaError::from),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(TollaError::from(err)),
+        }
+    }
+
+    fn set_audit_checkpoint(&self, checkpoint: &AuditCheckpoint) -> Result<(), TollaError> {
+        let body = serde_json::to_string(checkpoint)?;
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO audit_checkpoint (id, body) VALUES (1, ?1)",
+                &[&body],
+            )
+            .map(|_| ())
+            .map_err(TollaError::from)
+    }
+
+    fn audit_checkpoint(&self) -> Result<Option<AuditCheckpoint>, TollaError> {
+        self.find_one("audit_checkpoint", "id", &1)
+    }
+
+    fn add_revocation(&self, revocation: &Revocation) -> Result<(), TollaError> {
+        self.insert("revocations", &revocation.serial_number, revocation)
+    }
+
+    fn get_revocation(&self, serial_number: &String) -> Result<Option<Revocation>, TollaError> {
+        self.find_one("revocations", "id", serial_number)
+    }
+
+    fn get_revocations(&self) -> Result<Vec<Revocation>, TollaError> {
+        self.find_all("revocations")
+    }
+
+    fn next_crl_number(&self, first: i64) -> Result<i64, TollaError> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO crl_number (id, number) VALUES (1, ?1)",
+            &[&(first - 1)],
+        )?;
+        self.conn.execute(
+            "UPDATE crl_number SET number = number + 1 WHERE id = 1",
+            &[],
+        )?;
+        self.conn
+            .query_row("SELECT number FROM crl_number WHERE id = 1", &[], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map_err(TollaError::from)
+    }
+
+    fn add_certificate(&self, cert: &IssuedCertificate) -> Result<(), TollaError> {
+        self.insert("certificates", &cert.serial_number, cert)
+    }
+
+    fn get_certificate(
+        &self,
+        serial_number: &String,
+    ) -> Result<Option<IssuedCertificate>, TollaError> {
+        self.find_one("certificates", "id", serial_number)
+    }
+
+    fn get_certificates(&self) -> Result<Vec<IssuedCertificate>, TollaError> {
+        self.find_all("certificates")
+    }
+
+    fn update_certificate(&self, cert: &IssuedCertificate) -> Result<(), TollaError> {
+        let body = serde_json::to_string(cert)?;
+        let updated = self.conn
+            .execute(
+                "UPDATE certificates SET body = ?2 WHERE id = ?1",
+                &[&cert.serial_number, &body],
+            )?;
+
+        if updated == 0 {
+            return Err(TollaError::NotFound(
+                format!("no certificate {}", cert.serial_number),
+            ));
+        }
+        Ok(())
+    }
+
+    fn add_intent(&self, intent: &Intent) -> Result<(), TollaError> {
+        self.insert("intents", &intent.id, intent)
+    }
+
+    fn get_intent(&self, id: &String) -> Result<Option<Intent>, TollaError> {
+        self.find_one("intents", "id", id)
+    }
+
+    fn add_registered_intent(&self, intent: &RegisteredIntent) -> Result<(), TollaError> {
+        self.insert("registered_intents", &intent.name, intent)
+    }
+
+    fn get_registered_intent(
+        &self,
+        name: &String,
+    ) -> Result<Option<RegisteredIntent>, TollaError> {
+        self.find_one("registered_intents", "id", name)
+    }
+
+    fn get_registered_intents(&self) -> Result<Vec<RegisteredIntent>, TollaError> {
+        self.find_all("registered_intents")
+    }
+
+    fn add_view(&self, view: &View) -> Result<(), TollaError> {
+        self.insert("views", &view.id, view)
+    }
+
+    fn remove_view(&self, id: &String) -> Result<(), TollaError> {
+        self.delete("views", id)
+    }
+
+    fn get_views(&self) -> Result<Vec<View>, TollaError> {
+        self.find_all("views")
+    }
+}
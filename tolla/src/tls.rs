@@ -0,0 +1,170 @@
+use openssl::hash::MessageDigest;
+use openssl::nid;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SSL_VERIFY_PEER};
+use openssl::x509::X509Ref;
+use ca::Authority;
+use error::TollaError;
+use inventory::serial_to_hex;
+
+// Name the control channel's certificate is issued to.
+// Clients verify the server against it.
+pub const SERVER_NAME: &'static str = "tolla";
+
+// A client whose certificate was verified against the authority
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerIdentity {
+    pub common_name: String,
+    // hex-encoded sha256 of the DER certificate
+    pub fingerprint: String,
+    // hex serial number, checked against the revocations
+    pub serial_number: String,
+}
+
+impl PeerIdentity {
+    pub fn from_certificate(cert: &X509Ref) -> Result<PeerIdentity, TollaError> {
+        let common_name = cert.subject_name()
+            .entries_by_nid(nid::COMMONNAME)
+            .nth(0)
+            .ok_or(TollaError::Crypto(String::from("no common name")))?
+            .data()
+            .as_utf8()?
+            .to_string();
+
+        let digest = cert.fingerprint(MessageDigest::sha256())?;
+
+        let fingerprint = digest
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<String>>()
+            .concat();
+
+        Ok(PeerIdentity {
+            common_name: common_name,
+            fingerprint: fingerprint,
+            serial_number: serial_to_hex(&cert.serial_number().to_bn()?)?,
+        })
+    }
+}
+
+// Acceptor for the control channel. Client certificates are
+// optional so that new clients can enroll, but any certificate
+// presented must be issued by the authority. Clients only
+// presenting their own certificate are verified through the
+// intermediate kept in the store. The handshake does not see
+// revocations, so the engine checks the peer's serial number on
+// every message.
+pub fn acceptor(authority: &Authority) -> Result<SslAcceptor, TollaError> {
+    let (key, cert) = authority.create_server_certificate(SERVER_NAME)?;
+    let intermediate = authority.certificate()?;
+    let root = authority.root_certificate()?;
+
+    let mut builder = SslAcceptorBuilder::mozilla_intermediate(
+        SslMethod::tls(),
+        &key,
+        &cert,
+        vec![&intermediate],
+    )?;
+
+    {
+        let ctx = builder.builder_mut();
+        // A single tier authority is its own root
+        if root.to_der()? != intermediate.to_der()? {
+            ctx.cert_store_mut().add_cert(intermediate.clone())?;
+        }
+        ctx.cert_store_mut().add_cert(root)?;
+        ctx.set_verify(SSL_VERIFY_PEER);
+    }
+
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod test {
+    use ca::{self, Authority, CaSubject};
+    use keys::KeyPolicy;
+    use policy::IssuancePolicy;
+    use tls::{self, PeerIdentity};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslConnectorBuilder, SslMethod};
+    use openssl::x509::{X509, X509NameBuilder, X509ReqBuilder};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // Have the authority sign a client certificate
+    fn client_identity(authority: &Authority) -> (PKey, X509) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut req = X509ReqBuilder::new().unwrap();
+        req.set_pubkey(&key).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("C", "NO").unwrap();
+        name.append_entry_by_text("ST", "TR").unwrap();
+        name.append_entry_by_text("O", "IFI").unwrap();
+        name.append_entry_by_text("CN", "process").unwrap();
+        req.set_subject_name(&name.build()).unwrap();
+        req.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let pem = req.build().to_pem().unwrap();
+        let (_, cert) = authority
+            .sign_certificate(
+                &pem,
+                vec![String::from("Purpose")],
+                None,
+                &ca::random_serial_number().unwrap(),
+                &IssuancePolicy::default(),
+            )
+            .unwrap();
+
+        (key, X509::from_pem(&cert.request).unwrap())
+    }
+
+    // Connect to a fresh acceptor and return the identity it saw
+    fn handshake(authority: &Authority, client: Option<&(PKey, X509)>) -> Option<PeerIdentity> {
+        let acceptor = tls::acceptor(authority).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let stream = acceptor.accept(socket).unwrap();
+            stream.ssl().peer_certificate().map(|c| {
+                PeerIdentity::from_certificate(&c).unwrap()
+            })
+        });
+
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+        {
+            let ctx = builder.builder_mut();
+            ctx.cert_store_mut()
+                .add_cert(authority.root_certificate().unwrap())
+                .unwrap();
+            if let Some(&(ref key, ref cert)) = client {
+                ctx.set_certificate(cert).unwrap();
+                ctx.set_private_key(key).unwrap();
+            }
+        }
+
+        let socket = TcpStream::connect(addr).unwrap();
+        builder.build().connect(tls::SERVER_NAME, socket).unwrap();
+
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_client_certificate_is_verified() {
+        let authority = Authority::new(KeyPolicy::default(), &CaSubject::default()).unwrap();
+        let client = client_identity(&authority);
+
+        let peer = handshake(&authority, Some(&client)).unwrap();
+        assert_eq!(peer.common_name, "process");
+        assert_eq!(peer.fingerprint.len(), 64);
+    }
+
+    #[test]
+    fn test_client_without_certificate_is_anonymous() {
+        let authority = Authority::new(KeyPolicy::default(), &CaSubject::default()).unwrap();
+        assert_eq!(handshake(&authority, None), None);
+    }
+}
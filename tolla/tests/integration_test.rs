@@ -0,0 +1,908 @@
+extern crate lib_tolla;
+extern crate openssl;
+extern crate tolla_proto;
+extern crate uuid;
+
+use lib_tolla::consent::{self, Consent, ConsentEngine, ConsentEngineBuilder, Decision, Intent,
+                         RegisteredIntent, View};
+use lib_tolla::docker::{ContainerInfo, ContainerRuntime, ContainerSpec, FakeRuntime};
+use lib_tolla::error::TollaError;
+use lib_tolla::extension::{IntentExtension, LegalBasis};
+use lib_tolla::inventory::{serial_to_hex, CertificateQuery, CertificateStatus};
+use lib_tolla::revocation::Reason;
+use lib_tolla::storage::MemoryStore;
+use lib_tolla::tls::PeerIdentity;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::bn::BigNum;
+use openssl::ocsp::{self, Flag, OcspCertId, OcspRequest, OcspResponse};
+use openssl::stack::Stack;
+use openssl::x509::{X509, X509Crl, X509NameBuilder, X509ReqBuilder};
+use openssl::x509::store::X509StoreBuilder;
+use tolla_proto::proto;
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use uuid::Uuid;
+
+const ADMIN_TOKEN: &'static str = "admin-token";
+
+fn engine() -> ConsentEngine {
+    engine_with(Box::new(FakeRuntime::new())).0
+}
+
+// Engine on top of runtime, and the directory holding its mount dirs
+fn engine_with(runtime: Box<ContainerRuntime>) -> (ConsentEngine, PathBuf) {
+    let dir = env::temp_dir().join(format!("tolla-test-{}", Uuid::new_v4().simple()));
+    let mount_root = dir.join("certificates");
+
+    let engine = ConsentEngineBuilder::new()
+        .deamon(String::from("http://127.0.0.1:2375"))
+        .ca_pem(
+            dir.join("key.pem").to_string_lossy().into_owned(),
+            dir.join("cert.pem").to_string_lossy().into_owned(),
+        )
+        .mount_root(mount_root.to_string_lossy().into_owned())
+        .admin_token(String::from(ADMIN_TOKEN))
+        .build_with(Box::new(MemoryStore::new()), runtime)
+        .unwrap();
+    register_intents(&engine);
+
+    (engine, mount_root)
+}
+
+// Register the intents the tests request certificates for
+fn register_intents(engine: &ConsentEngine) {
+    for name in &["research", "statistics"] {
+        engine
+            .register_intent(&RegisteredIntent {
+                name: name.to_string(),
+                description: String::new(),
+            })
+            .unwrap();
+    }
+}
+
+// FakeRuntime with hooks to make starting containers fail, or
+// to change what inspecting them reports
+struct HookedRuntime {
+    inner: FakeRuntime,
+    before_start: Box<Fn() -> Result<(), TollaError> + Send>,
+    after_inspect: Box<Fn(&mut ContainerInfo) + Send>,
+}
+
+impl HookedRuntime {
+    fn new() -> HookedRuntime {
+        HookedRuntime {
+            inner: FakeRuntime::new(),
+            before_start: Box::new(|| Ok(())),
+            after_inspect: Box::new(|_| ()),
+        }
+    }
+}
+
+impl ContainerRuntime for HookedRuntime {
+    fn create(&self, spec: &ContainerSpec) -> Result<String, TollaError> {
+        self.inner.create(spec)
+    }
+
+    fn start(&self, id: &str) -> Result<(), TollaError> {
+        (self.before_start)()?;
+        self.inner.start(id)
+    }
+
+    fn restart(&self, id: &str) -> Result<(), TollaError> {
+        self.inner.restart(id)
+    }
+
+    fn inspect(&self, id: &str) -> Result<ContainerInfo, TollaError> {
+        let mut info = self.inner.inspect(id)?;
+        (self.after_inspect)(&mut info);
+        Ok(info)
+    }
+
+    fn remove(&self, id: &str) -> Result<(), TollaError> {
+        self.inner.remove(id)
+    }
+
+    fn list(&self) -> Result<Vec<ContainerInfo>, TollaError> {
+        self.inner.list()
+    }
+}
+
+// Fails to start the first failures containers
+fn flaky_runtime(failures: u32) -> HookedRuntime {
+    let failures = Mutex::new(failures);
+    let mut runtime = HookedRuntime::new();
+    runtime.before_start = Box::new(move || {
+        let mut failures = failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return Err(TollaError::Runtime(String::from("cannot start container")));
+        }
+        Ok(())
+    });
+    runtime
+}
+
+// Reports running containers at 10.1.0.2 once moved is set,
+// as if the deamon had given them new addresses
+fn moving_runtime(moved: Arc<AtomicBool>) -> HookedRuntime {
+    let mut runtime = HookedRuntime::new();
+    runtime.after_inspect = Box::new(move |info: &mut ContainerInfo| {
+        if info.running && moved.load(Ordering::SeqCst) {
+            info.ip = String::from("10.1.0.2");
+        }
+    });
+    runtime
+}
+
+// Consent of id to purposes, given now for the certificate with
+// serial_number, without a lifetime or flags
+fn consent(id: &str, serial_number: &str, purposes: &[&str]) -> Consent {
+    Consent {
+        id: String::from(id),
+        serial_number: String::from(serial_number),
+        purpose: purposes.iter().map(|p| String::from(*p)).collect(),
+        lifetime: 0,
+        marketing: false,
+        profiling: false,
+        public: false,
+        granted_at: consent::now(),
+        expired: false,
+    }
+}
+
+fn unique_user() -> String {
+    format!("user-{}", Uuid::new_v4().simple())
+}
+
+fn certificate_request(token: &str) -> proto::FromClient {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut req = X509ReqBuilder::new().unwrap();
+    req.set_pubkey(&key).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("C", "NO").unwrap();
+    name.append_entry_by_text("ST", "TR").unwrap();
+    name.append_entry_by_text("O", "IFI").unwrap();
+    name.append_entry_by_text("CN", "process").unwrap();
+    req.set_subject_name(&name.build()).unwrap();
+    req.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let mut msg = proto::FromClient::default();
+    msg.enrollment_token = String::from(token);
+    msg.msg = Some(proto::from_client::Msg::Certificaterequest(
+        proto::Certificate {
+            intents: vec![String::from("research")],
+            request: req.build().to_pem().unwrap(),
+            root_cert: Vec::new(),
+            users: Vec::new(),
+        },
+    ));
+    msg
+}
+
+fn is_unauthorized(resp: &proto::ToClient) -> bool {
+    match resp.msg {
+        Some(proto::to_client::Msg::Error(ref e)) => {
+            !resp.success && e.code == proto::ErrorCode::Unauthorized as i32
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn consent_roundtrip() {
+    let engine = engine();
+
+    engine.add_consent(&consent("alice", "2a", &["research"])).unwrap();
+
+    let consent = engine.consent_by_serial_num(&String::from("2a")).unwrap();
+    assert_eq!(consent.id, "alice");
+    assert!(engine.get_consent(String::from("bob")).is_err());
+}
+
+#[test]
+fn views_and_removal() {
+    let engine = engine();
+
+    engine
+        .register_view(&View {
+            id: String::from("alice"),
+            ip: String::from("172.17.0.3"),
+        })
+        .unwrap();
+    engine.add_consent(&consent("alice", "1", &[])).unwrap();
+
+    assert_eq!(engine.get_tenant_ips().unwrap(), vec![String::from("172.17.0.3")]);
+    assert_eq!(
+        engine.consent_based_view(&String::from("alice")).unwrap(),
+        "172.17.0.3"
+    );
+
+    engine.remove_user(&String::from("alice")).unwrap();
+    assert!(engine.get_tenant_ips().unwrap().is_empty());
+    assert!(engine.get_consent(String::from("alice")).is_err());
+}
+
+#[test]
+fn intents() {
+    let engine = engine();
+
+    engine
+        .add_intent(&Intent {
+            id: String::from("process"),
+            intent: vec![String::from("research")],
+        })
+        .unwrap();
+
+    let intent = engine.get_intent(&String::from("process")).unwrap();
+    assert_eq!(intent.intent, vec![String::from("research")]);
+}
+
+#[test]
+fn onboard_and_deboard_without_docker() {
+    let engine = engine();
+    let user = unique_user();
+
+    engine
+        .onboard_user(&user, vec![String::from("research")])
+        .unwrap();
+
+    let consent = engine.get_consent(user.clone()).unwrap();
+    assert_eq!(consent.purpose, vec![String::from("research")]);
+    assert!(!engine.consent_based_view(&user).unwrap().is_empty());
+    engine
+        .onboard_user(&user, vec![String::from("research")])
+        .unwrap();
+    match engine.onboard_user(&user, vec![]) {
+        Err(TollaError::AlreadyExists(_)) => (),
+        other => panic!("expected AlreadyExists, got {:?}", other),
+    }
+
+    engine.deboard_user(&user).unwrap();
+    match engine.get_consent(user.clone()) {
+        Err(TollaError::NotFound(_)) => (),
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+    assert!(engine.consent_based_view(&user).is_err());
+}
+
+#[test]
+fn user_ids_cannot_leave_the_mount_root() {
+    let (engine, mount_root) = engine_with(Box::new(FakeRuntime::new()));
+    let user = unique_user();
+    engine.onboard_user(&user, vec![]).unwrap();
+
+    for id in vec!["", "..", "../ca", "alice/..", "a b"] {
+        match engine.onboard_user(&String::from(id), vec![]) {
+            Err(TollaError::InvalidRequest(_)) => (),
+            other => panic!("expected InvalidRequest for {:?}, got {:?}", id, other),
+        }
+        match engine.deboard_user(&String::from(id)) {
+            Err(TollaError::InvalidRequest(_)) => (),
+            other => panic!("expected InvalidRequest for {:?}, got {:?}", id, other),
+        }
+    }
+    assert!(engine.onboard_user(&"x".repeat(65), vec![]).is_err());
+    assert!(mount_root.join(&user).exists());
+}
+
+#[test]
+fn certificate_request_needs_enrollment_token() {
+    let engine = engine();
+
+    let resp = engine.handle_incoming(certificate_request(""), None).unwrap();
+    assert!(is_unauthorized(&resp));
+
+    let token = engine.new_enrollment_token();
+    let resp = engine
+        .handle_incoming(certificate_request(&token), None)
+        .unwrap();
+    assert!(resp.success);
+
+    // Tokens can only be used once
+    let resp = engine
+        .handle_incoming(certificate_request(&token), None)
+        .unwrap();
+    assert!(is_unauthorized(&resp));
+}
+
+#[test]
+fn admin_requests_need_the_admin_token() {
+    let engine = engine();
+
+    assert!(engine.authorize_admin(Some(ADMIN_TOKEN)).is_ok());
+    match engine.authorize_admin(Some("admin")) {
+        Err(TollaError::Unauthorized(_)) => (),
+        other => panic!("expected Unauthorized, got {:?}", other),
+    }
+    match engine.authorize_admin(None) {
+        Err(TollaError::Unauthorized(_)) => (),
+        other => panic!("expected Unauthorized, got {:?}", other),
+    }
+}
+
+#[test]
+fn certificates_are_only_issued_for_registered_intents() {
+    let engine = engine();
+
+    let token = engine.new_enrollment_token();
+    let mut msg = certificate_request(&token);
+    if let Some(proto::from_client::Msg::Certificaterequest(ref mut req)) = msg.msg {
+        req.intents = vec![String::from("profiling")];
+    }
+    let resp = engine.handle_incoming(msg.clone(), None).unwrap();
+    assert_eq!(error_code(&resp), Some(proto::ErrorCode::Forbidden as i32));
+
+    let profiling = RegisteredIntent {
+        name: String::from("profiling"),
+        description: String::from("Tailored advertising"),
+    };
+    engine.register_intent(&profiling).unwrap();
+    assert!(engine.register_intent(&profiling).is_err());
+    assert_eq!(engine.registered_intents().unwrap().len(), 3);
+
+    let token = engine.new_enrollment_token();
+    msg.enrollment_token = token;
+    let resp = engine.handle_incoming(msg, None).unwrap();
+    assert!(resp.success);
+}
+
+fn error_code(resp: &proto::ToClient) -> Option<i32> {
+    match resp.msg {
+        Some(proto::to_client::Msg::Error(ref e)) if !resp.success => Some(e.code),
+        _ => None,
+    }
+}
+
+#[test]
+fn certificate_requests_are_checked_against_policy() {
+    let dir = env::temp_dir().join(format!("tolla-test-{}", Uuid::new_v4().simple()));
+    let engine = ConsentEngineBuilder::new()
+        .deamon(String::from("http://127.0.0.1:2375"))
+        .ca_pem(
+            dir.join("key.pem").to_string_lossy().into_owned(),
+            dir.join("cert.pem").to_string_lossy().into_owned(),
+        )
+        .mount_root(dir.join("certificates").to_string_lossy().into_owned())
+        .allow_organization(String::from("UIT"))
+        .allow_intent(String::from("research"))
+        .build_with(Box::new(MemoryStore::new()), Box::new(FakeRuntime::new()))
+        .unwrap();
+    register_intents(&engine);
+
+    // The request is for O=IFI
+    let token = engine.new_enrollment_token();
+    let resp = engine
+        .handle_incoming(certificate_request(&token), None)
+        .unwrap();
+    assert_eq!(error_code(&resp), Some(proto::ErrorCode::Forbidden as i32));
+
+    let token = engine.new_enrollment_token();
+    let mut msg = certificate_request(&token);
+    if let Some(proto::from_client::Msg::Certificaterequest(ref mut req)) = msg.msg {
+        req.request = b"garbage".to_vec();
+    }
+    let resp = engine.handle_incoming(msg, None).unwrap();
+    assert_eq!(error_code(&resp), Some(proto::ErrorCode::InvalidRequest as i32));
+}
+
+fn issued_certificate(resp: proto::ToClient) -> X509 {
+    match resp.msg {
+        Some(proto::to_client::Msg::Certificate(cert)) => X509::from_pem(&cert.request).unwrap(),
+        other => panic!("expected a certificate, got {:?}", other),
+    }
+}
+
+fn renewal(cert: &X509) -> proto::FromClient {
+    let mut msg = proto::FromClient::default();
+    msg.msg = Some(proto::from_client::Msg::Renewal(proto::Renewal {
+        certificate: cert.to_pem().unwrap(),
+        request: Vec::new(),
+    }));
+    msg
+}
+
+#[test]
+fn certificates_are_issued_for_several_intents() {
+    let engine = engine();
+
+    let token = engine.new_enrollment_token();
+    let mut msg = certificate_request(&token);
+    if let Some(proto::from_client::Msg::Certificaterequest(ref mut req)) = msg.msg {
+        req.intents.push(String::from("statistics"));
+    }
+    let cert = issued_certificate(engine.handle_incoming(msg, None).unwrap());
+
+    let serial_number = serial_to_hex(&cert.serial_number().to_bn().unwrap()).unwrap();
+    assert_eq!(
+        engine.get_certificate(&serial_number).unwrap().intents,
+        vec![String::from("research"), String::from("statistics")]
+    );
+
+    // The same intent twice is refused
+    let token = engine.new_enrollment_token();
+    let mut msg = certificate_request(&token);
+    if let Some(proto::from_client::Msg::Certificaterequest(ref mut req)) = msg.msg {
+        req.intents.push(String::from("research"));
+    }
+    let resp = engine.handle_incoming(msg, None).unwrap();
+    assert_eq!(error_code(&resp), Some(proto::ErrorCode::InvalidRequest as i32));
+}
+
+#[test]
+fn process_certificates_are_renewed_by_their_holder() {
+    let dir = env::temp_dir().join(format!("tolla-test-{}", Uuid::new_v4().simple()));
+    let engine = ConsentEngineBuilder::new()
+        .deamon(String::from("http://127.0.0.1:2375"))
+        .ca_pem(
+            dir.join("key.pem").to_string_lossy().into_owned(),
+            dir.join("cert.pem").to_string_lossy().into_owned(),
+        )
+        .mount_root(dir.join("certificates").to_string_lossy().into_owned())
+        .intent_validity(String::from("research"), 3600)
+        .intent_legal_basis(String::from("research"), LegalBasis::PublicTask)
+        .build_with(Box::new(MemoryStore::new()), Box::new(FakeRuntime::new()))
+        .unwrap();
+    register_intents(&engine);
+
+    let token = engine.new_enrollment_token();
+    let resp = engine
+        .handle_incoming(certificate_request(&token), None)
+        .unwrap();
+    let cert = issued_certificate(resp);
+    let old = serial_to_hex(&cert.serial_number().to_bn().unwrap()).unwrap();
+    let issued = engine.get_certificate(&old).unwrap();
+    assert!((issued.not_after - issued.not_before - 3600).abs() < 60);
+
+    // Someone else can not renew it
+    let peer = PeerIdentity {
+        common_name: String::from("process"),
+        fingerprint: String::new(),
+        serial_number: String::from("7"),
+    };
+    let resp = engine.handle_incoming(renewal(&cert), Some(&peer)).unwrap();
+    assert_eq!(error_code(&resp), Some(proto::ErrorCode::Forbidden as i32));
+
+    let peer = PeerIdentity::from_certificate(&cert).unwrap();
+    let resp = engine.handle_incoming(renewal(&cert), Some(&peer)).unwrap();
+    let renewed = issued_certificate(resp);
+    let new = serial_to_hex(&renewed.serial_number().to_bn().unwrap()).unwrap();
+    assert!(new != old);
+    let reissued = engine.get_certificate(&new).unwrap();
+    assert_eq!(reissued.subject, issued.subject);
+    assert_eq!(reissued.intents, vec![String::from("research")]);
+    let extension = IntentExtension::from_certificate(&renewed).unwrap().unwrap();
+    assert_eq!(extension.intents, vec![String::from("research")]);
+    assert_eq!(extension.legal_basis, LegalBasis::PublicTask);
+    assert_eq!(engine.get_certificate(&old).unwrap().status, CertificateStatus::Revoked);
+
+    // The old certificate is superseded and can not be renewed again
+    let resp = engine.handle_incoming(renewal(&cert), Some(&peer)).unwrap();
+    assert_eq!(error_code(&resp), Some(proto::ErrorCode::Forbidden as i32));
+
+    // Nor be presented to the endpoints any more
+    let verified = engine.verify_peer(&renewed.to_pem().unwrap()).unwrap();
+    assert_eq!(verified, PeerIdentity::from_certificate(&renewed).unwrap());
+    match engine.verify_peer(&cert.to_pem().unwrap()) {
+        Err(TollaError::Unauthorized(_)) => (),
+        other => panic!("expected Unauthorized, got {:?}", other),
+    }
+}
+
+#[test]
+fn control_messages_need_client_certificate() {
+    let engine = engine();
+
+    let mut msg = proto::FromClient::default();
+    msg.msg = Some(proto::from_client::Msg::Requestips(true));
+
+    let resp = engine.handle_incoming(msg.clone(), None).unwrap();
+    assert!(is_unauthorized(&resp));
+
+    let peer = PeerIdentity {
+        common_name: String::from("process"),
+        fingerprint: String::new(),
+        serial_number: String::from("7"),
+    };
+    let resp = engine.handle_incoming(msg.clone(), Some(&peer)).unwrap();
+    assert!(resp.success);
+
+    // Registering consent takes the admin token as well
+    let mut registration = proto::FromClient::default();
+    registration.msg = Some(proto::from_client::Msg::Consent(proto::Consent {
+        id: String::from("alice"),
+        purpose: vec![String::from("research")],
+        lifetime: 0,
+        marketing: false,
+        profiling: false,
+        public: false,
+    }));
+    let resp = engine.handle_incoming(registration.clone(), Some(&peer)).unwrap();
+    assert!(is_unauthorized(&resp));
+    assert!(engine.get_consent(String::from("alice")).is_err());
+    registration.admin_token = String::from(ADMIN_TOKEN);
+    let resp = engine.handle_incoming(registration, Some(&peer)).unwrap();
+    assert!(resp.success);
+
+    // Revoked certificates are turned away after the handshake
+    engine.revoke_certificate(&peer.serial_number, Reason::KeyCompromise).unwrap();
+    let resp = engine.handle_incoming(msg, Some(&peer)).unwrap();
+    assert!(is_unauthorized(&resp));
+}
+
+#[test]
+fn lease_honours_lifetime_and_flags() {
+    let engine = engine();
+    let now = consent::now();
+
+    let mut alice = consent("alice", "5", &["research"]);
+    alice.lifetime = 60;
+    alice.marketing = true;
+    alice.granted_at = now;
+    engine.add_consent(&alice).unwrap();
+    let mut bob = consent("bob", "6", &["research"]);
+    bob.lifetime = 60;
+    bob.granted_at = now - 120;
+    engine.add_consent(&bob).unwrap();
+
+    assert_eq!(engine.decide_lease(&String::from("5"), "research").unwrap(), Decision::Granted);
+    assert_eq!(engine.decide_lease(&String::from("5"), "marketing").unwrap(), Decision::Granted);
+    assert!(engine.decide_lease(&String::from("5"), "profiling").unwrap() != Decision::Granted);
+    assert!(engine.decide_lease(&String::from("6"), "research").unwrap() != Decision::Granted);
+
+    // A certificate for several intents needs consent to any of them
+    let alice = String::from("5");
+    let intents = vec![String::from("profiling"), String::from("research")];
+    assert_eq!(engine.decide_lease_any(&alice, &intents).unwrap(), Decision::Granted);
+    assert!(engine.decide_lease_any(&alice, &intents[..1]).unwrap() != Decision::Granted);
+    assert!(engine.decide_lease_any(&alice, &[]).unwrap() != Decision::Granted);
+
+    assert_eq!(engine.expire_consents().unwrap(), 1);
+    assert!(engine.get_consent(String::from("bob")).unwrap().expired);
+    assert_eq!(engine.expire_consents().unwrap(), 0);
+}
+
+#[test]
+fn lease_decisions_are_audited() {
+    let engine = engine();
+
+    engine.add_consent(&consent("alice", "9", &["research"])).unwrap();
+
+    engine.decide_lease(&String::from("9"), "research").unwrap();
+    engine.decide_lease(&String::from("9"), "marketing").unwrap();
+    assert!(engine.decide_lease(&String::from("a"), "research").is_err());
+
+    // Several intents are decided in a single entry
+    let intents = vec![String::from("marketing"), String::from("research")];
+    engine.decide_lease_any(&String::from("9"), &intents).unwrap();
+
+    let entries = engine.audit_entries(0, 10).unwrap();
+    assert_eq!(
+        entries.iter().map(|e| e.granted).collect::<Vec<bool>>(),
+        vec![true, false, false, true]
+    );
+    assert_eq!(entries[0].user, "alice");
+    assert_eq!(entries[2].certificate, "10");
+    assert_eq!(entries[3].intent, "marketing,research");
+    assert_eq!(engine.audit_entries(2, 10).unwrap().len(), 2);
+
+    assert_eq!(engine.verify_audit_log().unwrap(), 4);
+}
+
+#[test]
+fn consent_history_is_append_only() {
+    let engine = engine();
+    let alice = String::from("alice");
+    let before = consent::now() - 1;
+
+    engine.add_consent(&consent(&alice, "3", &["research"])).unwrap();
+
+    engine
+        .withdraw_purposes(&alice, vec![String::from("research")])
+        .unwrap();
+    engine
+        .grant_purposes(&alice, vec![String::from("statistics"), String::from("marketing")])
+        .unwrap();
+
+    let consent = engine.get_consent(alice.clone()).unwrap();
+    assert_eq!(consent.purpose, vec![String::from("statistics")]);
+    assert!(consent.marketing);
+    assert!(engine.grant_purposes(&String::from("bob"), vec![]).is_err());
+
+    engine.remove_user(&alice).unwrap();
+    assert!(engine.get_consent(alice.clone()).is_err());
+
+    let history = engine.consent_history(&alice).unwrap();
+    assert_eq!(
+        history.iter().map(|e| e.version).collect::<Vec<i64>>(),
+        vec![1, 2, 3, 4]
+    );
+    assert!(engine.consent_as_of(&alice, before).unwrap().is_none());
+    assert!(engine.consent_as_of(&alice, consent::now()).unwrap().is_none());
+}
+
+#[test]
+fn failed_onboarding_is_rolled_back() {
+    let (engine, mount_root) = engine_with(Box::new(flaky_runtime(1)));
+    let user = unique_user();
+
+    match engine.onboard_user(&user, vec![String::from("research")]) {
+        Err(TollaError::Runtime(_)) => (),
+        other => panic!("expected Runtime, got {:?}", other),
+    }
+    assert!(engine.get_consent(user.clone()).is_err());
+    assert!(engine.consent_based_view(&user).is_err());
+    assert!(!mount_root.join(&user).exists());
+
+    // Nothing was left behind to conflict with a retry
+    engine
+        .onboard_user(&user, vec![String::from("research")])
+        .unwrap();
+    assert!(mount_root.join(&user).exists());
+    assert!(engine.repair().unwrap().is_empty());
+}
+
+#[test]
+fn repair_cleans_up_half_onboarded_users() {
+    let (engine, mount_root) = engine_with(Box::new(FakeRuntime::new()));
+    let onboarded = unique_user();
+    let with_view = unique_user();
+    let with_dir = unique_user();
+
+    engine.onboard_user(&onboarded, vec![]).unwrap();
+    engine
+        .register_view(&View {
+            id: with_view.clone(),
+            ip: String::from("10.0.0.9"),
+        })
+        .unwrap();
+    fs::create_dir_all(mount_root.join(&with_dir)).unwrap();
+
+    let mut expected = vec![with_view.clone(), with_dir.clone()];
+    expected.sort();
+    assert_eq!(engine.repair().unwrap(), expected);
+
+    assert!(engine.consent_based_view(&with_view).is_err());
+    assert!(!mount_root.join(&with_dir).exists());
+    assert!(engine.consent_based_view(&onboarded).is_ok());
+    assert!(mount_root.join(&onboarded).exists());
+    assert!(engine.repair().unwrap().is_empty());
+}
+
+#[test]
+fn revoked_certificates_are_denied_and_listed() {
+    let engine = engine();
+    let user = unique_user();
+
+    engine
+        .onboard_user(&user, vec![String::from("research")])
+        .unwrap();
+    let serial_num = engine.get_consent(user.clone()).unwrap().serial_number;
+    // 128 random bits with the top bit set
+    assert_eq!(serial_num.len(), 32);
+    assert_eq!(engine.decide_lease(&serial_num, "research").unwrap(), Decision::Granted);
+
+    engine
+        .revoke_certificate(&serial_num, Reason::KeyCompromise)
+        .unwrap();
+    match engine.revoke_certificate(&serial_num, Reason::KeyCompromise) {
+        Err(TollaError::AlreadyExists(_)) => (),
+        other => panic!("expected AlreadyExists, got {:?}", other),
+    }
+    assert_eq!(
+        engine.decide_lease(&serial_num, "research").unwrap(),
+        Decision::Denied(String::from("Certificate revoked"))
+    );
+
+    let crl = X509Crl::from_der(&engine.crl().unwrap()).unwrap();
+    let serial = BigNum::from_hex_str(&serial_num).unwrap().to_asn1_integer().unwrap();
+    assert!(crl.get_by_serial(&serial).is_some());
+
+    // Deboarding a user with a revoked certificate still works
+    engine.deboard_user(&user).unwrap();
+}
+
+#[test]
+fn deboarding_revokes_the_database_certificate() {
+    let engine = engine();
+    let user = unique_user();
+
+    engine.onboard_user(&user, vec![]).unwrap();
+    let serial_num = engine.get_consent(user.clone()).unwrap().serial_number;
+    engine.deboard_user(&user).unwrap();
+
+    let crl = X509Crl::from_der(&engine.crl().unwrap()).unwrap();
+    let serial = BigNum::from_hex_str(&serial_num).unwrap().to_asn1_integer().unwrap();
+    assert!(crl.get_by_serial(&serial).is_some());
+}
+
+#[test]
+fn ocsp_responder_reports_revocations() {
+    let engine = engine();
+
+    let token = engine.new_enrollment_token();
+    let resp = engine
+        .handle_incoming(certificate_request(&token), None)
+        .unwrap();
+    let issued = match resp.msg {
+        Some(proto::to_client::Msg::Certificate(cert)) => cert,
+        other => panic!("expected a certificate, got {:?}", other),
+    };
+    let cert = X509::from_pem(&issued.request).unwrap();
+
+    // The chain is the issuing intermediate followed by the root
+    let chain = X509::stack_from_pem(&issued.root_cert).unwrap();
+    assert_eq!(chain.len(), 2);
+    assert!(chain[0].issued(&cert).is_ok());
+    assert!(chain[1].issued(&chain[0]).is_ok());
+    let issuer = chain[0].clone();
+
+    let mut store = X509StoreBuilder::new().unwrap();
+    for ca_cert in chain {
+        store.add_cert(ca_cert).unwrap();
+    }
+    let store = store.build();
+
+    let check_status = |engine: &ConsentEngine, expected: ocsp::OcspCertStatus| {
+        let mut req = OcspRequest::new().unwrap();
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &issuer).unwrap();
+        req.add_id(id).unwrap();
+        req.add_nonce().unwrap();
+
+        let der = engine.ocsp_response(&req.to_der().unwrap()).unwrap();
+        let resp = OcspResponse::from_der(&der).unwrap();
+        let basic = resp.basic().unwrap();
+        basic.verify(&Stack::<X509>::new().unwrap(), &store, Flag::empty()).unwrap();
+        assert!(req.check_nonce(&basic));
+
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &issuer).unwrap();
+        assert_eq!(basic.find_status(&id).unwrap().status, expected);
+    };
+
+    check_status(&engine, ocsp::CERT_STATUS_GOOD);
+
+    let serial_num = serial_to_hex(&cert.serial_number().to_bn().unwrap()).unwrap();
+    engine
+        .revoke_certificate(&serial_num, Reason::KeyCompromise)
+        .unwrap();
+    check_status(&engine, ocsp::CERT_STATUS_REVOKED);
+
+    // Requests that cannot be parsed get an OCSP error back
+    let der = engine.ocsp_response(b"garbage").unwrap();
+    let resp = OcspResponse::from_der(&der).unwrap();
+    assert_eq!(resp.status(), ocsp::RESPONSE_STATUS_MALFORMED_REQUEST);
+}
+
+#[test]
+fn issued_certificates_are_inventoried() {
+    let engine = engine();
+    let user = unique_user();
+
+    engine.onboard_user(&user, vec![]).unwrap();
+    let token = engine.new_enrollment_token();
+    let resp = engine
+        .handle_incoming(certificate_request(&token), None)
+        .unwrap();
+    assert!(resp.success);
+
+    let mut query = CertificateQuery::default();
+    query.intent = Some(String::from("research"));
+    let found = engine.find_certificates(&query).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].requester, format!("token:{}", consent::enrollment_token_id(&token)));
+    assert!(!found[0].requester.contains(&token));
+    assert!(found[0].subject.contains("CN=process"));
+    assert!(!found[0].subject_key_id.is_empty());
+
+    let mut query = CertificateQuery::default();
+    query.requester = Some(format!("user:{}", user));
+    let database = engine.find_certificates(&query).unwrap();
+    assert_eq!(database.len(), 1);
+
+    // Both are valid for a year
+    let mut query = CertificateQuery::default();
+    query.expires_within = Some(7 * 24 * 60 * 60);
+    assert!(engine.find_certificates(&query).unwrap().is_empty());
+    query.expires_within = Some(400 * 24 * 60 * 60);
+    assert_eq!(engine.find_certificates(&query).unwrap().len(), 2);
+
+    // Deboarding revokes the database certificate
+    engine.deboard_user(&user).unwrap();
+    let serial_number = &database[0].serial_number;
+    assert_eq!(
+        engine.get_certificate(serial_number).unwrap().status,
+        CertificateStatus::Revoked
+    );
+
+    match engine.get_certificate(&String::from("1")) {
+        Err(TollaError::NotFound(_)) => (),
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+}
+
+#[test]
+fn database_certificates_follow_the_container_address() {
+    let moved = Arc::new(AtomicBool::new(false));
+    let (engine, mount_root) = engine_with(Box::new(moving_runtime(moved.clone())));
+    let user = unique_user();
+    engine.onboard_user(&user, vec![]).unwrap();
+
+    // A user whose container is gone does not stop the others
+    let gone = unique_user();
+    engine.add_consent(&consent(&gone, "1", &[])).unwrap();
+    engine
+        .register_view(&View {
+            id: gone.clone(),
+            ip: String::from("10.0.0.254"),
+        })
+        .unwrap();
+
+    let certificate = || {
+        let mut pem = Vec::new();
+        File::open(mount_root.join(&user).join("certificate.pem"))
+            .unwrap()
+            .read_to_end(&mut pem)
+            .unwrap();
+        X509::from_pem(&pem).unwrap()
+    };
+
+    // Issued for the container's name and address
+    let cert = certificate();
+    let names = cert.subject_alt_names().unwrap();
+    let ip = engine.consent_based_view(&user).unwrap();
+    assert_eq!(names[0].dnsname(), Some(user.as_str()));
+    assert_eq!(names[1].ipaddress(), Some(&ip.parse::<Ipv4Addr>().unwrap().octets()[..]));
+    assert!(engine.refresh_db_certificates().unwrap().is_empty());
+
+    moved.store(true, Ordering::SeqCst);
+    assert_eq!(engine.refresh_db_certificates().unwrap(), vec![user.clone()]);
+    assert_eq!(engine.consent_based_view(&user).unwrap(), "10.1.0.2");
+
+    let renewed = certificate();
+    let names = renewed.subject_alt_names().unwrap();
+    assert_eq!(names[1].ipaddress(), Some(&[10, 1, 0, 2][..]));
+
+    // The consent points at the new certificate, the old one is revoked
+    let old = serial_to_hex(&cert.serial_number().to_bn().unwrap()).unwrap();
+    let new = serial_to_hex(&renewed.serial_number().to_bn().unwrap()).unwrap();
+    assert_eq!(engine.get_consent(user.clone()).unwrap().serial_number, new);
+    assert_eq!(engine.get_certificate(&old).unwrap().status, CertificateStatus::Revoked);
+    assert!(engine.refresh_db_certificates().unwrap().is_empty());
+}
+
+#[test]
+fn unwritten_database_certificates_are_revoked() {
+    // Puts a directory where the certificate file goes
+    let blocked: Arc<Mutex<Option<PathBuf>>> = Arc::new(Mutex::new(None));
+    let hook = blocked.clone();
+    let mut runtime = HookedRuntime::new();
+    runtime.before_start = Box::new(move || {
+        if let Some(ref path) = *hook.lock().unwrap() {
+            fs::create_dir_all(path).unwrap();
+        }
+        Ok(())
+    });
+
+    let (engine, mount_root) = engine_with(Box::new(runtime));
+    let user = unique_user();
+    *blocked.lock().unwrap() = Some(mount_root.join(&user).join("certificate.pem"));
+
+    match engine.onboard_user(&user, vec![]) {
+        Err(TollaError::Runtime(_)) => (),
+        other => panic!("expected Runtime, got {:?}", other),
+    }
+
+    let mut query = CertificateQuery::default();
+    query.requester = Some(format!("user:{}", user));
+    let issued = engine.find_certificates(&query).unwrap();
+    assert_eq!(issued.len(), 1);
+    assert_eq!(issued[0].status, CertificateStatus::Revoked);
+}
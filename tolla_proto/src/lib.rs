@@ -1,9 +1,79 @@
 #[cfg(test)]
 mod tests {
+    use bytes::BytesMut;
+    use tokio_io::codec::{Decoder, Encoder};
+    use proto::{self, ProtoClient, ProtoCodec};
+
+    fn user_request(name: &str) -> proto::FromClient {
+        let mut msg = proto::FromClient::default();
+        msg.msg = Some(proto::from_client::Msg::User(proto::NewUser {
+            userid: String::from(name),
+            email: String::from("user@example.com"),
+        }));
+        msg
+    }
+
+    fn encoded(msgs: Vec<proto::FromClient>) -> BytesMut {
+        let mut client = ProtoClient::new();
+        let mut buf = BytesMut::new();
+        for msg in msgs {
+            client.encode(msg, &mut buf).unwrap();
+        }
+        buf
+    }
+
     #[test]
-    fn it_works() {
+    fn partial_frame_waits_for_more_bytes() {
+        let full = encoded(vec![user_request("alice")]);
+        let mut codec = ProtoCodec::new();
+
+        let mut buf = BytesMut::from(&full[..full.len() - 1]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&full[full.len() - 1..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(user_request("alice")));
+        assert!(buf.is_empty());
     }
 
+    #[test]
+    fn pipelined_frames_are_decoded_in_order() {
+        let mut buf = encoded(vec![user_request("alice"), user_request("bob")]);
+        let mut codec = ProtoCodec::new();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(user_request("alice")));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(user_request("bob")));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_frame_is_an_error() {
+        // length 2 followed by a field header without its value
+        let mut buf = BytesMut::from(&[0x02, 0x22, 0x05][..]);
+        assert!(ProtoCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let mut buf = encoded(vec![user_request("alice")]);
+        assert!(ProtoCodec::with_max_frame_length(4).decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::new();
+        let mut client = ProtoClient::with_max_frame_length(4);
+        assert!(client.encode(user_request("alice"), &mut buf).is_err());
+    }
+
+    #[test]
+    fn overlong_length_prefix_is_rejected() {
+        // More than 64 bits of length
+        let mut buf = BytesMut::from(&[0xFF; 9][..]);
+        buf.extend_from_slice(&[0x7F]);
+        assert!(ProtoCodec::new().decode(&mut buf).is_err());
+
+        // More than 10 bytes of length
+        let mut buf = BytesMut::from(&[0x80; 10][..]);
+        buf.extend_from_slice(&[0x00]);
+        assert!(ProtoCodec::new().decode(&mut buf).is_err());
+    }
 }
 
 extern crate bytes;
@@ -22,30 +92,168 @@ pub mod proto {
     use tokio_proto::pipeline::ServerProto;
     use tokio_proto::pipeline::ClientProto;
     use tokio_io::codec::{Encoder, Decoder};
-    use bytes::BytesMut;
+    use bytes::{BufMut, BytesMut};
     use std::io;
 
-    pub struct ProtoCodec;
-    pub struct ProtoClient;
-    pub struct ProtoProto;
+    // Frames larger than this are rejected unless configured otherwise
+    pub const DEFAULT_MAX_FRAME_LENGTH: usize = 4 * 1024 * 1024;
+
+    // A varint never needs more than 10 bytes for a u64
+    const MAX_VARINT_LENGTH: usize = 10;
+
+    // Server side codec: decodes FromClient, encodes ToClient
+    pub struct ProtoCodec {
+        max_frame_length: usize,
+    }
+
+    // Client side codec: decodes ToClient, encodes FromClient
+    pub struct ProtoClient {
+        max_frame_length: usize,
+    }
+
+    pub struct ProtoProto {
+        max_frame_length: usize,
+    }
+
+    impl ProtoCodec {
+        pub fn new() -> ProtoCodec {
+            ProtoCodec::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+        }
+
+        pub fn with_max_frame_length(max_frame_length: usize) -> ProtoCodec {
+            ProtoCodec { max_frame_length: max_frame_length }
+        }
+    }
+
+    impl ProtoClient {
+        pub fn new() -> ProtoClient {
+            ProtoClient::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+        }
+
+        pub fn with_max_frame_length(max_frame_length: usize) -> ProtoClient {
+            ProtoClient { max_frame_length: max_frame_length }
+        }
+    }
+
+    impl ProtoProto {
+        pub fn new() -> ProtoProto {
+            ProtoProto::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+        }
+
+        pub fn with_max_frame_length(max_frame_length: usize) -> ProtoProto {
+            ProtoProto { max_frame_length: max_frame_length }
+        }
+    }
+
+    // Reads a varint from the start of buf. Returns the value and the
+    // number of bytes it occupied, or None if buf ends before the varint.
+    fn peek_varint(buf: &[u8]) -> io::Result<Option<(u64, usize)>> {
+        let mut value: u64 = 0;
+        for (i, byte) in buf.iter().enumerate() {
+            if i == MAX_VARINT_LENGTH {
+                break;
+            }
+            let bits = (byte & 0x7F) as u64;
+            // The last byte only has room for the top bit of a u64
+            if i == MAX_VARINT_LENGTH - 1 && bits > 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "frame length prefix overflows",
+                ));
+            }
+            value |= bits << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(Some((value, i + 1)));
+            }
+        }
+
+        if buf.len() >= MAX_VARINT_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid frame length prefix",
+            ));
+        }
+        Ok(None)
+    }
+
+    fn put_varint(mut value: u64, buf: &mut BytesMut) {
+        loop {
+            if value < 0x80 {
+                buf.put_u8(value as u8);
+                return;
+            }
+            buf.put_u8(((value & 0x7F) | 0x80) as u8);
+            value >>= 7;
+        }
+    }
+
+    // Decodes one varint length prefixed message from buf, leaving any
+    // following bytes in place. Returns None until the whole frame is read.
+    pub fn decode_frame<M: Message + Default>(
+        buf: &mut BytesMut,
+        max_frame_length: usize,
+    ) -> io::Result<Option<M>> {
+        let (length, prefix) = match peek_varint(&buf[..])? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        if length > max_frame_length as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes exceeds maximum of {}",
+                    length,
+                    max_frame_length
+                ),
+            ));
+        }
+
+        let length = length as usize;
+        if buf.len() < prefix + length {
+            buf.reserve(prefix + length - buf.len());
+            return Ok(None);
+        }
+
+        buf.split_to(prefix);
+        let frame = buf.split_to(length).freeze();
+
+        M::decode(frame).map(Some).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+        })
+    }
+
+    // Encodes msg into buf prefixed with its length as a varint
+    pub fn encode_frame<M: Message>(
+        msg: &M,
+        buf: &mut BytesMut,
+        max_frame_length: usize,
+    ) -> io::Result<()> {
+        let length = msg.encoded_len();
+        if length > max_frame_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes exceeds maximum of {}",
+                    length,
+                    max_frame_length
+                ),
+            ));
+        }
+
+        buf.reserve(MAX_VARINT_LENGTH + length);
+        put_varint(length as u64, buf);
+        msg.encode(buf).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+        })
+    }
 
     impl Decoder for ProtoClient {
         type Item = ToClient;
         type Error = io::Error;
 
         fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<ToClient>> {
-            let cbuf = buf.clone();
-            // Hack - decode doesnt seem to
-            // return an error if buf i empty
-            if buf.len() == 0 {
-                return Ok(None);
-            }
-            let msg = match ToClient::decode(cbuf) {
-                Err(_) => return Ok(None),
-                Ok(msg) => msg,
-            };
-            buf.clear();
-            Ok(Some(msg))
+            decode_frame(buf, self.max_frame_length)
         }
     }
 
@@ -54,12 +262,7 @@ pub mod proto {
         type Error = io::Error;
 
         fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
-            if let Err(err) = msg.encode(buf) {
-                return {
-                    Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
-                };
-            }
-            Ok(())
+            encode_frame(&msg, buf, self.max_frame_length)
         }
     }
 
@@ -68,18 +271,7 @@ pub mod proto {
         type Error = io::Error;
 
         fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<FromClient>> {
-            let cbuf = buf.clone();
-            // Hack - decode doesnt seem to
-            // return an error if buf i empty
-            if buf.len() == 0 {
-                return Ok(None);
-            }
-            let msg = match FromClient::decode(cbuf) {
-                Err(_) => return Ok(None),
-                Ok(msg) => msg,
-            };
-            buf.clear();
-            Ok(Some(msg))
+            decode_frame(buf, self.max_frame_length)
         }
     }
 
@@ -88,12 +280,7 @@ pub mod proto {
         type Error = io::Error;
 
         fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
-            if let Err(err) = msg.encode(buf) {
-                return {
-                    Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
-                };
-            }
-            Ok(())
+            encode_frame(&msg, buf, self.max_frame_length)
         }
     }
 
@@ -104,7 +291,7 @@ pub mod proto {
         type Transport = Framed<T, ProtoCodec>;
         type BindTransport = Result<Self::Transport, io::Error>;
         fn bind_transport(&self, io: T) -> Self::BindTransport {
-            Ok(io.framed(ProtoCodec))
+            Ok(io.framed(ProtoCodec::with_max_frame_length(self.max_frame_length)))
         }
     }
 
@@ -116,7 +303,7 @@ pub mod proto {
         type BindTransport = Result<Self::Transport, io::Error>;
 
         fn bind_transport(&self, io: T) -> Self::BindTransport {
-            Ok(io.framed(ProtoCodec))
+            Ok(io.framed(ProtoCodec::with_max_frame_length(self.max_frame_length)))
         }
     }
 }